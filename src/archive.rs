@@ -1,23 +1,349 @@
-use std::fmt::Debug;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
+use std::fmt::{self, Debug};
 use std::fs;
-use std::io::{self, BufReader, Read, Write};
-use std::path::{Path, PathBuf};
+use std::io::{self, BufReader, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use failure::Error;
-use indicatif::{ProgressBar, ProgressBarRead, ProgressStyle};
-use tree_magic;
+use console::Term;
+use filetime::{set_file_mtime, FileTime};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use log::{debug, trace};
+#[cfg(feature = "hashes")]
+use md5::Md5;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+#[cfg(feature = "hashes")]
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use strum_macros::EnumString;
 use uuid::Uuid;
 
-use crate::utils::{rename_resolving_conflict, TempDirectory};
+use crate::error::{bail, UnboxError};
+use crate::utils::{
+    apply_conflict_suffix, is_escaping_symlink, is_root, merge_into, rename_resolving_conflict,
+    resolve_conflict, safe_join, sync_dir, TempDirectory,
+};
 
-pub fn copy_with_progress<R: ?Sized, W: ?Sized>(
-    progress: &ProgressBar,
+/// Throttles IO to a maximum number of bytes per second.
+///
+/// Clones share the same budget.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    state: Arc<Mutex<(Instant, u64)>>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter for the given bytes per second.
+    pub fn new(bytes_per_sec: u64) -> RateLimiter {
+        RateLimiter {
+            bytes_per_sec: bytes_per_sec.max(1),
+            state: Arc::new(Mutex::new((Instant::now(), 0))),
+        }
+    }
+
+    /// Accounts for processed bytes and sleeps if we are ahead of the limit.
+    pub fn throttle(&self, bytes: u64) {
+        if let Some(delay) = self.delay(bytes, Instant::now()) {
+            thread::sleep(delay);
+        }
+    }
+
+    /// Accounts for processed bytes at the given time and returns how long
+    /// to wait to get back to the limit.
+    fn delay(&self, bytes: u64, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        state.1 += bytes;
+        let target = Duration::from_secs_f64(state.1 as f64 / self.bytes_per_sec as f64);
+        target.checked_sub(now.saturating_duration_since(state.0))
+    }
+}
+
+/// Enforces an upper limit on the number of decompressed bytes to guard
+/// against decompression bombs.
+///
+/// Clones share the same budget.
+#[derive(Debug, Clone)]
+pub struct SizeLimit {
+    max_size: u64,
+    used: Arc<AtomicU64>,
+}
+
+impl SizeLimit {
+    /// Creates a size limit for the given number of bytes.
+    pub fn new(max_size: u64) -> SizeLimit {
+        SizeLimit {
+            max_size,
+            used: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Accounts for decompressed bytes and fails once the limit is exceeded.
+    pub fn account(&self, bytes: u64) -> io::Result<()> {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if used > self.max_size {
+            Err(io::Error::other(format!(
+                "decompression limit exceeded (max {})",
+                HumanBytes(self.max_size)
+            )))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A reader that fails once more than the allowed number of bytes were
+/// read.
+#[derive(Debug)]
+pub struct SizeLimitedRead<R> {
+    rdr: R,
+    size_limit: Option<SizeLimit>,
+}
+
+impl<R: Read> Read for SizeLimitedRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.rdr.read(buf)?;
+        if let Some(ref size_limit) = self.size_limit {
+            size_limit.account(len as u64)?;
+        }
+        Ok(len)
+    }
+}
+
+/// Receives progress updates while an archive is unpacked.
+///
+/// The command line utility draws a progress bar with `IndicatifProgress`,
+/// library users can pass their own implementation to
+/// `UnpackHelper::create_with_progress`.
+pub trait ProgressReporter: Debug + Send + Sync {
+    /// Called for every entry that is unpacked.
+    fn on_file(&self, path: &Path);
+
+    /// Called when bytes were read from the archive or written to an
+    /// unpacked file.
+    fn on_bytes(&self, n: u64);
+
+    /// Called once unpacking finished.
+    fn on_finish(&self);
+
+    /// Prints a line (such as the path of an entry in verbose mode)
+    /// without garbling the progress display.
+    fn println(&self, line: &str) {
+        eprintln!("{}", line);
+    }
+}
+
+/// A progress reporter that ignores all updates.
+///
+/// This includes the lines printed through it, so warnings are dropped
+/// as well.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoProgress;
+
+impl ProgressReporter for NoProgress {
+    fn on_file(&self, _path: &Path) {}
+    fn on_bytes(&self, _n: u64) {}
+    fn on_finish(&self) {}
+    fn println(&self, _line: &str) {}
+}
+
+/// A reader that can also seek.
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// A stream an archive was opened from instead of a file.
+///
+/// Unlike a file it cannot be opened again, so it can only be read once.
+pub struct StreamSource(Mutex<Option<Box<dyn Read>>>);
+
+impl StreamSource {
+    pub fn new<R: Read + 'static>(rdr: R) -> StreamSource {
+        StreamSource(Mutex::new(Some(Box::new(rdr))))
+    }
+}
+
+impl Debug for StreamSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("StreamSource")
+    }
+}
+
+/// Opens the file of an archive or takes the stream it was opened from.
+pub fn open_source(
+    path: &Path,
+    source: Option<&StreamSource>,
+) -> Result<Box<dyn Read>, UnboxError> {
+    match source {
+        Some(source) => match source.0.lock().unwrap().take() {
+            Some(rdr) => Ok(rdr),
+            None => bail!("the archive was opened from a stream which can only be read once"),
+        },
+        None => Ok(Box::new(fs::File::open(path)?)),
+    }
+}
+
+/// A reader that reports the bytes read to a progress reporter.
+#[derive(Debug)]
+pub struct ProgressRead<R> {
+    rdr: R,
+    progress: Arc<dyn ProgressReporter>,
+}
+
+impl<R: Read> Read for ProgressRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.rdr.read(buf)?;
+        self.progress.on_bytes(len as u64);
+        Ok(len)
+    }
+}
+
+/// A reader that honors an optional rate limit.
+#[derive(Debug)]
+pub struct LimitedRead<R> {
+    rdr: R,
+    limiter: Option<RateLimiter>,
+}
+
+impl<R: Read> Read for LimitedRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.rdr.read(buf)?;
+        if let Some(ref limiter) = self.limiter {
+            limiter.throttle(len as u64);
+        }
+        Ok(len)
+    }
+}
+
+/// A reader that hashes everything read through it when deduplicating.
+struct HashRead<R> {
+    rdr: R,
+    hasher: Option<Sha256>,
+}
+
+impl<R: Read> HashRead<R> {
+    fn new(rdr: R, enabled: bool) -> HashRead<R> {
+        HashRead {
+            rdr,
+            hasher: if enabled { Some(Sha256::new()) } else { None },
+        }
+    }
+
+    fn digest(self) -> Option<[u8; 32]> {
+        self.hasher.map(|x| x.finalize().into())
+    }
+}
+
+impl<R: Read> Read for HashRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.rdr.read(buf)?;
+        if let Some(ref mut hasher) = self.hasher {
+            hasher.update(&buf[..len]);
+        }
+        Ok(len)
+    }
+}
+
+/// A hash algorithm for the digests of unpacked files.
+///
+/// Only sha256 is available without the `hashes` feature.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, EnumString)]
+pub enum HashAlgorithm {
+    #[strum(serialize = "md5")]
+    Md5,
+    #[strum(serialize = "sha1")]
+    Sha1,
+    #[strum(serialize = "sha256")]
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Returns `true` if digests of this algorithm can be computed.
+    pub fn is_supported(self) -> bool {
+        cfg!(feature = "hashes") || self == HashAlgorithm::Sha256
+    }
+
+    fn hasher(self) -> Box<dyn sha2::digest::DynDigest> {
+        match self {
+            #[cfg(feature = "hashes")]
+            HashAlgorithm::Md5 => Box::new(Md5::new()),
+            #[cfg(feature = "hashes")]
+            HashAlgorithm::Sha1 => Box::new(Sha1::new()),
+            HashAlgorithm::Sha256 => Box::new(Sha256::new()),
+            #[cfg(not(feature = "hashes"))]
+            _ => unreachable!("checked when the unpack helper is created"),
+        }
+    }
+}
+
+/// A reader that computes the digests of everything read through it.
+pub struct DigestRead<R> {
+    rdr: R,
+    hashers: Vec<(HashAlgorithm, Box<dyn sha2::digest::DynDigest>)>,
+    consumed: bool,
+}
+
+impl<R: Read> DigestRead<R> {
+    pub fn new(rdr: R, algorithms: &[HashAlgorithm]) -> DigestRead<R> {
+        DigestRead {
+            rdr,
+            hashers: algorithms.iter().map(|&x| (x, x.hasher())).collect(),
+            consumed: false,
+        }
+    }
+
+    /// Returns the hex digests, or `None` if nothing was read at all.
+    pub fn finish(self) -> Option<Vec<(HashAlgorithm, String)>> {
+        if !self.consumed {
+            return None;
+        }
+        Some(
+            self.hashers
+                .into_iter()
+                .map(|(algorithm, hasher)| {
+                    let digest = hasher.finalize();
+                    let hex = digest.iter().map(|x| format!("{:02x}", x)).collect();
+                    (algorithm, hex)
+                })
+                .collect(),
+        )
+    }
+}
+
+impl<R: Read> Read for DigestRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.rdr.read(buf)?;
+        self.consumed = true;
+        for (_, hasher) in self.hashers.iter_mut() {
+            hasher.update(&buf[..len]);
+        }
+        Ok(len)
+    }
+}
+
+/// Identifies files that can share their contents when deduplicating.
+///
+/// Hardlinked files share their metadata as well, so the restored mode and
+/// modification time are part of it.
+type DedupKey = ([u8; 32], Option<u32>, Option<(u32, u32)>, Option<FileTime>);
+
+pub fn copy_with_progress<R, W>(
+    progress: &dyn ProgressReporter,
+    limiter: Option<&RateLimiter>,
+    size_limit: Option<&SizeLimit>,
     reader: &mut R,
     writer: &mut W,
 ) -> io::Result<u64>
 where
-    R: Read,
-    W: Write,
+    R: Read + ?Sized,
+    W: Write + ?Sized,
 {
     let mut buf = [0; 131_072];
     let mut written = 0;
@@ -28,9 +354,69 @@ where
             Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
             Err(e) => return Err(e),
         };
+        if let Some(size_limit) = size_limit {
+            size_limit.account(len as u64)?;
+        }
         writer.write_all(&buf[..len])?;
         written += len as u64;
-        progress.inc(len as u64);
+        progress.on_bytes(len as u64);
+        if let Some(limiter) = limiter {
+            limiter.throttle(len as u64);
+        }
+    }
+}
+
+/// The kind of an entry in an archive.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    /// A regular file.
+    File,
+    /// A directory.
+    Directory,
+    /// Anything else such as symlinks or device nodes.
+    Other,
+}
+
+/// An entry in an archive as reported by `Archive::entries`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    /// The path of the entry within the archive.
+    pub name: String,
+    /// The uncompressed size in bytes.
+    pub size: u64,
+    /// What kind of entry this is.
+    pub kind: EntryKind,
+    /// The compression method for formats compressing entries one by one.
+    pub compression: Option<String>,
+    /// The compressed size in bytes if known.
+    pub compressed_size: Option<u64>,
+    /// The checksum of the contents if the format stores one.
+    pub crc32: Option<u32>,
+    /// The modification time if known.
+    pub mtime: Option<FileTime>,
+    /// The comment stored with the entry.
+    pub comment: Option<String>,
+    /// `true` if the name is also stored in a unicode path extra field
+    /// (only set for zip archives).
+    pub unicode_path: bool,
+}
+
+impl ArchiveEntry {
+    /// Creates an entry.  Trailing slashes of directory names are removed.
+    ///
+    /// The details beyond the size are left empty.
+    pub fn new<S: AsRef<str>>(name: S, size: u64, kind: EntryKind) -> ArchiveEntry {
+        ArchiveEntry {
+            name: name.as_ref().trim_end_matches('/').to_string(),
+            size,
+            kind,
+            compression: None,
+            compressed_size: None,
+            crc32: None,
+            mtime: None,
+            comment: None,
+            unicode_path: false,
+        }
     }
 }
 
@@ -38,132 +424,2590 @@ pub trait Archive: Debug {
     /// The path to the archive.
     fn path(&self) -> &Path;
 
+    /// The files the archive was read from.
+    ///
+    /// This is the path of the archive unless it is split over several
+    /// volumes.
+    fn volumes(&self) -> Vec<PathBuf> {
+        vec![self.path().to_path_buf()]
+    }
+
     /// The total size of the archive in bytes (uncompressed)
     fn total_size(&self) -> Option<u64> {
         None
     }
 
+    /// The number of entries in the archive if known upfront.
+    fn entry_count(&self) -> Option<u64> {
+        None
+    }
+
     /// Unpack the archive into the unpack helper.
-    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), Error>;
+    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), UnboxError>;
+
+    /// Lists the entries of the archive without unpacking it.
+    fn entries(&mut self) -> Result<Vec<ArchiveEntry>, UnboxError> {
+        bail!("listing entries is not supported for this archive")
+    }
+
+    /// Package metadata as key-value pairs.
+    ///
+    /// This is only provided by archives that are packages (such as debian
+    /// packages) and empty otherwise.
+    fn metadata(&self) -> Result<Vec<(String, String)>, UnboxError> {
+        Ok(vec![])
+    }
+
+    /// Verifies the signature of a signed archive.
+    ///
+    /// Returns the identity of the signer on success.
+    fn verify_signature(&mut self) -> Result<String, UnboxError> {
+        bail!("signature verification is not supported for this archive")
+    }
 }
 
-#[derive(Debug)]
-pub struct UnpackHelper {
-    archive_base: String,
-    dst: PathBuf,
-    tmp: TempDirectory,
-    pb: ProgressBar,
+/// What to do when the destination path already exists.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, EnumString)]
+pub enum OnConflict {
+    /// Pick a new name for the unpacked item.
+    #[default]
+    #[strum(serialize = "rename")]
+    Rename,
+    /// Move the existing item to the trash.
+    #[strum(serialize = "trash")]
+    Trash,
+    /// Leave the existing item alone and do not unpack the archive.
+    #[strum(serialize = "skip")]
+    Skip,
+    /// Remove the existing item before moving the unpacked one over.
+    #[strum(serialize = "replace")]
+    Replace,
+    /// Ask on the terminal whether to replace, rename or skip.  Without a
+    /// terminal the unpacked item is renamed.
+    #[strum(serialize = "ask")]
+    Ask,
 }
 
-impl UnpackHelper {
-    /// Creates an unpack helper for an archive.
-    pub fn create<P: AsRef<Path>>(archive: &Archive, dst: &P) -> Result<UnpackHelper, Error> {
-        let archive_base = archive
-            .path()
-            .file_stem()
-            .map(|x| x.to_string_lossy().to_string())
-            .unwrap_or_else(|| "Archive".to_string());
-        let dst = dst.as_ref().canonicalize()?;
-        let pb = match archive.total_size() {
-            Some(total_size) => {
-                let pb = ProgressBar::new(total_size);
-                pb.set_style(
-                    ProgressStyle::default_bar()
-                        .template(" {spinner} {bar:16.cyan.dim}  {wide_msg:.dim} {bytes}/{total_bytes} eta {eta}")
-                        .progress_chars("█▉▊▋▌▍▎▏  ")
-                );
-                pb
-            }
-            None => {
-                let pb = ProgressBar::new_spinner();
-                pb.set_style(ProgressStyle::default_bar().template("{spinner}  {wide_msg:.dim}"));
-                pb
+impl OnConflict {
+    /// Prompts on the terminal what to do about an existing destination.
+    fn ask(dst: &Path) -> Result<OnConflict, UnboxError> {
+        let term = Term::stderr();
+        if !term.is_term() {
+            return Ok(OnConflict::Rename);
+        }
+        loop {
+            term.write_str(&format!(
+                "{} already exists. [o]verwrite, [r]ename or [s]kip? ",
+                dst.display()
+            ))?;
+            let c = term.read_char()?;
+            term.write_line(&c.to_string())?;
+            match c.to_ascii_lowercase() {
+                'o' => return Ok(OnConflict::Replace),
+                'r' => return Ok(OnConflict::Rename),
+                's' => return Ok(OnConflict::Skip),
+                _ => continue,
             }
-        };
+        }
+    }
+}
 
-        pb.enable_steady_tick(200);
+/// What the progress bar shows.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, EnumString)]
+pub enum ProgressMode {
+    /// The number of bytes processed.
+    #[strum(serialize = "bytes")]
+    Bytes,
+    /// The number of entries processed.
+    #[strum(serialize = "count")]
+    Count,
+    /// Bytes processed and the number of entries.
+    #[strum(serialize = "both")]
+    Both,
+}
 
-        let tmp = TempDirectory::for_path(&dst.join(format!(".unbox-{}", Uuid::new_v4())))?;
-        Ok(UnpackHelper {
-            archive_base,
-            dst,
-            tmp,
-            pb,
+/// Selects entries by glob patterns on their paths.
+///
+/// Patterns are matched against the full path of an entry within the
+/// archive with `/` as separator.  If include patterns are given only
+/// matching entries are selected.  Exclude patterns always win.
+#[derive(Debug, Clone, Default)]
+pub struct EntryFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+/// Builds a glob set from the patterns or `None` if there are none.
+fn build_glob_set(patterns: &[&str]) -> Result<Option<GlobSet>, UnboxError> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        match Glob::new(pattern) {
+            Ok(glob) => builder.add(glob),
+            Err(err) => bail!("invalid glob pattern '{}': {}", pattern, err),
+        };
+    }
+    Ok(Some(builder.build()?))
+}
+
+impl EntryFilter {
+    /// Creates a filter from include and exclude patterns.
+    pub fn new(include: &[&str], exclude: &[&str]) -> Result<EntryFilter, UnboxError> {
+        Ok(EntryFilter {
+            include: build_glob_set(include)?,
+            exclude: build_glob_set(exclude)?,
         })
     }
 
-    /// Reports the temporary scratchpad path.
-    pub fn path(&self) -> &Path {
-        self.tmp.path()
+    /// Returns `true` if the filter selects all entries.
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_none()
     }
 
-    /// Reports operating on a file.
-    pub fn report_file<P: AsRef<Path>>(&mut self, filename: P) {
-        self.pb
-            .set_message(&format!("{}", filename.as_ref().display()));
+    /// Checks if the entry at the given path is selected.
+    pub fn matches(&self, path: &Path) -> bool {
+        let path = path
+            .components()
+            .filter_map(|x| match x {
+                Component::Normal(x) => Some(x.to_string_lossy()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        if let Some(ref include) = self.include {
+            if !include.is_match(&path) {
+                return false;
+            }
+        }
+        match self.exclude {
+            Some(ref exclude) => !exclude.is_match(&path),
+            None => true,
+        }
+    }
+}
+
+/// Options that control how an archive is unpacked.
+#[derive(Debug, Clone, Default)]
+pub struct UnpackOptions {
+    /// Only recreate the directory structure without writing any files.
+    pub dirs_only: bool,
+    /// Limits the extraction speed to this many bytes per second.
+    pub rate_limit: Option<u64>,
+    /// What to do if the destination already exists.
+    pub on_conflict: OnConflict,
+    /// What the progress bar shows.  By default both bytes and the entry
+    /// count are shown if their totals are known.
+    pub progress: Option<ProgressMode>,
+    /// The encoding of file names that are not UTF-8.  If not given it's
+    /// guessed and zip archives fall back to code page 437 (`cp437`).
+    pub filename_encoding: Option<String>,
+    /// Always unpack into a folder named after the archive even if the
+    /// archive only contains a single item.
+    pub into_archive_name: bool,
+    /// Move the items of archives with several items straight into the
+    /// destination instead of a folder named after the archive.  This only
+    /// happens if none of them exist there yet.
+    pub unwrap_contents: bool,
+    /// Also extract the boot catalog and boot images of bootable disk
+    /// images.
+    pub extract_boot: bool,
+    /// The image to unpack from files containing several images, counting
+    /// from 1.  Defaults to the first image.
+    pub image: Option<u32>,
+    /// Merge into an existing destination and only overwrite files whose
+    /// contents differ.
+    pub overwrite_if_different: bool,
+    /// Merge into an existing destination like `overwrite_if_different`
+    /// but keep existing files that are newer than the unpacked ones.
+    pub keep_newer: bool,
+    /// Only unpack the entry at this position in the archive.
+    pub entry_index: Option<u64>,
+    /// The pattern for alternative names of conflicting items.  `{n}` is
+    /// replaced with a counter.  Defaults to the `-N` style.
+    pub conflict_suffix: Option<String>,
+    /// Flush every unpacked file and the destination folder to disk.  This
+    /// is considerably slower, especially for archives with many files.
+    pub sync: bool,
+    /// Do not check upfront if the destination has enough free space.
+    pub skip_space_check: bool,
+    /// The password to decrypt encrypted entries with.
+    pub password: Option<String>,
+    /// Remove this many leading components from the paths of entries.
+    /// Entries that do not have more components are skipped.
+    pub strip_components: usize,
+    /// Only unpack the entries selected by this filter.
+    pub filter: EntryFilter,
+    /// Do not restore the permissions stored in the archive.
+    pub ignore_permissions: bool,
+    /// Do not restore the modification times stored in the archive.
+    pub ignore_mtime: bool,
+    /// Restore the owner and group stored in the archive.  This only has
+    /// an effect when running as root on unix.
+    pub owner: bool,
+    /// Abort once more than this many bytes were decompressed.
+    pub max_size: Option<u64>,
+    /// Never draw a progress bar.  It's also hidden automatically if stdout
+    /// is not a terminal.
+    pub quiet: bool,
+    /// Print the path of every unpacked entry.
+    pub verbose: bool,
+    /// Unpack the entries of archives with random access on this many
+    /// threads.  Other archives are always unpacked serially.
+    pub jobs: Option<usize>,
+    /// Go through the archive and print the entries without writing
+    /// anything.  Committing reports the destination without moving.
+    pub dry_run: bool,
+    /// Do not create an empty folder for archives without any entries.
+    /// Committing then skips the archive.
+    pub skip_empty: bool,
+    /// Drop the directory structure and unpack all files next to each
+    /// other.  Conflicting names are numbered.
+    pub flatten: bool,
+    /// Unpack straight into a folder named after the archive in the
+    /// destination instead of a scratchpad.  Every file is written under a
+    /// temporary name and renamed once complete, so a failure leaves only
+    /// complete files behind.  A single item is still moved out of the
+    /// folder when committing.  An existing folder that is replaced stays
+    /// untouched until then and the scratchpad is used instead.
+    pub atomic_per_file: bool,
+    /// Hash the contents of every file and hardlink files with the same
+    /// contents and metadata to the first one instead of keeping copies.
+    pub dedup: bool,
+    /// Compute these digests of the decompressed contents of single
+    /// compressed files while they are written.
+    pub hashes: Vec<HashAlgorithm>,
+    /// Read every entry to the end to check the integrity of the archive.
+    /// Formats with checksums compare them explicitly.  This is meant to
+    /// be combined with `dry_run`.
+    pub test: bool,
+}
+
+/// Metadata of an entry that is restored when it is unpacked.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct EntryMetadata {
+    /// The unix mode of the entry.
+    pub mode: Option<u32>,
+    /// The user and group id of the entry.
+    pub owner: Option<(u32, u32)>,
+    /// The modification time of the entry.
+    pub mtime: Option<FileTime>,
+}
+
+/// Statistics about the files written by an `UnpackHelper`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UnpackStats {
+    /// The number of files written.
+    pub files: u64,
+    /// The total size of the files written in bytes.
+    pub bytes: u64,
+    /// The number of existing files kept as they were newer.
+    pub kept_newer: u64,
+    /// The time since the helper was created.
+    pub elapsed: Duration,
+}
+
+/// Receives the files and directories written by an `UnpackHelper`.
+///
+/// All paths are relative to the folder the archive is unpacked into.
+/// `DiskSink` writes to the file system, other sinks can for instance keep
+/// the files in memory.  With a custom sink nothing is written to the
+/// destination folder, so deduplication, syncing and unpacking in place do
+/// not apply and committing the helper does not move anything.
+pub trait FileSink: Debug + Send + Sync {
+    /// Creates a file, replacing an existing one, and returns a writer for
+    /// its contents.
+    fn create_file(&self, path: &Path) -> io::Result<Box<dyn Write + Send>>;
+
+    /// Creates a directory and all of its missing parents.
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates a symlink pointing to the given target.
+    fn create_symlink(&self, path: &Path, target: &str) -> io::Result<()>;
+
+    /// Copies a file written earlier to another path, replacing what is
+    /// there, and returns the number of bytes copied.
+    ///
+    /// This is used for hardlinks.  Fails with `NotFound` if there is no
+    /// file at the source path.
+    fn copy_file(&self, from: &Path, to: &Path) -> io::Result<u64>;
+
+    /// Applies metadata to a file or directory once it is complete.
+    ///
+    /// Only the metadata that should be restored according to the unpack
+    /// options is set.
+    fn set_metadata(&self, path: &Path, metadata: &EntryMetadata) -> io::Result<()>;
+}
+
+/// A sink writing to the file system below a root folder.
+///
+/// This is what `UnpackHelper::create` uses.  Modes only have an effect
+/// on unix and where symlinks are not supported the target is written
+/// into a regular file instead.
+#[derive(Debug, Default)]
+pub struct DiskSink {
+    root: PathBuf,
+}
+
+impl DiskSink {
+    /// Creates a sink writing below the given folder.
+    pub fn new<P: AsRef<Path>>(root: P) -> DiskSink {
+        DiskSink {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl FileSink for DiskSink {
+    fn create_file(&self, path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(fs::File::create(self.root.join(path))?))
     }
 
-    /// Wraps a stream with the progress bar reader.
-    pub fn wrap_read<R: Read>(&self, read: R) -> ProgressBarRead<R> {
-        self.pb.wrap_read(read)
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(self.root.join(path))
     }
 
-    /// Writes into a file.
-    pub fn write_file<P: AsRef<Path>>(&mut self, filename: P) -> Result<fs::File, Error> {
-        let path = self.tmp.path().join(filename.as_ref());
-        if let Some(ref parent) = path.parent() {
-            fs::create_dir_all(parent)?;
+    fn create_symlink(&self, path: &Path, target: &str) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(target, self.root.join(path))
+        }
+        #[cfg(not(unix))]
+        {
+            fs::write(self.root.join(path), target)
         }
-        self.report_file(filename);
-        Ok(fs::File::create(path)?)
     }
 
-    /// Like `write_file` but writes directly from a reader
-    /// and advances the contained progress bar by the decompressed
-    /// bytes read.
-    pub fn write_file_with_progress<R: Read, P: AsRef<Path>>(
-        &mut self,
-        filename: P,
-        rdr: R,
-    ) -> Result<(), Error> {
-        let mut file = self.write_file(filename)?;
-        copy_with_progress(&self.pb, &mut BufReader::new(rdr), &mut file)?;
+    fn copy_file(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        let to = self.root.join(to);
+        // copying onto an existing symlink would write to where it points
+        if fs::symlink_metadata(&to).is_ok_and(|x| !x.is_dir()) {
+            fs::remove_file(&to)?;
+        }
+        fs::copy(self.root.join(from), to)
+    }
+
+    fn set_metadata(&self, path: &Path, metadata: &EntryMetadata) -> io::Result<()> {
+        let path = &self.root.join(path);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            // changing the owner clears the setuid and setgid bits so it
+            // has to happen before the mode is set.
+            if let Some((uid, gid)) = metadata.owner {
+                std::os::unix::fs::chown(path, Some(uid), Some(gid))?;
+            }
+            if let Some(mode) = metadata.mode {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))?;
+            }
+        }
+        if let Some(mtime) = metadata.mtime {
+            set_file_mtime(path, mtime)?;
+        }
         Ok(())
     }
+}
 
-    /// Commits the changes by moving the root of the unpacked
-    /// archive to the destination folder.
-    ///
-    /// Returns the canonical destination path.
-    pub fn commit(self) -> Result<PathBuf, Error> {
-        self.pb.finish_and_clear();
+/// Fails if an archive of the given size does not fit into the available
+/// space.
+fn check_space(total_size: Option<u64>, available: u64) -> Result<(), UnboxError> {
+    match total_size {
+        Some(total_size) if total_size > available => bail!(
+            "not enough space: need {}, have {}",
+            HumanBytes(total_size),
+            HumanBytes(available)
+        ),
+        _ => Ok(()),
+    }
+}
 
-        // if we found exactly one file or directory we can accept that as the
-        // resulting file.
-        let mut intended_dst = None;
-        let mut to_move = None;
-        for entry in self.tmp.path().read_dir()? {
-            let entry = entry?;
-            if intended_dst.is_none() {
-                intended_dst = Some(self.dst.join(entry.path().strip_prefix(self.tmp.path())?));
-                to_move = Some(entry.path().to_path_buf());
-            } else {
-                intended_dst = None;
-                break;
-            }
+/// Creates the progress bar for the given mode and the known totals.
+fn create_progress_bar(
+    progress: ProgressMode,
+    total_size: Option<u64>,
+    entry_count: Option<u64>,
+    rate_limit: Option<u64>,
+) -> ProgressBar {
+    let rate = rate_limit
+        .map(|x| format!(" (max {}/s)", HumanBytes(x)))
+        .unwrap_or_default();
+
+    let bar = |len, info: &str| {
+        let pb = ProgressBar::new(len);
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template(&format!(
+                    " {{spinner}} {{bar:16.cyan.dim}}  {{wide_msg:.dim}} {}",
+                    info
+                ))
+                .progress_chars("█▉▊▋▌▍▎▏  "),
+        );
+        pb
+    };
+    let spinner = |info: &str| {
+        let pb = ProgressBar::new_spinner();
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template(&format!("{{spinner}}  {{wide_msg:.dim}}{}", info)),
+        );
+        pb
+    };
+    match (progress, total_size, entry_count) {
+        (ProgressMode::Both, Some(total_size), Some(_)) => bar(
+            total_size,
+            &format!(
+                "{{prefix}} files {{bytes}}/{{total_bytes}} eta {{eta}}{}",
+                rate
+            ),
+        ),
+        (ProgressMode::Count, _, Some(entry_count)) => {
+            bar(entry_count, "{pos}/{len} files eta {eta}")
         }
+        (ProgressMode::Count, _, None) => spinner(" {pos} files"),
+        (_, Some(total_size), _) => bar(
+            total_size,
+            &format!("{{bytes}}/{{total_bytes}} eta {{eta}}{}", rate),
+        ),
+        (_, None, _) => spinner(""),
+    }
+}
 
-        // there was only one thing in the archive, move it over.
-        let rv = if let (Some(intended_dst), Some(to_move)) = (intended_dst, to_move) {
-            rename_resolving_conflict(&to_move, &intended_dst)?
+/// Reports progress with an indicatif progress bar on the terminal.
+///
+/// The bar is hidden in quiet mode or if stdout is not a terminal.
+#[derive(Debug)]
+pub struct IndicatifProgress {
+    pb: ProgressBar,
+    bytes_pb: ProgressBar,
+    progress: ProgressMode,
+    total_size: Option<u64>,
+    bytes_done: AtomicU64,
+    entry_count: Option<u64>,
+    entries_done: AtomicU64,
+}
+
+impl IndicatifProgress {
+    /// Creates the progress bar for unpacking an archive.
+    pub fn new(archive: &dyn Archive, options: &UnpackOptions) -> IndicatifProgress {
+        let total_size = archive.total_size();
+        let entry_count = archive.entry_count();
 
-        // otherwise move the root
+        // both is only possible if we know both totals, otherwise fall
+        // back to the one we know.
+        let progress = match (options.progress, total_size, entry_count) {
+            (Some(ProgressMode::Both), _, None) => ProgressMode::Bytes,
+            (Some(ProgressMode::Both), None, Some(_)) => ProgressMode::Count,
+            (Some(progress), _, _) => progress,
+            (None, Some(_), Some(_)) => ProgressMode::Both,
+            (None, _, _) => ProgressMode::Bytes,
+        };
+        let pb = if options.quiet || !Term::stdout().is_term() {
+            ProgressBar::hidden()
+        } else {
+            create_progress_bar(progress, total_size, entry_count, options.rate_limit)
+        };
+        let bytes_pb = if progress == ProgressMode::Count {
+            ProgressBar::hidden()
         } else {
-            let intended_path = self.dst.join(&self.archive_base);
-            rename_resolving_conflict(self.tmp.path(), &intended_path)?
+            pb.clone()
         };
 
-        self.tmp.cleanup()?;
-        Ok(rv)
+        pb.enable_steady_tick(200);
+
+        IndicatifProgress {
+            pb,
+            bytes_pb,
+            progress,
+            total_size,
+            bytes_done: AtomicU64::new(0),
+            entry_count,
+            entries_done: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ProgressReporter for IndicatifProgress {
+    fn on_file(&self, path: &Path) {
+        let entries_done = self.entries_done.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.progress {
+            ProgressMode::Bytes => {}
+            ProgressMode::Count => self.pb.inc(1),
+            ProgressMode::Both => self.pb.set_prefix(&format!(
+                "{}/{}",
+                entries_done,
+                self.entry_count.unwrap_or(0)
+            )),
+        }
+        self.pb.set_message(&format!("{}", path.display()));
+    }
+
+    fn on_bytes(&self, n: u64) {
+        // the bar stops at the total in case an archive reports more bytes
+        // than it announced, so it never runs past its end or goes back.
+        let clamp = |x: u64| self.total_size.map_or(x, |total| x.min(total));
+        let before = self.bytes_done.fetch_add(n, Ordering::Relaxed);
+        self.bytes_pb.inc(clamp(before + n) - clamp(before));
+    }
+
+    fn on_finish(&self) {
+        self.pb.finish_and_clear();
+    }
+
+    fn println(&self, line: &str) {
+        if self.pb.is_hidden() {
+            eprintln!("{}", line);
+        } else {
+            self.pb.println(line);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnpackHelper {
+    archive_path: PathBuf,
+    archive_base: String,
+    dst: PathBuf,
+    tmp: TempDirectory,
+    root: PathBuf,
+    skipped: bool,
+    progress: Arc<dyn ProgressReporter>,
+    sink: Box<dyn FileSink>,
+    custom_sink: bool,
+    entries_seen: u64,
+    started: Instant,
+    files_written: AtomicU64,
+    bytes_written: AtomicU64,
+    files_kept_newer: Arc<AtomicU64>,
+    bytes_kept_newer: Arc<AtomicU64>,
+    files_synced: AtomicU64,
+    dedup_files: Mutex<HashMap<DedupKey, PathBuf>>,
+    files_deduplicated: AtomicU64,
+    dir_mtimes: Mutex<Vec<(PathBuf, FileTime)>>,
+    dir_lock: Mutex<()>,
+    limiter: Option<RateLimiter>,
+    size_limit: Option<SizeLimit>,
+    pool: Option<ThreadPool>,
+    verbose: bool,
+    /// The roots reported in a dry run and if they contain other entries.
+    dry_run_roots: Mutex<BTreeMap<OsString, bool>>,
+    flat_names: Mutex<(HashMap<PathBuf, PathBuf>, HashSet<OsString>)>,
+    digests: Vec<(HashAlgorithm, String)>,
+    options: UnpackOptions,
+}
+
+impl UnpackHelper {
+    /// Creates an unpack helper for an archive.
+    ///
+    /// Progress is shown with an `IndicatifProgress` bar.
+    pub fn create<P: AsRef<Path>>(
+        archive: &dyn Archive,
+        dst: &P,
+        options: UnpackOptions,
+    ) -> Result<UnpackHelper, UnboxError> {
+        let progress = IndicatifProgress::new(archive, &options);
+        UnpackHelper::create_with_progress(archive, dst, options, Box::new(progress))
+    }
+
+    /// Creates an unpack helper for an archive that sends progress updates
+    /// to the given reporter.
+    pub fn create_with_progress<P: AsRef<Path>>(
+        archive: &dyn Archive,
+        dst: &P,
+        options: UnpackOptions,
+        progress: Box<dyn ProgressReporter>,
+    ) -> Result<UnpackHelper, UnboxError> {
+        UnpackHelper::new(archive, dst.as_ref(), options, progress, None)
+    }
+
+    /// Creates an unpack helper for an archive that writes the unpacked
+    /// files through the given sink.
+    ///
+    /// The sink is given paths relative to the folder the archive is
+    /// unpacked into.  Nothing is created below the destination and its
+    /// available space is not checked.
+    pub fn create_with_sink<P: AsRef<Path>>(
+        archive: &dyn Archive,
+        dst: &P,
+        options: UnpackOptions,
+        progress: Box<dyn ProgressReporter>,
+        sink: Box<dyn FileSink>,
+    ) -> Result<UnpackHelper, UnboxError> {
+        UnpackHelper::new(archive, dst.as_ref(), options, progress, Some(sink))
+    }
+
+    fn new(
+        archive: &dyn Archive,
+        dst: &Path,
+        options: UnpackOptions,
+        progress: Box<dyn ProgressReporter>,
+        sink: Option<Box<dyn FileSink>>,
+    ) -> Result<UnpackHelper, UnboxError> {
+        let archive_base = archive
+            .path()
+            .file_stem()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_else(|| "Archive".to_string());
+        // the destination of a dry run or of a custom sink does not have
+        // to exist
+        let custom_sink = sink.is_some();
+        let dst = match dst.canonicalize() {
+            Err(_) if options.dry_run || custom_sink => env::current_dir()?.join(dst),
+            rv => rv?,
+        };
+        let limiter = options.rate_limit.map(RateLimiter::new);
+        let size_limit = options.max_size.map(SizeLimit::new);
+        let total_size = archive.total_size();
+        if !options.skip_space_check && !options.dry_run && !custom_sink && total_size.is_some() {
+            check_space(total_size, fs2::available_space(&dst)?)?;
+        }
+
+        if let Some(algo) = options.hashes.iter().find(|x| !x.is_supported()) {
+            bail!(
+                "{} digests require the hashes feature",
+                format!("{:?}", algo).to_lowercase()
+            );
+        }
+
+        let pool = match options.jobs {
+            Some(jobs) if jobs > 1 => Some(ThreadPoolBuilder::new().num_threads(jobs).build()?),
+            _ => None,
+        };
+
+        let tmp_path = dst.join(format!(".unbox-{}", Uuid::new_v4()));
+        let tmp = if options.dry_run || custom_sink {
+            TempDirectory::unused(&tmp_path)
+        } else {
+            TempDirectory::for_path(&tmp_path)?
+        };
+        debug!(
+            "unpacking {} into scratchpad {}",
+            archive.path().display(),
+            tmp.path().display()
+        );
+        let sink = sink.unwrap_or_else(|| Box::new(DiskSink::new(tmp.path())));
+        let mut rv = UnpackHelper {
+            archive_path: archive.path().to_path_buf(),
+            archive_base,
+            dst,
+            root: tmp.path().to_path_buf(),
+            tmp,
+            skipped: false,
+            progress: Arc::from(progress),
+            custom_sink,
+            sink,
+            entries_seen: 0,
+            started: Instant::now(),
+            files_written: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            files_kept_newer: Arc::new(AtomicU64::new(0)),
+            bytes_kept_newer: Arc::new(AtomicU64::new(0)),
+            files_synced: AtomicU64::new(0),
+            dedup_files: Mutex::new(HashMap::new()),
+            files_deduplicated: AtomicU64::new(0),
+            dir_mtimes: Mutex::new(vec![]),
+            dir_lock: Mutex::new(()),
+            limiter,
+            size_limit,
+            pool,
+            verbose: options.verbose || (options.dry_run && !options.test),
+            dry_run_roots: Mutex::new(BTreeMap::new()),
+            flat_names: Mutex::new((HashMap::new(), HashSet::new())),
+            digests: vec![],
+            options,
+        };
+        if rv.atomic_per_file() {
+            rv.move_scratchpad_into_place()?;
+        }
+        Ok(rv)
+    }
+
+    /// Moves the still empty scratchpad to the destination so that the
+    /// entries are unpacked in place.
+    ///
+    /// Conflicts with an existing folder are resolved upfront.  When
+    /// merging, a new folder is used until the helper is committed.  An
+    /// existing folder that should be replaced is only replaced when
+    /// committing, so it survives a failed unpack, and the entries stay in
+    /// the scratchpad until then.
+    fn move_scratchpad_into_place(&mut self) -> Result<(), UnboxError> {
+        let intended_dst = self.dst.join(&self.archive_base);
+        let exists = fs::symlink_metadata(&intended_dst).is_ok();
+        if exists && !self.merges() && self.options.on_conflict == OnConflict::Ask {
+            // the answer is remembered for when the helper is committed
+            self.options.on_conflict = OnConflict::ask(&intended_dst)?;
+        }
+        if exists
+            && !self.merges()
+            && matches!(
+                self.options.on_conflict,
+                OnConflict::Replace | OnConflict::Trash
+            )
+        {
+            debug!(
+                "{} exists, unpacking into the scratchpad until committing",
+                intended_dst.display()
+            );
+            return Ok(());
+        }
+        let root = if self.merges() {
+            let root =
+                resolve_conflict(&intended_dst, self.options.conflict_suffix.as_deref(), true)?;
+            fs::rename(self.tmp.path(), &root)?;
+            Some(root)
+        } else {
+            self.move_to_destination(self.tmp.path(), &intended_dst)?
+        };
+        match root {
+            Some(root) => {
+                debug!("unpacking in place into {}", root.display());
+                self.set_root(root);
+            }
+            None => self.skipped = true,
+        }
+        Ok(())
+    }
+
+    /// Changes the folder the entries are unpacked into.
+    fn set_root(&mut self, root: PathBuf) {
+        if !self.custom_sink {
+            self.sink = Box::new(DiskSink::new(&root));
+        }
+        self.root = root;
+    }
+
+    /// Returns the path of an unpacked item relative to the root as the
+    /// sink expects it.
+    fn sink_path<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.root).unwrap_or(path)
+    }
+
+    /// Reports the path the entries are unpacked into.
+    ///
+    /// This is a temporary scratchpad unless unpacking in place.
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns `true` if only directories should be created.
+    pub fn dirs_only(&self) -> bool {
+        self.options.dirs_only
+    }
+
+    /// Returns `true` if nothing should be written.
+    ///
+    /// Formats that unpack entries on their own instead of going through
+    /// the helper have to use the helper in dry runs.
+    pub fn dry_run(&self) -> bool {
+        self.options.dry_run
+    }
+
+    /// The number of the image to unpack if one was selected.
+    pub fn image(&self) -> Option<u32> {
+        self.options.image
+    }
+
+    /// Returns `true` if the directory structure is dropped.
+    pub fn flatten(&self) -> bool {
+        self.options.flatten
+    }
+
+    /// Returns `true` if files are unpacked in place, one at a time.
+    pub fn atomic_per_file(&self) -> bool {
+        self.options.atomic_per_file && !self.options.dry_run && !self.custom_sink
+    }
+
+    /// Returns `true` if the integrity of the archive is tested.
+    pub fn is_testing(&self) -> bool {
+        self.options.test
+    }
+
+    /// Returns `true` if the files are written through a sink passed to
+    /// `create_with_sink`.
+    ///
+    /// Formats that write to the disk on their own have to go through the
+    /// helper instead.
+    pub fn has_custom_sink(&self) -> bool {
+        self.custom_sink
+    }
+
+    /// Returns `true` if files with the same contents are hardlinked.
+    ///
+    /// Formats that write files on their own have to write them through
+    /// the helper for this.
+    pub fn dedup(&self) -> bool {
+        self.options.dedup && !self.options.dry_run && !self.custom_sink
+    }
+
+    /// Returns `true` if the unpacked items are merged into an existing
+    /// destination.
+    fn merges(&self) -> bool {
+        self.options.overwrite_if_different || self.options.keep_newer
+    }
+
+    /// Returns `true` if the destination already exists and is left alone
+    /// because of the conflict policy.
+    ///
+    /// This is only known before unpacking when unpacking in place, the
+    /// archive does not have to be unpacked then.
+    pub fn is_skipped(&self) -> bool {
+        self.skipped
+    }
+
+    /// The encoding to use for file names that are not UTF-8.
+    pub fn filename_encoding(&self) -> Option<&str> {
+        self.options.filename_encoding.as_deref()
+    }
+
+    /// The password for encrypted entries if one was given.
+    pub fn password(&self) -> Option<&str> {
+        self.options.password.as_deref()
+    }
+
+    /// The number of leading path components removed from entries.
+    pub fn strip_components(&self) -> usize {
+        self.options.strip_components
+    }
+
+    /// Checks if the entry at the given path is selected by the filter.
+    pub fn is_selected(&self, path: &Path) -> bool {
+        self.options.filter.matches(path)
+    }
+
+    /// Removes the leading path components from the path of an entry.
+    ///
+    /// Returns `None` if nothing is left and the entry should be skipped.
+    /// Unsafe paths are skipped as well as stripping could otherwise turn
+    /// them into safe ones.
+    pub fn strip_path(&self, path: &Path) -> Option<PathBuf> {
+        if self.options.strip_components == 0 {
+            return Some(path.to_path_buf());
+        }
+        let rv: PathBuf = safe_join(Path::new(""), path)?
+            .components()
+            .skip(self.options.strip_components)
+            .collect();
+        if rv.as_os_str().is_empty() {
+            None
+        } else {
+            Some(rv)
+        }
+    }
+
+    /// Picks the name a file is unpacked to when flattening.
+    ///
+    /// This is the last component of the path, numbered if another file
+    /// of the archive already uses the name.  The same path always maps to
+    /// the same name so that links can be resolved.
+    pub fn flatten_path(&self, path: &Path) -> Option<PathBuf> {
+        let name = path.file_name()?;
+        let pattern = self.options.conflict_suffix.as_deref().unwrap_or("-{n}");
+        let (ref mut paths, ref mut names) = *self.flat_names.lock().unwrap();
+        if let Some(rv) = paths.get(path) {
+            return Some(rv.clone());
+        }
+        let mut rv = name.to_os_string();
+        for n in 2.. {
+            if names.insert(rv.clone()) {
+                break;
+            }
+            rv = apply_conflict_suffix(&name.to_string_lossy(), pattern, n, false).into();
+        }
+        paths.insert(path.to_path_buf(), PathBuf::from(&rv));
+        Some(PathBuf::from(rv))
+    }
+
+    /// Returns `true` if permissions stored in the archive are restored.
+    pub fn preserve_permissions(&self) -> bool {
+        !self.options.ignore_permissions
+    }
+
+    /// Returns `true` if the owners stored in the archive are restored.
+    ///
+    /// Only root can change the owner of files so this is ignored for
+    /// other users.
+    pub fn preserve_owner(&self) -> bool {
+        self.options.owner && !self.options.dry_run && is_root()
+    }
+
+    /// Returns `true` if modification times stored in the archive are
+    /// restored.
+    pub fn preserve_mtime(&self) -> bool {
+        !self.options.ignore_mtime
+    }
+
+    /// Returns `true` if boot images should be extracted.
+    pub fn extract_boot(&self) -> bool {
+        self.options.extract_boot
+    }
+
+    /// Returns `true` if entries should be unpacked on multiple threads.
+    pub fn is_parallel(&self) -> bool {
+        self.pool.is_some()
+    }
+
+    /// Calls `op` for every item, on the thread pool if there is one.
+    ///
+    /// Every thread creates its own state with `init` the first time it
+    /// picks up an item, which is typically a separate reader of the
+    /// archive.  Without a thread pool the items are processed in order.
+    pub fn for_each_parallel<T, S, I, F>(
+        &self,
+        items: Vec<T>,
+        init: I,
+        op: F,
+    ) -> Result<(), UnboxError>
+    where
+        T: Send,
+        I: Fn() -> Result<S, UnboxError> + Sync + Send,
+        F: Fn(&mut S, T) -> Result<(), UnboxError> + Sync + Send,
+    {
+        let pool = match self.pool {
+            Some(ref pool) if !items.is_empty() => pool,
+            _ => {
+                let mut state = None;
+                for item in items {
+                    let state = match state {
+                        Some(ref mut state) => state,
+                        None => state.get_or_insert(init()?),
+                    };
+                    op(state, item)?;
+                }
+                return Ok(());
+            }
+        };
+        pool.install(|| {
+            items.into_par_iter().try_for_each_init(
+                || None,
+                |state, item| {
+                    let state = match state {
+                        Some(state) => state,
+                        None => state.get_or_insert(init()?),
+                    };
+                    op(state, item)
+                },
+            )
+        })
+    }
+
+    /// Checks if the next entry of the archive should be skipped.
+    ///
+    /// Formats call this once for every entry in archive order so that a
+    /// single entry can be selected by its index.
+    pub fn skip_entry(&mut self) -> bool {
+        let index = self.entries_seen;
+        self.entries_seen += 1;
+        match self.options.entry_index {
+            Some(entry_index) => entry_index != index,
+            None => false,
+        }
+    }
+
+    /// Attaches the name of the entry that failed to unpack to an error.
+    ///
+    /// Formats call this for errors from unpacking a single entry so that
+    /// the error names the entry and the archive.
+    pub fn entry_error<P: AsRef<Path>>(&self, entry: P, err: UnboxError) -> UnboxError {
+        err.for_entry(entry.as_ref(), &self.archive_path)
+    }
+
+    /// Attaches the offset of an entry that could not be read to an error.
+    pub fn offset_error(&self, offset: u64, err: UnboxError) -> UnboxError {
+        err.at_offset(offset, &self.archive_path)
+    }
+
+    /// Reports operating on a file.
+    ///
+    /// In verbose mode and dry runs the path is printed above the progress
+    /// bar.
+    pub fn report_file<P: AsRef<Path>>(&self, filename: P) {
+        trace!("unpacking {}", filename.as_ref().display());
+        if self.options.dry_run || self.custom_sink {
+            let mut components = filename.as_ref().components();
+            if let Some(root) = components.next() {
+                *self
+                    .dry_run_roots
+                    .lock()
+                    .unwrap()
+                    .entry(root.as_os_str().to_os_string())
+                    .or_default() |= components.next().is_some();
+            }
+        }
+        if self.verbose {
+            self.progress
+                .println(&filename.as_ref().display().to_string());
+        }
+        self.progress.on_file(filename.as_ref());
+    }
+
+    /// Prints a warning about an entry, such as one that is skipped.
+    ///
+    /// It is printed above the progress bar like verbose output.
+    pub fn warn(&self, msg: &str) {
+        self.progress.println(&format!("warning: {}", msg));
+    }
+
+    /// Wraps a stream so that the bytes read are reported as progress.
+    ///
+    /// The returned reader also honors the rate limit.
+    pub fn wrap_read<R: Read>(&self, read: R) -> LimitedRead<ProgressRead<R>> {
+        LimitedRead {
+            rdr: ProgressRead {
+                rdr: read,
+                progress: self.progress.clone(),
+            },
+            limiter: self.limiter.clone(),
+        }
+    }
+
+    /// Wraps a decompressed stream so that it counts towards the size
+    /// limit.
+    pub fn limit_size<R: Read>(&self, read: R) -> SizeLimitedRead<R> {
+        SizeLimitedRead {
+            rdr: read,
+            size_limit: self.size_limit.clone(),
+        }
+    }
+
+    /// Creates a directory.
+    pub fn create_dir<P: AsRef<Path>>(&self, dirname: P) -> Result<(), UnboxError> {
+        self.create_dir_with_metadata(dirname, EntryMetadata::default())
+    }
+
+    /// Like `create_dir` but also restores the modification time of the
+    /// directory when the helper is committed.
+    pub fn create_dir_with_metadata<P: AsRef<Path>>(
+        &self,
+        dirname: P,
+        metadata: EntryMetadata,
+    ) -> Result<(), UnboxError> {
+        if !self.is_selected(dirname.as_ref()) {
+            return Ok(());
+        }
+        let dirname = match self.strip_path(dirname.as_ref()) {
+            Some(_) if self.options.flatten => return Ok(()),
+            Some(dirname) => dirname,
+            None => return Ok(()),
+        };
+        let path = match safe_join(&self.root, &dirname) {
+            Some(path) => path,
+            None => {
+                debug!("skipping unsafe path {}", dirname.display());
+                return Ok(());
+            }
+        };
+        self.create_dir_all(&path)?;
+        if let Some(mtime) = metadata.mtime {
+            self.restore_dir_mtime(&dirname, mtime);
+        }
+        self.report_file(dirname);
+        Ok(())
+    }
+
+    /// Remembers the modification time of a directory in the scratchpad.
+    ///
+    /// Unpacking files into a directory changes its modification time, so
+    /// they are only applied when the helper is committed.
+    pub fn restore_dir_mtime<P: AsRef<Path>>(&self, dirname: P, mtime: FileTime) {
+        if self.preserve_mtime() {
+            self.dir_mtimes
+                .lock()
+                .unwrap()
+                .push((dirname.as_ref().to_path_buf(), mtime));
+        }
+    }
+
+    /// Creates a directory and its parents in the scratchpad.
+    ///
+    /// This is serialized so that threads unpacking into the same folders
+    /// do not race each other.
+    fn create_dir_all(&self, path: &Path) -> Result<(), UnboxError> {
+        if self.options.dry_run {
+            return Ok(());
+        }
+        let _guard = self.dir_lock.lock().unwrap();
+        self.sink.create_dir(self.sink_path(path))?;
+        Ok(())
+    }
+
+    /// Makes sure the parent directory of a file exists and returns the
+    /// path the file should be written to.
+    ///
+    /// Returns `None` if the file is skipped because it is not selected,
+    /// because of stripped path components or because the path would
+    /// escape the scratchpad.
+    fn prepare_file(&self, filename: &Path) -> Result<Option<PathBuf>, UnboxError> {
+        if !self.is_selected(filename) {
+            return Ok(None);
+        }
+        let filename = match self.strip_path(filename) {
+            Some(filename) if self.options.flatten => match self.flatten_path(&filename) {
+                Some(filename) => filename,
+                None => return Ok(None),
+            },
+            Some(filename) => filename,
+            None => return Ok(None),
+        };
+        let path = match safe_join(&self.root, &filename) {
+            Some(ref path) if path != &self.root => path.clone(),
+            _ => {
+                debug!("skipping unsafe path {}", filename.display());
+                return Ok(None);
+            }
+        };
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        self.report_file(filename);
+        Ok(Some(path))
+    }
+
+    /// Creates a file to write into unless it is skipped.
+    ///
+    /// In dirs only mode only the parent directory is created.  In dry runs
+    /// the returned writer discards everything.
+    fn write_file(&self, filename: &Path) -> Result<Option<(FileWriter, PathBuf)>, UnboxError> {
+        let path = match self.prepare_file(filename)? {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        if self.options.dry_run {
+            return Ok(Some((FileWriter::Discard, path)));
+        } else if self.dirs_only() {
+            return Ok(None);
+        }
+        // an earlier entry with the same name might be hardlinked and
+        // truncating it would change the other links as well.
+        if self.dedup() && fs::symlink_metadata(&path).is_ok() {
+            fs::remove_file(&path)?;
+            self.dedup_files.lock().unwrap().retain(|_, x| *x != path);
+        }
+        let file = if self.atomic_per_file() {
+            let partial = PartialFile::new(path.clone());
+            FileWriter::Sink(
+                self.sink.create_file(self.sink_path(&partial.tmp))?,
+                Some(partial),
+            )
+        } else {
+            FileWriter::Sink(self.sink.create_file(self.sink_path(&path))?, None)
+        };
+        Ok(Some((file, path)))
+    }
+
+    /// Like `write_file` but writes directly from a reader.
+    ///
+    /// In dirs only mode only the parent directory is created and the
+    /// reader is not consumed.
+    pub fn write_file_from<R: Read, P: AsRef<Path>>(
+        &self,
+        filename: P,
+        rdr: R,
+    ) -> Result<(), UnboxError> {
+        let (mut file, path) = match self.write_file(filename.as_ref())? {
+            Some(rv) => rv,
+            None => return Ok(()),
+        };
+        let mut rdr = HashRead::new(self.limit_size(rdr), self.dedup());
+        let size = io::copy(&mut rdr, &mut file)?;
+        self.finish_file(file, &path, EntryMetadata::default())?;
+        self.count_file(size);
+        self.dedup_file(&path, rdr.digest(), EntryMetadata::default())
+    }
+
+    /// Like `write_file_from` but reports the decompressed bytes read as
+    /// progress.
+    pub fn write_file_with_progress<R: Read, P: AsRef<Path>>(
+        &self,
+        filename: P,
+        rdr: R,
+    ) -> Result<(), UnboxError> {
+        self.write_file_with_metadata(filename, rdr, EntryMetadata::default())
+    }
+
+    /// Like `write_file_with_progress` but also restores the metadata of
+    /// the entry unless disabled by the options.
+    ///
+    /// The metadata is applied once all contents are written so that
+    /// read-only files work and the modification time is not changed by
+    /// later writes.  The mode has no effect on other platforms than unix.
+    pub fn write_file_with_metadata<R: Read, P: AsRef<Path>>(
+        &self,
+        filename: P,
+        rdr: R,
+        metadata: EntryMetadata,
+    ) -> Result<(), UnboxError> {
+        let (mut file, path) = match self.write_file(filename.as_ref())? {
+            Some(rv) => rv,
+            None => return Ok(()),
+        };
+        let mut rdr = BufReader::new(HashRead::new(rdr, self.dedup()));
+        let size = copy_with_progress(
+            &*self.progress,
+            self.limiter.as_ref(),
+            self.size_limit.as_ref(),
+            &mut rdr,
+            &mut file,
+        )?;
+        self.finish_file(file, &path, metadata)?;
+        self.count_file(size);
+        self.dedup_file(&path, rdr.into_inner().digest(), metadata)
+    }
+
+    /// Creates a symlink pointing to the given target.
+    ///
+    /// Links that would point outside of the unpacked archive are skipped
+    /// with a warning.  Where symlinks are not supported the target is
+    /// written into a regular file instead.
+    pub fn create_symlink<P: AsRef<Path>>(
+        &self,
+        linkname: P,
+        target: &str,
+    ) -> Result<(), UnboxError> {
+        let path = match self.prepare_file(linkname.as_ref())? {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if self.dirs_only() || self.options.dry_run {
+            return Ok(());
+        }
+        if is_escaping_symlink(&self.root, &path, Path::new(target)) {
+            self.warn(&format!(
+                "skipping symlink {} as it points outside of the archive",
+                linkname.as_ref().display()
+            ));
+            return Ok(());
+        }
+        #[cfg(not(unix))]
+        {
+            if !self.custom_sink {
+                self.warn(&format!(
+                    "cannot create symlink {}, writing it as regular file",
+                    linkname.as_ref().display()
+                ));
+            }
+        }
+        self.sink.create_symlink(self.sink_path(&path), target)?;
+        Ok(())
+    }
+
+    /// Unpacks a hardlink by copying the contents of its target.
+    ///
+    /// The target has to be a regular file unpacked earlier, otherwise the
+    /// link is skipped with a warning.  Whatever is at the path of the link
+    /// already is replaced, it is never written through.
+    pub fn copy_hard_link<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        linkname: P,
+        target: Q,
+    ) -> Result<(), UnboxError> {
+        let skip = || {
+            self.warn(&format!(
+                "skipping hardlink {} as its target was not unpacked",
+                linkname.as_ref().display()
+            ));
+            Ok(())
+        };
+        let src = match self.strip_path(target.as_ref()).and_then(|x| {
+            if self.options.flatten {
+                self.flatten_path(&x)
+            } else {
+                Some(x)
+            }
+        }) {
+            Some(target) => match safe_join(&self.root, &target) {
+                Some(src) => src,
+                None => return skip(),
+            },
+            None => return skip(),
+        };
+        // files on disk are resolved to make sure the copied contents do
+        // not come from outside through a symlink.
+        if !self.custom_sink && !self.options.dry_run {
+            let root = self.root.canonicalize()?;
+            if !src
+                .canonicalize()
+                .is_ok_and(|x| x.starts_with(&root) && x.is_file())
+            {
+                return skip();
+            }
+        }
+        let path = match self.prepare_file(linkname.as_ref())? {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if self.dirs_only() || self.options.dry_run {
+            return Ok(());
+        }
+        if self.dedup() {
+            self.dedup_files.lock().unwrap().retain(|_, x| *x != path);
+        }
+        let partial = if self.atomic_per_file() {
+            Some(PartialFile::new(path.clone()))
+        } else {
+            None
+        };
+        let written = partial.as_ref().map_or(&path, |x| &x.tmp);
+        let size = match self
+            .sink
+            .copy_file(self.sink_path(&src), self.sink_path(written))
+        {
+            Ok(size) => size,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return skip(),
+            Err(err) => return Err(err.into()),
+        };
+        self.sync_path(written)?;
+        if let Some(partial) = partial {
+            partial.persist()?;
+        }
+        self.count_file(size);
+        Ok(())
+    }
+
+    /// Restores the metadata of a written file, flushes it to disk if
+    /// requested and moves it into place when unpacking in place.
+    fn finish_file(
+        &self,
+        file: FileWriter,
+        path: &Path,
+        metadata: EntryMetadata,
+    ) -> Result<(), UnboxError> {
+        let (mut writer, partial) = match file {
+            FileWriter::Sink(writer, partial) => (writer, partial),
+            FileWriter::Discard => return Ok(()),
+        };
+        writer.flush()?;
+        drop(writer);
+        let written = partial.as_ref().map_or(path, |x| &x.tmp);
+        let metadata = EntryMetadata {
+            mode: metadata.mode.filter(|_| self.preserve_permissions()),
+            owner: metadata.owner.filter(|_| self.preserve_owner()),
+            mtime: metadata.mtime.filter(|_| self.preserve_mtime()),
+        };
+        if metadata.mode.is_some() || metadata.owner.is_some() || metadata.mtime.is_some() {
+            self.sink.set_metadata(self.sink_path(written), &metadata)?;
+        }
+        self.sync_path(written)?;
+        if let Some(partial) = partial {
+            partial.persist()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the file at the given path to disk if requested.
+    fn sync_path(&self, path: &Path) -> Result<(), UnboxError> {
+        if self.options.sync && !self.options.dry_run && !self.custom_sink {
+            fs::File::open(path)?.sync_all()?;
+            self.files_synced.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Replaces a written file with a hardlink to an earlier file with the
+    /// same contents when deduplicating.
+    ///
+    /// If the link cannot be created, for instance because the file system
+    /// does not support hardlinks, the written copy is kept.
+    fn dedup_file(
+        &self,
+        path: &Path,
+        digest: Option<[u8; 32]>,
+        metadata: EntryMetadata,
+    ) -> Result<(), UnboxError> {
+        let digest = match digest {
+            Some(digest) => digest,
+            None => return Ok(()),
+        };
+        let key = (
+            digest,
+            metadata.mode.filter(|_| self.preserve_permissions()),
+            metadata.owner.filter(|_| self.preserve_owner()),
+            metadata.mtime.filter(|_| self.preserve_mtime()),
+        );
+        let mut files = self.dedup_files.lock().unwrap();
+        let original = match files.get(&key) {
+            Some(original) if original != path => original,
+            Some(_) => return Ok(()),
+            None => {
+                files.insert(key, path.to_path_buf());
+                return Ok(());
+            }
+        };
+        // link next to the file first so it is only replaced by a link
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let link = path.with_file_name(format!(".{}.unbox-{}", name, Uuid::new_v4()));
+        match fs::hard_link(original, &link) {
+            Ok(()) => {
+                fs::rename(&link, path)?;
+                self.files_deduplicated.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err) => debug!(
+                "keeping {} as it cannot be linked to {}: {}",
+                path.display(),
+                original.display(),
+                err
+            ),
+        }
+        Ok(())
+    }
+
+    /// Counts a file towards the statistics.
+    ///
+    /// Files written through the helper are counted automatically, this is
+    /// for formats that write files on their own.
+    pub fn count_file(&self, size: u64) {
+        self.files_written.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// Returns how many files were written so far and how long it took.
+    pub fn stats(&self) -> UnpackStats {
+        UnpackStats {
+            files: self.files_written.load(Ordering::Relaxed),
+            bytes: self.bytes_written.load(Ordering::Relaxed),
+            kept_newer: self.files_kept_newer.load(Ordering::Relaxed),
+            elapsed: self.started.elapsed(),
+        }
+    }
+
+    /// The digests that should be computed of single compressed files.
+    pub fn hashes(&self) -> &[HashAlgorithm] {
+        &self.options.hashes
+    }
+
+    /// Records the digests computed of a single compressed file.
+    pub fn record_digests(&mut self, digests: Vec<(HashAlgorithm, String)>) {
+        self.digests.extend(digests);
+    }
+
+    /// Returns the digests recorded so far.
+    pub fn digests(&self) -> &[(HashAlgorithm, String)] {
+        &self.digests
+    }
+
+    /// Flushes a file that was unpacked directly into the scratchpad to
+    /// disk if requested.
+    pub fn sync_file<P: AsRef<Path>>(&self, filename: P) -> Result<(), UnboxError> {
+        self.sync_path(&self.root.join(filename))
+    }
+
+    /// Writes a file to the given path in the scratchpad with a callback.
+    ///
+    /// This is for formats that write files on their own.  When unpacking
+    /// in place the callback is given a temporary path and the file is
+    /// renamed once the callback succeeded.
+    pub fn write_file_atomically<F>(&self, path: &Path, f: F) -> Result<(), UnboxError>
+    where
+        F: FnOnce(&Path) -> Result<(), UnboxError>,
+    {
+        if !self.atomic_per_file() {
+            return f(path);
+        }
+        let partial = PartialFile::new(path.to_path_buf());
+        f(&partial.tmp)?;
+        partial.persist()?;
+        Ok(())
+    }
+
+    /// Moves an unpacked item to its destination honoring the conflict
+    /// policy.
+    ///
+    /// Returns `None` if the destination exists and should be skipped.
+    fn move_to_destination(&self, src: &Path, dst: &Path) -> Result<Option<PathBuf>, UnboxError> {
+        if self.merges() && dst.exists() {
+            debug!("merging into existing {}", dst.display());
+            let (files, bytes) = merge_into(src, dst, self.options.keep_newer)?;
+            self.files_kept_newer.fetch_add(files, Ordering::Relaxed);
+            self.bytes_kept_newer.fetch_add(bytes, Ordering::Relaxed);
+            return Ok(Some(dst.to_path_buf()));
+        }
+        let existing = match fs::symlink_metadata(dst) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                fs::rename(src, dst)?;
+                return Ok(Some(dst.to_path_buf()));
+            }
+        };
+        let on_conflict = match self.options.on_conflict {
+            OnConflict::Ask => OnConflict::ask(dst)?,
+            on_conflict => on_conflict,
+        };
+        if on_conflict == OnConflict::Skip {
+            debug!("{} already exists, skipping", dst.display());
+            return Ok(None);
+        }
+        if on_conflict == OnConflict::Replace {
+            debug!("replacing existing {}", dst.display());
+            if existing.is_dir() {
+                fs::remove_dir_all(dst)?;
+            } else {
+                fs::remove_file(dst)?;
+            }
+            fs::rename(src, dst)?;
+            return Ok(Some(dst.to_path_buf()));
+        }
+        if on_conflict == OnConflict::Trash {
+            debug!("moving existing {} to trash", dst.display());
+            if let Err(err) = trash::delete(dst) {
+                self.warn(&format!(
+                    "could not move {} to trash ({}), renaming instead",
+                    dst.display(),
+                    err
+                ));
+            }
+        }
+        Ok(Some(rename_resolving_conflict(
+            src,
+            dst,
+            self.options.conflict_suffix.as_deref(),
+        )?))
+    }
+
+    /// Commits the changes by moving the root of the unpacked
+    /// archive to the destination folder.
+    ///
+    /// Returns the canonical destination path.  As the destination folder
+    /// is canonicalized when the helper is created the result is always
+    /// absolute and free of symlinks so it can safely be used by scripts.
+    /// If the destination exists and should be skipped, the path of the
+    /// existing item is returned.  With a custom sink nothing is moved and
+    /// the returned path is empty.
+    pub fn commit(self) -> Result<PathBuf, UnboxError> {
+        let (rv, _) = self.finish()?;
+        Ok(rv)
+    }
+
+    /// Like `commit` but returns `None` if the destination already existed
+    /// and was left untouched because of the conflict policy or if the
+    /// archive was empty and `skip_empty` is set.
+    pub fn commit_or_skip(self) -> Result<Option<PathBuf>, UnboxError> {
+        let (rv, skipped) = self.finish()?;
+        Ok(if skipped { None } else { Some(rv) })
+    }
+
+    /// Like `commit_or_skip` but also returns the statistics once the
+    /// unpacked items are in place.
+    ///
+    /// Unlike `stats` this includes the files kept when merging, which are
+    /// not counted as written.
+    pub fn commit_with_stats(self) -> Result<(Option<PathBuf>, UnpackStats), UnboxError> {
+        let files_kept_newer = self.files_kept_newer.clone();
+        let bytes_kept_newer = self.bytes_kept_newer.clone();
+        let started = self.started;
+        let mut stats = self.stats();
+        let (rv, skipped) = self.finish()?;
+        stats.kept_newer = files_kept_newer.load(Ordering::Relaxed);
+        stats.files = stats.files.saturating_sub(stats.kept_newer);
+        stats.bytes = stats
+            .bytes
+            .saturating_sub(bytes_kept_newer.load(Ordering::Relaxed));
+        stats.elapsed = started.elapsed();
+        Ok((if skipped { None } else { Some(rv) }, stats))
+    }
+
+    /// Returns `true` if nothing was unpacked so far.
+    pub fn is_empty(&self) -> bool {
+        if self.options.dry_run || self.custom_sink {
+            return self.dry_run_roots.lock().unwrap().is_empty();
+        }
+        match self.root.read_dir() {
+            Ok(mut entries) => entries.next().is_none(),
+            Err(_) => true,
+        }
+    }
+
+    /// Warns about an empty archive and returns `true` if it should be
+    /// skipped.
+    fn check_empty(&self) -> bool {
+        if !self.is_empty() {
+            return false;
+        }
+        if !self.options.filter.is_empty() {
+            self.warn("no entries matched the include and exclude patterns");
+        } else if !self.options.skip_empty {
+            self.warn("archive was empty");
+        }
+        self.options.skip_empty
+    }
+
+    /// Moves the unpacked archive over and returns the destination path
+    /// and if it was skipped.
+    fn finish(mut self) -> Result<(PathBuf, bool), UnboxError> {
+        self.progress.on_finish();
+        if self.dedup() {
+            debug!(
+                "replaced {} duplicate files with hardlinks",
+                self.files_deduplicated.load(Ordering::Relaxed)
+            );
+        }
+
+        if self.skipped {
+            self.tmp.cleanup()?;
+            return Ok((self.dst.join(&self.archive_base), true));
+        }
+
+        // entries unpacked in place go back into the scratchpad so that
+        // committing works the same.  These are renames on the same file
+        // system.
+        if self.atomic_per_file() && self.root != self.tmp.path() {
+            fs::rename(&self.root, self.tmp.path())?;
+            self.set_root(self.tmp.path().to_path_buf());
+        }
+
+        if let Some(entry_index) = self.options.entry_index {
+            if entry_index >= self.entries_seen {
+                let entries_seen = self.entries_seen;
+                self.tmp.cleanup()?;
+                bail!(
+                    "entry index {} is out of range, the archive has {} entries",
+                    entry_index,
+                    entries_seen
+                );
+            }
+        }
+
+        if self.options.dry_run {
+            return self.finish_dry_run();
+        }
+
+        for (dirname, mtime) in self.dir_mtimes.lock().unwrap().iter() {
+            let metadata = EntryMetadata {
+                mode: None,
+                owner: None,
+                mtime: Some(*mtime),
+            };
+            self.sink.set_metadata(dirname, &metadata)?;
+        }
+
+        // the sink already has everything, there is nothing to move.
+        if self.custom_sink {
+            let skipped = self.options.skip_empty && self.is_empty();
+            return Ok((PathBuf::new(), skipped));
+        }
+
+        // empty archives are unpacked into an empty folder named after the
+        // archive unless they should be skipped.
+        if self.check_empty() {
+            self.tmp.cleanup()?;
+            return Ok((self.dst.join(&self.archive_base), true));
+        }
+
+        // if we found exactly one file or directory we can accept that as the
+        // resulting file unless we were asked to always use the archive name.
+        let mut intended_dst = None;
+        let mut to_move = None;
+        if !self.options.into_archive_name {
+            for entry in self.tmp.path().read_dir()? {
+                let entry = entry?;
+                if intended_dst.is_none() {
+                    intended_dst = Some(self.dst.join(entry.path().strip_prefix(self.tmp.path())?));
+                    to_move = Some(entry.path().to_path_buf());
+                } else {
+                    intended_dst = None;
+                    break;
+                }
+            }
+        }
+
+        // several items are moved straight into the destination if that was
+        // asked for and none of them are in the way.
+        if intended_dst.is_none() && self.options.unwrap_contents && !self.options.into_archive_name
+        {
+            let items = self
+                .tmp
+                .path()
+                .read_dir()?
+                .map(|entry| entry.map(|x| x.file_name()))
+                .collect::<Result<Vec<_>, _>>()?;
+            if items
+                .iter()
+                .all(|item| fs::symlink_metadata(self.dst.join(item)).is_err())
+            {
+                debug!("archive has multiple items, moving them into destination");
+                for item in &items {
+                    fs::rename(self.tmp.path().join(item), self.dst.join(item))?;
+                }
+                self.tmp.cleanup()?;
+                if self.options.sync {
+                    sync_dir(&self.dst)?;
+                }
+                return Ok((self.dst.clone(), false));
+            }
+        }
+
+        // there was only one thing in the archive, move it over.
+        let (intended_dst, to_move) = match (intended_dst, to_move) {
+            (Some(intended_dst), Some(to_move)) => {
+                debug!("archive has a single item, moving it to destination");
+                (intended_dst, to_move)
+            }
+
+            // otherwise move the root
+            _ => {
+                debug!("archive has multiple items, moving root to destination");
+                (
+                    self.dst.join(&self.archive_base),
+                    self.tmp.path().to_path_buf(),
+                )
+            }
+        };
+        let moved = self.move_to_destination(&to_move, &intended_dst)?;
+        self.tmp.cleanup()?;
+        let rv = match moved {
+            Some(rv) => rv,
+            None => return Ok((intended_dst, true)),
+        };
+
+        // make sure the rename itself is durable
+        if self.options.sync {
+            debug!("synced {} files", self.files_synced.load(Ordering::Relaxed));
+            if let Some(parent) = rv.parent() {
+                sync_dir(parent)?;
+            }
+        }
+        Ok((rv, false))
+    }
+
+    /// Determines the destination like `finish` but from the reported
+    /// entries as nothing was unpacked.
+    fn finish_dry_run(self) -> Result<(PathBuf, bool), UnboxError> {
+        if self.check_empty() {
+            return Ok((self.dst.join(&self.archive_base), true));
+        }
+        let roots = self.dry_run_roots.into_inner().unwrap();
+        let dst = &self.dst;
+        if roots.len() > 1
+            && self.options.unwrap_contents
+            && !self.options.into_archive_name
+            && roots
+                .keys()
+                .all(|root| fs::symlink_metadata(dst.join(root)).is_err())
+        {
+            return Ok((dst.clone(), false));
+        }
+        let (intended_dst, is_dir) = match roots.iter().next() {
+            Some((root, &is_dir)) if roots.len() == 1 && !self.options.into_archive_name => {
+                (self.dst.join(root), is_dir)
+            }
+            _ => (self.dst.join(&self.archive_base), true),
+        };
+        if self.options.overwrite_if_different
+            || self.options.keep_newer
+            || fs::symlink_metadata(&intended_dst).is_err()
+        {
+            return Ok((intended_dst, false));
+        }
+        match self.options.on_conflict {
+            OnConflict::Skip => Ok((intended_dst, true)),
+            OnConflict::Rename => Ok((
+                resolve_conflict(
+                    &intended_dst,
+                    self.options.conflict_suffix.as_deref(),
+                    is_dir,
+                )?,
+                false,
+            )),
+            // the existing item is moved out of the way
+            OnConflict::Replace | OnConflict::Trash | OnConflict::Ask => Ok((intended_dst, false)),
+        }
+    }
+}
+
+/// A file that is written under a temporary name next to its final path
+/// and renamed once it is complete.
+///
+/// The temporary file is removed if it is dropped before that.
+struct PartialFile {
+    tmp: PathBuf,
+    path: PathBuf,
+    done: bool,
+}
+
+impl PartialFile {
+    fn new(path: PathBuf) -> PartialFile {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = path.with_file_name(format!(".{}.unbox-{}", name, Uuid::new_v4()));
+        PartialFile {
+            tmp,
+            path,
+            done: false,
+        }
+    }
+
+    fn persist(mut self) -> io::Result<()> {
+        fs::rename(&self.tmp, &self.path)?;
+        self.done = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.done {
+            fs::remove_file(&self.tmp).ok();
+        }
+    }
+}
+
+/// The file an entry is written to.
+///
+/// Files written in place are created under a temporary name first.
+enum FileWriter {
+    Sink(Box<dyn Write + Send>, Option<PartialFile>),
+    Discard,
+}
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            FileWriter::Sink(ref mut w, _) => w.write(buf),
+            FileWriter::Discard => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            FileWriter::Sink(ref mut w, _) => w.flush(),
+            FileWriter::Discard => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_check_space() {
+    assert!(check_space(None, 0).is_ok());
+    assert!(check_space(Some(1024), 1024).is_ok());
+    let err = check_space(Some(2 * 1024 * 1024), 1024).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "not enough space: need 2.00MB, have 1.00KB"
+    );
+}
+
+#[test]
+fn test_rate_limit() {
+    let limiter = RateLimiter::new(1024 * 1024);
+    let start = limiter.state.lock().unwrap().0;
+    let ms = Duration::from_millis;
+    assert_eq!(limiter.delay(256 * 1024, start), Some(ms(250)));
+    assert_eq!(limiter.delay(256 * 1024, start + ms(100)), Some(ms(400)));
+
+    // clones share the budget and time spent elsewhere counts
+    let clone = limiter.clone();
+    assert_eq!(clone.delay(512 * 1024, start + ms(600)), Some(ms(400)));
+    assert_eq!(limiter.delay(0, start + ms(1500)), None);
+    assert_eq!(limiter.delay(1024, start + ms(2000)), None);
+
+    // the copy goes through the limiter
+    let limiter = RateLimiter::new(u64::MAX);
+    let data = vec![0u8; 256 * 1024];
+    let mut out = vec![];
+    copy_with_progress(&NoProgress, Some(&limiter), None, &mut &data[..], &mut out).unwrap();
+    assert_eq!(out, data);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_commit_returns_canonical_path() {
+    use crate::formats::{Compression, SingleFileArchive};
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    fs::create_dir(dir.join("real")).unwrap();
+    std::os::unix::fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+    let archive_path = dir.join("hello.txt.gz");
+    let mut w = libflate::gzip::Encoder::new(fs::File::create(&archive_path).unwrap()).unwrap();
+    w.write_all(b"hello").unwrap();
+    w.finish().into_result().unwrap();
+
+    let mut archive = SingleFileArchive::open(&archive_path, Compression::Gz).unwrap();
+    let dst = dir.join("link");
+    let mut helper = UnpackHelper::create(&archive, &dst, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+
+    assert!(rv.is_absolute());
+    assert_eq!(rv, rv.canonicalize().unwrap());
+    assert_eq!(rv, dir.canonicalize().unwrap().join("real/hello.txt"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(all(unix, feature = "zip"))]
+#[test]
+fn test_overwrite_if_different() {
+    use std::os::unix::fs::MetadataExt;
+
+    use crate::formats::{build_zip, ZipArchive};
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let unpack = |entries: &[(&str, &[u8])]| {
+        let archive_path = dir.join("site.zip");
+        fs::write(&archive_path, build_zip(entries)).unwrap();
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        let options = UnpackOptions {
+            overwrite_if_different: true,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        helper.commit().unwrap()
+    };
+    let inode = |path: PathBuf| fs::metadata(path).unwrap().ino();
+
+    let rv = unpack(&[("same.txt", b"same"), ("changed.txt", b"old")]);
+    let same_inode = inode(rv.join("same.txt"));
+    let changed_inode = inode(rv.join("changed.txt"));
+
+    let rv = unpack(&[
+        ("same.txt", b"same"),
+        ("changed.txt", b"new"),
+        ("new.txt", b"new"),
+    ]);
+    assert_eq!(rv, dir.canonicalize().unwrap().join("site"));
+    assert_eq!(inode(rv.join("same.txt")), same_inode);
+    assert_ne!(inode(rv.join("changed.txt")), changed_inode);
+    assert_eq!(fs::read(rv.join("changed.txt")).unwrap(), b"new");
+    assert_eq!(fs::read(rv.join("new.txt")).unwrap(), b"new");
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_keep_newer() {
+    use std::time::SystemTime;
+
+    use crate::formats::{build_zip, ZipArchive};
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("site.zip");
+    let unpack = |entries: &[(&str, &[u8])]| {
+        fs::write(&archive_path, build_zip(entries)).unwrap();
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        let options = UnpackOptions {
+            keep_newer: true,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        helper.commit_with_stats().unwrap()
+    };
+
+    let (rv, _) = unpack(&[("old.txt", b"old"), ("new.txt", b"old")]);
+    let rv = rv.unwrap();
+    let tomorrow = SystemTime::now() + Duration::from_secs(86400);
+    set_file_mtime(rv.join("new.txt"), FileTime::from_system_time(tomorrow)).unwrap();
+    set_file_mtime(rv.join("old.txt"), FileTime::zero()).unwrap();
+
+    let (rv, stats) = unpack(&[("old.txt", b"new"), ("new.txt", b"new")]);
+    let rv = rv.unwrap();
+    assert_eq!(rv, dir.canonicalize().unwrap().join("site"));
+    assert_eq!(fs::read(rv.join("old.txt")).unwrap(), b"new");
+    assert_eq!(fs::read(rv.join("new.txt")).unwrap(), b"old");
+    assert_eq!(stats.kept_newer, 1);
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.bytes, 3);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_on_conflict() {
+    use crate::formats::{build_zip, ZipArchive};
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let unpack = |on_conflict, contents: &[u8]| {
+        let archive_path = dir.join("site.zip");
+        fs::write(
+            &archive_path,
+            build_zip(&[("a.txt", contents), ("b.txt", contents)]),
+        )
+        .unwrap();
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        let options = UnpackOptions {
+            on_conflict,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        helper.commit_or_skip().unwrap()
+    };
+
+    let rv = unpack(OnConflict::Rename, b"first").unwrap();
+    fs::write(rv.join("local.txt"), b"local").unwrap();
+
+    assert_eq!(unpack(OnConflict::Skip, b"second"), None);
+    assert_eq!(fs::read(rv.join("a.txt")).unwrap(), b"first");
+    assert!(rv.join("local.txt").exists());
+
+    let replaced = unpack(OnConflict::Replace, b"third").unwrap();
+    assert_eq!(replaced, rv);
+    assert_eq!(fs::read(rv.join("a.txt")).unwrap(), b"third");
+    assert!(!rv.join("local.txt").exists());
+
+    let renamed = unpack(OnConflict::Rename, b"fourth").unwrap();
+    assert_eq!(renamed.file_name().unwrap(), "site-2");
+    assert_eq!(fs::read(rv.join("a.txt")).unwrap(), b"third");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_dry_run() {
+    use crate::formats::{build_zip, ZipArchive};
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir().canonicalize().unwrap();
+    let dry_run = |name: &str, entries: &[(&str, &[u8])]| {
+        let archive_path = dir.join(name);
+        fs::write(&archive_path, build_zip(entries)).unwrap();
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        let options = UnpackOptions {
+            dry_run: true,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        let rv = helper.commit().unwrap();
+        fs::remove_file(&archive_path).unwrap();
+        rv
+    };
+
+    let entries: &[(&str, &[u8])] = &[("a.txt", b"a"), ("sub/b.txt", b"b")];
+    assert_eq!(dry_run("site.zip", entries), dir.join("site"));
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+    let entries: &[(&str, &[u8])] = &[("pkg/a.txt", b"a"), ("pkg/sub/b.txt", b"b")];
+    assert_eq!(dry_run("site.zip", entries), dir.join("pkg"));
+    fs::create_dir(dir.join("pkg")).unwrap();
+    assert_eq!(dry_run("site.zip", entries), dir.join("pkg-2"));
+    assert_eq!(fs::read_dir(dir.join("pkg")).unwrap().count(), 0);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(all(target_os = "linux", feature = "zip"))]
+#[test]
+fn test_dry_run_trash() {
+    use crate::formats::{build_zip, ZipArchive};
+    use crate::utils::make_test_dir;
+
+    // the trash is kept next to the test folder instead of the home folder
+    let root = make_test_dir().canonicalize().unwrap();
+    env::set_var("XDG_DATA_HOME", root.join("data"));
+    let dir = root.join("dst");
+    fs::create_dir(&dir).unwrap();
+    let archive_path = root.join("site.zip");
+    fs::write(
+        &archive_path,
+        build_zip(&[("pkg/a.txt", b"a"), ("pkg/b.txt", b"b")]),
+    )
+    .unwrap();
+    fs::create_dir(dir.join("pkg")).unwrap();
+    let unpack = |dry_run: bool| {
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        let options = UnpackOptions {
+            dry_run,
+            on_conflict: OnConflict::Trash,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        helper.commit().unwrap()
+    };
+
+    let rv = unpack(true);
+    assert_eq!(rv, dir.join("pkg"));
+    assert_eq!(unpack(false), rv);
+    assert_eq!(fs::read(rv.join("a.txt")).unwrap(), b"a");
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_flatten() {
+    use crate::formats::{build_zip, ZipArchive};
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("docs.zip");
+    fs::write(
+        &archive_path,
+        build_zip(&[
+            ("a/", b""),
+            ("a/x.txt", b"a"),
+            ("b/x.txt", b"b"),
+            ("b/c/x.txt", b"c"),
+        ]),
+    )
+    .unwrap();
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    let options = UnpackOptions {
+        flatten: true,
+        conflict_suffix: Some(" ({n})".to_string()),
+        ..UnpackOptions::default()
+    };
+    let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "docs");
+    assert_eq!(fs::read_dir(&rv).unwrap().count(), 3);
+    assert_eq!(fs::read(rv.join("x.txt")).unwrap(), b"a");
+    assert_eq!(fs::read(rv.join("x (2).txt")).unwrap(), b"b");
+    assert_eq!(fs::read(rv.join("x (3).txt")).unwrap(), b"c");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_atomic_per_file() {
+    use std::io::Cursor;
+
+    use ::zip::write::{FileOptions, ZipWriter};
+    use ::zip::CompressionMethod;
+
+    use crate::formats::{build_zip, ZipArchive};
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let unpack = |name: &str, entries: &[(&str, &[u8])], on_conflict: OnConflict| {
+        let archive_path = dir.join(name);
+        fs::write(&archive_path, build_zip(entries)).unwrap();
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        let options = UnpackOptions {
+            atomic_per_file: true,
+            on_conflict,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        // an existing folder is only replaced when committing
+        if !helper.is_skipped() && on_conflict != OnConflict::Replace {
+            assert_eq!(helper.path(), dir.join(archive_path.file_stem().unwrap()));
+        }
+        if !helper.is_skipped() {
+            archive.unpack(&mut helper).unwrap();
+        }
+        helper.commit_or_skip().unwrap()
+    };
+    let leftovers = || {
+        fs::read_dir(&dir)
+            .unwrap()
+            .filter(|x| {
+                x.as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .contains(".unbox-")
+            })
+            .count()
+    };
+
+    let rv = unpack(
+        "docs.zip",
+        &[("a.txt", b"a"), ("b/c.txt", b"c")],
+        OnConflict::Skip,
+    );
+    assert_eq!(rv, Some(dir.join("docs")));
+    assert_eq!(fs::read(dir.join("docs/a.txt")).unwrap(), b"a");
+    assert_eq!(fs::read(dir.join("docs/b/c.txt")).unwrap(), b"c");
+    assert_eq!(fs::read_dir(dir.join("docs/b")).unwrap().count(), 1);
+
+    // a single item is still moved out of the folder named after the archive
+    let rv = unpack("single.zip", &[("readme.txt", b"hi")], OnConflict::Skip);
+    assert_eq!(rv, Some(dir.join("readme.txt")));
+    assert!(!dir.join("single").exists());
+
+    // conflicts are known before unpacking
+    let rv = unpack("docs.zip", &[("a.txt", b"new")], OnConflict::Skip);
+    assert_eq!(rv, None);
+    assert_eq!(fs::read(dir.join("docs/a.txt")).unwrap(), b"a");
+    let rv = unpack(
+        "docs.zip",
+        &[("a.txt", b"new"), ("d.txt", b"d")],
+        OnConflict::Replace,
+    );
+    assert_eq!(rv, Some(dir.join("docs")));
+    assert_eq!(fs::read(dir.join("docs/a.txt")).unwrap(), b"new");
+    assert!(!dir.join("docs/b").exists());
+
+    // a failed unpack leaves the folder that should be replaced alone
+    let archive_path = dir.join("docs.zip");
+    let mut w = ZipWriter::new(Cursor::new(vec![]));
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    w.start_file("a.txt", stored).unwrap();
+    w.write_all(b"newer").unwrap();
+    w.start_file("b.txt", stored).unwrap();
+    w.write_all(b"broken").unwrap();
+    let mut buf = w.finish().unwrap().into_inner();
+    let pos = buf.windows(6).position(|x| x == b"broken").unwrap();
+    buf[pos] = b'B';
+    fs::write(&archive_path, buf).unwrap();
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    let options = UnpackOptions {
+        atomic_per_file: true,
+        on_conflict: OnConflict::Replace,
+        ..UnpackOptions::default()
+    };
+    let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+    assert!(archive.unpack(&mut helper).is_err());
+    drop(helper);
+    assert_eq!(fs::read(dir.join("docs/a.txt")).unwrap(), b"new");
+    assert_eq!(fs::read(dir.join("docs/d.txt")).unwrap(), b"d");
+
+    assert_eq!(leftovers(), 0);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(all(unix, feature = "zip"))]
+#[test]
+fn test_dedup() {
+    use std::os::unix::fs::MetadataExt;
+
+    use crate::formats::{build_zip, ZipArchive};
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("dupes.zip");
+    fs::write(
+        &archive_path,
+        build_zip(&[
+            ("a.txt", b"same"),
+            ("sub/b.txt", b"same"),
+            ("c.txt", b"other"),
+            ("d.txt", b"same"),
+            // overwriting a linked file must not change the others
+            ("a.txt", b"new"),
+            ("e.txt", b"same"),
+        ]),
+    )
+    .unwrap();
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    let options = UnpackOptions {
+        dedup: true,
+        ..UnpackOptions::default()
+    };
+    let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    assert_eq!(helper.files_deduplicated.load(Ordering::Relaxed), 2);
+    let rv = helper.commit().unwrap();
+
+    let inode = |name: &str| fs::metadata(rv.join(name)).unwrap().ino();
+    assert_eq!(inode("sub/b.txt"), inode("d.txt"));
+    assert_ne!(inode("c.txt"), inode("d.txt"));
+    assert_eq!(fs::read(rv.join("a.txt")).unwrap(), b"new");
+    assert_eq!(fs::read(rv.join("sub/b.txt")).unwrap(), b"same");
+    assert_eq!(fs::read(rv.join("d.txt")).unwrap(), b"same");
+    assert_eq!(fs::read(rv.join("e.txt")).unwrap(), b"same");
+    assert_eq!(fs::read_dir(&rv).unwrap().count(), 5);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(all(feature = "tar", feature = "zip"))]
+#[test]
+fn test_empty_archive() {
+    use crate::formats::{build_zip, ArchiveType};
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir().canonicalize().unwrap();
+    let unpack = |archive_path: &PathBuf, skip_empty: bool| {
+        let mut archive = ArchiveType::for_path(archive_path)
+            .unwrap()
+            .open(archive_path)
+            .unwrap();
+        let options = UnpackOptions {
+            skip_empty,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&*archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        assert!(helper.is_empty());
+        helper.commit_or_skip().unwrap()
+    };
+
+    let tar_path = dir.join("empty.tar");
+    fs::write(&tar_path, vec![0u8; 1024]).unwrap();
+    let zip_path = dir.join("nothing.zip");
+    fs::write(&zip_path, build_zip(&[])).unwrap();
+
+    // empty archives are unpacked into an empty folder
+    assert_eq!(unpack(&tar_path, false), Some(dir.join("empty")));
+    assert_eq!(unpack(&zip_path, false), Some(dir.join("nothing")));
+    assert_eq!(fs::read_dir(dir.join("empty")).unwrap().count(), 0);
+    assert_eq!(fs::read_dir(dir.join("nothing")).unwrap().count(), 0);
+    fs::remove_dir(dir.join("empty")).unwrap();
+    fs::remove_dir(dir.join("nothing")).unwrap();
+
+    // or skipped entirely
+    assert_eq!(unpack(&tar_path, true), None);
+    assert_eq!(unpack(&zip_path, true), None);
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_sync() {
+    use crate::formats::{build_zip, ZipArchive};
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir().canonicalize().unwrap();
+    let archive_path = dir.join("durable.zip");
+    fs::write(
+        &archive_path,
+        build_zip(&[("a.txt", b"a"), ("b/", b""), ("b/c.txt", b"c")]),
+    )
+    .unwrap();
+
+    for &sync in &[false, true] {
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        let options = UnpackOptions {
+            sync,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        assert_eq!(
+            helper.files_synced.load(Ordering::Relaxed),
+            if sync { 2 } else { 0 }
+        );
+        let rv = helper.commit().unwrap();
+        assert_eq!(fs::read(rv.join("b/c.txt")).unwrap(), b"c");
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_max_size() {
+    use crate::formats::{Compression, SingleFileArchive};
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("bomb.gz");
+    let mut w = libflate::gzip::Encoder::new(fs::File::create(&archive_path).unwrap()).unwrap();
+    io::copy(&mut io::repeat(0).take(1024 * 1024), &mut w).unwrap();
+    w.finish().into_result().unwrap();
+
+    let unpack = |max_size| {
+        let mut archive = SingleFileArchive::open(&archive_path, Compression::Gz).unwrap();
+        let options = UnpackOptions {
+            max_size: Some(max_size),
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        let scratchpad = helper.path().to_path_buf();
+        let rv = archive.unpack(&mut helper).and_then(|_| helper.commit());
+        (rv, scratchpad)
+    };
+
+    let (rv, scratchpad) = unpack(64 * 1024);
+    assert_eq!(
+        rv.unwrap_err().to_string(),
+        "decompression limit exceeded (max 64.00KB)"
+    );
+    assert!(!scratchpad.exists());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+    let (rv, _) = unpack(1024 * 1024);
+    assert_eq!(fs::metadata(rv.unwrap()).unwrap().len(), 1024 * 1024);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_custom_progress() {
+    use std::io::Cursor;
+
+    use ::zip::write::ZipWriter;
+
+    use crate::formats::{build_zip, ArchiveType};
+    use crate::utils::make_test_dir;
+
+    #[derive(Debug, Default)]
+    struct Recorder {
+        files: Mutex<Vec<PathBuf>>,
+        bytes: AtomicU64,
+        finished: AtomicU64,
+        lines: Mutex<Vec<String>>,
+    }
+
+    impl ProgressReporter for Arc<Recorder> {
+        fn on_file(&self, path: &Path) {
+            self.files.lock().unwrap().push(path.to_path_buf());
+        }
+        fn on_bytes(&self, n: u64) {
+            self.bytes.fetch_add(n, Ordering::Relaxed);
+        }
+        fn on_finish(&self) {
+            self.finished.fetch_add(1, Ordering::Relaxed);
+        }
+        fn println(&self, line: &str) {
+            self.lines.lock().unwrap().push(line.to_string());
+        }
+    }
+
+    let dir = make_test_dir().canonicalize().unwrap();
+    let archive_path = dir.join("docs.zip");
+    fs::write(
+        &archive_path,
+        build_zip(&[("a.txt", b"hello"), ("b.txt", b"world!")]),
+    )
+    .unwrap();
+    let mut archive = ArchiveType::for_path(&archive_path)
+        .unwrap()
+        .open(&archive_path)
+        .unwrap();
+    let recorder = Arc::new(Recorder::default());
+    let mut helper = UnpackHelper::create_with_progress(
+        &*archive,
+        &dir,
+        UnpackOptions::default(),
+        Box::new(recorder.clone()),
+    )
+    .unwrap();
+    archive.unpack(&mut helper).unwrap();
+    helper.commit().unwrap();
+    assert_eq!(
+        *recorder.files.lock().unwrap(),
+        vec![PathBuf::from("a.txt"), PathBuf::from("b.txt")]
+    );
+    assert_eq!(recorder.bytes.load(Ordering::Relaxed), 11);
+    assert_eq!(recorder.finished.load(Ordering::Relaxed), 1);
+    assert!(recorder.lines.lock().unwrap().is_empty());
+
+    // warnings are printed through the reporter as well
+    let archive_path = dir.join("empty.zip");
+    let mut w = ZipWriter::new(Cursor::new(vec![]));
+    fs::write(&archive_path, w.finish().unwrap().into_inner()).unwrap();
+    let mut archive = ArchiveType::Zip.open(&archive_path).unwrap();
+    let mut helper = UnpackHelper::create_with_progress(
+        &*archive,
+        &dir,
+        UnpackOptions::default(),
+        Box::new(recorder.clone()),
+    )
+    .unwrap();
+    archive.unpack(&mut helper).unwrap();
+    helper.commit().unwrap();
+    assert_eq!(
+        *recorder.lines.lock().unwrap(),
+        vec!["warning: archive was empty".to_string()]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "tar")]
+#[test]
+fn test_custom_sink() {
+    use tar::{Builder, EntryType, Header};
+
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    type Files = Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>;
+
+    #[derive(Debug, Default)]
+    struct MemorySink {
+        files: Files,
+        dirs: Mutex<Vec<PathBuf>>,
+    }
+
+    struct MemoryFile {
+        path: PathBuf,
+        buf: Vec<u8>,
+        files: Files,
+    }
+
+    impl Write for MemoryFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for MemoryFile {
+        fn drop(&mut self) {
+            let buf = std::mem::take(&mut self.buf);
+            self.files.lock().unwrap().insert(self.path.clone(), buf);
+        }
+    }
+
+    impl FileSink for Arc<MemorySink> {
+        fn create_file(&self, path: &Path) -> io::Result<Box<dyn Write + Send>> {
+            Ok(Box::new(MemoryFile {
+                path: path.to_path_buf(),
+                buf: vec![],
+                files: self.files.clone(),
+            }))
+        }
+        fn create_dir(&self, path: &Path) -> io::Result<()> {
+            self.dirs.lock().unwrap().push(path.to_path_buf());
+            Ok(())
+        }
+        fn create_symlink(&self, _path: &Path, _target: &str) -> io::Result<()> {
+            Ok(())
+        }
+        fn copy_file(&self, from: &Path, to: &Path) -> io::Result<u64> {
+            let mut files = self.files.lock().unwrap();
+            let buf = files.get(from).ok_or(io::ErrorKind::NotFound)?.clone();
+            files.insert(to.to_path_buf(), buf);
+            Ok(files[to].len() as u64)
+        }
+        fn set_metadata(&self, _path: &Path, _metadata: &EntryMetadata) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let dir = make_test_dir().canonicalize().unwrap();
+    let archive_path = dir.join("docs.tar");
+    let mut builder = Builder::new(fs::File::create(&archive_path).unwrap());
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::Directory);
+    header.set_size(0);
+    builder.append_data(&mut header, "sub/", &b""[..]).unwrap();
+    for (name, contents) in &[("sub/a.txt", &b"hello"[..]), ("b.txt", b"world!")] {
+        let mut header = Header::new_ustar();
+        header.set_size(contents.len() as u64);
+        builder.append_data(&mut header, name, *contents).unwrap();
+    }
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::Link);
+    header.set_size(0);
+    builder
+        .append_link(&mut header, "sub/c.txt", "b.txt")
+        .unwrap();
+    builder.finish().unwrap();
+    drop(builder);
+    let mut archive = ArchiveType::for_path(&archive_path)
+        .unwrap()
+        .open(&archive_path)
+        .unwrap();
+    let sink = Arc::new(MemorySink::default());
+    let mut helper = UnpackHelper::create_with_sink(
+        &*archive,
+        &dir,
+        UnpackOptions::default(),
+        Box::new(NoProgress),
+        Box::new(sink.clone()),
+    )
+    .unwrap();
+    assert!(helper.has_custom_sink());
+    archive.unpack(&mut helper).unwrap();
+    {
+        let files = sink.files.lock().unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[Path::new("sub/a.txt")], b"hello");
+        assert_eq!(files[Path::new("b.txt")], b"world!");
+        assert_eq!(files[Path::new("sub/c.txt")], b"world!");
     }
+    assert!(sink.dirs.lock().unwrap().contains(&PathBuf::from("sub")));
+    assert!(!helper.is_empty());
+    assert_eq!(helper.stats().files, 3);
+    assert_eq!(helper.commit().unwrap(), PathBuf::new());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    fs::remove_dir_all(&dir).unwrap();
 }
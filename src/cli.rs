@@ -1,14 +1,37 @@
-use clap::{App, AppSettings, Arg};
+use std::env;
+use std::fmt;
+use std::fs;
+#[cfg(feature = "tar")]
+use std::io::Write;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use clap::{App, AppSettings, Arg, Shell};
 use console::style;
-use failure::{bail, Error};
+use filetime::FileTime;
+use indicatif::HumanBytes;
 use strum::IntoEnumIterator;
-use tree_magic;
+use uuid::Uuid;
 
-use crate::archive::UnpackHelper;
-use crate::formats::ArchiveType;
+use crate::archive::{
+    Archive, ArchiveEntry, EntryFilter, EntryKind, HashAlgorithm, OnConflict, UnpackHelper,
+    UnpackOptions, UnpackStats,
+};
+use crate::error::{bail, UnboxError};
+use crate::formats::{
+    inner_mimetype_for_path, mimetype_for_path, ArchiveType, Compression, Detection, MAGIC_SIZE,
+};
+use crate::utils::{
+    civil_from_days, expand_globs, file_fingerprint, find_files, is_root,
+    merge_resolving_conflicts, parse_size, TempDirectory,
+};
 
-pub fn main() -> Result<(), Error> {
-    let app = App::new("unbox")
+/// Builds the command line interface.
+///
+/// This is shared with the shell completion generator so that the
+/// completions always match the accepted arguments.
+fn build_app() -> App<'static, 'static> {
+    App::new("unbox")
         .about(
             "\
              unbox unpacks archives.\n\n\
@@ -20,7 +43,8 @@ pub fn main() -> Result<(), Error> {
         )
         .after_help(
             "\
-             Files are always unpacked into the working directory by going \
+             Files are always unpacked into the working directory (or the \
+             directory given with --output) by going \
              through a temporary location first.  If you abort the tool all \
              files will be deleted.  Only one item is unpacked which is the \
              entire content of the archive.  If the archive does not have a \
@@ -38,6 +62,29 @@ pub fn main() -> Result<(), Error> {
                 .long("analyze")
                 .help("For each archive print out the format"),
         )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .requires("analyze")
+                .help("Print the analysis as one JSON object per file"),
+        )
+        .arg(
+            Arg::with_name("list")
+                .short("l")
+                .long("list")
+                .conflicts_with("analyze")
+                .help("For each archive print out the entries without unpacking it"),
+        )
+        .arg(
+            Arg::with_name("list_verbose")
+                .long("list-verbose")
+                .conflicts_with("analyze")
+                .help(
+                    "Like --list but also print the compression method, compressed \
+                     size, checksum and modification time of every entry as well as \
+                     zip entry comments and unicode path fields",
+                ),
+        )
         .arg(
             Arg::with_name("list_formats")
                 .long("list-formats")
@@ -48,61 +95,1858 @@ pub fn main() -> Result<(), Error> {
                 .long("skip-unknown")
                 .help("Skip silently over files that are not known archives"),
         )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+                .help("Print more information such as every unpacked file"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Do not show a progress bar or print the unpacked paths"),
+        )
+        .arg(
+            Arg::with_name("output_on_stderr")
+                .long("output-on-stderr")
+                .help(
+                    "Print the unpacked paths and other status messages on stderr \
+                     so that stdout only carries data",
+                ),
+        )
+        .arg(
+            Arg::with_name("show_metadata")
+                .long("show-metadata")
+                .help("Print package metadata (name, version, dependencies) for packages"),
+        )
+        .arg(
+            Arg::with_name("print_hash")
+                .long("print-hash")
+                .value_name("ALGO")
+                .possible_values(&["md5", "sha1", "sha256"])
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Print the digest of single compressed files after unpacking in \
+                     the format of sha256sum --tag (can be given multiple times)",
+                ),
+        )
+        .arg(
+            Arg::with_name("into_archive_name")
+                .long("into-archive-name")
+                .visible_alias("into-dir")
+                .help(
+                    "Always unpack into a folder named after the archive, even if it \
+                     only contains a single item",
+                ),
+        )
+        .arg(
+            Arg::with_name("no_into_dir")
+                .long("no-into-dir")
+                .conflicts_with_all(&["into_archive_name", "recursive"])
+                .help(
+                    "Move the items of archives with several items straight into the \
+                     destination if none of them exist there yet instead of creating \
+                     a folder named after the archive",
+                ),
+        )
+        .arg(
+            Arg::with_name("extract_boot")
+                .long("extract-boot")
+                .help("Also extract the El Torito boot catalog and boot image of iso images"),
+        )
+        .arg(
+            Arg::with_name("image")
+                .long("image")
+                .value_name("N")
+                .help("Unpack this image of wim files instead of the first (starting at 1)"),
+        )
+        .arg(
+            Arg::with_name("entry_index")
+                .long("entry-index")
+                .value_name("N")
+                .help("Only unpack the entry at this position in the archive (starting at 0)"),
+        )
+        .arg(
+            Arg::with_name("include")
+                .long("include")
+                .value_name("GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Only unpack entries whose path in the archive matches this \
+                     pattern (can be given multiple times)",
+                ),
+        )
+        .arg(
+            Arg::with_name("exclude")
+                .long("exclude")
+                .value_name("GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Do not unpack entries whose path in the archive matches this \
+                     pattern (can be given multiple times).  Takes precedence over \
+                     --include.  If nothing matches an empty folder is created",
+                ),
+        )
+        .arg(
+            Arg::with_name("no_preserve_permissions")
+                .long("no-preserve-permissions")
+                .help(
+                    "Do not restore the permissions stored in tar and zip archives \
+                     and use the default permissions instead",
+                ),
+        )
+        .arg(
+            Arg::with_name("no_preserve_mtime")
+                .long("no-preserve-mtime")
+                .help(
+                    "Do not restore the modification times stored in tar and zip \
+                     archives and use the current time instead",
+                ),
+        )
+        .arg(
+            Arg::with_name("owner")
+                .long("owner")
+                .alias("numeric-owner")
+                .help(
+                    "Restore the numeric user and group ids stored in tarballs.  \
+                     This requires running as root, otherwise the files belong \
+                     to the current user",
+                ),
+        )
+        .arg(
+            Arg::with_name("strip_components")
+                .long("strip-components")
+                .value_name("N")
+                .help(
+                    "Remove this many leading components from the paths of entries.  \
+                     Entries with fewer components are skipped",
+                ),
+        )
+        .arg(
+            Arg::with_name("flatten")
+                .long("flatten")
+                .conflicts_with("dirs_only")
+                .help(
+                    "Unpack all files into a single folder without the directory \
+                     structure.  Conflicting names are numbered",
+                ),
+        )
+        .arg(
+            Arg::with_name("atomic_per_file")
+                .long("atomic-per-file")
+                .help(
+                    "Write files directly into the destination instead of a scratchpad.  \
+                     Each file is renamed into place once complete so an interrupted \
+                     run only leaves complete files behind",
+                ),
+        )
+        .arg(Arg::with_name("dedup").long("dedup").help(
+            "Hardlink files with the same contents to each other instead of \
+                     keeping copies",
+        ))
+        .arg(Arg::with_name("sync").long("sync").help(
+            "Flush all unpacked files to disk before finishing.  This makes \
+                     unpacking considerably slower",
+        ))
+        .arg(
+            Arg::with_name("no_space_check")
+                .long("no-space-check")
+                .help("Do not check upfront if there is enough free space to unpack"),
+        )
+        .arg(
+            Arg::with_name("dirs_only")
+                .long("dirs-only")
+                .help("Only recreate the directory structure without extracting files"),
+        )
+        .arg(
+            Arg::with_name("rate_limit")
+                .long("rate-limit")
+                .value_name("BYTES/s")
+                .help("Limit the extraction speed to this many bytes per second"),
+        )
+        .arg(
+            Arg::with_name("max_size")
+                .long("max-size")
+                .value_name("BYTES")
+                .help(
+                    "Abort if the archive decompresses to more than this many \
+                     bytes (suffixes like 512M or 1G are accepted)",
+                ),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .short("j")
+                .long("jobs")
+                .value_name("N")
+                .help(
+                    "Unpack entries on this many threads (only for formats with \
+                     random access like zip and cab)",
+                ),
+        )
+        .arg(
+            Arg::with_name("on_conflict")
+                .long("on-conflict")
+                .alias("overwrite")
+                .value_name("MODE")
+                .possible_values(&["rename", "trash", "skip", "replace", "ask"])
+                .default_value("rename")
+                .help(
+                    "What to do if the destination already exists: rename the \
+                     unpacked item, move the existing one to the trash, skip \
+                     the archive, replace the existing item or ask",
+                ),
+        )
+        .arg(
+            Arg::with_name("interactive")
+                .short("i")
+                .long("interactive")
+                .help(
+                    "Ask whether to overwrite, rename or skip if the destination \
+                     already exists (same as --on-conflict=ask)",
+                ),
+        )
+        .arg(
+            Arg::with_name("conflict_suffix")
+                .long("conflict-suffix")
+                .value_name("PATTERN")
+                .help(
+                    "The suffix for renamed items with {n} as counter, \
+                     e.g. \" ({n})\" [default: -{n}]",
+                ),
+        )
+        .arg(
+            Arg::with_name("overwrite_if_different")
+                .long("overwrite-if-different")
+                .help(
+                    "Merge into an existing destination and only overwrite files \
+                     whose contents differ",
+                ),
+        )
+        .arg(
+            Arg::with_name("no_overwrite_newer")
+                .long("no-overwrite-newer")
+                .help(
+                    "Merge into an existing destination but keep files that are \
+                     newer than the ones in the archive",
+                ),
+        )
+        .arg(
+            Arg::with_name("progress")
+                .long("progress")
+                .value_name("MODE")
+                .possible_values(&["bytes", "count", "both"])
+                .help(
+                    "What the progress bar shows: bytes, the number of files or both \
+                     [default: both if the totals are known]",
+                ),
+        )
+        .arg(
+            Arg::with_name("encoding")
+                .long("encoding")
+                .alias("charset")
+                .value_name("ENCODING")
+                .help(
+                    "The encoding of file names in zip archives that are not flagged \
+                     as UTF-8 (e.g. cp437 or shift_jis).  With auto it is guessed \
+                     and falls back to cp437, the historical zip default [default: auto]",
+                ),
+        )
+        .arg(
+            Arg::with_name("password")
+                .long("password")
+                .value_name("PASSWORD")
+                .help("The password to decrypt encrypted zip archives with"),
+        )
+        .arg(
+            Arg::with_name("password_stdin")
+                .long("password-stdin")
+                .conflicts_with("password")
+                .help(
+                    "Read the password for encrypted zip archives from stdin so that \
+                     it does not end up in the shell history",
+                ),
+        )
+        .arg(
+            Arg::with_name("skip_empty")
+                .long("skip-empty")
+                .help("Do not create a folder for archives without any entries"),
+        )
+        .arg(
+            Arg::with_name("dry_run")
+                .long("dry-run")
+                .conflicts_with_all(&["recursive", "normalize"])
+                .help(
+                    "Print what would be unpacked and where to without writing \
+                     anything",
+                ),
+        )
+        .arg(
+            Arg::with_name("remove")
+                .short("r")
+                .long("remove")
+                .overrides_with("keep")
+                .conflicts_with_all(&["list", "list_verbose", "analyze", "dry_run", "normalize"])
+                .help(
+                    "Delete each archive once it was unpacked successfully.  Archives \
+                     that fail to unpack or are skipped are kept",
+                ),
+        )
+        .arg(
+            Arg::with_name("keep")
+                .long("keep")
+                .overrides_with("remove")
+                .help("Keep the archives after unpacking [default]"),
+        )
+        .arg(
+            Arg::with_name("test")
+                .short("t")
+                .long("test")
+                .alias("verify")
+                .conflicts_with_all(&[
+                    "list",
+                    "list_verbose",
+                    "analyze",
+                    "normalize",
+                    "recursive",
+                    "dirs_only",
+                ])
+                .help(
+                    "Read every entry of the archives and verify their checksums \
+                     without unpacking anything",
+                ),
+        )
+        .arg(
+            Arg::with_name("verify_signature")
+                .long("verify-signature")
+                .help(
+                    "Verify the v1 jar digest consistency of signed jar and apk files \
+                     and refuse to unpack them if it does not verify.  The signer is \
+                     reported but not checked and APK v2 signatures are not supported",
+                ),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("DIR")
+                .help(
+                    "Unpack into this directory instead of the working directory, \
+                     creating it if needed.  Pass - to write the unpacked files as \
+                     tar stream to stdout instead.  The directory structure, \
+                     modification times and permissions are retained",
+                ),
+        )
+        .arg(
+            Arg::with_name("directory")
+                .short("C")
+                .long("directory")
+                .value_name("DIR")
+                .conflicts_with("output")
+                .help("Same as --output DIR"),
+        )
+        .arg(
+            Arg::with_name("normalize")
+                .long("normalize")
+                .value_name("FORMAT")
+                .help(
+                    "Instead of unpacking, repack the archives into a tarball of this \
+                     format (tar, tar.gz, tar.xz, tar.bz2, tar.zst or tar.lz4) in the working \
+                     directory",
+                ),
+        )
+        .arg(
+            Arg::with_name("recursive")
+                .long("recursive")
+                .help("Also unpack archives contained in the unpacked archives"),
+        )
+        .arg(
+            Arg::with_name("max_depth")
+                .long("max-depth")
+                .value_name("DEPTH")
+                .default_value("4")
+                .help("How many levels of nested archives to unpack in recursive mode"),
+        )
+        .arg(
+            Arg::with_name("flat")
+                .long("flat")
+                .requires("recursive")
+                .help(
+                    "In recursive mode merge the contents of nested archives into the \
+                     folder they are in instead of creating a folder for each",
+                ),
+        )
+        .arg(
+            Arg::with_name("name")
+                .long("name")
+                .value_name("NAME")
+                .default_value("stdin-archive")
+                .help("The name of an archive read from stdin which names the unpacked folder"),
+        )
         .arg(
             Arg::with_name("archives")
                 .index(1)
                 .multiple(true)
-                .help("The archives to unpack"),
-        );
-    let matches = app.get_matches();
+                .help("The archives to unpack, - reads an archive from stdin"),
+        )
+        .arg(
+            Arg::with_name("generate_completions")
+                .long("generate-completions")
+                .value_name("SHELL")
+                .possible_values(&Shell::variants())
+                .hidden(true)
+                .help("Print the completion script for a shell to stdout"),
+        )
+}
+
+pub fn main() -> Result<(), UnboxError> {
+    let matches = build_app().get_matches();
+
+    if let Some(shell) = matches.value_of("generate_completions") {
+        let shell: Shell = match shell.parse() {
+            Ok(shell) => shell,
+            Err(err) => bail!("{}", err),
+        };
+        build_app().gen_completions_to("unbox", shell, &mut io::stdout());
+        return Ok(());
+    }
 
     if matches.is_present("list_formats") {
         println!("Supported file formats:");
         for variant in ArchiveType::iter() {
-            println!("- {}", style(variant).cyan());
+            println!(
+                "- {} {}",
+                style(variant).cyan(),
+                style(format_args!("({})", variant.aliases().join(", "))).dim()
+            );
         }
         return Ok(());
     }
 
-    let files: Vec<&str> = matches.values_of("archives").unwrap().collect();
+    let args: Vec<&str> = matches.values_of("archives").unwrap().collect();
+    let mut files = expand_globs(&args);
     let skip_unknown = matches.is_present("skip_unknown");
+
+    // archives streamed on stdin are read directly where the format allows
+    // it.  Analyzing, testing and normalizing work on files, so for those
+    // the archive is always buffered into a temporary file.
+    let mut stdin_archive = None;
+    let mut stdin_scratch = None;
+    if let Some(idx) = files.iter().position(|x| x == "-") {
+        if matches.is_present("password_stdin") {
+            bail!("Cannot read both the archive and the password from stdin");
+        }
+        if matches.is_present("remove") {
+            bail!("Cannot remove an archive read from stdin");
+        }
+        let stream = !["analyze", "test", "normalize"]
+            .iter()
+            .any(|&x| matches.is_present(x));
+        match read_stdin_archive(
+            io::stdin(),
+            matches.value_of("name").unwrap(),
+            stream,
+            skip_unknown,
+        )? {
+            StdinArchive::Streamed(archive) => stdin_archive = Some(archive),
+            StdinArchive::Buffered(scratch, path) => {
+                files[idx] = path.to_string_lossy().to_string();
+                stdin_scratch = Some(scratch);
+            }
+        }
+    }
+    let files: Vec<&str> = files.iter().map(|x| x.as_str()).collect();
+    let to_stdout = matches.value_of("output") == Some("-");
+    let dst = Path::new(
+        matches
+            .value_of("directory")
+            .or_else(|| matches.value_of("output").filter(|&x| x != "-"))
+            .unwrap_or("."),
+    );
+    let output = Output {
+        verbose: matches.occurrences_of("verbose"),
+        show_metadata: matches.is_present("show_metadata"),
+        on_stderr: matches.is_present("output_on_stderr") || to_stdout,
+        json: matches.is_present("json"),
+        quiet: matches.is_present("quiet"),
+    };
     if matches.is_present("analyze") {
-        analyze_archives(&files[..], skip_unknown)?;
+        analyze_archives(&files[..], skip_unknown, output)?;
+    } else if matches.is_present("list") || matches.is_present("list_verbose") {
+        let details = matches.is_present("list_verbose");
+        list_contents(&files[..], stdin_archive, skip_unknown, output, details)?;
     } else {
-        unpack_archives(&files[..], skip_unknown)?;
+        let rate_limit = match matches.value_of("rate_limit") {
+            Some(value) => match value.parse() {
+                Ok(value) => Some(value),
+                Err(_) => bail!("Invalid rate limit '{}'", value),
+            },
+            None => None,
+        };
+        let max_size = match matches.value_of("max_size") {
+            Some(value) => match parse_size(value) {
+                Some(value) => Some(value),
+                None => bail!("Invalid maximum size '{}'", value),
+            },
+            None => None,
+        };
+        let jobs = match matches.value_of("jobs") {
+            Some(value) => match value.parse() {
+                Ok(value) if value > 0 => Some(value),
+                _ => bail!("Invalid number of jobs '{}'", value),
+            },
+            None => None,
+        };
+        let entry_index = match matches.value_of("entry_index") {
+            Some(value) => match value.parse() {
+                Ok(value) => Some(value),
+                Err(_) => bail!("Invalid entry index '{}'", value),
+            },
+            None => None,
+        };
+        let image = match matches.value_of("image") {
+            Some(value) => match value.parse() {
+                Ok(value) if value > 0 => Some(value),
+                _ => bail!("Invalid image number '{}'", value),
+            },
+            None => None,
+        };
+        let strip_components = match matches.value_of("strip_components") {
+            Some(value) => match value.parse() {
+                Ok(value) => value,
+                Err(_) => bail!("Invalid number of components '{}'", value),
+            },
+            None => 0,
+        };
+        let filter = EntryFilter::new(
+            &matches
+                .values_of("include")
+                .map_or(vec![], |x| x.collect::<Vec<_>>()),
+            &matches
+                .values_of("exclude")
+                .map_or(vec![], |x| x.collect::<Vec<_>>()),
+        )?;
+        let hashes = matches.values_of("print_hash").map_or(Ok(vec![]), |x| {
+            x.map(|x| x.parse()).collect::<Result<Vec<_>, _>>()
+        })?;
+        let conflict_suffix = matches.value_of("conflict_suffix");
+        if let Some(pattern) = conflict_suffix {
+            if !pattern.contains("{n}") {
+                bail!("Conflict suffix '{}' does not contain {{n}}", pattern);
+            }
+        }
+        let password = if matches.is_present("password_stdin") {
+            Some(read_password_from_stdin()?)
+        } else {
+            matches.value_of("password").map(|x| x.to_string())
+        };
+        let options = UnpackOptions {
+            dirs_only: matches.is_present("dirs_only"),
+            rate_limit,
+            on_conflict: if matches.is_present("interactive") {
+                OnConflict::Ask
+            } else {
+                matches.value_of("on_conflict").unwrap().parse()?
+            },
+            progress: match matches.value_of("progress") {
+                Some(value) => Some(value.parse()?),
+                None => None,
+            },
+            filename_encoding: matches
+                .value_of("encoding")
+                .filter(|&x| x != "auto")
+                .map(|x| x.to_string()),
+            into_archive_name: matches.is_present("into_archive_name"),
+            unwrap_contents: matches.is_present("no_into_dir"),
+            extract_boot: matches.is_present("extract_boot"),
+            image,
+            overwrite_if_different: matches.is_present("overwrite_if_different"),
+            keep_newer: matches.is_present("no_overwrite_newer"),
+            entry_index,
+            conflict_suffix: conflict_suffix.map(|x| x.to_string()),
+            sync: matches.is_present("sync"),
+            skip_space_check: matches.is_present("no_space_check"),
+            password,
+            strip_components,
+            filter,
+            ignore_permissions: matches.is_present("no_preserve_permissions"),
+            ignore_mtime: matches.is_present("no_preserve_mtime"),
+            owner: matches.is_present("owner"),
+            max_size,
+            quiet: output.quiet,
+            verbose: output.verbose > 0,
+            jobs,
+            dry_run: matches.is_present("dry_run") || matches.is_present("test"),
+            skip_empty: matches.is_present("skip_empty"),
+            flatten: matches.is_present("flatten"),
+            atomic_per_file: matches.is_present("atomic_per_file"),
+            dedup: matches.is_present("dedup"),
+            hashes,
+            test: matches.is_present("test"),
+        };
+        let recursion = if matches.is_present("recursive") {
+            let value = matches.value_of("max_depth").unwrap();
+            match value.parse() {
+                Ok(max_depth) => Some(Recursion {
+                    max_depth,
+                    flat: matches.is_present("flat"),
+                }),
+                Err(_) => bail!("Invalid maximum depth '{}'", value),
+            }
+        } else {
+            None
+        };
+        if options.owner && !is_root() {
+            eprintln!("warning: --owner has no effect when not running as root");
+        }
+        if to_stdout && matches.is_present("remove") {
+            bail!("Cannot combine --remove with writing to stdout");
+        }
+        if options.dry_run {
+            if to_stdout {
+                bail!("Cannot combine --dry-run with writing to stdout");
+            }
+        } else if !to_stdout {
+            prepare_output_dir(dst)?;
+        }
+        if options.test {
+            test_archives(&files[..], dst, skip_unknown, output, &options)?;
+        } else if to_stdout {
+            stream_archives(&files[..], stdin_archive, skip_unknown, output, &options)?;
+        } else if let Some(format) = matches.value_of("normalize") {
+            let compression =
+                match ArchiveType::from_name(format).and_then(Compression::for_tarball) {
+                    Some(compression) => compression,
+                    None => bail!("Cannot normalize archives to '{}'", format),
+                };
+            normalize_archives(&files[..], dst, skip_unknown, output, &options, compression)?;
+        } else {
+            unpack_archives(
+                &files[..],
+                stdin_archive,
+                dst,
+                skip_unknown,
+                output,
+                &options,
+                recursion,
+                matches.is_present("verify_signature"),
+                matches.is_present("remove"),
+            )?;
+        }
     }
 
+    if let Some(scratch) = stdin_scratch {
+        scratch.cleanup()?;
+    }
     Ok(())
 }
 
-pub fn analyze_archives(files: &[&str], skip_unknown: bool) -> Result<(), Error> {
+/// An archive streamed on stdin.
+#[derive(Debug)]
+enum StdinArchive {
+    /// The archive is read from the stream directly.
+    Streamed(Box<dyn Archive>),
+    /// The archive was buffered into a file in a temporary folder.
+    Buffered(TempDirectory, PathBuf),
+}
+
+/// Reads an archive that is streamed from a reader.
+///
+/// If `stream` is set tarballs, ar archives and single compressed files
+/// are read from the stream directly.  Other formats need to seek, so they
+/// are buffered into a temporary file named after the given name so that
+/// the unpacked folder is named after it as well.  Fails if the type of
+/// the archive cannot be detected unless unknown archives are skipped.
+fn read_stdin_archive<R: Read + 'static>(
+    mut rdr: R,
+    name: &str,
+    stream: bool,
+    skip_unknown: bool,
+) -> Result<StdinArchive, UnboxError> {
+    if Path::new(name).file_name().and_then(|x| x.to_str()) != Some(name) {
+        bail!("Invalid archive name '{}'", name);
+    }
+    let mut prefix = vec![];
+    (&mut rdr)
+        .take(MAGIC_SIZE as u64)
+        .read_to_end(&mut prefix)?;
+    let ty = match ArchiveType::for_bytes(&prefix, Some(name)) {
+        Some(ty) => Some(ty),
+        None if skip_unknown => None,
+        None => bail!("Could not determine archive type of stdin"),
+    };
+    let mut rdr = io::Cursor::new(prefix).chain(rdr);
+    match ty {
+        Some(ty) if stream && ty.can_stream() => {
+            return Ok(StdinArchive::Streamed(ty.open_stream(rdr, name)?));
+        }
+        _ => {}
+    }
+    let scratch = TempDirectory::for_path(&".unbox-stdin")?;
+    fs::create_dir_all(scratch.path())?;
+    let path = scratch.path().join(name);
+    let mut f = fs::File::create(&path)?;
+    io::copy(&mut rdr, &mut f)?;
+    Ok(StdinArchive::Buffered(scratch, path))
+}
+
+/// Reads a password from the first line of stdin.
+fn read_password_from_stdin() -> Result<String, UnboxError> {
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    let password = line.trim_end_matches(&['\r', '\n'][..]);
+    if password.is_empty() {
+        bail!("No password was given on stdin");
+    }
+    Ok(password.to_string())
+}
+
+/// Formats how long detecting the type of an archive took.
+fn detection_timing(path: &str, detection: &Detection) -> String {
+    match detection.archive_type() {
+        Some(ty) => format!("{}: detected {} in {:.2?}", path, ty, detection.elapsed),
+        None => format!(
+            "{}: failed to detect type in {:.2?}",
+            path, detection.elapsed
+        ),
+    }
+}
+
+/// Detects the type of an archive.
+///
+/// In verbose mode this warns if the file name suggests a different type.
+/// With `-vv` the time spent on detection is printed as well.
+fn detect_archive_type(path: &str, verbose: u64) -> Option<ArchiveType> {
+    if verbose == 0 {
+        return ArchiveType::for_path(&path);
+    }
+    let detection = ArchiveType::detect(&path);
+    if verbose >= 2 {
+        eprintln!("{}", style(detection_timing(path, &detection)).dim());
+    }
+    if detection.is_mismatch() {
+        eprintln!(
+            "warning: {} looks like a {} but is named like a {}",
+            path,
+            detection.by_magic.unwrap(),
+            detection.by_filename.unwrap()
+        );
+    }
+    detection.archive_type()
+}
+
+/// Controls what is printed and where.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Output {
+    /// How verbose the output is.
+    pub verbose: u64,
+    /// Print package metadata.
+    pub show_metadata: bool,
+    /// Print status messages on stderr instead of stdout.
+    pub on_stderr: bool,
+    /// Print machine readable JSON instead of human readable output.
+    pub json: bool,
+    /// Do not print the paths of unpacked archives.
+    pub quiet: bool,
+}
+
+impl Output {
+    /// Prints a status line such as the path of an unpacked archive.
+    fn status<D: fmt::Display>(&self, msg: D) {
+        if self.on_stderr {
+            eprintln!("{}", msg);
+        } else {
+            println!("{}", msg);
+        }
+    }
+}
+
+/// Prints the package metadata of an archive.
+///
+/// Failing to read the metadata is not fatal and only reported.
+fn print_metadata(archive: &dyn Archive, output: Output) {
+    match archive.metadata() {
+        Ok(metadata) => {
+            for (key, value) in metadata {
+                output.status(format_args!("  {}: {}", style(key).dim(), value));
+            }
+        }
+        Err(err) => eprintln!(
+            "warning: could not read metadata of {}: {}",
+            archive.path().display(),
+            err
+        ),
+    }
+}
+
+/// Describes the analysis of a file as JSON object.
+fn analysis_to_json(path: &str, ty: Option<ArchiveType>) -> serde_json::Value {
+    serde_json::json!({
+        "path": path,
+        "detected": ty.is_some(),
+        "archive_type": ty.map(|ty| ty.to_string()),
+        "mimetype": mimetype_for_path(&path),
+        "inner_mimetype": ty.and_then(|ty| inner_mimetype_for_path(&path, ty)),
+    })
+}
+
+/// Detects the type of an archive given on the command line and opens it.
+///
+/// `-` stands for the archive streamed on stdin which was opened before.
+/// Returns `None` if the type of the archive is unknown.
+fn open_archive(
+    path: &str,
+    stdin: &mut Option<Box<dyn Archive>>,
+    verbose: u64,
+) -> Result<Option<Box<dyn Archive>>, UnboxError> {
+    if path == "-" {
+        if let Some(archive) = stdin.take() {
+            return Ok(Some(archive));
+        }
+    }
+    match detect_archive_type(path, verbose) {
+        Some(ty) => Ok(Some(ty.open(&path)?)),
+        None => Ok(None),
+    }
+}
+
+/// Describes the detected contents of a compressed file for analysis.
+fn describe_inner_mimetype(mimetype: &str) -> String {
+    if mimetype == "application/octet-stream" {
+        "contains unrecognized data".into()
+    } else {
+        format!("contains {}", mimetype)
+    }
+}
+
+pub fn analyze_archives(
+    files: &[&str],
+    skip_unknown: bool,
+    output: Output,
+) -> Result<(), UnboxError> {
     for path in files {
-        if let Some(ty) = ArchiveType::for_path(&path) {
-            println!("{}: {}", style(path).dim(), style(ty).cyan());
+        let ty = detect_archive_type(path, output.verbose);
+        if output.json {
+            if ty.is_some() || !skip_unknown {
+                output.status(analysis_to_json(path, ty));
+            }
+        } else if let Some(ty) = ty {
+            output.status(format_args!(
+                "{}: {}{}",
+                style(path).dim(),
+                style(ty.label(path)).cyan(),
+                match inner_mimetype_for_path(path, ty) {
+                    Some(mimetype) => format!(" ({})", describe_inner_mimetype(mimetype)),
+                    None => "".into(),
+                }
+            ));
+            if output.show_metadata {
+                print_metadata(&*ty.open(path)?, output);
+            }
         } else if !skip_unknown {
-            println!("{}: {}", style(path).dim(), style("unsupported").red());
+            output.status(format_args!(
+                "{}: {}",
+                style(path).dim(),
+                style("unsupported").red()
+            ));
         }
     }
     Ok(())
 }
 
-pub fn unpack_archives(files: &[&str], skip_unknown: bool) -> Result<(), Error> {
-    let mut archives = vec![];
+/// Formats an entry as line with the size and path.
+fn format_entry(entry: &ArchiveEntry) -> String {
+    match entry.kind {
+        EntryKind::Directory => format!(
+            "{:>12}  {}",
+            "-",
+            style(format_args!("{}/", entry.name)).cyan()
+        ),
+        EntryKind::File => format!("{:>12}  {}", entry.size, entry.name),
+        EntryKind::Other => format!("{:>12}  {}", entry.size, style(&entry.name).dim()),
+    }
+}
 
+/// Formats a timestamp as date and time in UTC.
+fn format_timestamp(time: FileTime) -> String {
+    let seconds = time.unix_seconds();
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let seconds = seconds.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Formats an entry like `format_entry` preceded by the compression
+/// method, compressed size, checksum and modification time.
+///
+/// Details the format does not store are shown as a dash.  Zip entries
+/// are followed by their comment and a note if they carry a unicode path.
+fn format_entry_details(entry: &ArchiveEntry) -> String {
+    let mut rv = format!(
+        "{:<10} {:>12}  {:>8}  {:>19}  {}",
+        entry.compression.as_deref().unwrap_or("-"),
+        entry
+            .compressed_size
+            .map_or_else(|| "-".to_string(), |x| x.to_string()),
+        entry
+            .crc32
+            .map_or_else(|| "-".to_string(), |x| format!("{:08x}", x)),
+        entry
+            .mtime
+            .map_or_else(|| "-".to_string(), format_timestamp),
+        format_entry(entry)
+    );
+    if entry.unicode_path {
+        rv.push_str(&format!(" {}", style("(unicode path)").dim()));
+    }
+    if let Some(ref comment) = entry.comment {
+        rv.push_str(&format!(" {}", style(format_args!("# {}", comment)).dim()));
+    }
+    rv
+}
+
+/// Prints the entries of archives without unpacking them.
+///
+/// If more than one archive is given each listing is preceded by the path
+/// of the archive.  With `details` the entries are formatted with
+/// `format_entry_details`.
+pub fn list_contents(
+    files: &[&str],
+    mut stdin: Option<Box<dyn Archive>>,
+    skip_unknown: bool,
+    output: Output,
+    details: bool,
+) -> Result<(), UnboxError> {
+    let mut archives = vec![];
     for path in files {
-        if let Some(ty) = ArchiveType::for_path(&path) {
-            archives.push(ty.open(&path)?);
+        if let Some(archive) = open_archive(path, &mut stdin, output.verbose)? {
+            archives.push((path, archive));
         } else if !skip_unknown {
             bail!("Could not determine archive type of '{}'", path);
         }
     }
 
-    for mut archive in archives {
-        let mut helper = UnpackHelper::create(&*archive, &".")?;
+    let show_path = archives.len() > 1;
+    for (path, mut archive) in archives {
+        if show_path {
+            output.status(style(format_args!("{}:", path)).dim());
+        }
+        for entry in archive.entries()? {
+            if details {
+                output.status(format_entry_details(&entry));
+            } else {
+                output.status(format_entry(&entry));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads every entry of the archives to check their integrity.
+///
+/// All archives are tested even if one fails and every failure is reported
+/// with its cause.  Fails if any archive did not pass.
+pub fn test_archives(
+    files: &[&str],
+    dst: &Path,
+    skip_unknown: bool,
+    output: Output,
+    options: &UnpackOptions,
+) -> Result<(), UnboxError> {
+    let mut tested = 0;
+    let mut failed = 0;
+    for path in files {
+        let ty = match detect_archive_type(path, output.verbose) {
+            Some(ty) => ty,
+            None if skip_unknown => continue,
+            None => bail!("Could not determine archive type of '{}'", path),
+        };
+        tested += 1;
+        let rv = ty.open(&path).and_then(|mut archive| {
+            let mut helper = UnpackHelper::create(&*archive, &dst, options.clone())?;
+            archive.unpack(&mut helper)
+        });
+        match rv {
+            Ok(()) => output.status(format_args!("{}: {}", path, style("ok").green())),
+            Err(err) => {
+                failed += 1;
+                eprintln!("{}: {} {}", path, style("failed:").red(), err);
+                let mut source = std::error::Error::source(&err);
+                while let Some(cause) = source {
+                    eprintln!("  caused by: {}", cause);
+                    source = cause.source();
+                }
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} archives failed the test", failed, tested);
+    }
+    Ok(())
+}
+
+/// Controls how nested archives are unpacked.
+#[derive(Debug, Copy, Clone)]
+pub struct Recursion {
+    /// How many levels of nested archives are unpacked.
+    pub max_depth: u32,
+    /// Merge the contents of nested archives into the folder they are in.
+    pub flat: bool,
+}
+
+/// Unpacks the archives nested in an unpacked path.
+///
+/// Nested archives are unpacked next to where they are and removed
+/// afterwards.  In flat mode their contents are merged into the folder
+/// instead.  Once `max_depth` levels were unpacked the remaining archives
+/// are left alone.  So are archives identical to one they were found in as
+/// they would expand forever.  `ancestors` holds the fingerprints of those.
+/// Returns how many items had to be renamed while merging.
+fn unpack_nested(
+    path: &Path,
+    depth: u32,
+    ancestors: &[u64],
+    recursion: Recursion,
+    options: &UnpackOptions,
+) -> Result<u64, UnboxError> {
+    let mut collisions = 0;
+    for nested_path in find_files(path)? {
+        let ty = match ArchiveType::for_path(&nested_path) {
+            Some(ty) => ty,
+            None => continue,
+        };
+        if depth >= recursion.max_depth {
+            eprintln!(
+                "note: not unpacking {} as the maximum depth of {} was reached",
+                nested_path.display(),
+                recursion.max_depth
+            );
+            continue;
+        }
+        let fingerprint = file_fingerprint(&nested_path)?;
+        if ancestors.contains(&fingerprint) {
+            eprintln!(
+                "warning: not unpacking {} as it contains itself",
+                nested_path.display()
+            );
+            continue;
+        }
+        let ancestors = [ancestors, &[fingerprint]].concat();
+        let mut archive = ty.open(&nested_path)?;
+        let parent = nested_path.parent().unwrap();
+        if !recursion.flat {
+            let mut helper = UnpackHelper::create(&*archive, &parent, options.clone())?;
+            archive.unpack(&mut helper)?;
+            let empty = helper.is_empty();
+            let rv = helper.commit()?;
+            // skipped empty archives are kept in place of their contents
+            if empty && options.skip_empty {
+                continue;
+            }
+            fs::remove_file(&nested_path)?;
+            unpack_nested(&rv, depth + 1, &ancestors, recursion, options)?;
+            continue;
+        }
+
+        // unpack into a scratch folder first so that nothing in the parent
+        // folder conflicts with the wrapper folder.
+        let scratch = TempDirectory::for_path(&parent.join(".unbox-flat"))?;
+        fs::create_dir_all(scratch.path())?;
+        let mut nested_options = options.clone();
+        nested_options.into_archive_name = true;
+        let mut helper = UnpackHelper::create(&*archive, &scratch.path(), nested_options)?;
         archive.unpack(&mut helper)?;
-        let path = helper.commit()?;
-        println!("{}", path.display());
+        let empty = helper.is_empty();
+        let rv = helper.commit()?;
+        if empty && options.skip_empty {
+            scratch.cleanup()?;
+            continue;
+        }
+        fs::remove_file(&nested_path)?;
+        collisions += unpack_nested(&rv, depth + 1, &ancestors, recursion, options)?;
+        collisions += merge_resolving_conflicts(&rv, parent, options.conflict_suffix.as_deref())?;
+        scratch.cleanup()?;
+    }
+    Ok(collisions)
+}
+
+/// Makes sure the output directory exists and can be written to.
+fn prepare_output_dir(path: &Path) -> Result<(), UnboxError> {
+    if let Err(err) = fs::create_dir_all(path) {
+        bail!(
+            "Could not create output directory '{}': {}",
+            path.display(),
+            err
+        );
+    }
+    // the permission bits do not tell if the current user can write or if
+    // the file system is mounted read-only, so probe instead.
+    let probe = path.join(format!(".unbox-{}", Uuid::new_v4()));
+    match fs::create_dir(&probe) {
+        Ok(()) => fs::remove_dir(&probe)?,
+        Err(err) => bail!(
+            "Output directory '{}' is not writable: {}",
+            path.display(),
+            err
+        ),
+    }
+    Ok(())
+}
+
+/// Formats the summary printed after unpacking an archive.
+///
+/// Paths below the working directory are shown relative to it.
+fn format_stats(stats: &UnpackStats, path: &Path) -> String {
+    let path = env::current_dir()
+        .ok()
+        .and_then(|cwd| path.strip_prefix(cwd).ok().map(|x| Path::new(".").join(x)))
+        .unwrap_or_else(|| path.to_path_buf());
+    let mut rv = format!(
+        "extracted {} {} ({}) into {} in {:.1}s",
+        stats.files,
+        if stats.files == 1 { "file" } else { "files" },
+        HumanBytes(stats.bytes),
+        path.display(),
+        stats.elapsed.as_secs_f64()
+    );
+    if stats.kept_newer > 0 {
+        rv.push_str(&format!(
+            ", kept {} newer existing {}",
+            stats.kept_newer,
+            if stats.kept_newer == 1 {
+                "file"
+            } else {
+                "files"
+            }
+        ));
+    }
+    rv
+}
+
+/// Formats a digest like `sha256sum --tag` does.
+///
+/// The unpacked path is shown below the destination as it was given.
+fn format_digest(algorithm: HashAlgorithm, digest: &str, dst: &Path, path: &Path) -> String {
+    let path = dst
+        .canonicalize()
+        .ok()
+        .and_then(|canonical| path.strip_prefix(canonical).ok().map(|x| dst.join(x)))
+        .unwrap_or_else(|| path.to_path_buf());
+    let name = match algorithm {
+        HashAlgorithm::Md5 => "MD5",
+        HashAlgorithm::Sha1 => "SHA1",
+        HashAlgorithm::Sha256 => "SHA256",
+    };
+    format!("{} ({}) = {}", name, path.display(), digest)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn unpack_archives(
+    files: &[&str],
+    mut stdin: Option<Box<dyn Archive>>,
+    dst: &Path,
+    skip_unknown: bool,
+    output: Output,
+    options: &UnpackOptions,
+    recursion: Option<Recursion>,
+    verify_signature: bool,
+    remove: bool,
+) -> Result<(), UnboxError> {
+    let mut archives = vec![];
+
+    for path in files {
+        if let Some(archive) = open_archive(path, &mut stdin, output.verbose)? {
+            archives.push((archive, *path == "-"));
+        } else if !skip_unknown {
+            bail!("Could not determine archive type of '{}'", path);
+        }
+    }
+
+    for (mut archive, streamed) in archives {
+        if verify_signature {
+            match archive.verify_signature() {
+                Ok(signer) => eprintln!(
+                    "{}: signed by {}",
+                    archive.path().display(),
+                    style(signer).green()
+                ),
+                Err(err) => bail!(
+                    "Could not verify signature of '{}': {}",
+                    archive.path().display(),
+                    err
+                ),
+            }
+        }
+        let mut helper = UnpackHelper::create(&*archive, &dst, options.clone())?;
+        if !helper.is_skipped() {
+            archive.unpack(&mut helper)?;
+        }
+        let empty = !helper.is_skipped() && helper.is_empty();
+        let digests = helper.digests().to_vec();
+        let (path, stats) = helper.commit_with_stats()?;
+        let path = match path {
+            Some(path) if options.dry_run => {
+                output.status(format_args!(
+                    "{}: would unpack into {}",
+                    archive.path().display(),
+                    path.display()
+                ));
+                continue;
+            }
+            Some(path) => path,
+            None if empty => {
+                eprintln!("{}: skipped as it is empty", archive.path().display());
+                continue;
+            }
+            None => {
+                eprintln!(
+                    "{}: skipped as the destination already exists",
+                    archive.path().display()
+                );
+                continue;
+            }
+        };
+        if let Some(recursion) = recursion {
+            // a streamed archive has no file to compare nested ones with
+            let ancestors = match streamed {
+                true => vec![],
+                false => vec![file_fingerprint(archive.path())?],
+            };
+            let collisions = unpack_nested(&path, 0, &ancestors, recursion, options)?;
+            if recursion.flat {
+                eprintln!(
+                    "{}: merged {} files ({} renamed on conflicts)",
+                    path.display(),
+                    find_files(&path)?.len(),
+                    collisions
+                );
+            }
+        }
+        if !output.quiet {
+            output.status(path.display());
+            eprintln!("{}", format_stats(&stats, &path));
+        }
+        for (algorithm, digest) in &digests {
+            output.status(format_digest(*algorithm, digest, dst, &path));
+        }
+        if !options.hashes.is_empty() && digests.is_empty() {
+            eprintln!(
+                "{}: no digest printed as it is not a single compressed file",
+                archive.path().display()
+            );
+        }
+        if output.show_metadata {
+            print_metadata(&*archive, output);
+        }
+        if remove {
+            for volume in archive.volumes() {
+                fs::remove_file(volume)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Unpacks an archive into a scratch folder and writes it as tarball.
+///
+/// The contents are placed below a folder named after the archive so that
+/// directory structure, modification times and permissions are retained.
+#[cfg(feature = "tar")]
+fn repack_archive<W: Write>(
+    archive: &mut dyn Archive,
+    scratch: &Path,
+    options: &UnpackOptions,
+    writer: W,
+) -> Result<W, UnboxError> {
+    use crate::formats::pack_directory;
+
+    let contents = scratch.join("contents");
+    fs::create_dir_all(&contents)?;
+    let mut helper = UnpackHelper::create(&*archive, &contents, options.clone())?;
+    archive.unpack(&mut helper)?;
+    let unpacked = helper.commit()?;
+
+    // a single unpacked file is placed in the folder rather than replacing it
+    let src = if unpacked.is_dir() {
+        &unpacked
+    } else {
+        &contents
+    };
+    pack_directory(src, &ArchiveType::name_hint(&archive.path()), writer)
+}
+
+/// Repacks an archive into a tarball in the given folder.
+///
+/// Returns the path of the tarball.
+#[cfg(feature = "tar")]
+fn normalize_archive(
+    archive: &mut dyn Archive,
+    dst: &Path,
+    options: &UnpackOptions,
+    compression: Compression,
+) -> Result<PathBuf, UnboxError> {
+    use std::io::BufWriter;
+
+    use crate::utils::rename_resolving_conflict;
+
+    let name = ArchiveType::name_hint(&archive.path());
+    let filename = format!("{}.tar{}", name, compression.extension());
+    let scratch = TempDirectory::for_path(&dst.join(&filename))?;
+    fs::create_dir_all(scratch.path())?;
+    let tmp_path = scratch.path().join(&filename);
+    let writer = compression.compress(BufWriter::new(fs::File::create(&tmp_path)?))?;
+    repack_archive(archive, scratch.path(), options, writer)?
+        .finish()?
+        .flush()?;
+    let rv = rename_resolving_conflict(
+        &tmp_path,
+        &dst.join(&filename),
+        options.conflict_suffix.as_deref(),
+    )?;
+    scratch.cleanup()?;
+    Ok(rv)
+}
+
+/// Writes an archive as uncompressed tar stream to stdout.
+#[cfg(feature = "tar")]
+fn stream_archive(archive: &mut dyn Archive, options: &UnpackOptions) -> Result<(), UnboxError> {
+    let scratch = TempDirectory::for_path(&ArchiveType::name_hint(&archive.path()))?;
+    let stdout = io::stdout();
+    repack_archive(archive, scratch.path(), options, stdout.lock())?.flush()?;
+    scratch.cleanup()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "tar"))]
+fn normalize_archive(
+    _archive: &mut dyn Archive,
+    _dst: &Path,
+    _options: &UnpackOptions,
+    _compression: Compression,
+) -> Result<PathBuf, UnboxError> {
+    bail!("normalizing archives requires the tar feature");
+}
+
+#[cfg(not(feature = "tar"))]
+fn stream_archive(_archive: &mut dyn Archive, _options: &UnpackOptions) -> Result<(), UnboxError> {
+    bail!("writing tar streams requires the tar feature");
+}
+
+pub fn normalize_archives(
+    files: &[&str],
+    dst: &Path,
+    skip_unknown: bool,
+    output: Output,
+    options: &UnpackOptions,
+    compression: Compression,
+) -> Result<(), UnboxError> {
+    for path in files {
+        if let Some(ty) = detect_archive_type(path, output.verbose) {
+            let mut archive = ty.open(&path)?;
+            let rv = normalize_archive(&mut *archive, dst, options, compression)?;
+            output.status(rv.display());
+        } else if !skip_unknown {
+            bail!("Could not determine archive type of '{}'", path);
+        }
     }
+    Ok(())
+}
 
+/// Unpacks a single archive as tar stream to stdout.
+pub fn stream_archives(
+    files: &[&str],
+    mut stdin: Option<Box<dyn Archive>>,
+    skip_unknown: bool,
+    output: Output,
+    options: &UnpackOptions,
+) -> Result<(), UnboxError> {
+    let mut archives = vec![];
+    for path in files {
+        if let Some(archive) = open_archive(path, &mut stdin, output.verbose)? {
+            archives.push(archive);
+        } else if !skip_unknown {
+            bail!("Could not determine archive type of '{}'", path);
+        }
+    }
+    if archives.len() > 1 {
+        bail!("Only one archive can be written to stdout");
+    }
+    for mut archive in archives {
+        stream_archive(&mut *archive, options)?;
+        output.status(format_args!(
+            "{}: written to stdout",
+            archive.path().display()
+        ));
+    }
     Ok(())
 }
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_max_depth() {
+    use crate::formats::{build_zip, ZipArchive};
+    use crate::utils::make_test_dir;
+
+    let inner = build_zip(&[("a.txt", b"a")]);
+    let mid = build_zip(&[("inner.zip", &inner)]);
+    let outer = build_zip(&[("mid.zip", &mid), ("readme.txt", b"readme")]);
+
+    let unpack = |max_depth| {
+        let dir = make_test_dir();
+        let archive_path = dir.join("outer.zip");
+        fs::write(&archive_path, &outer).unwrap();
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        let rv = helper.commit().unwrap();
+        let recursion = Recursion {
+            max_depth,
+            flat: false,
+        };
+        unpack_nested(&rv, 0, &[], recursion, &UnpackOptions::default()).unwrap();
+        (dir, rv)
+    };
+
+    let (dir, rv) = unpack(1);
+    assert!(!rv.join("mid.zip").exists());
+    assert_eq!(fs::read(rv.join("inner.zip")).unwrap(), inner);
+    fs::remove_dir_all(&dir).unwrap();
+
+    let (dir, rv) = unpack(4);
+    assert!(!rv.join("inner.zip").exists());
+    assert_eq!(fs::read(rv.join("a.txt")).unwrap(), b"a");
+    assert_eq!(fs::read(rv.join("readme.txt")).unwrap(), b"readme");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_skip_empty_nested() {
+    use crate::formats::{build_zip, ZipArchive};
+    use crate::utils::make_test_dir;
+
+    let empty = build_zip(&[]);
+    let outer = build_zip(&[("empty.zip", &empty), ("readme.txt", b"readme")]);
+    let options = UnpackOptions {
+        skip_empty: true,
+        ..UnpackOptions::default()
+    };
+
+    for &flat in &[false, true] {
+        let dir = make_test_dir();
+        let archive_path = dir.join("outer.zip");
+        fs::write(&archive_path, &outer).unwrap();
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        let mut helper = UnpackHelper::create(&archive, &dir, options.clone()).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        let rv = helper.commit().unwrap();
+        let recursion = Recursion { max_depth: 4, flat };
+        unpack_nested(&rv, 0, &[], recursion, &options).unwrap();
+        assert_eq!(fs::read(rv.join("empty.zip")).unwrap(), empty);
+        assert_eq!(fs::read(rv.join("readme.txt")).unwrap(), b"readme");
+        assert_eq!(fs::read_dir(&rv).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_self_containing() {
+    use crate::formats::{build_zip, ZipArchive};
+    use crate::utils::make_test_dir;
+
+    let inner = build_zip(&[("a.txt", b"a")]);
+    let outer = build_zip(&[("copy.zip", &inner), ("other.zip", &inner)]);
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("outer.zip");
+    fs::write(&archive_path, &outer).unwrap();
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+
+    // pretend the copies are the archive they were found in
+    let inner_path = dir.join("inner.zip");
+    fs::write(&inner_path, &inner).unwrap();
+    let recursion = Recursion {
+        max_depth: 10,
+        flat: false,
+    };
+    let ancestors = [file_fingerprint(&inner_path).unwrap()];
+    unpack_nested(&rv, 0, &ancestors, recursion, &UnpackOptions::default()).unwrap();
+    assert_eq!(fs::read(rv.join("copy.zip")).unwrap(), inner);
+    assert_eq!(fs::read(rv.join("other.zip")).unwrap(), inner);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_detection_timing() {
+    use crate::formats::build_zip;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("timed.zip");
+    fs::write(&archive_path, build_zip(&[("a.txt", b"a")])).unwrap();
+    let path = archive_path.to_str().unwrap();
+
+    let line = detection_timing(path, &ArchiveType::detect(&path));
+    assert!(line.starts_with(&format!("{}: detected zip archive in ", path)));
+
+    let missing = dir.join("missing.bin");
+    let missing = missing.to_str().unwrap();
+    let line = detection_timing(missing, &ArchiveType::detect(&missing));
+    assert!(line.starts_with(&format!("{}: failed to detect type in ", missing)));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(all(feature = "tar", feature = "zip"))]
+#[test]
+fn test_normalize() {
+    use std::io::Read;
+
+    use libflate::gzip;
+    use tar::Archive as TarArchiveReader;
+
+    use crate::formats::{build_zip, ZipArchive};
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("project.zip");
+    fs::write(
+        &archive_path,
+        build_zip(&[("a.txt", b"a"), ("sub/b.txt", b"b")]),
+    )
+    .unwrap();
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    let rv = normalize_archive(
+        &mut archive,
+        &dir,
+        &UnpackOptions::default(),
+        Compression::Gz,
+    )
+    .unwrap();
+    assert_eq!(rv, dir.canonicalize().unwrap().join("project.tar.gz"));
+
+    let mut tarball =
+        TarArchiveReader::new(gzip::Decoder::new(fs::File::open(&rv).unwrap()).unwrap());
+    let mut files = vec![];
+    for entry in tarball.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path().unwrap().to_string_lossy().to_string();
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents).unwrap();
+        files.push((path, contents));
+    }
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            ("project/".to_string(), "".to_string()),
+            ("project/a.txt".to_string(), "a".to_string()),
+            ("project/sub".to_string(), "".to_string()),
+            ("project/sub/b.txt".to_string(), "b".to_string()),
+        ]
+    );
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(all(feature = "tar", feature = "zip"))]
+#[test]
+fn test_flat() {
+    use tar::{Builder, Header};
+
+    use crate::formats::{build_zip, ZipArchive};
+    use crate::utils::make_test_dir;
+
+    let build_tar = |files: &[(&str, &[u8])]| {
+        let mut builder = Builder::new(vec![]);
+        for &(name, contents) in files {
+            let mut header = Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, contents).unwrap();
+        }
+        builder.into_inner().unwrap()
+    };
+    let one = build_tar(&[("docs/a.txt", b"a"), ("readme.txt", b"one")]);
+    let two = build_tar(&[("docs/b.txt", b"b"), ("readme.txt", b"two")]);
+    let outer = build_zip(&[("one.tar", &one), ("two.tar", &two)]);
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("outer.zip");
+    fs::write(&archive_path, &outer).unwrap();
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    let recursion = Recursion {
+        max_depth: 4,
+        flat: true,
+    };
+    let collisions = unpack_nested(&rv, 0, &[], recursion, &UnpackOptions::default()).unwrap();
+    assert_eq!(collisions, 1);
+
+    let mut files: Vec<_> = find_files(&rv)
+        .unwrap()
+        .into_iter()
+        .map(|x| x.strip_prefix(&rv).unwrap().to_string_lossy().to_string())
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        vec!["docs/a.txt", "docs/b.txt", "readme.txt", "readme.txt-2"]
+    );
+    let mut readmes = vec![
+        fs::read(rv.join("readme.txt")).unwrap(),
+        fs::read(rv.join("readme.txt-2")).unwrap(),
+    ];
+    readmes.sort();
+    assert_eq!(readmes, vec![b"one".to_vec(), b"two".to_vec()]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_prepare_output_dir() {
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let output = dir.join("a/b/extract");
+    prepare_output_dir(&output).unwrap();
+    assert!(output.is_dir());
+    assert_eq!(fs::read_dir(&output).unwrap().count(), 0);
+
+    let file = dir.join("file.txt");
+    fs::write(&file, b"").unwrap();
+    let err = prepare_output_dir(&file.join("extract")).unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("Could not create output directory"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_analysis_to_json() {
+    use crate::formats::build_zip;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("data.zip");
+    fs::write(&archive_path, build_zip(&[("a.txt", b"a")])).unwrap();
+    let unknown_path = dir.join("notes.txt");
+    fs::write(&unknown_path, b"just some text\n").unwrap();
+
+    let archive_path = archive_path.to_str().unwrap();
+    let rv = analysis_to_json(archive_path, ArchiveType::for_path(&archive_path));
+    assert_eq!(
+        rv,
+        serde_json::json!({
+            "path": archive_path,
+            "detected": true,
+            "archive_type": "zip archive",
+            "mimetype": "application/zip",
+            "inner_mimetype": null,
+        })
+    );
+
+    let unknown_path = unknown_path.to_str().unwrap();
+    let rv = analysis_to_json(unknown_path, ArchiveType::for_path(&unknown_path));
+    assert_eq!(rv["detected"], false);
+    assert!(rv["archive_type"].is_null());
+    assert_eq!(rv["mimetype"], "text/plain");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_read_stdin_archive() {
+    use std::io::Cursor;
+
+    use crate::formats::build_zip;
+
+    // zip archives need to seek so they are buffered
+    let zip = build_zip(&[("a.txt", b"a")]);
+    let (scratch, path) =
+        match read_stdin_archive(Cursor::new(zip.clone()), "download", true, false) {
+            Ok(StdinArchive::Buffered(scratch, path)) => (scratch, path),
+            other => panic!("unexpected {:?}", other),
+        };
+    assert_eq!(path.file_name().unwrap(), "download");
+    assert_eq!(fs::read(&path).unwrap(), zip);
+    assert_eq!(ArchiveType::for_path(&path), Some(ArchiveType::Zip));
+    scratch.cleanup().unwrap();
+
+    // single compressed files are read from the stream
+    let compressed = &include_bytes!("../tests/fixtures/words.txt.Z")[..];
+    let rv = read_stdin_archive(Cursor::new(compressed), "words.txt.Z", true, false);
+    match rv {
+        Ok(StdinArchive::Streamed(archive)) => {
+            assert_eq!(archive.path(), Path::new("words.txt.Z"));
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    let rv = read_stdin_archive(Cursor::new(compressed), "words.txt.Z", false, false);
+    match rv {
+        Ok(StdinArchive::Buffered(scratch, path)) => {
+            assert_eq!(fs::read(&path).unwrap(), compressed);
+            scratch.cleanup().unwrap();
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let err = read_stdin_archive(Cursor::new(b"text"), "download", true, false).unwrap_err();
+    assert_eq!(err.to_string(), "Could not determine archive type of stdin");
+    assert!(read_stdin_archive(Cursor::new(zip), "../escape", true, false).is_err());
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_test_archives() {
+    use crate::formats::build_zip;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let zip = build_zip(&[("a.txt", b"hello world"), ("b.txt", b"b")]);
+    let good_path = dir.join("good.zip");
+    fs::write(&good_path, &zip).unwrap();
+    let bad_path = dir.join("bad.zip");
+    let pos = zip.windows(11).position(|x| x == b"hello world").unwrap();
+    let mut bad = zip.clone();
+    bad[pos] = b'j';
+    fs::write(&bad_path, &bad).unwrap();
+
+    let options = UnpackOptions {
+        dry_run: true,
+        test: true,
+        ..UnpackOptions::default()
+    };
+    let good = good_path.to_str().unwrap();
+    let bad = bad_path.to_str().unwrap();
+    let output = Output::default();
+    test_archives(&[good], &dir, false, output, &options).unwrap();
+    let err = test_archives(&[good, bad], &dir, false, output, &options).unwrap_err();
+    assert_eq!(err.to_string(), "1 of 2 archives failed the test");
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_generate_completions() {
+    for name in Shell::variants().iter() {
+        let args = ["unbox", "--generate-completions", name];
+        let matches = build_app().get_matches_from_safe(args).unwrap();
+        let shell: Shell = matches
+            .value_of("generate_completions")
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut buf = vec![];
+        build_app().gen_completions_to("unbox", shell, &mut buf);
+        let script = String::from_utf8(buf).unwrap();
+        assert!(script.contains("unbox"), "{}", name);
+        assert!(script.contains("recursive"), "{}", name);
+    }
+    let args = ["unbox", "--generate-completions", "tcsh"];
+    assert!(build_app().get_matches_from_safe(args).is_err());
+}
+
+#[test]
+fn test_format_entry_details() {
+    let mut entry = ArchiveEntry::new("docs/readme.txt", 11, EntryKind::File);
+    assert_eq!(
+        console::strip_ansi_codes(&format_entry_details(&entry)),
+        format!(
+            "{:<10} {:>12}  {:>8}  {:>19}  {:>12}  docs/readme.txt",
+            "-", "-", "-", "-", 11
+        )
+    );
+
+    entry.compression = Some("deflate".into());
+    entry.compressed_size = Some(9);
+    entry.crc32 = Some(0x0d4a_1185);
+    entry.mtime = Some(FileTime::from_unix_time(1_275_898_150, 0));
+    entry.comment = Some("written on dos".into());
+    entry.unicode_path = true;
+    assert_eq!(
+        console::strip_ansi_codes(&format_entry_details(&entry)),
+        "deflate               9  0d4a1185  2010-06-07 08:09:10            11  docs/readme.txt \
+         (unicode path) # written on dos"
+    );
+}
+
+#[test]
+fn test_format_stats() {
+    use std::time::Duration;
+
+    let stats = UnpackStats {
+        files: 412,
+        bytes: 1_288_490_189,
+        kept_newer: 0,
+        elapsed: Duration::from_millis(3400),
+    };
+    assert_eq!(
+        format_stats(&stats, Path::new("/nonexistent/project")),
+        "extracted 412 files (1.20GB) into /nonexistent/project in 3.4s"
+    );
+
+    let stats = UnpackStats {
+        files: 1,
+        bytes: 5,
+        kept_newer: 2,
+        elapsed: Duration::from_millis(20),
+    };
+    let path = env::current_dir().unwrap().join("hello.txt");
+    assert_eq!(
+        format_stats(&stats, &path),
+        "extracted 1 file (5B) into ./hello.txt in 0.0s, kept 2 newer existing files"
+    );
+}
+
+#[test]
+fn test_format_digest() {
+    let dst = env::current_dir().unwrap();
+    let digest = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+    assert_eq!(
+        format_digest(
+            HashAlgorithm::Sha256,
+            digest,
+            Path::new("."),
+            &dst.join("hello.txt")
+        ),
+        format!("SHA256 (./hello.txt) = {}", digest)
+    );
+    assert_eq!(
+        format_digest(
+            HashAlgorithm::Md5,
+            "b1946ac92492d2347c6235b4d2611184",
+            Path::new("src"),
+            &dst.join("src/hello.txt")
+        ),
+        "MD5 (src/hello.txt) = b1946ac92492d2347c6235b4d2611184"
+    );
+    assert_eq!(
+        format_digest(
+            HashAlgorithm::Sha1,
+            "f572d396",
+            Path::new("."),
+            Path::new("/elsewhere")
+        ),
+        "SHA1 (/elsewhere) = f572d396"
+    );
+}
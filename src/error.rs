@@ -0,0 +1,141 @@
+use std::error::Error as StdError;
+use std::io;
+use std::path::{Path, StripPrefixError};
+
+use thiserror::Error;
+
+/// The error type for everything that can go wrong while unpacking.
+#[derive(Debug, Error)]
+pub enum UnboxError {
+    /// Reading the archive or writing the unpacked files failed.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The zip library failed to read the archive.
+    #[cfg(feature = "zip")]
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+    /// A problem that is described by its message alone.
+    #[error("{0}")]
+    Message(String),
+    /// Unpacking a single entry of an archive failed.
+    #[error("failed to extract entry '{entry}' from {archive}")]
+    Entry {
+        entry: String,
+        archive: String,
+        #[source]
+        source: Box<UnboxError>,
+    },
+    /// Reading the entry at an offset of an archive failed.
+    #[error("failed to read the entry at offset {offset} of {archive}")]
+    Offset {
+        offset: u64,
+        archive: String,
+        #[source]
+        source: Box<UnboxError>,
+    },
+    /// An error reported by one of the libraries used to read archives.
+    #[error(transparent)]
+    Other(Box<dyn StdError + Send + Sync>),
+}
+
+impl UnboxError {
+    /// Attaches the entry that was being unpacked and the archive it is in
+    /// to the error.
+    ///
+    /// Errors that already name an entry are returned unchanged.
+    pub fn for_entry(self, entry: &Path, archive: &Path) -> UnboxError {
+        match self {
+            UnboxError::Entry { .. } | UnboxError::Offset { .. } => self,
+            err => UnboxError::Entry {
+                entry: entry.display().to_string(),
+                archive: archive_name(archive),
+                source: Box::new(err),
+            },
+        }
+    }
+
+    /// Attaches the offset of an entry that could not be read to the error.
+    pub fn at_offset(self, offset: u64, archive: &Path) -> UnboxError {
+        match self {
+            UnboxError::Entry { .. } | UnboxError::Offset { .. } => self,
+            err => UnboxError::Offset {
+                offset,
+                archive: archive_name(archive),
+                source: Box::new(err),
+            },
+        }
+    }
+}
+
+fn archive_name(archive: &Path) -> String {
+    archive
+        .file_name()
+        .unwrap_or(archive.as_os_str())
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Implements conversions for errors that are reported as `Other`.
+macro_rules! impl_from_other {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl From<$ty> for UnboxError {
+                fn from(err: $ty) -> UnboxError {
+                    UnboxError::Other(Box::new(err))
+                }
+            }
+        )*
+    };
+}
+
+impl_from_other!(
+    StripPrefixError,
+    globset::Error,
+    strum::ParseError,
+    rayon::ThreadPoolBuildError,
+    xz2::stream::Error,
+);
+#[cfg(feature = "cab")]
+impl_from_other!(goblin::error::Error);
+#[cfg(feature = "signatures")]
+impl_from_other!(base64::DecodeError, rsa::signature::Error);
+
+/// Returns early with an `UnboxError::Message` built from a format string.
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err($crate::error::UnboxError::Message(format!($($arg)*)))
+    };
+}
+
+pub(crate) use bail;
+
+#[test]
+fn test_source_chain() {
+    fn fails(n: u64) -> Result<(), UnboxError> {
+        if n > 1 {
+            bail!("{} is too large", n);
+        }
+        Err(io::Error::other(UnboxError::Message("inner".into())).into())
+    }
+
+    assert_eq!(fails(2).unwrap_err().to_string(), "2 is too large");
+    let err = fails(1).unwrap_err();
+    assert!(matches!(err, UnboxError::Io(..)));
+    assert_eq!(err.to_string(), "inner");
+    assert!(err.source().is_none());
+}
+
+#[test]
+fn test_for_entry() {
+    let err = UnboxError::Message("crc mismatch".into())
+        .for_entry(Path::new("dir/file.txt"), Path::new("/tmp/archive.tar"));
+    assert_eq!(
+        err.to_string(),
+        "failed to extract entry 'dir/file.txt' from archive.tar"
+    );
+    assert_eq!(err.source().unwrap().to_string(), "crc mismatch");
+
+    // the innermost entry is kept
+    let err = err.for_entry(Path::new("outer.txt"), Path::new("outer.tar"));
+    assert!(err.to_string().contains("dir/file.txt"));
+}
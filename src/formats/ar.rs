@@ -1,27 +1,236 @@
-use std::fs::File;
-use std::io::{copy, BufReader};
-use std::path::{Component, Path, PathBuf};
+#[cfg(feature = "tar")]
+use std::io::Cursor;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::str;
 
 #[cfg(unix)]
 use {std::ffi::OsStr, std::os::unix::ffi::OsStrExt};
 
-use ar::Archive as ArArchiveReader;
-use failure::Error;
-
-use crate::archive::{Archive, UnpackHelper};
+use crate::archive::{open_source, Archive, ArchiveEntry, EntryKind, StreamSource, UnpackHelper};
+use crate::error::{bail, UnboxError};
+#[cfg(feature = "tar")]
+use crate::formats::Compression;
 
 #[derive(Debug)]
 pub struct ArArchive {
     path: PathBuf,
-    total_size: u64,
+    total_size: Option<u64>,
+    source: Option<StreamSource>,
 }
 
 impl ArArchive {
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, UnboxError> {
         let path = path.as_ref().canonicalize()?;
         let total_size = path.metadata()?.len();
-        Ok(ArArchive { path, total_size })
+        Ok(ArArchive {
+            path,
+            total_size: Some(total_size),
+            source: None,
+        })
+    }
+
+    /// Opens an ar archive from a stream instead of a file.
+    ///
+    /// The name stands in for the path of the archive.  The stream can
+    /// only be read once, so the archive can either be listed, unpacked or
+    /// have its package metadata read.
+    pub fn from_reader<R: Read + 'static>(rdr: R, name: &str) -> Self {
+        ArArchive {
+            path: PathBuf::from(name),
+            total_size: None,
+            source: Some(StreamSource::new(rdr)),
+        }
+    }
+
+    fn open_source(&self) -> Result<Box<dyn Read>, UnboxError> {
+        open_source(&self.path, self.source.as_ref())
+    }
+}
+
+const GLOBAL_HEADER: &[u8] = b"!<arch>\n";
+const HEADER_SIZE: usize = 60;
+const HEADER_END: &[u8] = b"`\n";
+
+/// The member in which GNU ar stores long names.
+const GNU_NAMES: &[u8] = b"//";
+/// Upper bound for the size of the long name member so that a corrupted
+/// header cannot make us allocate arbitrary amounts of memory.
+const MAX_NAMES_SIZE: u64 = 16 * 1024 * 1024;
+/// The symbol tables of GNU ar.
+const GNU_SYMBOL_TABLES: [&[u8]; 2] = [b"/", b"/SYM64/"];
+/// The symbol tables of BSD ar.
+const BSD_SYMBOL_TABLES: [&[u8]; 2] = [b"__.SYMDEF", b"__.SYMDEF SORTED"];
+/// The prefix of BSD ar identifiers followed by the length of the name.
+const BSD_NAME_PREFIX: &[u8] = b"#1/";
+
+/// Reads the members of an ar archive one after another.
+///
+/// GNU ar stores names that do not fit into the header in the `//` member
+/// and refers to them with `/<offset>`, BSD ar stores them in front of the
+/// data with a `#1/<length>` identifier.  Both are resolved and the symbol
+/// tables are skipped.  The reader reads the data of the current member.
+struct MemberReader<R> {
+    rdr: R,
+    names: Vec<u8>,
+    remaining: u64,
+    padding: u64,
+}
+
+impl<R: Read> MemberReader<R> {
+    fn new(mut rdr: R) -> Result<MemberReader<R>, UnboxError> {
+        let mut magic = [0u8; 8];
+        rdr.read_exact(&mut magic)?;
+        if &magic[..] != GLOBAL_HEADER {
+            bail!("not an ar archive");
+        }
+        Ok(MemberReader {
+            rdr,
+            names: vec![],
+            remaining: 0,
+            padding: 0,
+        })
+    }
+
+    /// Advances to the next member and returns its name and size.
+    fn next_member(&mut self) -> Result<Option<(Vec<u8>, u64)>, UnboxError> {
+        loop {
+            // whatever was not read still needs to be skipped
+            let rest = self.remaining + self.padding;
+            io::copy(&mut (&mut self.rdr).take(rest), &mut io::sink())?;
+            let mut header = [0u8; HEADER_SIZE];
+            let read = read_full(&mut self.rdr, &mut header)?;
+            if read == 0 {
+                return Ok(None);
+            } else if read < HEADER_SIZE || &header[58..] != HEADER_END {
+                bail!("invalid ar member header");
+            }
+            let identifier = trim_end(&header[..16], b' ');
+            let size = parse_field(&header[48..58])?;
+            self.remaining = size;
+            self.padding = size % 2;
+
+            let name = if identifier == GNU_NAMES {
+                if size > MAX_NAMES_SIZE {
+                    bail!("ar long name table too large ({} bytes)", size);
+                }
+                self.names.clear();
+                (&mut self.rdr).take(size).read_to_end(&mut self.names)?;
+                if (self.names.len() as u64) < size {
+                    bail!("truncated ar long name table");
+                }
+                self.remaining = 0;
+                continue;
+            } else if GNU_SYMBOL_TABLES.contains(&identifier) {
+                continue;
+            } else if let Some(len) = identifier.strip_prefix(BSD_NAME_PREFIX) {
+                let len = parse_field(len)?;
+                if len > size || len > MAX_NAMES_SIZE {
+                    bail!("invalid ar member name length");
+                }
+                let mut name = vec![0; len as usize];
+                self.rdr.read_exact(&mut name)?;
+                self.remaining -= len;
+                // names are padded with null bytes by some versions
+                let name = trim_end(&name, 0).to_vec();
+                if BSD_SYMBOL_TABLES.contains(&&name[..]) {
+                    continue;
+                }
+                name
+            } else if let Some(offset) = identifier.strip_prefix(b"/") {
+                let offset = parse_field(offset)? as usize;
+                let name = match self.names.get(offset..) {
+                    Some(names) => names.split(|&x| x == b'\n' || x == 0).next().unwrap(),
+                    None => bail!("invalid ar long name offset {}", offset),
+                };
+                name.strip_suffix(b"/").unwrap_or(name).to_vec()
+            } else if BSD_SYMBOL_TABLES.contains(&identifier) {
+                continue;
+            } else {
+                // gnu ar terminates short names with a slash
+                identifier.strip_suffix(b"/").unwrap_or(identifier).to_vec()
+            };
+            return Ok(Some((name, self.remaining)));
+        }
+    }
+}
+
+impl<R: Read> Read for MemberReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf
+            .len()
+            .min(self.remaining.min(usize::MAX as u64) as usize);
+        let read = self.rdr.read(&mut buf[..len])?;
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Reads as much as possible into the buffer and returns the bytes read.
+fn read_full<R: Read>(rdr: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match rdr.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+fn trim_end(bytes: &[u8], byte: u8) -> &[u8] {
+    let len = bytes.iter().rposition(|&x| x != byte).map_or(0, |x| x + 1);
+    &bytes[..len]
+}
+
+fn parse_field(field: &[u8]) -> Result<u64, UnboxError> {
+    match str::from_utf8(field)
+        .ok()
+        .and_then(|x| x.trim().parse().ok())
+    {
+        Some(value) => Ok(value),
+        None => bail!("invalid ar header field"),
+    }
+}
+
+/// Converts the name of a member into a path.
+fn entry_path(name: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    {
+        PathBuf::from(OsStr::from_bytes(name))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(name).into_owned())
+    }
+}
+
+/// The fields of a debian control file reported as metadata.
+#[cfg(feature = "tar")]
+const DEB_CONTROL_FIELDS: [&str; 4] = ["Package", "Version", "Architecture", "Depends"];
+/// Upper bound for the size of the control archive of a deb package which
+/// is read into memory to find the control file.
+#[cfg(feature = "tar")]
+const MAX_CONTROL_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Parses the interesting fields out of a debian control file.
+#[cfg(feature = "tar")]
+fn parse_deb_control(control: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = vec![];
+    for line in control.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(last) = fields.last_mut() {
+                last.1.push(' ');
+                last.1.push_str(line.trim());
+            }
+        } else if let Some(idx) = line.find(':') {
+            fields.push((line[..idx].to_string(), line[idx + 1..].trim().to_string()));
+        }
     }
+    DEB_CONTROL_FIELDS
+        .iter()
+        .filter_map(|&key| fields.iter().find(|x| x.0 == key).cloned())
+        .collect()
 }
 
 impl Archive for ArArchive {
@@ -30,38 +239,256 @@ impl Archive for ArArchive {
     }
 
     fn total_size(&self) -> Option<u64> {
-        Some(self.total_size)
+        self.total_size
     }
 
-    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), Error> {
-        let f = BufReader::new(helper.wrap_read(File::open(&self.path)?));
-        let mut archive = ArArchiveReader::new(f);
+    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), UnboxError> {
+        let f = BufReader::new(helper.wrap_read(self.open_source()?));
+        let mut members = MemberReader::new(f)?;
 
-        while let Some(entry) = archive.next_entry() {
-            let mut entry = entry?;
-            let header = entry.header();
-            let path = {
-                #[cfg(windows)]
-                {
-                    PathBuf::new(String::from_utf8(header.identifier())?)
-                }
-                #[cfg(unix)]
-                {
-                    PathBuf::from(OsStr::from_bytes(header.identifier()))
-                }
-            };
+        while let Some((name, _)) = members.next_member()? {
+            if helper.skip_entry() {
+                continue;
+            }
+            let path = entry_path(&name);
+            helper
+                .write_file_from(&path, &mut members)
+                .map_err(|err| helper.entry_error(&path, err))?;
+        }
+        Ok(())
+    }
+
+    fn entries(&mut self) -> Result<Vec<ArchiveEntry>, UnboxError> {
+        let mut members = MemberReader::new(BufReader::new(self.open_source()?))?;
+        let mut rv = vec![];
+        while let Some((name, size)) = members.next_member()? {
+            rv.push(ArchiveEntry::new(
+                String::from_utf8_lossy(&name),
+                size,
+                EntryKind::File,
+            ));
+        }
+        Ok(rv)
+    }
+
+    #[cfg(feature = "tar")]
+    fn metadata(&self) -> Result<Vec<(String, String)>, UnboxError> {
+        let mut members = MemberReader::new(BufReader::new(self.open_source()?))?;
+        let mut is_deb = false;
 
-            if path.components().any(|component| match component {
-                Component::ParentDir | Component::RootDir | Component::Prefix(..) => true,
-                Component::Normal(..) | Component::CurDir => false,
-            }) {
+        while let Some((name, _)) = members.next_member()? {
+            let name = String::from_utf8_lossy(&name).to_string();
+            if name == "debian-binary" {
+                is_deb = true;
+                continue;
+            } else if !is_deb || !name.starts_with("control.tar") {
                 continue;
             }
-            helper.report_file(&path);
 
-            let mut f = File::create(helper.path().join(&path))?;
-            copy(&mut entry, &mut f)?;
+            let compression = match &name[..] {
+                "control.tar" => Compression::Uncompressed,
+                "control.tar.gz" => Compression::Gz,
+                "control.tar.xz" => Compression::Xz,
+                "control.tar.zst" => Compression::Zstd,
+                "control.tar.bz2" => Compression::Bz2,
+                "control.tar.lzma" => Compression::Lzma,
+                _ => bail!("unsupported control archive '{}'", name),
+            };
+            let mut buf = vec![];
+            (&mut members)
+                .take(MAX_CONTROL_SIZE + 1)
+                .read_to_end(&mut buf)?;
+            if buf.len() as u64 > MAX_CONTROL_SIZE {
+                bail!("deb control archive too large");
+            }
+            let mut control = tar::Archive::new(compression.decompress(Cursor::new(buf))?);
+            for control_entry in control.entries()? {
+                let mut control_entry = control_entry?;
+                if control_entry.path()?.file_name() == Some("control".as_ref()) {
+                    let mut contents = String::new();
+                    control_entry.read_to_string(&mut contents)?;
+                    return Ok(parse_deb_control(&contents));
+                }
+            }
         }
-        Ok(())
+
+        Ok(vec![])
+    }
+}
+
+#[cfg(feature = "tar")]
+#[test]
+fn test_deb_metadata() {
+    use std::fs;
+    use std::io::Write;
+
+    use crate::utils::make_test_dir;
+
+    let control = b"Package: unbox\nVersion: 0.2.0-1\nArchitecture: amd64\n\
+        Maintainer: Nobody <nobody@example.com>\nDepends: libc6 (>= 2.17),\n liblzma5\n\
+        Description: unpacks archives\n";
+    let mut tarball = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.set_path("./control").unwrap();
+    header.set_size(control.len() as u64);
+    header.set_cksum();
+    tarball.append(&header, &control[..]).unwrap();
+    let tarball = tarball.into_inner().unwrap();
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("unbox_0.2.0-1_amd64.deb");
+    let mut deb = b"!<arch>\n".to_vec();
+    append_member(&mut deb, "debian-binary", b"2.0\n");
+    append_member(&mut deb, "control.tar", &tarball);
+    fs::write(&archive_path, deb).unwrap();
+
+    let archive = ArArchive::open(&archive_path).unwrap();
+    let metadata = archive.metadata().unwrap();
+    let metadata: Vec<_> = metadata
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    assert_eq!(
+        metadata,
+        vec![
+            ("Package", "unbox"),
+            ("Version", "0.2.0-1"),
+            ("Architecture", "amd64"),
+            ("Depends", "libc6 (>= 2.17), liblzma5"),
+        ]
+    );
+
+    // current distributions compress the control archive
+    for &compression in &[
+        Compression::Gz,
+        Compression::Xz,
+        Compression::Zstd,
+        Compression::Bz2,
+        Compression::Lzma,
+    ] {
+        let mut w = compression.compress(vec![]).unwrap();
+        w.write_all(&tarball).unwrap();
+        let mut deb = b"!<arch>\n".to_vec();
+        append_member(&mut deb, "debian-binary", b"2.0\n");
+        append_member(
+            &mut deb,
+            &format!("control.tar{}", compression.extension()),
+            &w.finish().unwrap(),
+        );
+        fs::write(&archive_path, deb).unwrap();
+        let archive = ArArchive::open(&archive_path).unwrap();
+        let metadata = archive.metadata().unwrap();
+        assert_eq!(metadata[0], ("Package".to_string(), "unbox".to_string()));
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Appends a member with the given raw identifier to an ar archive.
+#[cfg(test)]
+fn append_member(rv: &mut Vec<u8>, identifier: &str, data: &[u8]) {
+    rv.extend(
+        format!(
+            "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            identifier,
+            0,
+            0,
+            0,
+            644,
+            data.len()
+        )
+        .as_bytes(),
+    );
+    rv.extend(data);
+    if data.len() % 2 == 1 {
+        rv.push(b'\n');
+    }
+}
+
+#[test]
+fn test_long_names() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let long = "a_very_long_member_name_one.txt";
+    let other = "another_long_member_name.o";
+
+    // gnu ar keeps long names in the `//` member and refers to them by offset
+    let mut gnu = b"!<arch>\n".to_vec();
+    append_member(&mut gnu, "/", &[0; 4]);
+    let names = format!("{}/\n{}/\n", long, other);
+    append_member(&mut gnu, "//", names.as_bytes());
+    append_member(&mut gnu, "/0", b"one");
+    append_member(&mut gnu, &format!("/{}", long.len() + 2), b"two");
+    append_member(&mut gnu, "short.txt/", b"three");
+
+    // bsd ar stores them in front of the data
+    let mut bsd = b"!<arch>\n".to_vec();
+    append_member(&mut bsd, "#1/12", b"__.SYMDEF\0\0\0\0\0\0\0");
+    for (name, data) in &[(long, &b"one"[..]), (other, b"two")] {
+        append_member(
+            &mut bsd,
+            &format!("#1/{}", name.len()),
+            &[name.as_bytes(), data].concat(),
+        );
+    }
+    append_member(&mut bsd, "short.txt", b"three");
+
+    let dir = make_test_dir();
+    for (name, contents) in &[("gnu.a", gnu), ("bsd.a", bsd)] {
+        let archive_path = dir.join(name);
+        fs::write(&archive_path, contents).unwrap();
+        let mut archive = ArArchive::open(&archive_path).unwrap();
+        let names: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .into_iter()
+            .map(|x| x.name)
+            .collect();
+        assert_eq!(names, vec![long, other, "short.txt"]);
+
+        let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        let rv = helper.commit().unwrap();
+        assert_eq!(fs::read_dir(&rv).unwrap().count(), 3);
+        assert_eq!(fs::read(rv.join(long)).unwrap(), b"one");
+        assert_eq!(fs::read(rv.join(other)).unwrap(), b"two");
+        assert_eq!(fs::read(rv.join("short.txt")).unwrap(), b"three");
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_oversized_long_names() {
+    use std::fs;
+
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    for (identifier, size, message) in &[
+        (
+            "//",
+            "9999999999",
+            "ar long name table too large (9999999999 bytes)",
+        ),
+        ("//", "1000", "truncated ar long name table"),
+        (
+            "#1/999999999",
+            "9999999999",
+            "invalid ar member name length",
+        ),
+    ] {
+        let mut contents = b"!<arch>\n".to_vec();
+        contents.extend(format!("{:<48}{:<10}`\n", identifier, size).as_bytes());
+        contents.extend(b"name.txt/\n");
+        let archive_path = dir.join("broken.a");
+        fs::write(&archive_path, contents).unwrap();
+        let err = ArArchive::open(&archive_path)
+            .unwrap()
+            .entries()
+            .unwrap_err();
+        assert_eq!(err.to_string(), *message);
     }
+    fs::remove_dir_all(&dir).unwrap();
 }
@@ -5,21 +5,19 @@ use std::ops::Deref;
 use std::path::{Path, PathBuf};
 
 use cab::Cabinet;
-use failure::{bail, Error};
 use goblin::pe::PE;
 use memmap::Mmap;
 use owning_ref::OwningRef;
 
-use crate::archive::{Archive, UnpackHelper};
-
-trait ReadSeek: Read + Seek {}
-
-impl<T: Read + Seek> ReadSeek for T {}
+use crate::archive::{Archive, ArchiveEntry, EntryKind, ReadSeek, UnpackHelper};
+use crate::error::{bail, UnboxError};
 
 pub struct CabArchive {
     cab: Cabinet<Box<dyn ReadSeek>>,
     total_size: u64,
     path: PathBuf,
+    offset: Option<usize>,
+    from_reader: bool,
     files: Vec<String>,
 }
 
@@ -45,35 +43,88 @@ impl Deref for StableDerefMmap {
 
 unsafe impl stable_deref_trait::StableDeref for StableDerefMmap {}
 
+/// Finds the offset of a cabinet appended to a PE executable.
+fn cab_offset(bytes: &[u8]) -> Result<usize, UnboxError> {
+    let pe = PE::parse(bytes)?;
+    let exesize = pe.sections.last().map_or(0, |sect| {
+        (sect.pointer_to_raw_data + sect.size_of_raw_data) as usize
+    });
+    if bytes.get(exesize..exesize + 4) == Some(&b"MSCF"[..]) {
+        Ok(exesize)
+    } else {
+        bail!("no cab in executable");
+    }
+}
+
+/// Checks if the PE executable in the given bytes has a cabinet appended.
+pub fn contains_cab(bytes: &[u8]) -> bool {
+    cab_offset(bytes).is_ok()
+}
+
+/// Opens the cabinet at the given offset of a file.
+///
+/// Without an offset the file is a plain cabinet, otherwise the file is
+/// mapped into memory and the cabinet is read from the offset on.
+fn open_cabinet(
+    path: &Path,
+    offset: Option<usize>,
+) -> Result<Cabinet<Box<dyn ReadSeek>>, UnboxError> {
+    let f = File::open(path)?;
+    let rdr: Box<dyn ReadSeek> = match offset {
+        None => Box::new(BufReader::new(f)),
+        Some(offset) => {
+            let mmap = unsafe { StableDerefMmap(Mmap::map(&f)?) };
+            let owning_mmap = OwningRef::new(mmap);
+            Box::new(Cursor::new(owning_mmap.map(|mmap| &mmap[offset..])))
+        }
+    };
+    Ok(Cabinet::new(rdr)?)
+}
+
+/// Unpacks the file with the given name from a cabinet.
+fn unpack_file(
+    cab: &mut Cabinet<Box<dyn ReadSeek>>,
+    name: &str,
+    helper: &UnpackHelper,
+) -> Result<(), UnboxError> {
+    let path = name.replace('\\', "/");
+    cab.read_file(name)
+        .map_err(UnboxError::from)
+        .and_then(|rdr| helper.write_file_with_progress(&path, rdr))
+        .map_err(|err| helper.entry_error(&path, err))
+}
+
 impl CabArchive {
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, UnboxError> {
         let path = path.as_ref().canonicalize()?;
-        let f = BufReader::new(File::open(&path)?);
-        let cab = Cabinet::new(Box::new(f) as Box<dyn ReadSeek>)?;
-        CabArchive::from_cab_and_path(cab, path)
+        let cab = open_cabinet(&path, None)?;
+        CabArchive::from_cab_and_path(cab, path, None)
     }
 
-    pub fn find_in_executable<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+    pub fn find_in_executable<P: AsRef<Path>>(path: P) -> Result<Self, UnboxError> {
         let path = path.as_ref().canonicalize()?;
         let f = File::open(&path)?;
-        let mmap = unsafe { StableDerefMmap(Mmap::map(&f)?) };
-        let pe = PE::parse(&mmap[..])?;
-
-        let exesize = pe.sections.last().map_or(0, |sect| {
-            u64::from(sect.pointer_to_raw_data + sect.size_of_raw_data)
-        });
+        let offset = cab_offset(&unsafe { Mmap::map(&f)? }[..])?;
+        let cab = open_cabinet(&path, Some(offset))?;
+        CabArchive::from_cab_and_path(cab, path, Some(offset))
+    }
 
-        if mmap.get(exesize as usize..exesize as usize + 4) == Some(&b"MSCF"[..]) {
-            let owning_mmap = OwningRef::new(mmap);
-            let owning_ref = owning_mmap.map(|mmap| &mmap[exesize as usize..]);
-            let cab = Cabinet::new(Box::new(Cursor::new(owning_ref)) as Box<dyn ReadSeek>)?;
-            CabArchive::from_cab_and_path(cab, path)
-        } else {
-            bail!("no cab in executable");
-        }
+    /// Opens a cabinet from a reader instead of a file.
+    ///
+    /// The name stands in for the path of the archive.  As the reader
+    /// cannot be opened again, files are always unpacked serially.
+    pub fn from_reader<R: Read + Seek + 'static>(rdr: R, name: &str) -> Result<Self, UnboxError> {
+        let cab = Cabinet::new(Box::new(rdr) as Box<dyn ReadSeek>)?;
+        let mut rv = CabArchive::from_cab_and_path(cab, PathBuf::from(name), None)?;
+        rv.from_reader = true;
+        Ok(rv)
     }
 
-    fn from_cab_and_path(cab: Cabinet<Box<dyn ReadSeek>>, path: PathBuf) -> Result<Self, Error> {
+    fn from_cab_and_path(
+        cab: Cabinet<Box<dyn ReadSeek>>,
+        path: PathBuf,
+        offset: Option<usize>,
+    ) -> Result<Self, UnboxError> {
         let mut total_size = 0;
         let mut files = vec![];
         for folder_entry in cab.folder_entries() {
@@ -86,6 +137,8 @@ impl CabArchive {
             path,
             cab,
             total_size,
+            offset,
+            from_reader: false,
             files,
         })
     }
@@ -100,11 +153,92 @@ impl Archive for CabArchive {
         Some(self.total_size)
     }
 
-    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), Error> {
+    fn entry_count(&self) -> Option<u64> {
+        Some(self.files.len() as u64)
+    }
+
+    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), UnboxError> {
+        let mut files = vec![];
         for name in &self.files {
-            let rdr = self.cab.read_file(&name)?;
-            helper.write_file_with_progress(&name.replace('\\', "/"), rdr)?;
+            if !helper.skip_entry() {
+                files.push(name.as_str());
+            }
+        }
+        if !helper.is_parallel() || self.from_reader {
+            for name in files {
+                unpack_file(&mut self.cab, name, helper)?;
+            }
+            return Ok(());
+        }
+
+        // every thread reads from its own cabinet
+        let (path, offset) = (&self.path, self.offset);
+        let helper = &*helper;
+        helper.for_each_parallel(
+            files,
+            || open_cabinet(path, offset),
+            |cab, name| unpack_file(cab, name, helper),
+        )
+    }
+
+    fn entries(&mut self) -> Result<Vec<ArchiveEntry>, UnboxError> {
+        let mut rv = vec![];
+        for folder_entry in self.cab.folder_entries() {
+            for file_entry in folder_entry.file_entries() {
+                rv.push(ArchiveEntry::new(
+                    file_entry.name().replace('\\', "/"),
+                    u64::from(file_entry.uncompressed_size()),
+                    EntryKind::File,
+                ));
+            }
         }
-        Ok(())
+        Ok(rv)
+    }
+}
+
+#[test]
+fn test_parallel() {
+    use std::fs;
+    use std::io::Write;
+
+    use cab::{CabinetBuilder, CompressionType};
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("many.cab");
+    let mut builder = CabinetBuilder::new();
+    {
+        let folder = builder.add_folder(CompressionType::MsZip);
+        for idx in 0..16 {
+            folder.add_file(format!("dir\\file{}.txt", idx));
+        }
+    }
+    let mut w = builder.build(File::create(&archive_path).unwrap()).unwrap();
+    let mut idx = 0;
+    while let Some(mut file) = w.next_file().unwrap() {
+        write!(file, "contents {}", idx).unwrap();
+        idx += 1;
+    }
+    w.finish().unwrap();
+
+    let mut archive = CabArchive::open(&archive_path).unwrap();
+    let options = UnpackOptions {
+        jobs: Some(4),
+        ..UnpackOptions::default()
+    };
+    let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+
+    assert_eq!(rv.file_name().unwrap(), "dir");
+    for idx in 0..16 {
+        let path = rv.join(format!("file{}.txt", idx));
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            format!("contents {}", idx)
+        );
     }
+    fs::remove_dir_all(&dir).unwrap();
 }
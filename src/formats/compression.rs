@@ -1,15 +1,24 @@
 use std::ffi::OsStr;
-use std::fs::File;
-use std::io::{copy, BufReader, Read};
+use std::io::{self, BufReader, Cursor, Read, Write};
 use std::path::{Path, PathBuf};
 
+use brotli::{CompressorWriter as BrotliEncoder, Decompressor as BrotliDecoder};
 use bzip2::read::BzDecoder;
-use failure::Error;
+use bzip2::write::BzEncoder;
 use libflate::gzip;
+use snap::read::FrameDecoder as SnappyDecoder;
+use snap::write::FrameEncoder as SnappyEncoder;
 use xz2::read::XzDecoder;
+use xz2::stream::{LzmaOptions, Stream};
+use xz2::write::XzEncoder;
 
-use crate::archive::{Archive, UnpackHelper};
-use crate::formats::ArchiveType;
+use crate::archive::{
+    open_source, Archive, ArchiveEntry, DigestRead, EntryKind, StreamSource, UnpackHelper,
+};
+use crate::error::{bail, UnboxError};
+use crate::formats::{
+    handler_for, nested_compression, type_for_compression, ArchiveType, MAGIC_SIZE,
+};
 
 /// The compression of a normal file.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -18,25 +27,367 @@ pub enum Compression {
     Gz,
     Xz,
     Bz2,
+    Zstd,
+    Lz4,
+    Brotli,
+    Lzma,
+    Compress,
+    Snappy,
 }
 
+/// How many compression layers are peeled off at most.
+///
+/// Files are occasionally compressed twice, anything nested deeper than
+/// this is refused as a likely compression bomb.
+pub const MAX_COMPRESSION_LAYERS: usize = 3;
+
+/// The magic number at the start of a zstd frame.
+const ZSTD_MAGIC: &[u8] = b"\x28\xb5\x2f\xfd";
+
+/// The magic number at the start of an lz4 frame.
+const LZ4_FRAME_MAGIC: &[u8] = b"\x04\x22\x4d\x18";
+
+/// The magic number of the legacy lz4 format.
+const LZ4_LEGACY_MAGIC: &[u8] = b"\x02\x21\x4c\x18";
+
+/// Returns how many bytes of an lz4 frame are needed to decode its first
+/// block.
+///
+/// Blocks can only be decoded as a whole and may be up to 4MB large, which
+/// is more than is usually sniffed.
+pub fn lz4_peek_size(buf: &[u8]) -> Option<usize> {
+    if !buf.starts_with(LZ4_FRAME_MAGIC) {
+        return None;
+    }
+    let block_size = match (buf.get(5)? >> 4) & 0x7 {
+        4 => 64 << 10,
+        5 => 256 << 10,
+        6 => 1 << 20,
+        _ => 4 << 20,
+    };
+    // the frame header, the block size and the block checksum
+    Some(19 + 4 + block_size + 4)
+}
+
+/// The magic number of files made by unix compress.
+const COMPRESS_MAGIC: &[u8] = b"\x1f\x9d";
+
+/// The stream identifier chunk at the start of the snappy framing format.
+const SNAPPY_MAGIC: &[u8] = b"\xff\x06\x00\x00sNaPpY";
+
+/// Checks if a buffer starts with the header of the legacy lzma format.
+///
+/// Unlike xz it has no magic number, so the properties byte, the
+/// dictionary size and the uncompressed size are checked for the values
+/// encoders actually write.
+#[allow(clippy::manual_is_multiple_of)]
+fn is_lzma_header(buf: &[u8]) -> bool {
+    if buf.len() < 13 || buf[0] >= 9 * 5 * 5 {
+        return false;
+    }
+    let dict_size = u32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]);
+    let mut size = [0u8; 8];
+    size.copy_from_slice(&buf[5..13]);
+    let size = u64::from_le_bytes(size);
+    // dictionaries are powers of two or halfway between two of them
+    let plausible_dict = dict_size >= 4096
+        && (dict_size.is_power_of_two()
+            || (dict_size % 3 == 0 && (dict_size / 3).is_power_of_two()));
+    plausible_dict && (size == u64::MAX || size < 1 << 40)
+}
+
+/// A writer that compresses into the wrapped writer.
+///
+/// It has to be finished explicitly so that errors writing the trailer are
+/// not lost.
+pub enum CompressWriter<W: Write> {
+    Uncompressed(W),
+    Gz(gzip::Encoder<W>),
+    Xz(XzEncoder<W>),
+    Bz2(BzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Lz4(lz4::Encoder<W>),
+    Brotli(Box<BrotliEncoder<W>>),
+    Lzma(XzEncoder<W>),
+    Snappy(Box<SnappyEncoder<W>>),
+}
+
+impl<W: Write> Write for CompressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            CompressWriter::Uncompressed(ref mut w) => w.write(buf),
+            CompressWriter::Gz(ref mut w) => w.write(buf),
+            CompressWriter::Xz(ref mut w) => w.write(buf),
+            CompressWriter::Bz2(ref mut w) => w.write(buf),
+            CompressWriter::Zstd(ref mut w) => w.write(buf),
+            CompressWriter::Lz4(ref mut w) => w.write(buf),
+            CompressWriter::Brotli(ref mut w) => w.write(buf),
+            CompressWriter::Lzma(ref mut w) => w.write(buf),
+            CompressWriter::Snappy(ref mut w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            CompressWriter::Uncompressed(ref mut w) => w.flush(),
+            CompressWriter::Gz(ref mut w) => w.flush(),
+            CompressWriter::Xz(ref mut w) => w.flush(),
+            CompressWriter::Bz2(ref mut w) => w.flush(),
+            CompressWriter::Zstd(ref mut w) => w.flush(),
+            CompressWriter::Lz4(ref mut w) => w.flush(),
+            CompressWriter::Brotli(ref mut w) => w.flush(),
+            CompressWriter::Lzma(ref mut w) => w.flush(),
+            CompressWriter::Snappy(ref mut w) => w.flush(),
+        }
+    }
+}
+
+impl<W: Write> CompressWriter<W> {
+    /// Writes the trailer and returns the wrapped writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            CompressWriter::Uncompressed(w) => Ok(w),
+            CompressWriter::Gz(w) => w.finish().into_result(),
+            CompressWriter::Xz(w) => w.finish(),
+            CompressWriter::Bz2(w) => w.finish(),
+            CompressWriter::Zstd(w) => w.finish(),
+            CompressWriter::Lz4(w) => {
+                let (w, rv) = w.finish();
+                rv.map(|_| w)
+            }
+            CompressWriter::Brotli(mut w) => {
+                // the encoder swallows errors writing the trailer, so flush
+                // before and after to at least catch the common ones.
+                w.flush()?;
+                let mut w = (*w).into_inner();
+                w.flush()?;
+                Ok(w)
+            }
+            CompressWriter::Lzma(w) => w.finish(),
+            CompressWriter::Snappy(w) => (*w).into_inner().map_err(|err| err.into_error()),
+        }
+    }
+}
+
+/// Decodes the LZW streams written by unix compress.
+///
+/// Codes start out with 9 bits and grow up to the maximum from the header.
+/// In block mode code 256 resets the table.  Compress reads codes in groups
+/// of eight and whenever the code size changes the rest of the current
+/// group is skipped, which has to be mirrored here.
+pub struct LzwDecoder<R> {
+    rdr: R,
+    block_mode: bool,
+    max_bits: u32,
+    n_bits: u32,
+    bit_buf: u64,
+    bit_count: u32,
+    codes_in_group: u32,
+    prefix: Vec<u16>,
+    suffix: Vec<u8>,
+    free_entry: u32,
+    old_code: Option<u32>,
+    first_char: u8,
+    out: Vec<u8>,
+    out_pos: usize,
+    eof: bool,
+}
+
+const LZW_INIT_BITS: u32 = 9;
+const LZW_CLEAR: u32 = 256;
+
+impl<R: Read> LzwDecoder<R> {
+    /// Reads the header and creates the decoder.
+    pub fn new(mut rdr: R) -> Result<LzwDecoder<R>, UnboxError> {
+        let mut header = [0u8; 3];
+        rdr.read_exact(&mut header)?;
+        if &header[..2] != COMPRESS_MAGIC {
+            bail!("not a compress file");
+        }
+        let max_bits = u32::from(header[2] & 0x1f);
+        if !(LZW_INIT_BITS..=16).contains(&max_bits) {
+            bail!("unsupported compress code size of {} bits", max_bits);
+        }
+        let block_mode = header[2] & 0x80 != 0;
+        Ok(LzwDecoder {
+            rdr,
+            block_mode,
+            max_bits,
+            n_bits: LZW_INIT_BITS,
+            bit_buf: 0,
+            bit_count: 0,
+            codes_in_group: 0,
+            prefix: vec![0; 1 << 16],
+            suffix: (0..1 << 16).map(|x| x as u8).collect(),
+            free_entry: if block_mode { 257 } else { 256 },
+            old_code: None,
+            first_char: 0,
+            out: vec![],
+            out_pos: 0,
+            eof: false,
+        })
+    }
+
+    /// Reads the given number of bits or `None` at the end of the stream.
+    fn read_bits(&mut self, n: u32) -> io::Result<Option<u32>> {
+        while self.bit_count < n {
+            let mut byte = [0u8; 1];
+            loop {
+                match self.rdr.read(&mut byte) {
+                    Ok(0) => return Ok(None),
+                    Ok(_) => break,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            self.bit_buf |= u64::from(byte[0]) << self.bit_count;
+            self.bit_count += 8;
+        }
+        let rv = (self.bit_buf & ((1 << n) - 1)) as u32;
+        self.bit_buf >>= n;
+        self.bit_count -= n;
+        Ok(Some(rv))
+    }
+
+    /// Skips the rest of the current group of codes and switches the code
+    /// size.
+    fn set_code_size(&mut self, n_bits: u32) -> io::Result<bool> {
+        let skip = (8 - self.codes_in_group % 8) % 8;
+        for _ in 0..skip {
+            if self.read_bits(self.n_bits)?.is_none() {
+                return Ok(false);
+            }
+        }
+        self.codes_in_group = 0;
+        self.n_bits = n_bits;
+        Ok(true)
+    }
+
+    /// Decodes the next code into the output buffer.
+    ///
+    /// Returns `false` at the end of the stream.
+    fn decode_next(&mut self) -> io::Result<bool> {
+        let max_code = if self.n_bits == self.max_bits {
+            1 << self.max_bits
+        } else {
+            (1 << self.n_bits) - 1
+        };
+        if self.free_entry > max_code && !self.set_code_size(self.n_bits + 1)? {
+            return Ok(false);
+        }
+        let code = match self.read_bits(self.n_bits)? {
+            Some(code) => code,
+            None => return Ok(false),
+        };
+        self.codes_in_group += 1;
+
+        let old_code = match self.old_code {
+            Some(old_code) => old_code,
+            None => {
+                if code > 255 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "corrupt compress data",
+                    ));
+                }
+                self.old_code = Some(code);
+                self.first_char = code as u8;
+                self.out.push(code as u8);
+                return Ok(true);
+            }
+        };
+
+        if code == LZW_CLEAR && self.block_mode {
+            self.free_entry = LZW_CLEAR;
+            return self.set_code_size(LZW_INIT_BITS);
+        }
+
+        // walk the chain of prefixes which yields the string in reverse
+        let start = self.out.len();
+        let mut cur = code;
+        if cur >= self.free_entry {
+            if cur > self.free_entry {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "corrupt compress data",
+                ));
+            }
+            self.out.push(self.first_char);
+            cur = old_code;
+        }
+        while cur >= 256 {
+            self.out.push(self.suffix[cur as usize]);
+            cur = u32::from(self.prefix[cur as usize]);
+        }
+        self.first_char = cur as u8;
+        self.out.push(self.first_char);
+        self.out[start..].reverse();
+
+        if self.free_entry < 1 << self.max_bits {
+            self.prefix[self.free_entry as usize] = old_code as u16;
+            self.suffix[self.free_entry as usize] = self.first_char;
+            self.free_entry += 1;
+        }
+        self.old_code = Some(code);
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for LzwDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.out_pos >= self.out.len() {
+            self.out.clear();
+            self.out_pos = 0;
+            if self.eof || !self.decode_next()? {
+                self.eof = true;
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.out.len() - self.out_pos);
+        buf[..len].copy_from_slice(&self.out[self.out_pos..self.out_pos + len]);
+        self.out_pos += len;
+        Ok(len)
+    }
+}
+
+/// A single compressed file.
+///
+/// The decompressed size is only known after decompressing (the gzip
+/// footer has it but only modulo 4GB) so the total size is the size of
+/// the compressed file.  Progress is reported for the compressed bytes
+/// read to match it, the decompressed bytes written are not reported.
 #[derive(Debug)]
 pub struct SingleFileArchive {
     path: PathBuf,
     compression: Compression,
-    total_size: u64,
+    total_size: Option<u64>,
+    source: Option<StreamSource>,
 }
 
 impl SingleFileArchive {
-    pub fn open<P: AsRef<Path>>(path: P, compression: Compression) -> Result<Self, Error> {
+    pub fn open<P: AsRef<Path>>(path: P, compression: Compression) -> Result<Self, UnboxError> {
         let path = path.as_ref().canonicalize()?;
         let total_size = path.metadata()?.len();
         Ok(SingleFileArchive {
             path,
             compression,
-            total_size,
+            total_size: Some(total_size),
+            source: None,
         })
     }
+
+    /// Opens a compressed file from a stream instead of a file.
+    ///
+    /// The name stands in for the path of the file and its stem names the
+    /// decompressed file.  The stream can only be read once.
+    pub fn from_reader<R: Read + 'static>(rdr: R, name: &str, compression: Compression) -> Self {
+        SingleFileArchive {
+            path: PathBuf::from(name),
+            compression,
+            total_size: None,
+            source: Some(StreamSource::new(rdr)),
+        }
+    }
 }
 
 impl Archive for SingleFileArchive {
@@ -45,20 +396,54 @@ impl Archive for SingleFileArchive {
     }
 
     fn total_size(&self) -> Option<u64> {
-        Some(self.total_size)
+        self.total_size
     }
 
-    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), Error> {
-        let f = BufReader::new(helper.wrap_read(File::open(&self.path)?));
-        let mut rdr = self.compression.decompress(f)?;
-        helper.report_file(&self.path);
-        let filename = self
+    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), UnboxError> {
+        if helper.skip_entry() {
+            return Ok(());
+        }
+        let f = BufReader::new(helper.wrap_read(open_source(&self.path, self.source.as_ref())?));
+        let (rdr, layers) = self.compression.decompress_layers(f)?;
+        let mut rdr = DigestRead::new(rdr, helper.hashes());
+        helper.write_file_from(self.filename(&layers), &mut rdr)?;
+        if let Some(digests) = rdr.finish() {
+            helper.record_digests(digests);
+        }
+        Ok(())
+    }
+
+    fn entries(&mut self) -> Result<Vec<ArchiveEntry>, UnboxError> {
+        // the uncompressed size is only known after decompressing
+        let f = BufReader::new(open_source(&self.path, self.source.as_ref())?);
+        let (mut rdr, layers) = self.compression.decompress_layers(f)?;
+        let size = io::copy(&mut rdr, &mut io::sink())?;
+        Ok(vec![ArchiveEntry::new(
+            self.filename(&layers).to_string_lossy(),
+            size,
+            EntryKind::File,
+        )])
+    }
+}
+
+impl SingleFileArchive {
+    /// The name of the decompressed file.
+    ///
+    /// The extensions of nested compression layers (`foo.gz.gz`) are
+    /// stripped along with the outer one.
+    fn filename(&self, layers: &[Compression]) -> &OsStr {
+        let mut filename = self
             .path
             .file_stem()
             .unwrap_or_else(|| OsStr::new("Unknown"));
-        let mut w = File::create(helper.path().join(filename))?;
-        copy(&mut rdr, &mut w)?;
-        Ok(())
+        for layer in layers.iter().skip(1) {
+            let path = Path::new(filename);
+            let ext = path.extension().and_then(|x| x.to_str());
+            if ext.is_some() && ext == layer.extension().get(1..) {
+                filename = path.file_stem().unwrap_or(filename);
+            }
+        }
+        filename
     }
 }
 
@@ -68,37 +453,613 @@ impl Compression {
         match mimetype {
             "application/gzip" => Some(Compression::Gz),
             "application/x-xz" => Some(Compression::Xz),
-            "application/bzip2" => Some(Compression::Bz2),
+            "application/bzip2" | "application/x-bzip" | "application/x-bzip2" => {
+                Some(Compression::Bz2)
+            }
+            "application/zstd" => Some(Compression::Zstd),
+            "application/x-lz4" => Some(Compression::Lz4),
+            "application/x-brotli" => Some(Compression::Brotli),
+            "application/x-lzma" => Some(Compression::Lzma),
+            "application/x-compress" => Some(Compression::Compress),
             _ => None,
         }
     }
 
+    /// Returns the compression for the magic number at the start of a
+    /// buffer.
+    ///
+    /// This is used for compressions the mimetype detection does not know.
+    pub fn for_magic(buf: &[u8]) -> Option<Compression> {
+        if buf.starts_with(ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else if buf.starts_with(LZ4_FRAME_MAGIC) || buf.starts_with(LZ4_LEGACY_MAGIC) {
+            Some(Compression::Lz4)
+        } else if buf.starts_with(COMPRESS_MAGIC) {
+            Some(Compression::Compress)
+        } else if buf.starts_with(SNAPPY_MAGIC) {
+            Some(Compression::Snappy)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the legacy lzma compression for a buffer named like an
+    /// lzma compressed file.
+    ///
+    /// The format has no magic number so its header alone matches too
+    /// much unrelated data to be trusted without the extension.
+    pub fn for_lzma_name(buf: &[u8], name: &str) -> Option<Compression> {
+        if name.to_ascii_lowercase().ends_with(".lzma") && is_lzma_header(buf) {
+            Some(Compression::Lzma)
+        } else {
+            None
+        }
+    }
+
     /// Wraps a reader for transparent decompression.
-    pub fn decompress<R: Read + 'static>(self, rdr: R) -> Result<Box<dyn Read>, Error> {
+    pub fn decompress<R: Read + 'static>(self, rdr: R) -> Result<Box<dyn Read>, UnboxError> {
         match self {
             Compression::Uncompressed => Ok(Box::new(rdr)),
-            Compression::Gz => Ok(Box::new(gzip::Decoder::new(rdr)?)),
+            // concatenated gzip members (`cat a.gz b.gz`) form one stream
+            Compression::Gz => Ok(Box::new(gzip::MultiDecoder::new(rdr)?)),
             Compression::Xz => Ok(Box::new(XzDecoder::new(rdr))),
             Compression::Bz2 => Ok(Box::new(BzDecoder::new(rdr))),
+            Compression::Zstd => Ok(Box::new(zstd::Decoder::new(rdr)?)),
+            Compression::Lz4 => {
+                // the legacy format is made of blocks whose length we cannot
+                // know upfront, so only the frame format is supported.
+                let mut rdr = rdr;
+                let mut magic = [0u8; 4];
+                rdr.read_exact(&mut magic)?;
+                if magic != LZ4_FRAME_MAGIC {
+                    bail!("not an lz4 frame (legacy lz4 and raw lz4 blocks are not supported)");
+                }
+                Ok(Box::new(lz4::Decoder::new(Cursor::new(magic).chain(rdr))?))
+            }
+            Compression::Brotli => Ok(Box::new(BrotliDecoder::new(rdr, 4096))),
+            Compression::Lzma => {
+                let stream = Stream::new_lzma_decoder(u64::MAX)?;
+                Ok(Box::new(XzDecoder::new_stream(rdr, stream)))
+            }
+            Compression::Compress => Ok(Box::new(LzwDecoder::new(rdr)?)),
+            Compression::Snappy => {
+                // raw snappy blocks do not store their length, so only the
+                // framing format can be streamed.
+                let mut rdr = rdr;
+                let mut magic = [0u8; 10];
+                rdr.read_exact(&mut magic)?;
+                if magic != SNAPPY_MAGIC {
+                    bail!("not a framed snappy stream (raw snappy blocks are not supported)");
+                }
+                Ok(Box::new(SnappyDecoder::new(Cursor::new(magic).chain(rdr))))
+            }
+        }
+    }
+
+    /// Like `decompress` but also peels off nested compression layers.
+    ///
+    /// Fails if there are more than `MAX_COMPRESSION_LAYERS` layers.
+    pub fn decompress_nested<R: Read + 'static>(self, rdr: R) -> Result<Box<dyn Read>, UnboxError> {
+        Ok(self.decompress_layers(rdr)?.0)
+    }
+
+    /// Like `decompress_nested` but also returns the compressions of all
+    /// layers from the outside in.
+    fn decompress_layers<R: Read + 'static>(
+        self,
+        rdr: R,
+    ) -> Result<(Box<dyn Read>, Vec<Compression>), UnboxError> {
+        let mut rdr = self.decompress(rdr)?;
+        let mut layers = vec![self];
+        loop {
+            let mut buf = Vec::with_capacity(MAGIC_SIZE);
+            rdr.by_ref().take(MAGIC_SIZE as u64).read_to_end(&mut buf)?;
+            let inner = nested_compression(&buf);
+            rdr = Box::new(Cursor::new(buf).chain(rdr));
+            let inner = match inner {
+                Some(inner) => inner,
+                None => return Ok((rdr, layers)),
+            };
+            if layers.len() >= MAX_COMPRESSION_LAYERS {
+                bail!(
+                    "refusing to decompress more than {} nested compression layers",
+                    MAX_COMPRESSION_LAYERS
+                );
+            }
+            rdr = inner.decompress(rdr)?;
+            layers.push(inner);
         }
     }
 
-    /// Returns the single file archive type.
+    /// Wraps a writer for compression.
+    pub fn compress<W: Write>(self, w: W) -> Result<CompressWriter<W>, UnboxError> {
+        Ok(match self {
+            Compression::Uncompressed => CompressWriter::Uncompressed(w),
+            Compression::Gz => CompressWriter::Gz(gzip::Encoder::new(w)?),
+            Compression::Xz => CompressWriter::Xz(XzEncoder::new(w, 6)),
+            Compression::Bz2 => CompressWriter::Bz2(BzEncoder::new(w, bzip2::Compression::Default)),
+            Compression::Zstd => CompressWriter::Zstd(zstd::Encoder::new(w, 0)?),
+            Compression::Lz4 => CompressWriter::Lz4(lz4::EncoderBuilder::new().build(w)?),
+            Compression::Brotli => {
+                CompressWriter::Brotli(Box::new(BrotliEncoder::new(w, 4096, 9, 22)))
+            }
+            Compression::Lzma => {
+                let stream = Stream::new_lzma_encoder(&LzmaOptions::new_preset(6)?)?;
+                CompressWriter::Lzma(XzEncoder::new_stream(w, stream))
+            }
+            Compression::Compress => bail!("compressing with unix compress is not supported"),
+            Compression::Snappy => CompressWriter::Snappy(Box::new(SnappyEncoder::new(w))),
+        })
+    }
+
+    /// The file extension for the compression including the leading dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Uncompressed => "",
+            Compression::Gz => ".gz",
+            Compression::Xz => ".xz",
+            Compression::Bz2 => ".bz2",
+            Compression::Zstd => ".zst",
+            Compression::Lz4 => ".lz4",
+            Compression::Brotli => ".br",
+            Compression::Lzma => ".lzma",
+            Compression::Compress => ".Z",
+            Compression::Snappy => ".sz",
+        }
+    }
+
+    /// Returns the compression of a tarball type.
+    pub fn for_tarball(ty: ArchiveType) -> Option<Compression> {
+        match handler_for(ty)?.compression()? {
+            #[cfg(feature = "tar")]
+            (compression, Some(ArchiveType::Tar)) => Some(compression),
+            _ => None,
+        }
+    }
+
+    /// Returns the compression of a compressed tarball or file type.
+    pub fn for_archive_type(ty: ArchiveType) -> Option<Compression> {
+        handler_for(ty)?
+            .compression()
+            .map(|(compression, _)| compression)
+            .filter(|&x| x != Compression::Uncompressed)
+    }
+
+    /// Returns the type of a file compressed with this compression, or of
+    /// a compressed archive of the given type.
     pub fn as_archive_type(self, parent: Option<ArchiveType>) -> Option<ArchiveType> {
-        match parent {
-            None => match self {
-                Compression::Uncompressed => None,
-                Compression::Gz => Some(ArchiveType::SingleFileGz),
-                Compression::Bz2 => Some(ArchiveType::SingleFileBz2),
-                Compression::Xz => Some(ArchiveType::SingleFileXz),
-            },
-            Some(ArchiveType::Tar) => match self {
-                Compression::Uncompressed => Some(ArchiveType::Tar),
-                Compression::Gz => Some(ArchiveType::TarGz),
-                Compression::Bz2 => Some(ArchiveType::TarBz2),
-                Compression::Xz => Some(ArchiveType::TarXz),
-            },
-            Some(..) => None,
+        type_for_compression(self, parent)
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_single_file_bounded_memory() {
+    use std::fs;
+    use std::fs::File;
+    use std::io::{self, Write};
+
+    use bzip2::write::BzEncoder;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    // the peak memory is tracked for the whole process, so the measurement
+    // runs in a child process where no other tests allocate concurrently.
+    if std::env::var_os("UNBOX_MEASURE_MEMORY").is_none() {
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "formats::compression::test_single_file_bounded_memory",
+                "--test-threads=1",
+            ])
+            .env("UNBOX_MEASURE_MEMORY", "1")
+            .stdout(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        return;
+    }
+
+    fn peak_rss_kb() -> u64 {
+        let status = fs::read_to_string("/proc/self/status").unwrap();
+        let line = status.lines().find(|x| x.starts_with("VmHWM:")).unwrap();
+        line.split_whitespace().nth(1).unwrap().parse().unwrap()
+    }
+
+    const SIZE: u64 = 64 * 1024 * 1024;
+    let dir = make_test_dir();
+    let archive_path = dir.join("zeroes.bz2");
+    let mut w = BzEncoder::new(
+        File::create(&archive_path).unwrap(),
+        bzip2::Compression::Default,
+    );
+    io::copy(&mut io::repeat(0).take(SIZE), &mut w).unwrap();
+    w.finish().unwrap().flush().unwrap();
+
+    let before = peak_rss_kb();
+    let mut archive = SingleFileArchive::open(&archive_path, Compression::Bz2).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    let after = peak_rss_kb();
+
+    assert_eq!(rv.metadata().unwrap().len(), SIZE);
+    assert!(after - before < 32 * 1024);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_single_file_progress() {
+    use std::fs;
+    use std::fs::File;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    use crate::archive::{ProgressReporter, UnpackOptions};
+    use crate::utils::make_test_dir;
+
+    #[derive(Debug, Default)]
+    struct Recorder(AtomicU64);
+
+    impl ProgressReporter for Arc<Recorder> {
+        fn on_file(&self, _path: &Path) {}
+        fn on_bytes(&self, n: u64) {
+            self.0.fetch_add(n, Ordering::Relaxed);
         }
+        fn on_finish(&self) {}
+    }
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("zeroes.gz");
+    let mut w = gzip::Encoder::new(File::create(&archive_path).unwrap()).unwrap();
+    io::copy(&mut io::repeat(0).take(1024 * 1024), &mut w).unwrap();
+    w.finish().into_result().unwrap();
+
+    // the progress has to add up to the total which is the compressed size
+    let mut archive = SingleFileArchive::open(&archive_path, Compression::Gz).unwrap();
+    let total_size = archive.total_size().unwrap();
+    assert_eq!(total_size, fs::metadata(&archive_path).unwrap().len());
+    let recorder = Arc::new(Recorder::default());
+    let mut helper = UnpackHelper::create_with_progress(
+        &archive,
+        &dir,
+        UnpackOptions::default(),
+        Box::new(recorder.clone()),
+    )
+    .unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(recorder.0.load(Ordering::Relaxed), total_size);
+    assert_eq!(fs::metadata(&rv).unwrap().len(), 1024 * 1024);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_single_file_zstd() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("hello.txt.zst");
+    fs::write(
+        &archive_path,
+        zstd::encode_all(&b"hello zstd"[..], 0).unwrap(),
+    )
+    .unwrap();
+    fs::copy(&archive_path, dir.join("renamed.bin")).unwrap();
+    assert_eq!(
+        ArchiveType::for_path(&archive_path),
+        Some(ArchiveType::SingleFileZst)
+    );
+    assert_eq!(
+        ArchiveType::for_path(&dir.join("renamed.bin")),
+        Some(ArchiveType::SingleFileZst)
+    );
+
+    let mut archive = SingleFileArchive::open(&archive_path, Compression::Zstd).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "hello.txt");
+    assert_eq!(fs::read(&rv).unwrap(), b"hello zstd");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_lz4_legacy_format() {
+    // a legacy frame header followed by the size of the first block
+    let legacy = b"\x02\x21\x4c\x18\x05\x00\x00\x00hello";
+    let err = Compression::Lz4.decompress(&legacy[..]).err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "not an lz4 frame (legacy lz4 and raw lz4 blocks are not supported)"
+    );
+
+    let mut w = Compression::Lz4.compress(vec![]).unwrap();
+    w.write_all(b"hello lz4").unwrap();
+    let frame = w.finish().unwrap();
+    let mut rv = vec![];
+    Compression::Lz4
+        .decompress(Cursor::new(frame))
+        .unwrap()
+        .read_to_end(&mut rv)
+        .unwrap();
+    assert_eq!(rv, b"hello lz4");
+}
+
+#[test]
+fn test_single_file_gzip_members() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let mut data = vec![];
+    for chunk in &[&b"hello "[..], &b"world"[..]] {
+        let mut w = Compression::Gz.compress(vec![]).unwrap();
+        w.write_all(chunk).unwrap();
+        data.extend(w.finish().unwrap());
     }
+    let archive_path = dir.join("hello.txt.gz");
+    fs::write(&archive_path, &data).unwrap();
+
+    let mut archive = SingleFileArchive::open(&archive_path, Compression::Gz).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "hello.txt");
+    assert_eq!(fs::read(&rv).unwrap(), b"hello world");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_single_file_brotli() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let mut w = Compression::Brotli.compress(vec![]).unwrap();
+    w.write_all(b"body { color: red; }\n").unwrap();
+    let data = w.finish().unwrap();
+    let archive_path = dir.join("style.css.br");
+    fs::write(&archive_path, &data).unwrap();
+    assert_eq!(
+        ArchiveType::for_path(&archive_path),
+        Some(ArchiveType::SingleFileBr)
+    );
+    assert_eq!(
+        ArchiveType::for_bytes(&data, Some("style.css.br")),
+        Some(ArchiveType::SingleFileBr)
+    );
+    assert_eq!(
+        ArchiveType::from_name("brotli"),
+        Some(ArchiveType::SingleFileBr)
+    );
+
+    let mut archive = SingleFileArchive::open(&archive_path, Compression::Brotli).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "style.css");
+    assert_eq!(fs::read(&rv).unwrap(), b"body { color: red; }\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_single_file_lzma() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    for &(compression, ty) in &[
+        // without its extension legacy lzma is not trusted
+        (Compression::Lzma, None),
+        (Compression::Xz, Some(ArchiveType::SingleFileXz)),
+    ] {
+        let mut w = compression.compress(vec![]).unwrap();
+        w.write_all(b"hello lzma").unwrap();
+        let data = w.finish().unwrap();
+        assert_eq!(is_lzma_header(&data), compression == Compression::Lzma);
+        fs::write(dir.join("renamed.bin"), &data).unwrap();
+        assert_eq!(ArchiveType::for_path(&dir.join("renamed.bin")), ty);
+    }
+
+    let archive_path = dir.join("notes.txt.lzma");
+    let mut w = Compression::Lzma.compress(vec![]).unwrap();
+    w.write_all(b"hello lzma").unwrap();
+    fs::write(&archive_path, w.finish().unwrap()).unwrap();
+    let mut archive = SingleFileArchive::open(&archive_path, Compression::Lzma).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "notes.txt");
+    assert_eq!(fs::read(&rv).unwrap(), b"hello lzma");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_single_file_compress() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    // the fixture uses 10 bit codes and is long enough to fill the table
+    // and reset it a few times.
+    let fixture = &include_bytes!("../../tests/fixtures/words.txt.Z")[..];
+    let mut data = vec![];
+    Compression::Compress
+        .decompress(fixture)
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    assert_eq!(data.len(), 7395);
+    assert!(data.starts_with(b"sit75 dolor47 compress80 ipsum77 lorem60"));
+    let words = [
+        "lorem", "ipsum", "dolor", "sit", "amet", "unbox", "archive", "compress",
+    ];
+    assert!(String::from_utf8(data.clone())
+        .unwrap()
+        .split_whitespace()
+        .all(|x| words.contains(&x.trim_end_matches(|c: char| c.is_ascii_digit()))));
+    assert!(Compression::Compress.compress(vec![]).is_err());
+
+    let dir = make_test_dir();
+    fs::write(dir.join("renamed.bin"), fixture).unwrap();
+    assert_eq!(
+        ArchiveType::for_path(&dir.join("renamed.bin")),
+        Some(ArchiveType::SingleFileZ)
+    );
+    let archive_path = dir.join("words.txt.Z");
+    fs::write(&archive_path, fixture).unwrap();
+    assert_eq!(
+        ArchiveType::for_path(&archive_path),
+        Some(ArchiveType::SingleFileZ)
+    );
+    let mut archive = SingleFileArchive::open(&archive_path, Compression::Compress).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "words.txt");
+    assert_eq!(fs::read(&rv).unwrap(), data);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_single_file_snappy() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let contents = b"hello snappy ".repeat(1000);
+    let mut w = Compression::Snappy.compress(vec![]).unwrap();
+    w.write_all(&contents).unwrap();
+    let data = w.finish().unwrap();
+    assert!(data.starts_with(SNAPPY_MAGIC));
+
+    let dir = make_test_dir();
+    fs::write(dir.join("renamed.bin"), &data).unwrap();
+    assert_eq!(
+        ArchiveType::for_path(&dir.join("renamed.bin")),
+        Some(ArchiveType::SingleFileSnappy)
+    );
+    let archive_path = dir.join("events.json.sz");
+    fs::write(&archive_path, &data).unwrap();
+    assert_eq!(
+        ArchiveType::for_path(&archive_path),
+        Some(ArchiveType::SingleFileSnappy)
+    );
+    let mut archive = SingleFileArchive::open(&archive_path, Compression::Snappy).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "events.json");
+    assert_eq!(fs::read(&rv).unwrap(), contents);
+    fs::remove_dir_all(&dir).unwrap();
+
+    // raw snappy blocks start with the varint encoded length
+    let raw = snap::raw::Encoder::new().compress_vec(&contents).unwrap();
+    match Compression::Snappy.decompress(Cursor::new(raw)) {
+        Err(err) => assert_eq!(
+            err.to_string(),
+            "not a framed snappy stream (raw snappy blocks are not supported)"
+        ),
+        Ok(_) => panic!("raw snappy was accepted"),
+    }
+}
+
+#[test]
+fn test_single_file_nested_compression() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    fn compress(compression: Compression, data: &[u8]) -> Vec<u8> {
+        let mut w = compression.compress(vec![]).unwrap();
+        w.write_all(data).unwrap();
+        w.finish().unwrap()
+    }
+
+    let dir = make_test_dir();
+    let data = compress(
+        Compression::Gz,
+        &compress(Compression::Gz, b"hello world\n"),
+    );
+    let archive_path = dir.join("hello.txt.gz.gz");
+    fs::write(&archive_path, &data).unwrap();
+    assert_eq!(
+        ArchiveType::for_path(&archive_path),
+        Some(ArchiveType::SingleFileGz)
+    );
+
+    let mut archive = SingleFileArchive::open(&archive_path, Compression::Gz).unwrap();
+    let entries = archive.entries().unwrap();
+    assert_eq!(entries[0].name, "hello.txt");
+    assert_eq!(entries[0].size, 12);
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "hello.txt");
+    assert_eq!(fs::read(&rv).unwrap(), b"hello world\n");
+
+    // layers beyond the limit are refused
+    let mut data = b"hello world\n".to_vec();
+    for _ in 0..=MAX_COMPRESSION_LAYERS {
+        data = compress(Compression::Gz, &data);
+    }
+    let err = Compression::Gz
+        .decompress_nested(Cursor::new(data))
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("nested compression layers"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "hashes")]
+#[test]
+fn test_single_file_digests() {
+    use std::fs;
+
+    use crate::archive::{HashAlgorithm, UnpackOptions};
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let mut w = Compression::Xz.compress(vec![]).unwrap();
+    w.write_all(b"hello world\n").unwrap();
+    let archive_path = dir.join("hello.txt.xz");
+    fs::write(&archive_path, w.finish().unwrap()).unwrap();
+
+    let mut archive = SingleFileArchive::open(&archive_path, Compression::Xz).unwrap();
+    let options = UnpackOptions {
+        hashes: vec![HashAlgorithm::Sha256, HashAlgorithm::Md5],
+        ..UnpackOptions::default()
+    };
+    let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    assert_eq!(
+        helper.digests(),
+        &[
+            (
+                HashAlgorithm::Sha256,
+                "a948904f2f0f479b8f8197694b30184b0d2ed1c1cd2a1ec0fb85d299a192a447".to_string()
+            ),
+            (
+                HashAlgorithm::Md5,
+                "6f5902ac237024bdd0c176cb93063dc4".to_string()
+            ),
+        ]
+    );
+    let rv = helper.commit().unwrap();
+    assert_eq!(fs::read(&rv).unwrap(), b"hello world\n");
+    fs::remove_dir_all(&dir).unwrap();
 }
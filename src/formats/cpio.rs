@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::archive::{Archive, ArchiveEntry, EntryKind, UnpackHelper};
+use crate::error::{bail, UnboxError};
+
+pub const NEWC_MAGIC: &[u8] = b"070701";
+pub const NEWC_CRC_MAGIC: &[u8] = b"070702";
+pub const ODC_MAGIC: &[u8] = b"070707";
+const BINARY_MAGIC: u16 = 0o070707;
+
+const TRAILER: &str = "TRAILER!!!";
+/// Upper bound for the size of a file name (`PATH_MAX` on linux) so that a
+/// corrupted header cannot make us allocate arbitrary amounts of memory.
+const MAX_NAME_SIZE: u64 = 4096;
+
+const S_IFMT: u32 = 0o170_000;
+const S_IFDIR: u32 = 0o040_000;
+const S_IFREG: u32 = 0o100_000;
+const S_IFLNK: u32 = 0o120_000;
+
+/// The variants of cpio headers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Format {
+    /// The portable format with hex fields padded to four bytes.
+    Newc,
+    /// The old portable format with octal fields.
+    Odc,
+    /// The old binary format padded to two bytes.
+    Binary { big_endian: bool },
+}
+
+impl Format {
+    /// The number of bytes needed to pad the given size.
+    fn padding(self, size: u64) -> u64 {
+        let align = match self {
+            Format::Newc => 4,
+            Format::Odc => 1,
+            Format::Binary { .. } => 2,
+        };
+        (align - size % align) % align
+    }
+}
+
+#[derive(Debug)]
+struct Header {
+    format: Format,
+    mode: u32,
+    size: u64,
+    name: String,
+    /// The device and inode of a file with several links.
+    ///
+    /// Only set in the portable format which stores the data of hard linked
+    /// files once with the last link.
+    link: Option<(u64, u64, u64)>,
+}
+
+#[derive(Debug)]
+pub struct CpioArchive {
+    path: PathBuf,
+    total_size: u64,
+}
+
+fn parse_field(field: &[u8], radix: u32) -> Result<u64, UnboxError> {
+    match std::str::from_utf8(field)
+        .ok()
+        .and_then(|x| u64::from_str_radix(x, radix).ok())
+    {
+        Some(value) => Ok(value),
+        None => bail!("invalid cpio header field"),
+    }
+}
+
+fn skip<R: Read>(rdr: &mut R, len: u64) -> io::Result<()> {
+    io::copy(&mut rdr.take(len), &mut io::sink())?;
+    Ok(())
+}
+
+/// Reads the next header including the file name.
+fn read_header<R: Read>(rdr: &mut R) -> Result<Header, UnboxError> {
+    let mut magic = [0u8; 6];
+    rdr.read_exact(&mut magic)?;
+    let mut link = None;
+    let (format, mode, size, name_size, header_size) =
+        if &magic[..] == NEWC_MAGIC || &magic[..] == NEWC_CRC_MAGIC {
+            let mut buf = [0u8; 104];
+            rdr.read_exact(&mut buf)?;
+            let field = |idx: usize| parse_field(&buf[idx * 8..idx * 8 + 8], 16);
+            if field(4)? > 1 {
+                link = Some((field(7)?, field(8)?, field(0)?));
+            }
+            (Format::Newc, field(1)?, field(6)?, field(11)?, 110)
+        } else if &magic[..] == ODC_MAGIC {
+            let mut buf = [0u8; 70];
+            rdr.read_exact(&mut buf)?;
+            (
+                Format::Odc,
+                parse_field(&buf[12..18], 8)?,
+                parse_field(&buf[59..70], 8)?,
+                parse_field(&buf[53..59], 8)?,
+                76,
+            )
+        } else {
+            let big_endian = if u16::from_le_bytes([magic[0], magic[1]]) == BINARY_MAGIC {
+                false
+            } else if u16::from_be_bytes([magic[0], magic[1]]) == BINARY_MAGIC {
+                true
+            } else {
+                bail!("invalid cpio header");
+            };
+            let mut buf = [0u8; 26];
+            buf[..6].copy_from_slice(&magic);
+            rdr.read_exact(&mut buf[6..])?;
+            let word = |idx: usize| {
+                let bytes = [buf[idx * 2], buf[idx * 2 + 1]];
+                u64::from(if big_endian {
+                    u16::from_be_bytes(bytes)
+                } else {
+                    u16::from_le_bytes(bytes)
+                })
+            };
+            // 32 bit values are stored as two words, most significant first
+            (
+                Format::Binary { big_endian },
+                word(3),
+                (word(11) << 16) | word(12),
+                word(10),
+                26,
+            )
+        };
+
+    if name_size > MAX_NAME_SIZE {
+        bail!("invalid cpio header");
+    }
+    let mut name = vec![0u8; name_size as usize];
+    rdr.read_exact(&mut name)?;
+    skip(rdr, format.padding(header_size + name_size))?;
+    while name.last() == Some(&0) {
+        name.pop();
+    }
+    Ok(Header {
+        format,
+        mode: mode as u32,
+        size,
+        name: String::from_utf8_lossy(&name).to_string(),
+        link,
+    })
+}
+
+impl CpioArchive {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, UnboxError> {
+        let path = path.as_ref().canonicalize()?;
+        let total_size = path.metadata()?.len();
+        Ok(CpioArchive { path, total_size })
+    }
+}
+
+impl Archive for CpioArchive {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn total_size(&self) -> Option<u64> {
+        Some(self.total_size)
+    }
+
+    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), UnboxError> {
+        let rdr = helper.wrap_read(File::open(&self.path)?);
+        unpack_cpio(rdr, helper)
+    }
+
+    fn entries(&mut self) -> Result<Vec<ArchiveEntry>, UnboxError> {
+        cpio_entries(File::open(&self.path)?)
+    }
+}
+
+/// Unpacks a cpio archive from a stream.
+///
+/// This is also used for archives with a cpio payload such as rpm packages.
+pub fn unpack_cpio<R: Read>(rdr: R, helper: &mut UnpackHelper) -> Result<(), UnboxError> {
+    let mut rdr = BufReader::new(rdr);
+    // hard links seen before the link that carries the data
+    let mut pending_links = HashMap::<_, Vec<PathBuf>>::new();
+    loop {
+        let header = read_header(&mut rdr)?;
+        if header.name == TRAILER {
+            break;
+        }
+        let mut data = (&mut rdr).take(header.size);
+        let path = PathBuf::from(header.name.trim_start_matches("./"));
+        let link = header.link.filter(|_| header.mode & S_IFMT == S_IFREG);
+        if path.as_os_str().is_empty() || helper.skip_entry() {
+            // nothing to do
+        } else if header.mode & S_IFMT == S_IFDIR {
+            helper.create_dir(&path)?;
+        } else if let (Some(link), 0) = (link, header.size) {
+            pending_links.entry(link).or_default().push(path);
+        } else if header.mode & S_IFMT == S_IFREG {
+            helper
+                .write_file_from(&path, &mut data)
+                .map_err(|err| helper.entry_error(&path, err))?;
+            for linkname in link
+                .and_then(|x| pending_links.remove(&x))
+                .unwrap_or_default()
+            {
+                helper.copy_hard_link(&linkname, &path)?;
+            }
+        } else if header.mode & S_IFMT == S_IFLNK {
+            // the data of a symlink is its target
+            let mut target = String::new();
+            (&mut data).take(4096).read_to_string(&mut target)?;
+            helper.create_symlink(&path, &target)?;
+        } else {
+            debug!("skipping special file {}", path.display());
+        }
+        // whatever was not written still needs to be skipped
+        skip(&mut data, header.size)?;
+        skip(&mut rdr, header.format.padding(header.size))?;
+    }
+    // links without any data are empty files
+    for path in pending_links.into_values().flatten() {
+        helper
+            .write_file_from(&path, io::empty())
+            .map_err(|err| helper.entry_error(&path, err))?;
+    }
+    Ok(())
+}
+
+/// Lists the entries of a cpio archive from a stream.
+pub fn cpio_entries<R: Read>(rdr: R) -> Result<Vec<ArchiveEntry>, UnboxError> {
+    let mut rdr = BufReader::new(rdr);
+    let mut rv = vec![];
+    loop {
+        let header = read_header(&mut rdr)?;
+        if header.name == TRAILER {
+            break;
+        }
+        let kind = match header.mode & S_IFMT {
+            S_IFDIR => EntryKind::Directory,
+            S_IFREG => EntryKind::File,
+            _ => EntryKind::Other,
+        };
+        rv.push(ArchiveEntry::new(&header.name, header.size, kind));
+        skip(&mut rdr, header.size + header.format.padding(header.size))?;
+    }
+    Ok(rv)
+}
+
+/// Builds a cpio archive in the portable format.
+#[cfg(all(test, feature = "rpm"))]
+pub fn build_newc_cpio(entries: &[(&str, u32, &[u8])]) -> Vec<u8> {
+    build_cpio(Format::Newc, entries)
+}
+
+/// Builds a cpio archive in the given format.
+#[cfg(test)]
+fn build_cpio(format: Format, entries: &[(&str, u32, &[u8])]) -> Vec<u8> {
+    let mut rv = vec![];
+    let trailer = (TRAILER, 0, &b""[..]);
+    for &(name, mode, data) in entries.iter().chain(Some(&trailer)) {
+        let name_size = name.len() as u64 + 1;
+        let header_size = match format {
+            Format::Newc => {
+                rv.extend(NEWC_MAGIC);
+                for &value in &[0, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0] {
+                    rv.extend(format!("{:08x}", value).as_bytes());
+                }
+                rv.extend(format!("{:08x}{:08x}", name_size, 0).as_bytes());
+                110
+            }
+            Format::Odc => {
+                rv.extend(ODC_MAGIC);
+                rv.extend(format!("{:06o}{:06o}{:06o}", 0, 0, mode).as_bytes());
+                rv.extend(format!("{:06o}{:06o}{:06o}{:06o}", 0, 0, 1, 0).as_bytes());
+                rv.extend(format!("{:011o}{:06o}{:011o}", 0, name_size, data.len()).as_bytes());
+                76
+            }
+            Format::Binary { big_endian } => {
+                let size = data.len() as u32;
+                let words = [
+                    BINARY_MAGIC,
+                    0,
+                    0,
+                    mode as u16,
+                    0,
+                    0,
+                    1,
+                    0,
+                    0,
+                    0,
+                    name_size as u16,
+                    (size >> 16) as u16,
+                    size as u16,
+                ];
+                for word in &words {
+                    if big_endian {
+                        rv.extend(&word.to_be_bytes());
+                    } else {
+                        rv.extend(&word.to_le_bytes());
+                    }
+                }
+                26
+            }
+        };
+        rv.extend(name.as_bytes());
+        rv.push(0);
+        rv.resize(
+            rv.len() + format.padding(header_size + name_size) as usize,
+            0,
+        );
+        rv.extend(data);
+        rv.resize(rv.len() + format.padding(data.len() as u64) as usize, 0);
+    }
+    rv
+}
+
+#[test]
+fn test_cpio() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    let entries: &[(&str, u32, &[u8])] = &[
+        (".", S_IFDIR | 0o755, b""),
+        ("./etc", S_IFDIR | 0o755, b""),
+        ("./etc/hostname", S_IFREG | 0o644, b"unbox\n"),
+        ("./etc/link", S_IFLNK | 0o777, b"hostname"),
+        ("./etc/escape", S_IFLNK | 0o777, b"../../outside"),
+        ("../evil", S_IFREG | 0o644, b"evil"),
+        ("./init", S_IFREG | 0o755, b"#!/bin/sh\n"),
+    ];
+    for &format in &[
+        Format::Newc,
+        Format::Odc,
+        Format::Binary { big_endian: false },
+        Format::Binary { big_endian: true },
+    ] {
+        let dir = make_test_dir();
+        let archive_path = dir.join("initramfs.cpio");
+        fs::write(&archive_path, build_cpio(format, entries)).unwrap();
+        assert_eq!(
+            ArchiveType::for_path(&archive_path),
+            Some(ArchiveType::Cpio)
+        );
+
+        let mut archive = CpioArchive::open(&archive_path).unwrap();
+        let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        let rv = helper.commit().unwrap();
+        assert_eq!(rv.file_name().unwrap(), "initramfs");
+        assert_eq!(fs::read(rv.join("etc/hostname")).unwrap(), b"unbox\n");
+        assert_eq!(fs::read(rv.join("init")).unwrap(), b"#!/bin/sh\n");
+        #[cfg(unix)]
+        {
+            let link = rv.join("etc/link");
+            assert_eq!(fs::read_link(&link).unwrap(), Path::new("hostname"));
+            assert_eq!(fs::read(&link).unwrap(), b"unbox\n");
+        }
+        assert!(fs::symlink_metadata(rv.join("etc/escape")).is_err());
+        assert!(!dir.join("evil").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[test]
+fn test_newc_hard_links() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let entry = |name: &str, ino: u32, nlink: u32, data: &[u8]| {
+        let mut rv = NEWC_MAGIC.to_vec();
+        let fields = [ino, S_IFREG | 0o644, 0, 0, nlink, 0, data.len() as u32];
+        for value in fields.iter().chain(&[0, 0, 0, 0, name.len() as u32 + 1, 0]) {
+            rv.extend(format!("{:08x}", value).as_bytes());
+        }
+        rv.extend(name.as_bytes());
+        rv.push(0);
+        rv.resize(rv.len() + Format::Newc.padding(rv.len() as u64) as usize, 0);
+        rv.extend(data);
+        rv.resize(
+            rv.len() + Format::Newc.padding(data.len() as u64) as usize,
+            0,
+        );
+        rv
+    };
+    let dir = make_test_dir();
+    let archive_path = dir.join("links.cpio");
+    let mut contents = vec![];
+    contents.extend(entry("a.txt", 1, 2, b""));
+    contents.extend(entry("b.txt", 2, 1, b"b"));
+    contents.extend(entry("c.txt", 1, 2, b"linked"));
+    contents.extend(entry("empty1", 3, 2, b""));
+    contents.extend(entry("empty2", 3, 2, b""));
+    contents.extend(entry(TRAILER, 0, 1, b""));
+    fs::write(&archive_path, &contents).unwrap();
+
+    let mut archive = CpioArchive::open(&archive_path).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(fs::read(rv.join("a.txt")).unwrap(), b"linked");
+    assert_eq!(fs::read(rv.join("b.txt")).unwrap(), b"b");
+    assert_eq!(fs::read(rv.join("c.txt")).unwrap(), b"linked");
+    assert_eq!(fs::read(rv.join("empty1")).unwrap(), b"");
+    assert_eq!(fs::read(rv.join("empty2")).unwrap(), b"");
+
+    // the size of the name is bounded
+    let mut corrupted = entry("a.txt", 1, 1, b"a");
+    corrupted[94..102].copy_from_slice(b"ffffffff");
+    fs::write(&archive_path, &corrupted).unwrap();
+    let mut archive = CpioArchive::open(&archive_path).unwrap();
+    let err = archive.entries().unwrap_err();
+    assert_eq!(err.to_string(), "invalid cpio header");
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,372 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use libflate::zlib;
+use log::debug;
+
+use crate::archive::{Archive, ArchiveEntry, EntryKind, UnpackHelper};
+use crate::error::{bail, UnboxError};
+use crate::utils::is_unsafe_path;
+
+/// The magic number in little endian byte order.
+pub const MAGIC: &[u8] = b"\x45\x3d\xcd\x28";
+
+const BLOCK_SIZE: u64 = 4096;
+const INODE_SIZE: u64 = 12;
+const FLAG_EXT_BLOCK_POINTERS: u32 = 0x800;
+
+const S_IFMT: u32 = 0o170_000;
+const S_IFDIR: u32 = 0o040_000;
+const S_IFREG: u32 = 0o100_000;
+
+/// A cramfs inode which is followed by its name in the image.
+#[derive(Debug, Clone, Copy)]
+struct Inode {
+    mode: u32,
+    size: u64,
+    name_len: u64,
+    offset: u64,
+}
+
+impl Inode {
+    fn parse(buf: &[u8; 12]) -> Inode {
+        let word =
+            |idx: usize| u32::from_le_bytes([buf[idx], buf[idx + 1], buf[idx + 2], buf[idx + 3]]);
+        let (a, b, c) = (word(0), word(4), word(8));
+        Inode {
+            mode: a & 0xffff,
+            size: u64::from(b & 0x00ff_ffff),
+            name_len: u64::from(c & 0x3f) * 4,
+            offset: u64::from(c >> 6) * 4,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CramfsEntry {
+    path: PathBuf,
+    inode: Inode,
+}
+
+#[derive(Debug)]
+pub struct CramfsArchive {
+    path: PathBuf,
+    total_size: u64,
+    entries: Vec<CramfsEntry>,
+}
+
+fn read_inode<R: Read + Seek>(rdr: &mut R, offset: u64) -> Result<(Inode, Vec<u8>), UnboxError> {
+    let mut buf = [0u8; 12];
+    rdr.seek(SeekFrom::Start(offset))?;
+    rdr.read_exact(&mut buf)?;
+    let inode = Inode::parse(&buf);
+    let mut name = vec![0u8; inode.name_len as usize];
+    rdr.read_exact(&mut name)?;
+    while name.last() == Some(&0) {
+        name.pop();
+    }
+    Ok((inode, name))
+}
+
+/// Recursively reads a directory and all its children.
+fn read_directory<R: Read + Seek>(
+    rdr: &mut R,
+    dir: Inode,
+    prefix: &Path,
+    seen: &mut HashSet<u64>,
+    entries: &mut Vec<CramfsEntry>,
+) -> Result<(), UnboxError> {
+    // guard against directories referencing themselves
+    if !seen.insert(dir.offset) {
+        return Ok(());
+    }
+
+    let mut offset = dir.offset;
+    while offset < dir.offset + dir.size {
+        let (inode, name) = read_inode(rdr, offset)?;
+        offset += INODE_SIZE + inode.name_len;
+        let path = prefix.join(String::from_utf8_lossy(&name).as_ref());
+        if name.is_empty() || is_unsafe_path(&path) {
+            continue;
+        }
+        match inode.mode & S_IFMT {
+            S_IFDIR => {
+                entries.push(CramfsEntry { path, inode });
+                let prefix = entries.last().unwrap().path.clone();
+                read_directory(rdr, inode, &prefix, seen, entries)?;
+            }
+            S_IFREG => entries.push(CramfsEntry { path, inode }),
+            _ => debug!("skipping special file {}", path.display()),
+        }
+    }
+    Ok(())
+}
+
+/// Reads and decompresses the contents of a file block by block.
+///
+/// Files are split into blocks which are compressed individually.  A table
+/// of pointers to the end of each block precedes the data.
+struct FileReader<'a, R> {
+    rdr: &'a mut R,
+    pointers: std::vec::IntoIter<u64>,
+    start: u64,
+    remaining: u64,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a, R: Read + Seek> FileReader<'a, R> {
+    fn new(rdr: &'a mut R, inode: Inode) -> Result<FileReader<'a, R>, UnboxError> {
+        let blocks = inode.size.div_ceil(BLOCK_SIZE);
+        let mut pointers = vec![0u8; blocks as usize * 4];
+        rdr.seek(SeekFrom::Start(inode.offset))?;
+        rdr.read_exact(&mut pointers)?;
+        Ok(FileReader {
+            rdr,
+            pointers: pointers
+                .chunks(4)
+                .map(|x| u64::from(u32::from_le_bytes([x[0], x[1], x[2], x[3]])))
+                .collect::<Vec<_>>()
+                .into_iter(),
+            start: inode.offset + blocks * 4,
+            remaining: inode.size,
+            block: vec![],
+            pos: 0,
+        })
+    }
+
+    /// Decompresses the next block, returns `false` at the end of the file.
+    fn next_block(&mut self) -> io::Result<bool> {
+        let end = match self.pointers.next() {
+            Some(end) if self.remaining > 0 => end,
+            _ => return Ok(false),
+        };
+        if end < self.start {
+            return Err(corrupt("invalid block pointer"));
+        }
+        let len = self.remaining.min(BLOCK_SIZE);
+        self.block.clear();
+        self.pos = 0;
+        // empty blocks are holes in sparse files
+        if end == self.start {
+            self.block.resize(len as usize, 0);
+        } else {
+            self.rdr.seek(SeekFrom::Start(self.start))?;
+            let decoder = zlib::Decoder::new(self.rdr.by_ref().take(end - self.start))?;
+            decoder.take(BLOCK_SIZE + 1).read_to_end(&mut self.block)?;
+            if self.block.len() as u64 > BLOCK_SIZE {
+                return Err(corrupt("block is larger than the block size"));
+            }
+            self.block.truncate(len as usize);
+            self.start = end;
+        }
+        self.remaining -= self.block.len() as u64;
+        Ok(true)
+    }
+}
+
+impl<R: Read + Seek> Read for FileReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.block.len() {
+            if !self.next_block()? {
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.block.len() - self.pos);
+        buf[..len].copy_from_slice(&self.block[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+fn corrupt(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl CramfsArchive {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, UnboxError> {
+        let path = path.as_ref().canonicalize()?;
+        let mut rdr = BufReader::new(File::open(&path)?);
+
+        // the superblock might be preceded by a 512 byte boot sector
+        let mut buf = [0u8; 76];
+        let mut base = 0;
+        for &offset in &[0, 512] {
+            rdr.seek(SeekFrom::Start(offset))?;
+            rdr.read_exact(&mut buf)?;
+            if &buf[..4] == MAGIC {
+                base = offset;
+                break;
+            } else if offset == 512 {
+                bail!("not a little endian cramfs image");
+            }
+        }
+        let flags = u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]);
+        if flags & FLAG_EXT_BLOCK_POINTERS != 0 {
+            bail!("cramfs images with extended block pointers are not supported");
+        }
+
+        let (root, _) = read_inode(&mut rdr, base + 64)?;
+        let mut entries = vec![];
+        read_directory(
+            &mut rdr,
+            root,
+            Path::new(""),
+            &mut HashSet::new(),
+            &mut entries,
+        )?;
+
+        let total_size = u64::from(u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]));
+        Ok(CramfsArchive {
+            path,
+            total_size,
+            entries,
+        })
+    }
+}
+
+impl Archive for CramfsArchive {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn total_size(&self) -> Option<u64> {
+        Some(self.total_size)
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        Some(self.entries.len() as u64)
+    }
+
+    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), UnboxError> {
+        let mut rdr = BufReader::new(File::open(&self.path)?);
+        for entry in &self.entries {
+            if helper.skip_entry() {
+                continue;
+            }
+            if entry.inode.mode & S_IFMT == S_IFDIR {
+                helper.create_dir(&entry.path)?;
+            } else {
+                FileReader::new(&mut rdr, entry.inode)
+                    .and_then(|contents| helper.write_file_with_progress(&entry.path, contents))
+                    .map_err(|err| helper.entry_error(&entry.path, err))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn entries(&mut self) -> Result<Vec<ArchiveEntry>, UnboxError> {
+        Ok(self
+            .entries
+            .iter()
+            .map(|entry| match entry.inode.mode & S_IFMT {
+                S_IFDIR => ArchiveEntry::new(entry.path.to_string_lossy(), 0, EntryKind::Directory),
+                S_IFREG => ArchiveEntry::new(
+                    entry.path.to_string_lossy(),
+                    entry.inode.size,
+                    EntryKind::File,
+                ),
+                _ => ArchiveEntry::new(
+                    entry.path.to_string_lossy(),
+                    entry.inode.size,
+                    EntryKind::Other,
+                ),
+            })
+            .collect())
+    }
+}
+
+/// Builds a small cramfs image with a file in a sub directory.
+///
+/// The file is stored in a single block and claims to be `size` bytes.
+#[cfg(test)]
+fn build_cramfs(contents: &[u8], size: u32) -> Vec<u8> {
+    use std::io::Write;
+
+    fn inode(mode: u32, size: u32, name: &[u8], offset: u32) -> Vec<u8> {
+        let name_len = (name.len() as u32).div_ceil(4);
+        let mut rv = vec![];
+        rv.extend(&mode.to_le_bytes());
+        rv.extend(&size.to_le_bytes());
+        rv.extend(&(name_len | ((offset / 4) << 6)).to_le_bytes());
+        rv.extend(name);
+        rv.resize(12 + name_len as usize * 4, 0);
+        rv
+    }
+
+    let mut encoder = zlib::Encoder::new(vec![]).unwrap();
+    encoder.write_all(contents).unwrap();
+    let block = encoder.finish().into_result().unwrap();
+
+    // layout: superblock and root inode, root entries, sub entries, data
+    let sub_entries_offset = 76 + 16;
+    let data_offset = sub_entries_offset + 20;
+    let sub_inode = inode(S_IFDIR | 0o755, 20, b"sub", sub_entries_offset);
+    let file_inode = inode(S_IFREG | 0o644, size, b"file.txt", data_offset);
+
+    let mut rv = vec![];
+    rv.extend(MAGIC);
+    rv.extend(&[0u8; 12]);
+    rv.extend(b"Compressed ROMFS");
+    rv.extend(&[0u8; 32]);
+    rv.extend(inode(S_IFDIR | 0o755, sub_inode.len() as u32, b"", 76));
+    rv.extend(sub_inode);
+    rv.extend(file_inode);
+    rv.extend(&(data_offset + 4 + block.len() as u32).to_le_bytes());
+    rv.extend(block);
+    let len = rv.len() as u32;
+    rv[4..8].copy_from_slice(&len.to_le_bytes());
+    rv
+}
+
+#[test]
+fn test_cramfs() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("firmware.bin");
+    let contents = b"hello cramfs ".repeat(100);
+    let image = build_cramfs(&contents, contents.len() as u32);
+    fs::write(&archive_path, &image).unwrap();
+    assert_eq!(
+        ArchiveType::for_path(&archive_path),
+        Some(ArchiveType::Cramfs)
+    );
+
+    let mut archive = CramfsArchive::open(&archive_path).unwrap();
+    assert_eq!(archive.total_size(), Some(image.len() as u64));
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "sub");
+    assert_eq!(fs::read(rv.join("file.txt")).unwrap(), contents);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_oversized_block() {
+    use std::error::Error;
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    // a block inflating to more than the block size is rejected instead
+    // of being buffered
+    let dir = make_test_dir();
+    let archive_path = dir.join("bomb.cramfs");
+    fs::write(&archive_path, build_cramfs(&[0; 1 << 20], 10)).unwrap();
+    let mut archive = CramfsArchive::open(&archive_path).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    let err = archive.unpack(&mut helper).unwrap_err();
+    let source = err.source().unwrap().to_string();
+    assert!(source.contains("larger than the block size"), "{}", source);
+    drop(helper);
+    fs::remove_dir_all(&dir).unwrap();
+}
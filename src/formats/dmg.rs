@@ -0,0 +1,993 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bzip2::read::BzDecoder;
+use filetime::FileTime;
+use libflate::zlib;
+use log::debug;
+use roxmltree::{Document, Node, ParsingOptions};
+
+use crate::archive::{Archive, ArchiveEntry, EntryKind, EntryMetadata, UnpackHelper};
+use crate::error::{bail, UnboxError};
+use crate::utils::is_unsafe_path;
+
+pub const KOLY_MAGIC: &[u8] = b"koly";
+const KOLY_SIZE: u64 = 512;
+const MISH_MAGIC: &[u8] = b"mish";
+const MISH_HEADER_SIZE: usize = 204;
+const MISH_CHUNK_SIZE: usize = 40;
+const MAX_PLIST_SIZE: u64 = 0x1000_0000;
+const MAX_CHUNK_SIZE: u64 = 0x400_0000;
+const SECTOR_SIZE: u64 = 512;
+
+const CHUNK_ZERO: u32 = 0x0000_0000;
+const CHUNK_RAW: u32 = 0x0000_0001;
+const CHUNK_IGNORE: u32 = 0x0000_0002;
+const CHUNK_ADC: u32 = 0x8000_0004;
+const CHUNK_ZLIB: u32 = 0x8000_0005;
+const CHUNK_BZIP2: u32 = 0x8000_0006;
+const CHUNK_LZFSE: u32 = 0x8000_0007;
+const CHUNK_COMMENT: u32 = 0x7fff_fffe;
+const CHUNK_END: u32 = 0xffff_ffff;
+
+const VOLUME_HEADER_OFFSET: u64 = 1024;
+const VOLUME_HEADER_SIZE: usize = 512;
+const HFS_PLUS_MAGIC: &[u8] = b"H+";
+const HFSX_MAGIC: &[u8] = b"HX";
+const APFS_MAGIC: &[u8] = b"NXSB";
+const MAX_BTREE_SIZE: u64 = 0x4000_0000;
+
+const ROOT_FOLDER_ID: u32 = 2;
+const EXTENTS_FILE_ID: u32 = 3;
+const CATALOG_FILE_ID: u32 = 4;
+const RECORD_FOLDER: u16 = 1;
+const RECORD_FILE: u16 = 2;
+const FOLDER_RECORD_SIZE: usize = 88;
+const FILE_RECORD_SIZE: usize = 248;
+const FORK_DATA_SIZE: usize = 80;
+const MAX_DEPTH: usize = 256;
+
+/// Hidden folders holding the targets of file and directory hard links.
+const PRIVATE_DATA: &str = "\0\0\0\0HFS+ Private Data";
+const PRIVATE_DIR_DATA: &str = ".HFS+ Private Directory Data\r";
+const JOURNAL_FILES: [&str; 2] = [".journal", ".journal_info_block"];
+
+/// The owner flag of files using hfs+ file compression.
+const UF_COMPRESSED: u8 = 0x20;
+const S_IFMT: u16 = 0o170_000;
+const S_IFREG: u16 = 0o100_000;
+const S_IFLNK: u16 = 0o120_000;
+
+/// Seconds between the hfs epoch (1904) and the unix epoch.
+const HFS_EPOCH_OFFSET: i64 = 2_082_844_800;
+
+fn be_u16(buf: &[u8], idx: usize) -> u16 {
+    u16::from_be_bytes([buf[idx], buf[idx + 1]])
+}
+
+fn be_u32(buf: &[u8], idx: usize) -> u32 {
+    u32::from_be_bytes([buf[idx], buf[idx + 1], buf[idx + 2], buf[idx + 3]])
+}
+
+fn be_u64(buf: &[u8], idx: usize) -> u64 {
+    let mut rv = [0u8; 8];
+    rv.copy_from_slice(&buf[idx..idx + 8]);
+    u64::from_be_bytes(rv)
+}
+
+/// A run of sectors of the disk image and where its data is stored.
+#[derive(Debug, Clone)]
+struct Chunk {
+    kind: u32,
+    /// The first sector of the chunk on the disk.
+    sector: u64,
+    sectors: u64,
+    offset: u64,
+    length: u64,
+}
+
+impl Chunk {
+    fn start(&self) -> u64 {
+        self.sector * SECTOR_SIZE
+    }
+
+    fn end(&self) -> u64 {
+        (self.sector + self.sectors) * SECTOR_SIZE
+    }
+}
+
+/// A partition of the disk image as described by a `blkx` resource.
+#[derive(Debug)]
+struct Partition {
+    name: String,
+    start: u64,
+    chunks: Vec<Chunk>,
+}
+
+/// Parses a `mish` block table into the chunks of a partition.
+fn parse_block_table(data: &[u8], data_fork_offset: u64) -> Result<(u64, Vec<Chunk>), UnboxError> {
+    if data.len() < MISH_HEADER_SIZE || &data[..4] != MISH_MAGIC {
+        bail!("invalid dmg block table");
+    }
+    let first_sector = be_u64(data, 8);
+    let data_offset = be_u64(data, 24);
+    let count = be_u32(data, 200) as usize;
+    let mut chunks = vec![];
+    for idx in 0..count {
+        let offset = MISH_HEADER_SIZE + idx * MISH_CHUNK_SIZE;
+        let chunk = match data.get(offset..offset + MISH_CHUNK_SIZE) {
+            Some(chunk) => chunk,
+            None => bail!("invalid dmg block table"),
+        };
+        let kind = be_u32(chunk, 0);
+        match kind {
+            CHUNK_END => break,
+            CHUNK_COMMENT => continue,
+            CHUNK_ZERO | CHUNK_RAW | CHUNK_IGNORE | CHUNK_ZLIB | CHUNK_BZIP2 => {}
+            CHUNK_ADC => bail!("adc compressed dmg images are not supported"),
+            CHUNK_LZFSE => bail!("lzfse compressed dmg images are not supported"),
+            kind => bail!("unsupported dmg chunk type {:#010x}", kind),
+        }
+        let sectors = be_u64(chunk, 16);
+        if sectors == 0 {
+            continue;
+        }
+        if sectors > MAX_CHUNK_SIZE / SECTOR_SIZE {
+            bail!("invalid dmg block table: chunk too large");
+        }
+        chunks.push(Chunk {
+            kind,
+            sector: first_sector + be_u64(chunk, 8),
+            sectors,
+            offset: data_fork_offset + data_offset + be_u64(chunk, 24),
+            length: be_u64(chunk, 32),
+        });
+    }
+    Ok((first_sector, chunks))
+}
+
+/// Returns the value element following the `<key>` with the given name.
+fn dict_value<'a, 'input>(dict: Node<'a, 'input>, key: &str) -> Option<Node<'a, 'input>> {
+    dict.children()
+        .find(|x| x.has_tag_name("key") && x.text() == Some(key))
+        .and_then(|x| x.next_sibling_element())
+}
+
+/// Reads the partitions from the `blkx` resources of the property list.
+fn read_partitions(plist: &str, data_fork_offset: u64) -> Result<Vec<Partition>, UnboxError> {
+    let options = ParsingOptions {
+        allow_dtd: true,
+        ..ParsingOptions::default()
+    };
+    let doc = match Document::parse_with_options(plist, options) {
+        Ok(doc) => doc,
+        Err(err) => bail!("invalid dmg property list: {}", err),
+    };
+    let blkx = match doc
+        .descendants()
+        .find(|x| x.has_tag_name("key") && x.text() == Some("blkx"))
+        .and_then(|x| x.next_sibling_element())
+    {
+        Some(blkx) => blkx,
+        None => bail!("invalid dmg property list: missing blkx resources"),
+    };
+
+    let mut partitions = vec![];
+    for dict in blkx.children().filter(|x| x.has_tag_name("dict")) {
+        let name = dict_value(dict, "Name")
+            .or_else(|| dict_value(dict, "CFName"))
+            .and_then(|x| x.text())
+            .unwrap_or("")
+            .to_string();
+        let data: String = match dict_value(dict, "Data").and_then(|x| x.text()) {
+            Some(data) => data.chars().filter(|x| !x.is_ascii_whitespace()).collect(),
+            None => bail!("invalid dmg property list: blkx resource without data"),
+        };
+        let data = match BASE64.decode(data) {
+            Ok(data) => data,
+            Err(err) => bail!("invalid dmg property list: {}", err),
+        };
+        let (start, chunks) = parse_block_table(&data, data_fork_offset)?;
+        partitions.push(Partition {
+            name,
+            start: start * SECTOR_SIZE,
+            chunks,
+        });
+    }
+    Ok(partitions)
+}
+
+/// Reads the disk stored in the chunks of a dmg image.
+///
+/// Compressed chunks are decompressed as a whole and the last one is kept
+/// around as reads are usually sequential.
+struct DiskReader {
+    file: File,
+    chunks: Vec<Chunk>,
+    size: u64,
+    pos: u64,
+    cache: Option<(usize, Vec<u8>)>,
+}
+
+impl DiskReader {
+    fn new(file: File, mut chunks: Vec<Chunk>) -> DiskReader {
+        chunks.sort_by_key(|x| x.sector);
+        let size = chunks.iter().map(Chunk::end).max().unwrap_or(0);
+        DiskReader {
+            file,
+            chunks,
+            size,
+            pos: 0,
+            cache: None,
+        }
+    }
+
+    /// Decompresses the chunk with the given index into the cache.
+    fn load_chunk(&mut self, idx: usize) -> io::Result<&[u8]> {
+        if self.cache.as_ref().map(|x| x.0) != Some(idx) {
+            let chunk = &self.chunks[idx];
+            let mut data = Vec::new();
+            self.file.seek(SeekFrom::Start(chunk.offset))?;
+            (&mut self.file).take(chunk.length).read_to_end(&mut data)?;
+            let rdr: Box<dyn Read> = match chunk.kind {
+                CHUNK_ZLIB => Box::new(zlib::Decoder::new(Cursor::new(data))?),
+                CHUNK_BZIP2 => Box::new(BzDecoder::new(Cursor::new(data))),
+                _ => unreachable!(),
+            };
+            let size = chunk.end() - chunk.start();
+            let mut buf = Vec::with_capacity(size as usize);
+            rdr.take(size).read_to_end(&mut buf)?;
+            if buf.len() as u64 != size {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated dmg chunk",
+                ));
+            }
+            self.cache = Some((idx, buf));
+        }
+        Ok(&self.cache.as_ref().unwrap().1)
+    }
+}
+
+impl Read for DiskReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let pos = self.pos;
+        let rv = match self.chunks.binary_search_by(|chunk| {
+            if chunk.end() <= pos {
+                std::cmp::Ordering::Less
+            } else if chunk.start() > pos {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        }) {
+            // sectors not covered by any chunk read as zeroes
+            Err(idx) => {
+                let end = self.chunks.get(idx).map_or(self.size, Chunk::start);
+                let n = buf.len().min((end - pos) as usize);
+                buf[..n].iter_mut().for_each(|x| *x = 0);
+                n
+            }
+            Ok(idx) => {
+                let chunk = &self.chunks[idx];
+                let within = pos - chunk.start();
+                let n = buf.len().min((chunk.end() - pos) as usize);
+                match chunk.kind {
+                    CHUNK_ZERO | CHUNK_IGNORE => {
+                        buf[..n].iter_mut().for_each(|x| *x = 0);
+                        n
+                    }
+                    CHUNK_RAW => {
+                        let offset = chunk.offset + within;
+                        self.file.seek(SeekFrom::Start(offset))?;
+                        match self.file.read(&mut buf[..n])? {
+                            0 => {
+                                return Err(io::Error::new(
+                                    io::ErrorKind::UnexpectedEof,
+                                    "truncated dmg chunk",
+                                ))
+                            }
+                            n => n,
+                        }
+                    }
+                    _ => {
+                        let data = self.load_chunk(idx)?;
+                        let within = within as usize;
+                        buf[..n].copy_from_slice(&data[within..within + n]);
+                        n
+                    }
+                }
+            }
+        };
+        self.pos += rv as u64;
+        Ok(rv)
+    }
+}
+
+impl Seek for DiskReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => self.size.saturating_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.saturating_add_signed(offset),
+        };
+        Ok(self.pos)
+    }
+}
+
+/// The start block and block count of the extents of a fork.
+type Extents = Vec<(u64, u64)>;
+
+/// The data of a file on the hfs+ volume.
+#[derive(Debug, Clone, Default)]
+struct Fork {
+    size: u64,
+    extents: Extents,
+}
+
+impl Fork {
+    fn parse(buf: &[u8]) -> Fork {
+        Fork {
+            size: be_u64(buf, 0),
+            extents: parse_extents(&buf[16..]),
+        }
+    }
+}
+
+/// Parses a record of eight extent descriptors.
+fn parse_extents(buf: &[u8]) -> Extents {
+    (0..8)
+        .map(|idx| {
+            let start = u64::from(be_u32(buf, idx * 8));
+            let count = u64::from(be_u32(buf, 4 + idx * 8));
+            (start, count)
+        })
+        .filter(|&(_, count)| count > 0)
+        .collect()
+}
+
+/// Reads the contents of a fork from the disk.
+struct ForkReader<'a> {
+    disk: &'a mut DiskReader,
+    /// The byte offset and length of the remaining extents.
+    extents: VecDeque<(u64, u64)>,
+    remaining: u64,
+}
+
+impl Read for ForkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.remaining == 0 || buf.is_empty() {
+                return Ok(0);
+            }
+            let (offset, length) = match self.extents.front_mut() {
+                Some(extent) => extent,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "hfs+ file is missing extents",
+                    ))
+                }
+            };
+            if *length == 0 {
+                self.extents.pop_front();
+                continue;
+            }
+            let n = (buf.len() as u64).min(*length).min(self.remaining) as usize;
+            self.disk.seek(SeekFrom::Start(*offset))?;
+            let n = self.disk.read(&mut buf[..n])?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "hfs+ file extends past the end of the disk",
+                ));
+            }
+            *offset += n as u64;
+            *length -= n as u64;
+            self.remaining -= n as u64;
+            return Ok(n);
+        }
+    }
+}
+
+/// An hfs+ volume on the disk.
+struct Volume {
+    offset: u64,
+    block_size: u64,
+    /// Extents of data forks that did not fit into the catalog record.
+    overflow: HashMap<u32, Vec<(u32, Extents)>>,
+}
+
+impl Volume {
+    fn fork_reader<'a>(&self, disk: &'a mut DiskReader, fork: &Fork) -> ForkReader<'a> {
+        ForkReader {
+            disk,
+            extents: fork
+                .extents
+                .iter()
+                .map(|&(start, count)| {
+                    (
+                        self.offset + start * self.block_size,
+                        count * self.block_size,
+                    )
+                })
+                .collect(),
+            remaining: fork.size,
+        }
+    }
+
+    /// Adds the extents from the extents overflow file to a fork.
+    fn complete_fork(&self, id: u32, mut fork: Fork) -> Fork {
+        if let Some(records) = self.overflow.get(&id) {
+            let mut records = records.clone();
+            records.sort_by_key(|x| x.0);
+            for (_, extents) in records {
+                fork.extents.extend(extents);
+            }
+        }
+        fork
+    }
+
+    /// Reads a whole b-tree file into memory.
+    fn read_btree(
+        &self,
+        disk: &mut DiskReader,
+        id: u32,
+        fork: Fork,
+    ) -> Result<Vec<u8>, UnboxError> {
+        if fork.size > MAX_BTREE_SIZE {
+            bail!("invalid hfs+ volume: b-tree too large");
+        }
+        let fork = self.complete_fork(id, fork);
+        // the size is only a claim of the volume, do not trust it blindly
+        let capacity = fork.size.min(disk.file.metadata()?.len());
+        let mut rv = Vec::with_capacity(capacity as usize);
+        self.fork_reader(disk, &fork).read_to_end(&mut rv)?;
+        Ok(rv)
+    }
+}
+
+/// Calls `f` with the key and data of every record in the leaf nodes of a
+/// b-tree.
+fn for_each_leaf_record<F>(tree: &[u8], mut f: F) -> Result<(), UnboxError>
+where
+    F: FnMut(&[u8], &[u8]) -> Result<(), UnboxError>,
+{
+    // an empty extents overflow file has no nodes at all
+    if tree.len() < 34 {
+        return Ok(());
+    }
+    let node_size = usize::from(be_u16(tree, 32));
+    if node_size < 512 {
+        bail!("invalid hfs+ b-tree: bad node size");
+    }
+    let mut node = be_u32(tree, 24);
+    let mut seen = HashSet::new();
+    while node != 0 {
+        if !seen.insert(node) {
+            bail!("invalid hfs+ b-tree: loop in leaf nodes");
+        }
+        let start = node as usize * node_size;
+        let data = match tree.get(start..start + node_size) {
+            Some(data) if data[8] == 0xff => data,
+            _ => bail!("invalid hfs+ b-tree: bad leaf node {}", node),
+        };
+        let count = usize::from(be_u16(data, 10));
+        if 2 * (count + 1) > node_size - 14 {
+            bail!("invalid hfs+ b-tree: bad leaf node {}", node);
+        }
+        for idx in 0..count {
+            let offset = usize::from(be_u16(data, node_size - 2 * (idx + 1)));
+            let end = usize::from(be_u16(data, node_size - 2 * (idx + 2)));
+            let record = match data.get(offset..end) {
+                Some(record) if record.len() >= 2 => record,
+                _ => bail!("invalid hfs+ b-tree: bad record in node {}", node),
+            };
+            let key_end = 2 + usize::from(be_u16(record, 0));
+            // records are aligned to two bytes
+            match (record.get(2..key_end), record.get(key_end + key_end % 2..)) {
+                (Some(key), Some(value)) => f(key, value)?,
+                _ => bail!("invalid hfs+ b-tree: bad record in node {}", node),
+            }
+        }
+        node = be_u32(data, 0);
+    }
+    Ok(())
+}
+
+/// A file record of the catalog.
+#[derive(Debug, Clone)]
+struct CatalogFile {
+    parent: u32,
+    name: String,
+    id: u32,
+    mode: u16,
+    owner_flags: u8,
+    special: u32,
+    mtime: u32,
+    file_type: [u8; 4],
+    creator: [u8; 4],
+    fork: Fork,
+}
+
+#[derive(Debug)]
+enum Kind {
+    Directory,
+    File(Fork),
+    Symlink(Fork),
+    /// A file using hfs+ file compression whose contents cannot be read.
+    Compressed,
+}
+
+#[derive(Debug)]
+struct DmgEntry {
+    path: PathBuf,
+    kind: Kind,
+    mode: Option<u32>,
+    mtime: Option<FileTime>,
+}
+
+fn decode_name(units: &[u8]) -> String {
+    let units: Vec<u16> = units
+        .chunks_exact(2)
+        .map(|x| u16::from_be_bytes([x[0], x[1]]))
+        .collect();
+    // colons are stored as slashes as they are the classic path separator
+    String::from_utf16_lossy(&units).replace('/', ":")
+}
+
+fn hfs_mtime(value: u32) -> Option<FileTime> {
+    if value == 0 {
+        None
+    } else {
+        Some(FileTime::from_unix_time(
+            i64::from(value) - HFS_EPOCH_OFFSET,
+            0,
+        ))
+    }
+}
+
+fn hfs_mode(mode: u16) -> Option<u32> {
+    if mode & S_IFMT == 0 {
+        None
+    } else {
+        Some(u32::from(mode))
+    }
+}
+
+/// Resolves the path of a folder relative to the root folder.
+fn folder_path(folders: &HashMap<u32, (u32, String)>, mut id: u32) -> Option<PathBuf> {
+    let mut names = vec![];
+    while id != ROOT_FOLDER_ID {
+        let (parent, name) = folders.get(&id)?;
+        if names.len() >= MAX_DEPTH {
+            return None;
+        }
+        names.push(name.as_str());
+        id = *parent;
+    }
+    Some(names.iter().rev().collect())
+}
+
+/// Reads the files and folders from the catalog of an hfs+ volume.
+fn read_catalog(catalog: &[u8], volume: &Volume) -> Result<Vec<DmgEntry>, UnboxError> {
+    let mut folders = HashMap::new();
+    let mut folder_records = vec![];
+    let mut files = vec![];
+    for_each_leaf_record(catalog, |key, value| {
+        if key.len() < 6 || value.len() < 2 {
+            bail!("invalid hfs+ catalog record");
+        }
+        let parent = be_u32(key, 0);
+        let name_len = usize::from(be_u16(key, 4));
+        let name = match key.get(6..6 + name_len * 2) {
+            Some(name) => decode_name(name),
+            None => bail!("invalid hfs+ catalog record"),
+        };
+        match be_u16(value, 0) {
+            RECORD_FOLDER if value.len() >= FOLDER_RECORD_SIZE => {
+                let id = be_u32(value, 8);
+                folders.insert(id, (parent, name));
+                folder_records.push((id, be_u16(value, 42), be_u32(value, 16)));
+            }
+            RECORD_FILE if value.len() >= FILE_RECORD_SIZE => {
+                let mut file_type = [0u8; 4];
+                file_type.copy_from_slice(&value[48..52]);
+                let mut creator = [0u8; 4];
+                creator.copy_from_slice(&value[52..56]);
+                files.push(CatalogFile {
+                    parent,
+                    name,
+                    id: be_u32(value, 8),
+                    mode: be_u16(value, 42),
+                    owner_flags: value[41],
+                    special: be_u32(value, 44),
+                    mtime: be_u32(value, 16),
+                    file_type,
+                    creator,
+                    fork: Fork::parse(&value[88..88 + FORK_DATA_SIZE]),
+                });
+            }
+            RECORD_FOLDER | RECORD_FILE => bail!("invalid hfs+ catalog record"),
+            // thread records only repeat what we already know
+            _ => {}
+        }
+        Ok(())
+    })?;
+
+    // the private folders hold the targets of hard links and are not
+    // unpacked themselves.
+    let private: Vec<u32> = folders
+        .iter()
+        .filter(|(_, (parent, name))| {
+            *parent == ROOT_FOLDER_ID && (name == PRIVATE_DATA || name == PRIVATE_DIR_DATA)
+        })
+        .map(|(&id, _)| id)
+        .collect();
+    let inodes: HashMap<&str, &CatalogFile> = files
+        .iter()
+        .filter(|file| private.contains(&file.parent))
+        .map(|file| (file.name.as_str(), file))
+        .collect();
+    for id in &private {
+        folders.remove(id);
+    }
+
+    let mut entries = vec![];
+    for (id, mode, mtime) in folder_records {
+        let path = match folder_path(&folders, id) {
+            Some(path) if !path.as_os_str().is_empty() && !is_unsafe_path(&path) => path,
+            _ => continue,
+        };
+        entries.push(DmgEntry {
+            path,
+            kind: Kind::Directory,
+            mode: hfs_mode(mode),
+            mtime: hfs_mtime(mtime),
+        });
+    }
+
+    for file in &files {
+        if file.parent == ROOT_FOLDER_ID && JOURNAL_FILES.contains(&file.name.as_str()) {
+            continue;
+        }
+        let path = match folder_path(&folders, file.parent) {
+            Some(path) => path.join(&file.name),
+            None => continue,
+        };
+        if is_unsafe_path(&path) {
+            debug!("skipping unsafe path {}", path.display());
+            continue;
+        }
+
+        // hard links point to a file in the private folder
+        let target = match (&file.file_type, &file.creator) {
+            (b"hlnk", b"hfs+") => match inodes.get(format!("iNode{}", file.special).as_str()) {
+                Some(target) => target,
+                None => bail!(
+                    "invalid hfs+ catalog: unknown hard link target of {}",
+                    path.display()
+                ),
+            },
+            (b"fdrp", b"MACS") => {
+                debug!("skipping directory hard link {}", path.display());
+                continue;
+            }
+            _ => file,
+        };
+        let fork = volume.complete_fork(target.id, target.fork.clone());
+        let kind = match target.mode & S_IFMT {
+            0 | S_IFREG if target.owner_flags & UF_COMPRESSED != 0 => Kind::Compressed,
+            0 | S_IFREG => Kind::File(fork),
+            S_IFLNK => Kind::Symlink(fork),
+            _ => {
+                debug!("skipping special file {}", path.display());
+                continue;
+            }
+        };
+        entries.push(DmgEntry {
+            path,
+            kind,
+            mode: hfs_mode(target.mode),
+            mtime: hfs_mtime(target.mtime),
+        });
+    }
+
+    // sorting puts folders before their contents
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+pub struct DmgArchive {
+    path: PathBuf,
+    disk: DiskReader,
+    volume: Volume,
+    entries: Vec<DmgEntry>,
+}
+
+impl std::fmt::Debug for DmgArchive {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("DmgArchive")
+            .field("path", &self.path)
+            .field("entries", &self.entries)
+            .finish()
+    }
+}
+
+impl DmgArchive {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, UnboxError> {
+        let path = path.as_ref().canonicalize()?;
+        let mut file = File::open(&path)?;
+        let mut magic = [0u8; 8];
+        if file.read_exact(&mut magic).is_ok() && (&magic == b"encrcdsa" || &magic == b"cdsaencr") {
+            bail!("encrypted dmg images are not supported");
+        }
+        if file.metadata()?.len() < KOLY_SIZE {
+            bail!("not a dmg image");
+        }
+        let mut trailer = [0u8; KOLY_SIZE as usize];
+        file.seek(SeekFrom::End(-(KOLY_SIZE as i64)))?;
+        file.read_exact(&mut trailer)?;
+        if &trailer[..4] != KOLY_MAGIC {
+            bail!("not a dmg image");
+        }
+        let data_fork_offset = be_u64(&trailer, 24);
+        let plist_offset = be_u64(&trailer, 216);
+        let plist_length = be_u64(&trailer, 224);
+        if plist_length == 0 {
+            bail!("dmg images without a property list are not supported");
+        } else if plist_length > MAX_PLIST_SIZE {
+            bail!("invalid dmg image: property list too large");
+        }
+
+        let mut plist = String::new();
+        file.seek(SeekFrom::Start(plist_offset))?;
+        (&mut file).take(plist_length).read_to_string(&mut plist)?;
+        let partitions = read_partitions(&plist, data_fork_offset)?;
+        let starts: Vec<(String, u64)> = partitions
+            .iter()
+            .map(|x| (x.name.clone(), x.start))
+            .collect();
+        let mut disk = DiskReader::new(
+            file,
+            partitions.into_iter().flat_map(|x| x.chunks).collect(),
+        );
+
+        // look for the first partition holding an hfs+ volume
+        let mut header = vec![0u8; VOLUME_HEADER_SIZE];
+        let mut found = None;
+        let mut apfs = false;
+        for (name, start) in starts {
+            disk.seek(SeekFrom::Start(start))?;
+            if disk.read_exact(&mut header[..36]).is_ok() && &header[32..36] == APFS_MAGIC {
+                apfs = true;
+            }
+            disk.seek(SeekFrom::Start(start + VOLUME_HEADER_OFFSET))?;
+            if disk.read_exact(&mut header).is_ok()
+                && (&header[..2] == HFS_PLUS_MAGIC || &header[..2] == HFSX_MAGIC)
+            {
+                debug!("found hfs+ volume in partition '{}'", name);
+                found = Some(start);
+                break;
+            }
+        }
+        let offset = match found {
+            Some(offset) => offset,
+            None if apfs => bail!("apfs dmg images are not supported"),
+            None => bail!("dmg image does not contain an hfs+ volume"),
+        };
+
+        let block_size = u64::from(be_u32(&header, 40));
+        if block_size < 512 || !block_size.is_power_of_two() {
+            bail!("invalid hfs+ volume: bad block size");
+        }
+        let mut volume = Volume {
+            offset,
+            block_size,
+            overflow: HashMap::new(),
+        };
+        let extents_fork = Fork::parse(&header[192..192 + FORK_DATA_SIZE]);
+        let catalog_fork = Fork::parse(&header[272..272 + FORK_DATA_SIZE]);
+
+        let extents = volume.read_btree(&mut disk, EXTENTS_FILE_ID, extents_fork)?;
+        let mut overflow = HashMap::new();
+        for_each_leaf_record(&extents, |key, value| {
+            // only data forks are unpacked, the resource forks are skipped
+            if key.len() >= 10 && key[0] == 0 && value.len() >= 64 {
+                let id = be_u32(key, 2);
+                let start = be_u32(key, 6);
+                let extents = parse_extents(value);
+                overflow
+                    .entry(id)
+                    .or_insert_with(Vec::new)
+                    .push((start, extents));
+            }
+            Ok(())
+        })?;
+        volume.overflow = overflow;
+
+        let catalog = volume.read_btree(&mut disk, CATALOG_FILE_ID, catalog_fork)?;
+        let entries = read_catalog(&catalog, &volume)?;
+
+        Ok(DmgArchive {
+            path,
+            disk,
+            volume,
+            entries,
+        })
+    }
+}
+
+impl Archive for DmgArchive {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn total_size(&self) -> Option<u64> {
+        Some(
+            self.entries
+                .iter()
+                .map(|entry| match entry.kind {
+                    Kind::File(ref fork) => fork.size,
+                    _ => 0,
+                })
+                .sum(),
+        )
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        Some(self.entries.len() as u64)
+    }
+
+    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), UnboxError> {
+        for entry in &self.entries {
+            if helper.skip_entry() {
+                continue;
+            }
+            let metadata = EntryMetadata {
+                mode: entry.mode,
+                owner: None,
+                mtime: entry.mtime,
+            };
+            match entry.kind {
+                Kind::Directory => helper.create_dir_with_metadata(&entry.path, metadata)?,
+                Kind::File(ref fork) => {
+                    let rdr = self.volume.fork_reader(&mut self.disk, fork);
+                    helper
+                        .write_file_with_metadata(&entry.path, rdr, metadata)
+                        .map_err(|err| helper.entry_error(&entry.path, err))?;
+                }
+                Kind::Symlink(ref fork) => {
+                    let mut target = String::new();
+                    self.volume
+                        .fork_reader(&mut self.disk, fork)
+                        .take(4096)
+                        .read_to_string(&mut target)
+                        .map_err(|err| helper.entry_error(&entry.path, err.into()))?;
+                    helper.create_symlink(&entry.path, &target)?;
+                }
+                Kind::Compressed => helper.warn(&format!(
+                    "skipping {} which uses hfs+ file compression",
+                    entry.path.display()
+                )),
+            }
+        }
+        Ok(())
+    }
+
+    fn entries(&mut self) -> Result<Vec<ArchiveEntry>, UnboxError> {
+        Ok(self
+            .entries
+            .iter()
+            .map(|entry| {
+                let name = entry.path.to_string_lossy();
+                match entry.kind {
+                    Kind::Directory => ArchiveEntry::new(name, 0, EntryKind::Directory),
+                    Kind::File(ref fork) => ArchiveEntry::new(name, fork.size, EntryKind::File),
+                    Kind::Symlink(..) | Kind::Compressed => {
+                        ArchiveEntry::new(name, 0, EntryKind::Other)
+                    }
+                }
+            })
+            .collect())
+    }
+}
+
+#[test]
+fn test_dmg() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    // a zlib compressed image of an hfsx volume.  Next to the regular
+    // files it holds a journal, a file named `..`, a name with a slash and
+    // packed.txt which uses hfs+ file compression.
+    let dir = make_test_dir();
+    let archive_path = dir.join("Test.dmg");
+    fs::write(
+        &archive_path,
+        &include_bytes!("../../tests/fixtures/test.dmg")[..],
+    )
+    .unwrap();
+    assert_eq!(ArchiveType::for_path(&archive_path), Some(ArchiveType::Dmg));
+
+    let contents = b"hello dmg ".repeat(2000);
+    let mut archive = DmgArchive::open(&archive_path).unwrap();
+    let names: Vec<_> = archive
+        .entries()
+        .unwrap()
+        .into_iter()
+        .map(|x| x.name)
+        .collect();
+    assert_eq!(
+        names,
+        ["a:b.txt", "app", "app/big.txt", "app/link", "packed.txt"]
+    );
+    assert_eq!(archive.total_size(), Some(contents.len() as u64 + 5));
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "Test");
+    assert_eq!(fs::read(rv.join("app/big.txt")).unwrap(), contents);
+    assert_eq!(fs::read(rv.join("a:b.txt")).unwrap(), b"colon");
+    assert!(!rv.join("packed.txt").exists());
+    let mtime =
+        FileTime::from_last_modification_time(&fs::metadata(rv.join("app/big.txt")).unwrap());
+    assert_eq!(mtime.unix_seconds(), 3_600_000_000 - HFS_EPOCH_OFFSET);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(
+            fs::read_link(rv.join("app/link")).unwrap(),
+            Path::new("big.txt")
+        );
+        let mode = fs::metadata(rv.join("a:b.txt"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_unsupported_chunks() {
+    // a block table with a single chunk of the given kind
+    let block_table = |kind: u32| {
+        let mut rv = MISH_MAGIC.to_vec();
+        rv.resize(200, 0);
+        rv.extend(&1u32.to_be_bytes());
+        rv.extend(&kind.to_be_bytes());
+        rv.resize(MISH_HEADER_SIZE + MISH_CHUNK_SIZE, 0);
+        rv[MISH_HEADER_SIZE + 16..MISH_HEADER_SIZE + 24].copy_from_slice(&8u64.to_be_bytes());
+        rv
+    };
+    assert_eq!(
+        parse_block_table(&block_table(CHUNK_ZLIB), 0)
+            .unwrap()
+            .1
+            .len(),
+        1
+    );
+    assert_eq!(
+        parse_block_table(&block_table(CHUNK_LZFSE), 0)
+            .unwrap_err()
+            .to_string(),
+        "lzfse compressed dmg images are not supported"
+    );
+    assert_eq!(
+        parse_block_table(&block_table(CHUNK_ADC), 0)
+            .unwrap_err()
+            .to_string(),
+        "adc compressed dmg images are not supported"
+    );
+}
@@ -0,0 +1,505 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::archive::{Archive, ArchiveEntry, EntryKind, UnpackHelper};
+use crate::error::{bail, UnboxError};
+use crate::utils::is_unsafe_path;
+
+const SECTOR_SIZE: u64 = 2048;
+
+/// The folder boot images are placed in when requested.
+const BOOT_FOLDER: &str = "[BOOT]";
+
+#[derive(Debug)]
+struct IsoEntry {
+    path: PathBuf,
+    /// The sector and size of each extent of the file.
+    extents: Vec<(u32, u32)>,
+    size: u64,
+    is_dir: bool,
+}
+
+/// Reads the extents of a file one after another.
+struct ExtentReader<'a> {
+    f: &'a mut File,
+    extents: std::slice::Iter<'a, (u32, u32)>,
+    remaining: u64,
+}
+
+impl<'a> ExtentReader<'a> {
+    fn new(f: &'a mut File, extents: &'a [(u32, u32)]) -> ExtentReader<'a> {
+        ExtentReader {
+            f,
+            extents: extents.iter(),
+            remaining: 0,
+        }
+    }
+}
+
+impl Read for ExtentReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.remaining == 0 {
+            match self.extents.next() {
+                Some(&(sector, size)) => {
+                    self.f
+                        .seek(SeekFrom::Start(u64::from(sector) * SECTOR_SIZE))?;
+                    self.remaining = u64::from(size);
+                }
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.remaining as usize);
+        let read = self.f.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// The El Torito boot information of a bootable image.
+#[derive(Debug)]
+struct BootInfo {
+    catalog_sector: u32,
+    image_sector: u32,
+    image_size: u64,
+}
+
+#[derive(Debug)]
+pub struct IsoArchive {
+    path: PathBuf,
+    total_size: u64,
+    entries: Vec<IsoEntry>,
+    /// The sector of the el torito boot catalog.
+    boot_catalog: Option<u32>,
+}
+
+fn read_sector<R: Read + Seek>(rdr: &mut R, sector: u32) -> Result<[u8; 2048], UnboxError> {
+    let mut buf = [0u8; 2048];
+    rdr.seek(SeekFrom::Start(u64::from(sector) * SECTOR_SIZE))?;
+    rdr.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn u16_le(buf: &[u8], offset: usize) -> u16 {
+    u16::from(buf[offset]) | (u16::from(buf[offset + 1]) << 8)
+}
+
+fn u32_le(buf: &[u8], offset: usize) -> u32 {
+    u32::from(u16_le(buf, offset)) | (u32::from(u16_le(buf, offset + 2)) << 16)
+}
+
+/// Decodes a file identifier stripping the version suffix.
+fn decode_name(raw: &[u8], joliet: bool) -> String {
+    let name = if joliet {
+        let units: Vec<u16> = raw
+            .chunks(2)
+            .filter(|x| x.len() == 2)
+            .map(|x| (u16::from(x[0]) << 8) | u16::from(x[1]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    } else {
+        String::from_utf8_lossy(raw).to_string()
+    };
+    let name = match name.rfind(';') {
+        Some(idx) => &name[..idx],
+        None => &name[..],
+    };
+    name.trim_end_matches('.').to_string()
+}
+
+/// Parses the boot catalog to find the initial boot image.
+fn read_boot_info<R: Read + Seek>(
+    rdr: &mut R,
+    catalog_sector: u32,
+) -> Result<BootInfo, UnboxError> {
+    let catalog = read_sector(rdr, catalog_sector)?;
+    if catalog[0] != 1 || catalog[30] != 0x55 || catalog[31] != 0xaa {
+        bail!("invalid el torito boot catalog");
+    }
+    let entry = &catalog[32..64];
+    let image_size = match entry[1] {
+        1 => 1_228_800,
+        2 => 1_474_560,
+        3 => 2_949_120,
+        _ => u64::from(u16_le(entry, 6)) * 512,
+    };
+    Ok(BootInfo {
+        catalog_sector,
+        image_sector: u32_le(entry, 8),
+        image_size,
+    })
+}
+
+/// Recursively reads a directory and all its children.
+fn read_directory<R: Read + Seek>(
+    rdr: &mut R,
+    sector: u32,
+    size: u32,
+    prefix: &Path,
+    joliet: bool,
+    seen: &mut HashSet<u32>,
+    entries: &mut Vec<IsoEntry>,
+) -> Result<(), UnboxError> {
+    // guard against directories referencing themselves
+    if !seen.insert(sector) {
+        return Ok(());
+    }
+
+    // files larger than 4GB are split into multiple extents with all but
+    // the last record flagged
+    let mut pending: Option<IsoEntry> = None;
+    let sectors = u64::from(size).div_ceil(SECTOR_SIZE);
+    for idx in 0..sectors as u32 {
+        let buf = match sector.checked_add(idx) {
+            Some(sector) => read_sector(rdr, sector)?,
+            None => bail!("invalid directory extent"),
+        };
+        let mut offset = 0;
+        while offset < buf.len() {
+            let len = buf[offset] as usize;
+            // records never span sectors, a zero length pads to the next one
+            if len == 0 {
+                break;
+            }
+            if len < 34 || offset + len > buf.len() {
+                bail!("invalid directory record");
+            }
+            let record = &buf[offset..offset + len];
+            offset += len;
+
+            let name_len = record[32] as usize;
+            if 33 + name_len > record.len() {
+                bail!("invalid directory record");
+            }
+            let raw_name = &record[33..33 + name_len];
+            // skip the entries for the directory itself and its parent
+            if raw_name == [0] || raw_name == [1] {
+                continue;
+            }
+
+            let path = prefix.join(decode_name(raw_name, joliet));
+            if is_unsafe_path(&path) {
+                continue;
+            }
+            let flags = record[25];
+            let (sector, size) = (u32_le(record, 2), u32_le(record, 10));
+            let mut entry = match pending.take() {
+                Some(mut entry) if entry.path == path => {
+                    entry.extents.push((sector, size));
+                    entry.size += u64::from(size);
+                    entry
+                }
+                Some(_) => bail!("incomplete multi-extent file"),
+                None => IsoEntry {
+                    path,
+                    extents: vec![(sector, size)],
+                    size: u64::from(size),
+                    is_dir: flags & 2 != 0,
+                },
+            };
+            if flags & 0x80 != 0 {
+                pending = Some(entry);
+                continue;
+            }
+            if entry.is_dir {
+                read_directory(rdr, sector, size, &entry.path, joliet, seen, entries)?;
+                entry.size = 0;
+            }
+            entries.push(entry);
+        }
+    }
+    if pending.is_some() {
+        bail!("incomplete multi-extent file");
+    }
+    Ok(())
+}
+
+impl IsoArchive {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, UnboxError> {
+        let path = path.as_ref().canonicalize()?;
+        let mut rdr = BufReader::new(File::open(&path)?);
+
+        let mut root = None;
+        let mut joliet_root = None;
+        let mut boot_catalog = None;
+        for sector in 16.. {
+            let buf = read_sector(&mut rdr, sector)?;
+            if &buf[1..6] != b"CD001" {
+                bail!("invalid volume descriptor");
+            }
+            match buf[0] {
+                0 if buf[7..30] == b"EL TORITO SPECIFICATION"[..] => {
+                    boot_catalog = Some(u32_le(&buf, 0x47));
+                }
+                1 => root = Some((u32_le(&buf, 158), u32_le(&buf, 166))),
+                2 if buf[88..90] == b"%/"[..] && b"@CE".contains(&buf[90]) => {
+                    joliet_root = Some((u32_le(&buf, 158), u32_le(&buf, 166)));
+                }
+                255 => break,
+                _ => {}
+            }
+        }
+
+        // joliet names are preferred as they are not limited to 8.3
+        let joliet = joliet_root.is_some();
+        let (root_sector, root_size) = match joliet_root.or(root) {
+            Some(root) => root,
+            None => bail!("no primary volume descriptor"),
+        };
+        let mut entries = vec![];
+        read_directory(
+            &mut rdr,
+            root_sector,
+            root_size,
+            Path::new(""),
+            joliet,
+            &mut HashSet::new(),
+            &mut entries,
+        )?;
+        entries.reverse();
+
+        let total_size = entries.iter().map(|x| x.size).sum();
+        Ok(IsoArchive {
+            path,
+            total_size,
+            entries,
+            boot_catalog,
+        })
+    }
+}
+
+impl Archive for IsoArchive {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn total_size(&self) -> Option<u64> {
+        Some(self.total_size)
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        Some(self.entries.len() as u64)
+    }
+
+    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), UnboxError> {
+        let mut f = File::open(&self.path)?;
+        for entry in &self.entries {
+            if helper.skip_entry() {
+                continue;
+            }
+            if entry.is_dir {
+                helper.create_dir(&entry.path)?;
+            } else {
+                let rdr = ExtentReader::new(&mut f, &entry.extents);
+                helper
+                    .write_file_with_progress(&entry.path, rdr)
+                    .map_err(|err| helper.entry_error(&entry.path, err))?;
+            }
+        }
+
+        // boot images live outside of the file system.  The catalog is only
+        // parsed when requested so a broken one does not fail extraction.
+        if let (true, Some(catalog_sector)) = (helper.extract_boot(), self.boot_catalog) {
+            let boot = read_boot_info(&mut f, catalog_sector)?;
+            let boot_folder = Path::new(BOOT_FOLDER);
+            f.seek(SeekFrom::Start(
+                u64::from(boot.catalog_sector) * SECTOR_SIZE,
+            ))?;
+            helper.write_file_from(boot_folder.join("boot.catalog"), (&mut f).take(SECTOR_SIZE))?;
+            f.seek(SeekFrom::Start(u64::from(boot.image_sector) * SECTOR_SIZE))?;
+            helper.write_file_from(boot_folder.join("boot.img"), (&mut f).take(boot.image_size))?;
+        }
+        Ok(())
+    }
+
+    fn entries(&mut self) -> Result<Vec<ArchiveEntry>, UnboxError> {
+        Ok(self
+            .entries
+            .iter()
+            .map(|entry| {
+                if entry.is_dir {
+                    ArchiveEntry::new(entry.path.to_string_lossy(), 0, EntryKind::Directory)
+                } else {
+                    ArchiveEntry::new(entry.path.to_string_lossy(), entry.size, EntryKind::File)
+                }
+            })
+            .collect())
+    }
+}
+
+/// Builds a small bootable iso image.
+#[cfg(test)]
+fn build_bootable_iso() -> Vec<u8> {
+    fn record(name: &[u8], sector: u32, size: u32, flags: u8) -> Vec<u8> {
+        let mut rv = vec![0u8; 33];
+        rv[2..6].copy_from_slice(&sector.to_le_bytes());
+        rv[6..10].copy_from_slice(&sector.to_be_bytes());
+        rv[10..14].copy_from_slice(&size.to_le_bytes());
+        rv[14..18].copy_from_slice(&size.to_be_bytes());
+        rv[25] = flags;
+        rv[32] = name.len() as u8;
+        rv.extend(name);
+        if rv.len() % 2 == 1 {
+            rv.push(0);
+        }
+        rv[0] = rv.len() as u8;
+        rv
+    }
+
+    let mut iso = vec![0u8; 28 * SECTOR_SIZE as usize];
+    let mut put = |sector: usize, offset: usize, data: &[u8]| {
+        let start = sector * SECTOR_SIZE as usize + offset;
+        iso[start..start + data.len()].copy_from_slice(data);
+    };
+
+    // primary volume descriptor, boot record and terminator
+    put(16, 0, b"\x01CD001\x01");
+    put(16, 156, &record(&[0], 20, 2048, 2));
+    put(17, 0, b"\x00CD001\x01EL TORITO SPECIFICATION");
+    put(17, 0x47, &22u32.to_le_bytes());
+    put(18, 0, b"\xffCD001\x01");
+
+    // root directory and file
+    let mut root = record(&[0], 20, 2048, 2);
+    root.extend(record(&[1], 20, 2048, 2));
+    root.extend(record(b"HELLO.TXT;1", 21, 5, 0));
+    root.extend(record(b"SUB", 23, 2048, 2));
+    root.extend(record(b"SPLIT.BIN;1", 26, 2048, 0x80));
+    root.extend(record(b"SPLIT.BIN;1", 27, 3, 0));
+    put(20, 0, &root);
+    put(21, 0, b"hello");
+
+    // boot catalog with a no emulation boot image of four virtual sectors
+    let mut catalog = [0u8; 64];
+    catalog[0] = 1;
+    catalog[30] = 0x55;
+    catalog[31] = 0xaa;
+    catalog[32] = 0x88;
+    catalog[38..40].copy_from_slice(&4u16.to_le_bytes());
+    catalog[40..44].copy_from_slice(&24u32.to_le_bytes());
+    put(22, 0, &catalog);
+
+    // sub directory, boot image and nested file
+    let mut sub = record(&[0], 23, 2048, 2);
+    sub.extend(record(&[1], 20, 2048, 2));
+    sub.extend(record(b"INNER.TXT;1", 25, 5, 0));
+    put(23, 0, &sub);
+    put(24, 0, &[0xab; 2048]);
+    put(25, 0, b"inner");
+
+    // the extents of the split file
+    put(26, 0, &[0xcd; 2048]);
+    put(27, 0, b"end");
+    iso
+}
+
+#[test]
+fn test_extract_boot() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("bootable.iso");
+    fs::write(&archive_path, build_bootable_iso()).unwrap();
+    assert_eq!(ArchiveType::for_path(&archive_path), Some(ArchiveType::Iso));
+
+    let unpack = |extract_boot| {
+        let mut archive = IsoArchive::open(&archive_path).unwrap();
+        let options = UnpackOptions {
+            extract_boot,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        helper.commit().unwrap()
+    };
+
+    let rv = unpack(false);
+    assert_eq!(fs::read(rv.join("HELLO.TXT")).unwrap(), b"hello");
+    assert_eq!(fs::read(rv.join("SUB/INNER.TXT")).unwrap(), b"inner");
+    assert!(!rv.join(BOOT_FOLDER).exists());
+
+    let rv = unpack(true);
+    assert_eq!(fs::read(rv.join("HELLO.TXT")).unwrap(), b"hello");
+    assert_eq!(
+        fs::read(rv.join(BOOT_FOLDER).join("boot.img")).unwrap(),
+        vec![0xab; 2048]
+    );
+    assert_eq!(
+        fs::metadata(rv.join(BOOT_FOLDER).join("boot.catalog"))
+            .unwrap()
+            .len(),
+        SECTOR_SIZE
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_multi_extent() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("split.iso");
+    fs::write(&archive_path, build_bootable_iso()).unwrap();
+
+    let mut archive = IsoArchive::open(&archive_path).unwrap();
+    assert_eq!(archive.total_size(), Some(5 + 5 + 2048 + 3));
+    let entry = archive
+        .entries()
+        .unwrap()
+        .into_iter()
+        .find(|x| x.name == "SPLIT.BIN")
+        .unwrap();
+    assert_eq!(entry.size, 2048 + 3);
+
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    let mut expected = vec![0xcd; 2048];
+    expected.extend(b"end");
+    assert_eq!(fs::read(rv.join("SPLIT.BIN")).unwrap(), expected);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_broken_boot_catalog() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("broken.iso");
+    let mut iso = build_bootable_iso();
+    iso[22 * SECTOR_SIZE as usize] = 0;
+    fs::write(&archive_path, iso).unwrap();
+
+    let unpack = |extract_boot| {
+        let mut archive = IsoArchive::open(&archive_path).unwrap();
+        let options = UnpackOptions {
+            extract_boot,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper)?;
+        helper.commit()
+    };
+
+    // the catalog only matters when boot images are extracted
+    let rv = unpack(false).unwrap();
+    assert_eq!(fs::read(rv.join("HELLO.TXT")).unwrap(), b"hello");
+    fs::remove_dir_all(&rv).unwrap();
+    let err = unpack(true).unwrap_err();
+    assert_eq!(err.to_string(), "invalid el torito boot catalog");
+    fs::remove_dir_all(&dir).unwrap();
+}
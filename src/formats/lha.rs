@@ -0,0 +1,754 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str;
+
+use chardetng::EncodingDetector;
+use encoding_rs::Encoding;
+use filetime::FileTime;
+use log::debug;
+
+use crate::archive::{Archive, ArchiveEntry, EntryKind, EntryMetadata, UnpackHelper};
+use crate::error::{bail, UnboxError};
+use crate::utils::{days_from_civil, is_unsafe_path};
+
+/// LHA has no magic number, the method id at offset 2 starts with this.
+pub const METHOD_PREFIX: &[u8] = b"-lh";
+
+/// The number of literal and match length codes.
+const NC: usize = 510;
+/// The number of codes of the table that encodes the code lengths.
+const NT: usize = 19;
+const TBIT: u32 = 5;
+const CBIT: u32 = 9;
+const THRESHOLD: usize = 3;
+
+const EXT_FILENAME: u8 = 0x01;
+const EXT_DIRNAME: u8 = 0x02;
+const EXT_UNIX_MODE: u8 = 0x50;
+const EXT_UNIX_MTIME: u8 = 0x54;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Method {
+    Directory,
+    Stored,
+    /// One of the `-lh4-` to `-lh7-` methods with the dictionary size in
+    /// bits.
+    Lh(u32),
+    Unsupported(String),
+}
+
+impl Method {
+    fn parse(id: &[u8]) -> Method {
+        match id {
+            b"-lhd-" => Method::Directory,
+            b"-lh0-" | b"-lz4-" => Method::Stored,
+            b"-lh4-" => Method::Lh(12),
+            b"-lh5-" => Method::Lh(13),
+            b"-lh6-" => Method::Lh(15),
+            b"-lh7-" => Method::Lh(16),
+            _ => Method::Unsupported(String::from_utf8_lossy(id).to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LhaEntry {
+    /// The raw name with `/` as separator between directory and file name.
+    name: Vec<u8>,
+    method: Method,
+    offset: u64,
+    compressed_size: u64,
+    size: u64,
+    crc: u16,
+    mode: Option<u32>,
+    mtime: Option<FileTime>,
+}
+
+#[derive(Debug)]
+pub struct LhaArchive {
+    path: PathBuf,
+    entries: Vec<LhaEntry>,
+}
+
+fn word(buf: &[u8], idx: usize) -> u16 {
+    u16::from_le_bytes([buf[idx], buf[idx + 1]])
+}
+
+fn long(buf: &[u8], idx: usize) -> u32 {
+    u32::from_le_bytes([buf[idx], buf[idx + 1], buf[idx + 2], buf[idx + 3]])
+}
+
+/// Converts an MS-DOS timestamp.  It has no time zone and is interpreted
+/// as UTC.
+fn dos_mtime(value: u32) -> Option<FileTime> {
+    let (date, time) = (i64::from(value >> 16), i64::from(value & 0xffff));
+    let (month, day) = ((date >> 5) & 0xf, date & 0x1f);
+    if month == 0 || day == 0 {
+        return None;
+    }
+    let days = days_from_civil((date >> 9) + 1980, month, day);
+    let seconds = (time >> 11) * 3600 + ((time >> 5) & 0x3f) * 60 + (time & 0x1f) * 2;
+    Some(FileTime::from_unix_time(days * 86400 + seconds, 0))
+}
+
+/// Reads the chain of extended headers of level 1 and 2 headers.
+///
+/// Returns the number of bytes read.
+fn read_extended<R: Read>(
+    rdr: &mut R,
+    mut next_size: usize,
+    entry: &mut LhaEntry,
+) -> Result<u64, UnboxError> {
+    let mut filename = None;
+    let mut dirname = None;
+    let mut total = 0;
+    while next_size > 0 {
+        if next_size < 3 {
+            bail!("invalid lha extended header");
+        }
+        let mut buf = vec![0u8; next_size];
+        rdr.read_exact(&mut buf)?;
+        total += next_size as u64;
+        let data = &buf[1..next_size - 2];
+        match buf[0] {
+            EXT_FILENAME => filename = Some(data.to_vec()),
+            EXT_DIRNAME => dirname = Some(data.to_vec()),
+            EXT_UNIX_MODE if data.len() >= 2 => entry.mode = Some(u32::from(word(data, 0))),
+            EXT_UNIX_MTIME if data.len() >= 4 => {
+                entry.mtime = Some(FileTime::from_unix_time(i64::from(long(data, 0)), 0))
+            }
+            _ => {}
+        }
+        next_size = usize::from(word(&buf, next_size - 2));
+    }
+
+    if let Some(filename) = filename {
+        entry.name = filename;
+    }
+    if let Some(mut dirname) = dirname {
+        // directory names use 0xff as separator
+        for byte in dirname.iter_mut() {
+            if *byte == 0xff {
+                *byte = b'/';
+            }
+        }
+        if !dirname.is_empty() && !dirname.ends_with(b"/") {
+            dirname.push(b'/');
+        }
+        dirname.extend(&entry.name);
+        entry.name = dirname;
+    }
+    Ok(total)
+}
+
+/// Reads the header of the entry at the given offset.
+///
+/// Returns `None` at the end of the archive.
+fn read_header<R: Read + Seek>(
+    rdr: &mut R,
+    offset: u64,
+) -> Result<Option<(LhaEntry, u64)>, UnboxError> {
+    rdr.seek(SeekFrom::Start(offset))?;
+    let mut base = [0u8; 22];
+    if rdr.read(&mut base[..1])? == 0 || base[0] == 0 {
+        return Ok(None);
+    }
+    rdr.read_exact(&mut base[1..])?;
+
+    let mut entry = LhaEntry {
+        name: vec![],
+        method: Method::parse(&base[2..7]),
+        offset: 0,
+        compressed_size: u64::from(long(&base, 7)),
+        size: u64::from(long(&base, 11)),
+        crc: 0,
+        mode: None,
+        mtime: None,
+    };
+    let level = base[20];
+    let header_size = match level {
+        0 | 1 => {
+            let header_size = usize::from(base[0]) + 2;
+            let name_len = usize::from(base[21]);
+            if header_size < 24 + name_len + if level == 1 { 3 } else { 0 } {
+                bail!("invalid lha header");
+            }
+            let mut header = base.to_vec();
+            header.resize(header_size, 0);
+            rdr.read_exact(&mut header[22..])?;
+            entry.name = header[22..22 + name_len].to_vec();
+            entry.crc = word(&header, 22 + name_len);
+            entry.mtime = dos_mtime(long(&header, 15));
+            let mut header_size = header_size as u64;
+            if level == 0 {
+                // unix lha stores the mode and a unix timestamp here
+                let ext = &header[24 + name_len..];
+                if ext.len() >= 12 && ext[0] == b'U' {
+                    entry.mtime = Some(FileTime::from_unix_time(i64::from(long(ext, 2)), 0));
+                    entry.mode = Some(u32::from(word(ext, 6)));
+                }
+            } else {
+                // the size of the extended headers is part of the skip size
+                let next_size = usize::from(word(&header, header_size as usize - 2));
+                let ext_size = read_extended(rdr, next_size, &mut entry)?;
+                entry.compressed_size = match entry.compressed_size.checked_sub(ext_size) {
+                    Some(size) => size,
+                    None => bail!("invalid lha header"),
+                };
+                header_size += ext_size;
+            }
+            header_size
+        }
+        2 => {
+            let header_size = usize::from(word(&base, 0));
+            if header_size < 26 {
+                bail!("invalid lha header");
+            }
+            let mut header = base.to_vec();
+            header.resize(header_size, 0);
+            rdr.read_exact(&mut header[22..])?;
+            entry.crc = word(&header, 21);
+            entry.mtime = Some(FileTime::from_unix_time(i64::from(long(&header, 15)), 0));
+            let next_size = usize::from(word(&header, 24));
+            read_extended(&mut &header[26..], next_size, &mut entry)?;
+            header_size as u64
+        }
+        level => bail!("unsupported lha header level {}", level),
+    };
+    entry.offset = offset + header_size;
+    let next = entry.offset + entry.compressed_size;
+    Ok(Some((entry, next)))
+}
+
+/// Picks the encoding for entry names that are not valid UTF-8.
+///
+/// LHA archives usually come from MS-DOS or Japanese systems so their names
+/// are guessed unless an encoding label is given.
+fn name_encoding(
+    entries: &[LhaEntry],
+    label: Option<&str>,
+) -> Result<Option<&'static Encoding>, UnboxError> {
+    if let Some(label) = label {
+        match Encoding::for_label(label.as_bytes()) {
+            Some(encoding) => return Ok(Some(encoding)),
+            None => bail!("unknown encoding '{}'", label),
+        }
+    }
+
+    let mut detector = EncodingDetector::new();
+    let mut found = false;
+    for entry in entries {
+        if str::from_utf8(&entry.name).is_err() {
+            detector.feed(&entry.name, false);
+            found = true;
+        }
+    }
+    if !found {
+        return Ok(None);
+    }
+    detector.feed(b"", true);
+    Ok(Some(detector.guess(None, true)))
+}
+
+/// Decodes the name of an entry into a path.
+///
+/// This has to happen before the MS-DOS separators are replaced as the
+/// second byte of a Shift JIS character can be a backslash.
+fn decode_name(name: &[u8], encoding: Option<&'static Encoding>) -> String {
+    let name = match (str::from_utf8(name), encoding) {
+        (Err(_), Some(encoding)) => encoding.decode_without_bom_handling(name).0,
+        _ => String::from_utf8_lossy(name),
+    };
+    name.replace('\\', "/").trim_matches('/').to_string()
+}
+
+/// Updates a CRC-16 (as used by LHA) with the given bytes.
+fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= u16::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// A reader that fails at the end if the CRC does not match.
+struct CrcRead<R> {
+    rdr: R,
+    crc: u16,
+    expected: u16,
+}
+
+impl<R: Read> Read for CrcRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.rdr.read(buf)?;
+        self.crc = crc16(self.crc, &buf[..len]);
+        if len == 0 && !buf.is_empty() && self.crc != self.expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "lha crc mismatch",
+            ));
+        }
+        Ok(len)
+    }
+}
+
+/// Reads bits starting with the most significant one.
+///
+/// Reading past the end yields zeroes like the reference implementation.
+struct BitReader<R> {
+    rdr: R,
+    bits: u64,
+    count: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    fn peek(&mut self, n: u32) -> io::Result<u32> {
+        while self.count < n {
+            let mut byte = [0u8; 1];
+            if self.rdr.read(&mut byte)? == 0 {
+                byte[0] = 0;
+            }
+            self.bits = (self.bits << 8) | u64::from(byte[0]);
+            self.count += 8;
+        }
+        Ok(((self.bits >> (self.count - n)) & ((1 << n) - 1)) as u32)
+    }
+
+    fn skip(&mut self, n: u32) {
+        self.count -= n;
+    }
+
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        let value = self.peek(n)?;
+        self.skip(n);
+        Ok(value)
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A canonical huffman code.
+#[derive(Default)]
+struct Huffman {
+    /// The number of codes per length.
+    counts: [u16; 17],
+    /// The symbols ordered by code.
+    symbols: Vec<u16>,
+    /// Tables with a single symbol use no bits at all.
+    single: Option<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Huffman> {
+        let mut counts = [0u16; 17];
+        for &len in lengths {
+            if len > 16 {
+                return Err(invalid_data("invalid lha huffman table"));
+            }
+            counts[usize::from(len)] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols = vec![];
+        for len in 1..=16 {
+            for (symbol, &x) in lengths.iter().enumerate() {
+                if x == len {
+                    symbols.push(symbol as u16);
+                }
+            }
+        }
+        Ok(Huffman {
+            counts,
+            symbols,
+            single: None,
+        })
+    }
+
+    fn single(symbol: usize, nn: usize) -> io::Result<Huffman> {
+        if symbol >= nn {
+            return Err(invalid_data("invalid lha huffman table"));
+        }
+        Ok(Huffman {
+            counts: [0; 17],
+            symbols: vec![],
+            single: Some(symbol as u16),
+        })
+    }
+
+    fn decode<R: Read>(&self, bits: &mut BitReader<R>) -> io::Result<u16> {
+        if let Some(symbol) = self.single {
+            return Ok(symbol);
+        }
+        let (mut code, mut first, mut index) = (0u32, 0u32, 0u32);
+        for &count in &self.counts[1..] {
+            code |= bits.bits(1)?;
+            let count = u32::from(count);
+            if code < first + count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid_data("invalid lha huffman code"))
+    }
+}
+
+/// Decodes the `-lh4-` to `-lh7-` methods (LZSS with static huffman
+/// coding per block).
+struct LhDecoder<R> {
+    bits: BitReader<R>,
+    dict: Vec<u8>,
+    pos: usize,
+    remaining: u64,
+    np: usize,
+    pbit: u32,
+    block_size: u32,
+    c_table: Huffman,
+    p_table: Huffman,
+    copy_distance: usize,
+    copy_len: usize,
+}
+
+impl<R: Read> LhDecoder<R> {
+    fn new(rdr: R, dict_bits: u32, size: u64) -> LhDecoder<R> {
+        let (np, pbit) = match dict_bits {
+            16 => (17, 5),
+            15 => (16, 5),
+            _ => (14, 4),
+        };
+        LhDecoder {
+            bits: BitReader {
+                rdr,
+                bits: 0,
+                count: 0,
+            },
+            // the reference implementation starts out with spaces
+            dict: vec![b' '; 1 << dict_bits],
+            pos: 0,
+            remaining: size,
+            np,
+            pbit,
+            block_size: 0,
+            c_table: Huffman::default(),
+            p_table: Huffman::default(),
+            copy_distance: 0,
+            copy_len: 0,
+        }
+    }
+
+    /// Reads the code lengths of the tables for code lengths and positions.
+    fn read_pt_len(&mut self, nn: usize, nbit: u32, special: usize) -> io::Result<Huffman> {
+        let n = self.bits.bits(nbit)? as usize;
+        if n == 0 {
+            return Huffman::single(self.bits.bits(nbit)? as usize, nn);
+        }
+        if n > nn {
+            return Err(invalid_data("invalid lha huffman table"));
+        }
+        let mut lengths = vec![0u8; nn];
+        let mut i = 0;
+        while i < n {
+            let mut len = self.bits.peek(3)?;
+            if len == 7 {
+                // longer lengths continue in unary
+                self.bits.skip(3);
+                while self.bits.bits(1)? == 1 {
+                    len += 1;
+                    if len > 16 {
+                        return Err(invalid_data("invalid lha huffman table"));
+                    }
+                }
+            } else {
+                self.bits.skip(3);
+            }
+            lengths[i] = len as u8;
+            i += 1;
+            if i == special {
+                i += self.bits.bits(2)? as usize;
+            }
+        }
+        Huffman::new(&lengths)
+    }
+
+    /// Reads the code lengths of the literal and length table.
+    fn read_c_len(&mut self, pt_table: &Huffman) -> io::Result<Huffman> {
+        let n = self.bits.bits(CBIT)? as usize;
+        if n == 0 {
+            return Huffman::single(self.bits.bits(CBIT)? as usize, NC);
+        }
+        if n > NC {
+            return Err(invalid_data("invalid lha huffman table"));
+        }
+        let mut lengths = vec![0u8; NC];
+        let mut i = 0;
+        while i < n {
+            match pt_table.decode(&mut self.bits)? {
+                // runs of unused codes
+                0 => i += 1,
+                1 => i += self.bits.bits(4)? as usize + 3,
+                2 => i += self.bits.bits(CBIT)? as usize + 20,
+                len => {
+                    lengths[i] = (len - 2) as u8;
+                    i += 1;
+                }
+            }
+        }
+        Huffman::new(&lengths)
+    }
+
+    fn read_block_header(&mut self) -> io::Result<()> {
+        self.block_size = self.bits.bits(16)?;
+        let pt_table = self.read_pt_len(NT, TBIT, 3)?;
+        self.c_table = self.read_c_len(&pt_table)?;
+        self.p_table = self.read_pt_len(self.np, self.pbit, 0)?;
+        Ok(())
+    }
+
+    fn push(&mut self, byte: u8) -> u8 {
+        let mask = self.dict.len() - 1;
+        self.dict[self.pos & mask] = byte;
+        self.pos = self.pos.wrapping_add(1);
+        self.remaining -= 1;
+        byte
+    }
+}
+
+impl<R: Read> Read for LhDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() && self.remaining > 0 {
+            if self.copy_len == 0 {
+                if self.block_size == 0 {
+                    self.read_block_header()?;
+                }
+                self.block_size = self.block_size.saturating_sub(1);
+                let c = usize::from(self.c_table.decode(&mut self.bits)?);
+                if c < 256 {
+                    buf[n] = self.push(c as u8);
+                    n += 1;
+                    continue;
+                }
+                let p = u32::from(self.p_table.decode(&mut self.bits)?);
+                let distance = if p > 1 {
+                    (1 << (p - 1)) + self.bits.bits(p - 1)?
+                } else {
+                    p
+                };
+                self.copy_len = c - 256 + THRESHOLD;
+                self.copy_distance = distance as usize + 1;
+            }
+            let mask = self.dict.len() - 1;
+            let byte = self.dict[self.pos.wrapping_sub(self.copy_distance) & mask];
+            buf[n] = self.push(byte);
+            n += 1;
+            self.copy_len -= 1;
+        }
+        Ok(n)
+    }
+}
+
+impl LhaArchive {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, UnboxError> {
+        let path = path.as_ref().canonicalize()?;
+        let mut rdr = BufReader::new(File::open(&path)?);
+        let mut entries = vec![];
+        let mut offset = 0;
+        while let Some((entry, next)) = read_header(&mut rdr, offset)? {
+            entries.push(entry);
+            offset = next;
+        }
+        Ok(LhaArchive { path, entries })
+    }
+
+    /// Opens a reader for the decompressed contents of an entry.
+    fn open_entry(&self, entry: &LhaEntry) -> Result<Box<dyn Read>, UnboxError> {
+        let mut f = File::open(&self.path)?;
+        f.seek(SeekFrom::Start(entry.offset))?;
+        let rdr = BufReader::new(f.take(entry.compressed_size));
+        let rdr: Box<dyn Read> = match entry.method {
+            Method::Stored => Box::new(rdr.take(entry.size)),
+            Method::Lh(dict_bits) => Box::new(LhDecoder::new(rdr, dict_bits, entry.size)),
+            Method::Unsupported(ref method) => bail!("unsupported lha method '{}'", method),
+            Method::Directory => unreachable!(),
+        };
+        Ok(Box::new(CrcRead {
+            rdr,
+            crc: 0,
+            expected: entry.crc,
+        }))
+    }
+}
+
+impl Archive for LhaArchive {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn total_size(&self) -> Option<u64> {
+        Some(self.entries.iter().map(|entry| entry.size).sum())
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        Some(self.entries.len() as u64)
+    }
+
+    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), UnboxError> {
+        let encoding = name_encoding(&self.entries, helper.filename_encoding())?;
+        for entry in &self.entries {
+            if helper.skip_entry() {
+                continue;
+            }
+            let name = decode_name(&entry.name, encoding);
+            // unix lha stores symlinks as directories named `link|target`
+            let (name, target) = match name.find('|') {
+                Some(idx) if entry.method == Method::Directory => {
+                    (&name[..idx], Some(&name[idx + 1..]))
+                }
+                _ => (&name[..], None),
+            };
+            let path = Path::new(name);
+            if name.is_empty() || is_unsafe_path(path) {
+                debug!("skipping unsafe path {}", path.display());
+                continue;
+            }
+            match (&entry.method, target) {
+                (Method::Directory, Some(target)) => helper.create_symlink(path, target)?,
+                (Method::Directory, None) => helper.create_dir(path)?,
+                _ => {
+                    let metadata = EntryMetadata {
+                        mode: entry.mode,
+                        owner: None,
+                        mtime: entry.mtime,
+                    };
+                    self.open_entry(entry)
+                        .and_then(|rdr| helper.write_file_with_metadata(path, rdr, metadata))
+                        .map_err(|err| helper.entry_error(path, err))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn entries(&mut self) -> Result<Vec<ArchiveEntry>, UnboxError> {
+        let encoding = name_encoding(&self.entries, None)?;
+        Ok(self
+            .entries
+            .iter()
+            .map(|entry| {
+                let name = decode_name(&entry.name, encoding);
+                match entry.method {
+                    Method::Directory if name.contains('|') => {
+                        ArchiveEntry::new(name, 0, EntryKind::Other)
+                    }
+                    Method::Directory => ArchiveEntry::new(name, 0, EntryKind::Directory),
+                    _ => ArchiveEntry::new(name, entry.size, EntryKind::File),
+                }
+            })
+            .collect())
+    }
+}
+
+#[test]
+fn test_lh5() {
+    use std::io::Cursor;
+
+    // data.bin is the last entry and lh5 compressed
+    let lha = &include_bytes!("../../tests/fixtures/software.lzh")[..];
+    let mut rdr = Cursor::new(lha);
+    let mut offset = 0;
+    let mut last = None;
+    while let Some((entry, next)) = read_header(&mut rdr, offset).unwrap() {
+        offset = next;
+        last = Some(entry);
+    }
+    let entry = last.unwrap();
+    assert_eq!(entry.method, Method::Lh(13));
+    let compressed = &lha[entry.offset as usize..(entry.offset + entry.compressed_size) as usize];
+    let data: Vec<u8> = (0..3000u32).map(|x| (x * 7 % 251) as u8).collect();
+
+    let mut rdr = LhDecoder::new(compressed, 13, entry.size);
+    let mut out = vec![];
+    rdr.read_to_end(&mut out).unwrap();
+    assert_eq!(out, data);
+    assert_eq!(crc16(0, &out), entry.crc);
+
+    let mut rdr = CrcRead {
+        rdr: LhDecoder::new(compressed, 13, entry.size),
+        crc: 0,
+        expected: entry.crc ^ 1,
+    };
+    let err = rdr.read_to_end(&mut vec![]).unwrap_err();
+    assert_eq!(err.to_string(), "lha crc mismatch");
+}
+
+#[test]
+fn test_lha() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    // "ソフト\readme.txt" is stored in Shift JIS in a level 0 header where
+    // the second byte of the first character is a backslash.  docs is a
+    // level 2 directory, docs/notes.txt a stored level 1 entry and
+    // data.bin is lh5 compressed with a level 2 header.
+    let lha = &include_bytes!("../../tests/fixtures/software.lzh")[..];
+    let text = b"Hello World!\n".repeat(100);
+    let binary: Vec<u8> = (0..3000u32).map(|x| (x * 7 % 251) as u8).collect();
+
+    let dir = make_test_dir().canonicalize().unwrap();
+    let archive_path = dir.join("software.lzh");
+    fs::write(&archive_path, lha).unwrap();
+    let detected = dir.join("software.dat");
+    fs::write(&detected, lha).unwrap();
+    assert_eq!(ArchiveType::for_path(&archive_path), Some(ArchiveType::Lha));
+    assert_eq!(ArchiveType::for_path(&detected), Some(ArchiveType::Lha));
+
+    let mut archive = LhaArchive::open(&archive_path).unwrap();
+    let names: Vec<_> = archive
+        .entries()
+        .unwrap()
+        .into_iter()
+        .map(|x| x.name)
+        .collect();
+    assert_eq!(names.len(), 4);
+    assert_eq!(&names[1..], &["docs", "docs/notes.txt", "data.bin"]);
+
+    let options = UnpackOptions {
+        filename_encoding: Some("shift_jis".into()),
+        ..UnpackOptions::default()
+    };
+    let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv, dir.join("software"));
+    assert_eq!(fs::read(rv.join("ソフト/readme.txt")).unwrap(), text);
+    assert_eq!(fs::read(rv.join("docs/notes.txt")).unwrap(), b"hello");
+    assert_eq!(fs::read(rv.join("data.bin")).unwrap(), binary);
+    assert_eq!(
+        FileTime::from_last_modification_time(&rv.join("data.bin").metadata().unwrap()),
+        FileTime::from_unix_time(1_555_526_400, 0)
+    );
+    assert_eq!(
+        FileTime::from_last_modification_time(&rv.join("docs/notes.txt").metadata().unwrap()),
+        FileTime::from_unix_time(1_555_526_400, 0)
+    );
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |name: &str| rv.join(name).metadata().unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode("docs/notes.txt"), 0o600);
+        assert_eq!(mode("data.bin"), 0o755);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
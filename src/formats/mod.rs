@@ -1,26 +1,82 @@
 use std::fmt;
 use std::fs;
-use std::io::{BufReader, Cursor, Read};
+use std::io::{BufReader, Cursor, Read, Seek};
 use std::path::Path;
+use std::time::{Duration, Instant};
 
-use failure::Error;
 use lazy_static::lazy_static;
+use log::debug;
 use petgraph::Direction;
 use regex::Regex;
+use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
-use crate::archive::Archive;
+use crate::archive::{Archive, ReadSeek};
+use crate::error::{bail, UnboxError};
 
+#[cfg(feature = "ar")]
 mod ar;
+#[cfg(feature = "cab")]
 mod cab;
 mod compression;
+#[cfg(feature = "cpio")]
+mod cpio;
+#[cfg(feature = "cramfs")]
+mod cramfs;
+#[cfg(feature = "dmg")]
+mod dmg;
+#[cfg(feature = "iso")]
+mod iso;
+#[cfg(feature = "lha")]
+mod lha;
+#[cfg(feature = "rar")]
+mod rar;
+#[cfg(feature = "romfs")]
+mod romfs;
+#[cfg(feature = "rpm")]
+mod rpm;
+#[cfg(feature = "signatures")]
+mod signature;
+#[cfg(feature = "tar")]
 mod tar;
+#[cfg(feature = "wim")]
+mod wim;
+#[cfg(feature = "xar")]
+mod xar;
+#[cfg(feature = "zip")]
 mod zip;
 
+#[cfg(feature = "ar")]
 pub use self::ar::ArArchive;
+#[cfg(feature = "cab")]
 pub use self::cab::CabArchive;
+use self::compression::{lz4_peek_size, MAX_COMPRESSION_LAYERS};
 pub use self::compression::{Compression, SingleFileArchive};
-pub use self::tar::TarArchive;
+#[cfg(feature = "cpio")]
+pub use self::cpio::CpioArchive;
+#[cfg(feature = "cramfs")]
+pub use self::cramfs::CramfsArchive;
+#[cfg(feature = "dmg")]
+pub use self::dmg::DmgArchive;
+#[cfg(feature = "iso")]
+pub use self::iso::IsoArchive;
+#[cfg(feature = "lha")]
+pub use self::lha::LhaArchive;
+#[cfg(feature = "rar")]
+pub use self::rar::RarArchive;
+#[cfg(feature = "romfs")]
+pub use self::romfs::RomfsArchive;
+#[cfg(feature = "rpm")]
+pub use self::rpm::RpmArchive;
+#[cfg(feature = "tar")]
+pub use self::tar::{pack_directory, TarArchive};
+#[cfg(feature = "wim")]
+pub use self::wim::WimArchive;
+#[cfg(feature = "xar")]
+pub use self::xar::XarArchive;
+#[cfg(all(test, feature = "zip"))]
+pub use self::zip::build_zip;
+#[cfg(feature = "zip")]
 pub use self::zip::ZipArchive;
 
 // base types we do not care about.
@@ -33,35 +89,152 @@ const BASE_TYPES: [&str; 5] = [
 ];
 
 /// An enum of supported archive types.
-#[derive(Copy, Clone, PartialEq, Eq, EnumIter)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, EnumIter)]
 pub enum ArchiveType {
+    #[cfg(feature = "ar")]
     Ar,
+    #[cfg(feature = "cab")]
     Cab,
+    #[cfg(feature = "cab")]
     PeCab,
+    #[cfg(feature = "zip")]
     Zip,
+    #[cfg(feature = "zip")]
+    PeZip,
+    #[cfg(feature = "iso")]
+    Iso,
+    #[cfg(feature = "cpio")]
+    Cpio,
+    #[cfg(feature = "cramfs")]
+    Cramfs,
+    #[cfg(feature = "dmg")]
+    Dmg,
+    #[cfg(feature = "romfs")]
+    Romfs,
+    #[cfg(feature = "lha")]
+    Lha,
+    #[cfg(feature = "rar")]
+    Rar,
+    #[cfg(feature = "rpm")]
+    Rpm,
+    #[cfg(feature = "wim")]
+    Wim,
+    #[cfg(feature = "xar")]
+    Xar,
+    #[cfg(feature = "tar")]
     Tar,
+    #[cfg(feature = "tar")]
     TarGz,
+    #[cfg(feature = "tar")]
     TarXz,
+    #[cfg(feature = "tar")]
     TarBz2,
+    #[cfg(feature = "tar")]
+    TarZst,
+    #[cfg(feature = "tar")]
+    TarLz4,
+    #[cfg(feature = "tar")]
+    TarLzma,
+    #[cfg(feature = "tar")]
+    TarZ,
     SingleFileGz,
     SingleFileXz,
     SingleFileBz2,
+    SingleFileZst,
+    SingleFileLz4,
+    SingleFileBr,
+    SingleFileLzma,
+    SingleFileZ,
+    SingleFileSnappy,
 }
 
 impl fmt::Display for ArchiveType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            #[cfg(feature = "ar")]
             ArchiveType::Ar => write!(f, "unix ar archive"),
+            #[cfg(feature = "cab")]
             ArchiveType::Cab => write!(f, "microsoft cabinet"),
+            #[cfg(feature = "cab")]
             ArchiveType::PeCab => write!(f, "portable executable containing microsoft cabinet"),
+            #[cfg(feature = "zip")]
             ArchiveType::Zip => write!(f, "zip archive"),
+            #[cfg(feature = "zip")]
+            ArchiveType::PeZip => write!(f, "portable executable containing zip archive"),
+            #[cfg(feature = "iso")]
+            ArchiveType::Iso => write!(f, "iso9660 image"),
+            #[cfg(feature = "cpio")]
+            ArchiveType::Cpio => write!(f, "cpio archive"),
+            #[cfg(feature = "cramfs")]
+            ArchiveType::Cramfs => write!(f, "cramfs image"),
+            #[cfg(feature = "dmg")]
+            ArchiveType::Dmg => write!(f, "apple disk image"),
+            #[cfg(feature = "romfs")]
+            ArchiveType::Romfs => write!(f, "romfs image"),
+            #[cfg(feature = "lha")]
+            ArchiveType::Lha => write!(f, "lha archive"),
+            #[cfg(feature = "rar")]
+            ArchiveType::Rar => write!(f, "rar archive"),
+            #[cfg(feature = "rpm")]
+            ArchiveType::Rpm => write!(f, "rpm package"),
+            #[cfg(feature = "wim")]
+            ArchiveType::Wim => write!(f, "windows imaging image"),
+            #[cfg(feature = "xar")]
+            ArchiveType::Xar => write!(f, "xar archive"),
+            #[cfg(feature = "tar")]
             ArchiveType::Tar => write!(f, "uncompressed tarball"),
+            #[cfg(feature = "tar")]
             ArchiveType::TarGz => write!(f, "gzip-compressed tarball"),
+            #[cfg(feature = "tar")]
             ArchiveType::TarXz => write!(f, "xz-compressed tarball"),
+            #[cfg(feature = "tar")]
             ArchiveType::TarBz2 => write!(f, "bzip2-compressed tarball"),
+            #[cfg(feature = "tar")]
+            ArchiveType::TarZst => write!(f, "zstd-compressed tarball"),
+            #[cfg(feature = "tar")]
+            ArchiveType::TarLz4 => write!(f, "lz4-compressed tarball"),
+            #[cfg(feature = "tar")]
+            ArchiveType::TarLzma => write!(f, "lzma-compressed tarball"),
+            #[cfg(feature = "tar")]
+            ArchiveType::TarZ => write!(f, "lzw-compressed tarball"),
             ArchiveType::SingleFileGz => write!(f, "gzip-compressed file"),
             ArchiveType::SingleFileBz2 => write!(f, "bzip2-compressed file"),
             ArchiveType::SingleFileXz => write!(f, "xz-compressed file"),
+            ArchiveType::SingleFileZst => write!(f, "zstd-compressed file"),
+            ArchiveType::SingleFileLz4 => write!(f, "lz4-compressed file"),
+            ArchiveType::SingleFileBr => write!(f, "brotli-compressed file"),
+            ArchiveType::SingleFileLzma => write!(f, "lzma-compressed file"),
+            ArchiveType::SingleFileZ => write!(f, "lzw-compressed file"),
+            ArchiveType::SingleFileSnappy => write!(f, "snappy-compressed file"),
+        }
+    }
+}
+
+/// The outcome of detecting the type of an archive.
+#[derive(Copy, Clone)]
+pub struct Detection {
+    /// The type determined from the file contents.
+    pub by_magic: Option<ArchiveType>,
+    /// The type implied by the file name.
+    pub by_filename: Option<ArchiveType>,
+    /// How long the detection took.
+    pub elapsed: Duration,
+}
+
+impl Detection {
+    /// The detected archive type, preferring the file contents.
+    pub fn archive_type(&self) -> Option<ArchiveType> {
+        self.by_magic.or(self.by_filename)
+    }
+
+    /// Returns `true` if the file name implies a different type than the
+    /// file contents.
+    ///
+    /// This usually indicates a renamed or corrupt file.
+    pub fn is_mismatch(&self) -> bool {
+        match (self.by_magic, self.by_filename) {
+            (Some(by_magic), Some(by_filename)) => by_magic != by_filename,
+            _ => false,
         }
     }
 }
@@ -73,9 +246,14 @@ impl fmt::Display for ArchiveType {
 fn get_mimetype(bytes: &[u8]) -> &'static str {
     let mut mimetype = tree_magic::from_u8(bytes);
 
-    // walk up the graph until we hit the first non base type
+    // walk up the graph until we hit the first non base type.  Types that
+    // are not in the graph are returned as they are so that detection can
+    // fall back to the filename.
     let graph = &tree_magic::TYPE.graph;
-    let node_index = tree_magic::TYPE.hash[&mimetype];
+    let node_index = match tree_magic::TYPE.hash.get(&mimetype) {
+        Some(&node_index) => node_index,
+        None => return mimetype,
+    };
     for index in graph.neighbors_directed(node_index, Direction::Incoming) {
         let parent_mimetype = graph[index];
         if BASE_TYPES.contains(&parent_mimetype) {
@@ -87,20 +265,230 @@ fn get_mimetype(bytes: &[u8]) -> &'static str {
     mimetype
 }
 
+/// Determines the most specific mimetype of the item.
+///
+/// Unlike `get_mimetype` this does not walk up to the base type, so an
+/// open office text document is reported as such and not as zip archive.
+pub fn detailed_mimetype(bytes: &[u8]) -> &'static str {
+    tree_magic::from_u8(bytes)
+}
+
+/// Returns a human readable name for formats built on top of archives.
+pub(crate) fn describe_mimetype(mimetype: &str) -> Option<&'static str> {
+    Some(match mimetype {
+        "application/epub+zip" => "epub ebook",
+        "application/vnd.oasis.opendocument.text" => "opendocument text",
+        "application/vnd.oasis.opendocument.spreadsheet" => "opendocument spreadsheet",
+        "application/vnd.oasis.opendocument.presentation" => "opendocument presentation",
+        "application/vnd.android.package-archive" => "android package",
+        "application/x-java-archive" => "java archive",
+        x if x.contains("wordprocessingml") => "word document",
+        x if x.contains("spreadsheetml") => "excel workbook",
+        x if x.contains("presentationml") => "powerpoint presentation",
+        _ => return None,
+    })
+}
+
+/// How many bytes at the start of a file are used to sniff its type.
+pub const MAGIC_SIZE: usize = 131_072;
+
+/// Reads the start of a file which is enough to sniff its type.
+///
+/// For lz4 frames this covers the entire first block so that the contents
+/// can be sniffed as well.
+fn read_magic<P: AsRef<Path>>(path: &P) -> Option<Vec<u8>> {
+    let mut buf = vec![0u8; MAGIC_SIZE];
+    let f = fs::File::open(path).ok()?;
+    let mut reader = BufReader::new(f);
+    let size = reader.read(&mut buf[..]).ok()?;
+    buf.truncate(size);
+    if let Some(peek_size) = lz4_peek_size(&buf) {
+        if size == MAGIC_SIZE && peek_size > MAGIC_SIZE {
+            reader
+                .take((peek_size - MAGIC_SIZE) as u64)
+                .read_to_end(&mut buf)
+                .ok()?;
+        }
+    }
+    Some(buf)
+}
+
+/// Determines the mimetype of the file at the given path.
+///
+/// Returns `None` if the file cannot be read.
+pub fn mimetype_for_path<P: AsRef<Path>>(path: &P) -> Option<&'static str> {
+    read_magic(path).map(|buf| get_mimetype(&buf))
+}
+
+/// Determines the mimetype of the contents of a compressed tarball or file.
+///
+/// Returns `None` if the type is not compressed or nothing could be
+/// decompressed.
+pub fn inner_mimetype_for_path<P: AsRef<Path>>(path: &P, ty: ArchiveType) -> Option<&'static str> {
+    let compression = Compression::for_archive_type(ty)?;
+    mimetype_behind_compression(&read_magic(path)?, compression)
+}
+
+/// Decompresses the start of a compressed buffer to sniff the mimetype of
+/// its contents.
+fn mimetype_behind_compression(buf: &[u8], compression: Compression) -> Option<&'static str> {
+    let zbuf = decompress_magic(buf, compression)?;
+    let mimetype = get_mimetype(&zbuf);
+    debug!(
+        "mimetype behind {:?} compression is {}",
+        compression, mimetype
+    );
+    Some(mimetype)
+}
+
+/// Decompresses as much of the start of a compressed buffer as is needed
+/// to sniff the contents.
+///
+/// Returns `None` if nothing could be decompressed.
+fn decompress_magic(buf: &[u8], compression: Compression) -> Option<Vec<u8>> {
+    let mut rdr = compression.decompress(Cursor::new(buf.to_vec())).ok()?;
+    let mut zbuf = vec![0u8; MAGIC_SIZE];
+
+    // decoders might return less than requested and the buffer is cut
+    // off somewhere in the stream, so read as much as we can.
+    let mut size = 0;
+    while size < zbuf.len() {
+        match rdr.read(&mut zbuf[size..]) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => size += read,
+        }
+    }
+    if size == 0 {
+        return None;
+    }
+    zbuf.truncate(size);
+    Some(zbuf)
+}
+
+/// Sniffs another compression layer at the start of decompressed data.
+///
+/// The layer is only accepted if its start can be decompressed, which
+/// weeds out data that merely looks like a compression header.
+fn nested_compression(buf: &[u8]) -> Option<Compression> {
+    let compression =
+        Compression::for_mimetype(get_mimetype(buf)).or_else(|| Compression::for_magic(buf))?;
+    decompress_magic(buf, compression)?;
+    Some(compression)
+}
+
 impl ArchiveType {
+    /// Short names of the archive type in addition to the display name.
+    pub fn aliases(self) -> &'static [&'static str] {
+        match self {
+            #[cfg(feature = "ar")]
+            ArchiveType::Ar => &["ar"],
+            #[cfg(feature = "cab")]
+            ArchiveType::Cab => &["cab"],
+            #[cfg(feature = "cab")]
+            ArchiveType::PeCab => &["pecab"],
+            #[cfg(feature = "zip")]
+            ArchiveType::Zip => &["zip"],
+            #[cfg(feature = "zip")]
+            ArchiveType::PeZip => &["pezip"],
+            #[cfg(feature = "iso")]
+            ArchiveType::Iso => &["iso", "iso9660"],
+            #[cfg(feature = "cpio")]
+            ArchiveType::Cpio => &["cpio"],
+            #[cfg(feature = "cramfs")]
+            ArchiveType::Cramfs => &["cramfs"],
+            #[cfg(feature = "dmg")]
+            ArchiveType::Dmg => &["dmg"],
+            #[cfg(feature = "romfs")]
+            ArchiveType::Romfs => &["romfs"],
+            #[cfg(feature = "lha")]
+            ArchiveType::Lha => &["lha", "lzh"],
+            #[cfg(feature = "rar")]
+            ArchiveType::Rar => &["rar"],
+            #[cfg(feature = "rpm")]
+            ArchiveType::Rpm => &["rpm"],
+            #[cfg(feature = "wim")]
+            ArchiveType::Wim => &["wim", "esd"],
+            #[cfg(feature = "xar")]
+            ArchiveType::Xar => &["xar", "pkg"],
+            #[cfg(feature = "tar")]
+            ArchiveType::Tar => &["tar", "pax"],
+            #[cfg(feature = "tar")]
+            ArchiveType::TarGz => &["tar.gz", "tgz"],
+            #[cfg(feature = "tar")]
+            ArchiveType::TarXz => &["tar.xz", "txz"],
+            #[cfg(feature = "tar")]
+            ArchiveType::TarBz2 => &["tar.bz2", "tbz2", "tbz"],
+            #[cfg(feature = "tar")]
+            ArchiveType::TarZst => &["tar.zst", "tzst"],
+            #[cfg(feature = "tar")]
+            ArchiveType::TarLz4 => &["tar.lz4"],
+            #[cfg(feature = "tar")]
+            ArchiveType::TarLzma => &["tar.lzma", "tlz"],
+            #[cfg(feature = "tar")]
+            ArchiveType::TarZ => &["tar.z", "taz"],
+            ArchiveType::SingleFileGz => &["gz", "gzip"],
+            ArchiveType::SingleFileXz => &["xz"],
+            ArchiveType::SingleFileBz2 => &["bz2", "bzip2"],
+            ArchiveType::SingleFileZst => &["zst", "zstd"],
+            ArchiveType::SingleFileLz4 => &["lz4"],
+            ArchiveType::SingleFileBr => &["br", "brotli"],
+            ArchiveType::SingleFileLzma => &["lzma"],
+            ArchiveType::SingleFileZ => &["z", "compress"],
+            ArchiveType::SingleFileSnappy => &["sz", "snappy"],
+        }
+    }
+
+    /// Looks up an archive type by its display name or one of its aliases.
+    ///
+    /// The lookup is case insensitive.
+    pub fn from_name(name: &str) -> Option<ArchiveType> {
+        ArchiveType::iter().find(|ty| {
+            ty.to_string().eq_ignore_ascii_case(name)
+                || ty.aliases().iter().any(|x| x.eq_ignore_ascii_case(name))
+        })
+    }
+
     /// Determines the archive type for the given path.
     ///
     /// This first tries to determine the file contents purely by reading the
     /// first few hundred of kilobytes and then falls back to guessing based
     /// on the filename.
     pub fn for_path<P: AsRef<Path>>(path: &P) -> Option<ArchiveType> {
-        ArchiveType::determine_by_magic(path).or_else(|| ArchiveType::determine_by_filename(path))
+        let start = Instant::now();
+        if let Some(ty) = ArchiveType::determine_by_magic(path) {
+            debug!(
+                "{}: detected {} by magic in {:.2?}",
+                path.as_ref().display(),
+                ty,
+                start.elapsed()
+            );
+            return Some(ty);
+        }
+        let rv = ArchiveType::determine_by_filename(path);
+        match rv {
+            Some(ty) => debug!("{}: detected {} by filename", path.as_ref().display(), ty),
+            None => debug!("{}: unable to detect archive type", path.as_ref().display()),
+        }
+        rv
+    }
+
+    /// Like `for_path` but always determines the archive type both by
+    /// contents and by filename so they can be compared.
+    pub fn detect<P: AsRef<Path>>(path: &P) -> Detection {
+        let start = Instant::now();
+        let by_magic = ArchiveType::determine_by_magic(path);
+        let by_filename = ArchiveType::determine_by_filename(path);
+        Detection {
+            by_magic,
+            by_filename,
+            elapsed: start.elapsed(),
+        }
     }
 
     fn determine_by_filename<P: AsRef<Path>>(path: &P) -> Option<ArchiveType> {
         // determine by filename
         if let Some(filename) = path.as_ref().file_name().and_then(|x| x.to_str()) {
-            for &(ref regex, ty) in BY_PATTERN.iter() {
+            for (regex, ty) in filename_patterns() {
                 if regex.is_match(filename) {
                     return Some(ty);
                 }
@@ -109,94 +497,1061 @@ impl ArchiveType {
         None
     }
 
+    /// Derives a name for the contents of an archive from its path.
+    ///
+    /// This strips known archive extensions including compound ones such
+    /// as `.tar.gz` and falls back to removing the last extension.
+    pub fn name_hint<P: AsRef<Path>>(path: &P) -> String {
+        let path = path.as_ref();
+        if let Some(filename) = path.file_name().and_then(|x| x.to_str()) {
+            for (regex, _) in filename_patterns() {
+                if let Some(m) = regex.find(filename) {
+                    if m.start() > 0 {
+                        return filename[..m.start()].to_string();
+                    }
+                }
+            }
+        }
+        path.file_stem()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_else(|| "Archive".to_string())
+    }
+
+    /// Determines the archive type for archive contents held in memory.
+    ///
+    /// This runs the same detection as `for_path` on the bytes.  If that
+    /// fails and a file name is given, the type is guessed from it instead.
+    pub fn for_bytes(bytes: &[u8], hint_name: Option<&str>) -> Option<ArchiveType> {
+        let probe_executable = || {
+            #[cfg(feature = "cab")]
+            if self::cab::contains_cab(bytes) {
+                return Some(ArchiveType::PeCab);
+            }
+            #[cfg(feature = "zip")]
+            if self::zip::contains_zip(bytes) {
+                return Some(ArchiveType::PeZip);
+            }
+            None
+        };
+        let magic_size = lz4_peek_size(bytes).map_or(MAGIC_SIZE, |x| x.max(MAGIC_SIZE));
+        let magic = &bytes[..bytes.len().min(magic_size)];
+        if let Some(ty) = ArchiveType::determine_by_bytes(magic, hint_name, probe_executable) {
+            debug!("detected {} by magic in bytes", ty);
+            return Some(ty);
+        }
+        let rv = hint_name.and_then(|name| ArchiveType::determine_by_filename(&name));
+        if let Some(ty) = rv {
+            debug!("detected {} by hint name", ty);
+        }
+        rv
+    }
+
     fn determine_by_magic<P: AsRef<Path>>(path: &P) -> Option<ArchiveType> {
-        // determine by magic
-        let mut buf = [0u8; 131_072];
-        let f = fs::File::open(path).ok()?;
-        let mut reader = BufReader::new(f);
-        let size = reader.read(&mut buf[..]).ok()?;
-        let mimetype = get_mimetype(&buf[..size]);
-
-        // cabinet files might be hidden in PE files :(
-        if mimetype == "application/x-executable" {
-            if let Ok(_) = CabArchive::find_in_executable(&path) {
+        let buf = read_magic(path)?;
+        debug!("{}: sniffing {} bytes", path.as_ref().display(), buf.len());
+        let probe_executable = || {
+            #[cfg(feature = "cab")]
+            if CabArchive::find_in_executable(path).is_ok() {
                 return Some(ArchiveType::PeCab);
             }
+            #[cfg(feature = "zip")]
+            if ZipArchive::find_in_executable(path).is_ok() {
+                return Some(ArchiveType::PeZip);
+            }
+            None
+        };
+        let name = path.as_ref().file_name().and_then(|x| x.to_str());
+        ArchiveType::determine_by_bytes(&buf, name, probe_executable)
+    }
+
+    /// Sniffs the archive type from the start of an archive.
+    ///
+    /// As cabinets and zip archives hidden in executables are placed after
+    /// the executable, looking for them is left to the caller.  The name is
+    /// only consulted for compressions without a magic number.
+    fn determine_by_bytes<F>(
+        buf: &[u8],
+        name: Option<&str>,
+        probe_executable: F,
+    ) -> Option<ArchiveType>
+    where
+        F: FnOnce() -> Option<ArchiveType>,
+    {
+        let mimetype = get_mimetype(buf);
+        debug!("mimetype is {}", mimetype);
+
+        // cabinet files and self-extracting zips might be hidden in PE
+        // files :(
+        if mimetype == "application/x-executable" {
+            if let Some(ty) = probe_executable() {
+                return Some(ty);
+            }
         }
 
         // if we get a direct hit, then we know what we are dealing with.  These
         // intentionally do not include mimetypes for pure compession algorithms
         // such as gzip
-        if let Some(&rv) = BY_MIMETYPE.get(mimetype) {
+        if let Some(rv) = type_for_mimetype(mimetype) {
             return Some(rv);
         }
 
+        // some formats are not known to tree_magic so we look for their
+        // signatures ourselves.
+        for &(offset, signature, ty) in BY_SIGNATURE.iter() {
+            if buf.get(offset..offset + signature.len()) == Some(signature) {
+                return Some(ty);
+            }
+        }
+
         // if the mimetype points to a compression we unpack a bit of the magic
         // to see if we can detect an interior archive.
-        let compression = Compression::for_mimetype(mimetype)?;
-        let inner_ty = ArchiveType::determine_behind_compession(&buf[..size], compression);
-        compression.as_archive_type(inner_ty)
+        let compression = Compression::for_mimetype(mimetype)
+            .or_else(|| Compression::for_magic(buf))
+            .or_else(|| name.and_then(|name| Compression::for_lzma_name(buf, name)))?;
+        let inner_ty = ArchiveType::determine_behind_compession(buf, compression);
+        compression
+            .as_archive_type(inner_ty)
+            .or_else(|| compression.as_archive_type(None))
     }
 
+    /// Looks for an archive behind the compression of a buffer.
+    ///
+    /// Files are occasionally compressed more than once, so this keeps
+    /// peeling off compression layers up to `MAX_COMPRESSION_LAYERS`.
     fn determine_behind_compession(buf: &[u8], compression: Compression) -> Option<ArchiveType> {
-        let mut rdr = compression.decompress(Cursor::new(buf.to_vec())).ok()?;
-        let mut zbuf = [0u8; 131_072];
-        let size = rdr.read(&mut zbuf[..]).ok()?;
-        let mimetype = get_mimetype(&zbuf[..size]);
+        let mut buf = decompress_magic(buf, compression)?;
+        for _ in 1..MAX_COMPRESSION_LAYERS {
+            if let Some(ty) = type_for_mimetype(get_mimetype(&buf)) {
+                return Some(ty);
+            }
+            let inner = nested_compression(&buf)?;
+            debug!("found nested {:?} compression", inner);
+            buf = decompress_magic(&buf, inner)?;
+        }
+        type_for_mimetype(get_mimetype(&buf))
+    }
+
+    /// Returns a human readable label for the archive at the given path.
+    ///
+    /// This is usually the name of the type but for generic containers
+    /// such as zip it is refined by looking into the archive.  Otherwise if
+    /// the mimetype reveals a known format built on top of the type it is
+    /// added in parentheses.
+    pub fn label<P: AsRef<Path>>(self, path: &P) -> String {
+        #[cfg(feature = "zip")]
+        if self == ArchiveType::Zip {
+            if let Some(label) = self::zip::refined_label(path) {
+                return label.to_string();
+            }
+        }
+        match read_magic(path).and_then(|buf| describe_mimetype(detailed_mimetype(&buf))) {
+            Some(detail) => format!("{} ({})", self, detail),
+            None => self.to_string(),
+        }
+    }
 
-        if let Some(&ty) = BY_MIMETYPE.get(mimetype) {
-            return compression.as_archive_type(Some(ty));
+    /// Opens the given path as an archive of the type.
+    pub fn open<P: AsRef<Path>>(self, path: &P) -> Result<Box<dyn Archive>, UnboxError> {
+        match handler_for(self) {
+            Some(handler) => handler.open(path.as_ref()),
+            None => bail!("no handler registered for {}", self),
         }
+    }
 
+    /// Opens an archive of the type from a reader instead of a path.
+    ///
+    /// This is useful for archives held in memory or decrypted on the fly.
+    /// The name stands in for the path of the archive and is used to name
+    /// what it is unpacked into.  Formats that need to open other files or
+    /// map the file into memory are not supported.  Tarballs and ar
+    /// archives only read the reader once, so they can either be listed or
+    /// unpacked.
+    pub fn open_reader<R: Read + Seek + 'static>(
+        self,
+        rdr: R,
+        name: &str,
+    ) -> Result<Box<dyn Archive>, UnboxError> {
+        match handler_for(self) {
+            Some(handler) => handler.open_reader(Box::new(rdr), name),
+            None => bail!("no handler registered for {}", self),
+        }
+    }
+
+    /// Checks if archives of the type can be opened with `open_stream`.
+    pub fn can_stream(self) -> bool {
+        handler_for(self).is_some_and(|handler| handler.can_stream())
+    }
+
+    /// Opens an archive of the type from a stream that cannot seek.
+    ///
+    /// Only tarballs, ar archives and single compressed files are read
+    /// front to back, see `can_stream`.  Like with `open_reader` the stream
+    /// can only be read once.
+    pub fn open_stream<R: Read + 'static>(
+        self,
+        rdr: R,
+        name: &str,
+    ) -> Result<Box<dyn Archive>, UnboxError> {
+        match handler_for(self) {
+            Some(handler) => handler.open_stream(Box::new(rdr), name),
+            None => bail!("no handler registered for {}", self),
+        }
+    }
+}
+
+/// Describes how a format is detected and opened.
+///
+/// All supported formats are registered in `FORMATS` which is consulted
+/// for detection by mimetype and filename as well as for opening.
+pub trait FormatHandler: Send + Sync {
+    /// The archive type the handler is responsible for.
+    fn archive_type(&self) -> ArchiveType;
+
+    /// The mimetypes identifying the format.
+    ///
+    /// These do not contain mimetypes for compression algorithms as they
+    /// are specially handled.
+    fn mimetypes(&self) -> &[&'static str];
+
+    /// Regexes matched against the filename to guess the format.
+    fn filename_patterns(&self) -> &[Regex];
+
+    /// The compression of compressed tarballs and files together with the
+    /// type of archive inside, `None` for single compressed files.
+    ///
+    /// Plain tarballs are uncompressed tarballs.  Other formats are not
+    /// compressed as a whole and return `None`.
+    fn compression(&self) -> Option<(Compression, Option<ArchiveType>)> {
         None
     }
 
-    /// Opens the given path as an archive of the type.
-    pub fn open<P: AsRef<Path>>(self, path: &P) -> Result<Box<dyn Archive>, Error> {
-        match self {
-            ArchiveType::Ar => Ok(Box::new(ArArchive::open(path)?)),
-            ArchiveType::Cab => Ok(Box::new(CabArchive::open(path)?)),
-            ArchiveType::PeCab => Ok(Box::new(CabArchive::find_in_executable(path)?)),
-            ArchiveType::Zip => Ok(Box::new(ZipArchive::open(path)?)),
-            ArchiveType::Tar => Ok(Box::new(TarArchive::open(path, Compression::Uncompressed)?)),
-            ArchiveType::TarGz => Ok(Box::new(TarArchive::open(path, Compression::Gz)?)),
-            ArchiveType::TarXz => Ok(Box::new(TarArchive::open(path, Compression::Xz)?)),
-            ArchiveType::TarBz2 => Ok(Box::new(TarArchive::open(path, Compression::Bz2)?)),
-            ArchiveType::SingleFileGz => {
-                Ok(Box::new(SingleFileArchive::open(path, Compression::Gz)?))
-            }
-            ArchiveType::SingleFileBz2 => {
-                Ok(Box::new(SingleFileArchive::open(path, Compression::Bz2)?))
-            }
-            ArchiveType::SingleFileXz => {
-                Ok(Box::new(SingleFileArchive::open(path, Compression::Xz)?))
-            }
+    /// Opens the given path as an archive of the format.
+    fn open(&self, path: &Path) -> Result<Box<dyn Archive>, UnboxError>;
+
+    /// Opens an archive of the format from a reader.
+    ///
+    /// Formats that can be streamed are opened with `open_stream`.
+    fn open_reader(
+        &self,
+        rdr: Box<dyn ReadSeek>,
+        name: &str,
+    ) -> Result<Box<dyn Archive>, UnboxError> {
+        if self.can_stream() {
+            return self.open_stream(Box::new(rdr), name);
         }
+        bail!(
+            "opening a {} from a reader is not supported",
+            self.archive_type()
+        )
     }
+
+    /// Returns `true` if archives of the format can be read front to back
+    /// with `open_stream`.
+    fn can_stream(&self) -> bool {
+        false
+    }
+
+    /// Opens an archive of the format from a stream that cannot seek.
+    fn open_stream(
+        &self,
+        _rdr: Box<dyn Read>,
+        _name: &str,
+    ) -> Result<Box<dyn Archive>, UnboxError> {
+        bail!(
+            "opening a {} from a stream is not supported",
+            self.archive_type()
+        )
+    }
+}
+
+#[cfg(any(
+    feature = "ar",
+    feature = "cab",
+    feature = "cpio",
+    feature = "cramfs",
+    feature = "dmg",
+    feature = "iso",
+    feature = "lha",
+    feature = "rar",
+    feature = "romfs",
+    feature = "rpm",
+    feature = "wim",
+    feature = "xar",
+    feature = "zip"
+))]
+type OpenFn = fn(&Path) -> Result<Box<dyn Archive>, UnboxError>;
+#[cfg(any(
+    feature = "ar",
+    feature = "cab",
+    feature = "cpio",
+    feature = "cramfs",
+    feature = "dmg",
+    feature = "iso",
+    feature = "lha",
+    feature = "rar",
+    feature = "romfs",
+    feature = "rpm",
+    feature = "wim",
+    feature = "xar",
+    feature = "zip"
+))]
+type OpenReaderFn = fn(Box<dyn ReadSeek>, &str) -> Result<Box<dyn Archive>, UnboxError>;
+#[cfg(any(
+    feature = "ar",
+    feature = "cab",
+    feature = "cpio",
+    feature = "cramfs",
+    feature = "dmg",
+    feature = "iso",
+    feature = "lha",
+    feature = "rar",
+    feature = "romfs",
+    feature = "rpm",
+    feature = "wim",
+    feature = "xar",
+    feature = "zip"
+))]
+type OpenStreamFn = fn(Box<dyn Read>, &str) -> Result<Box<dyn Archive>, UnboxError>;
+
+/// A format handler backed by plain functions.
+#[cfg(any(
+    feature = "ar",
+    feature = "cab",
+    feature = "cpio",
+    feature = "cramfs",
+    feature = "dmg",
+    feature = "iso",
+    feature = "lha",
+    feature = "rar",
+    feature = "romfs",
+    feature = "rpm",
+    feature = "wim",
+    feature = "xar",
+    feature = "zip"
+))]
+struct BuiltinFormat {
+    ty: ArchiveType,
+    mimetypes: &'static [&'static str],
+    filename_patterns: Vec<Regex>,
+    open: OpenFn,
+    open_reader: Option<OpenReaderFn>,
+    open_stream: Option<OpenStreamFn>,
+}
+
+#[cfg(any(
+    feature = "ar",
+    feature = "cab",
+    feature = "cpio",
+    feature = "cramfs",
+    feature = "dmg",
+    feature = "iso",
+    feature = "lha",
+    feature = "rar",
+    feature = "romfs",
+    feature = "rpm",
+    feature = "wim",
+    feature = "xar",
+    feature = "zip"
+))]
+impl FormatHandler for BuiltinFormat {
+    fn archive_type(&self) -> ArchiveType {
+        self.ty
+    }
+
+    fn mimetypes(&self) -> &[&'static str] {
+        self.mimetypes
+    }
+
+    fn filename_patterns(&self) -> &[Regex] {
+        &self.filename_patterns
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Archive>, UnboxError> {
+        (self.open)(path)
+    }
+
+    fn open_reader(
+        &self,
+        rdr: Box<dyn ReadSeek>,
+        name: &str,
+    ) -> Result<Box<dyn Archive>, UnboxError> {
+        match self.open_reader {
+            Some(open_reader) => open_reader(rdr, name),
+            None if self.can_stream() => self.open_stream(Box::new(rdr), name),
+            None => bail!("opening a {} from a reader is not supported", self.ty),
+        }
+    }
+
+    fn can_stream(&self) -> bool {
+        self.open_stream.is_some()
+    }
+
+    fn open_stream(&self, rdr: Box<dyn Read>, name: &str) -> Result<Box<dyn Archive>, UnboxError> {
+        match self.open_stream {
+            Some(open_stream) => open_stream(rdr, name),
+            None => bail!("opening a {} from a stream is not supported", self.ty),
+        }
+    }
+}
+
+/// A compressed tarball or a single compressed file.
+///
+/// Both are read front to back, so they can be opened from streams.
+struct CompressedFormat {
+    ty: ArchiveType,
+    mimetypes: &'static [&'static str],
+    filename_patterns: Vec<Regex>,
+    compression: Compression,
+    inner: Option<ArchiveType>,
+}
+
+impl FormatHandler for CompressedFormat {
+    fn archive_type(&self) -> ArchiveType {
+        self.ty
+    }
+
+    fn mimetypes(&self) -> &[&'static str] {
+        self.mimetypes
+    }
+
+    fn filename_patterns(&self) -> &[Regex] {
+        &self.filename_patterns
+    }
+
+    fn compression(&self) -> Option<(Compression, Option<ArchiveType>)> {
+        Some((self.compression, self.inner))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Archive>, UnboxError> {
+        #[cfg(feature = "tar")]
+        if self.inner == Some(ArchiveType::Tar) {
+            return Ok(Box::new(TarArchive::open(path, self.compression)?));
+        }
+        Ok(Box::new(SingleFileArchive::open(path, self.compression)?))
+    }
+
+    fn can_stream(&self) -> bool {
+        true
+    }
+
+    fn open_stream(&self, rdr: Box<dyn Read>, name: &str) -> Result<Box<dyn Archive>, UnboxError> {
+        #[cfg(feature = "tar")]
+        if self.inner == Some(ArchiveType::Tar) {
+            return Ok(Box::new(TarArchive::from_reader(
+                rdr,
+                name,
+                self.compression,
+            )));
+        }
+        Ok(Box::new(SingleFileArchive::from_reader(
+            rdr,
+            name,
+            self.compression,
+        )))
+    }
+}
+
+fn compile_patterns(filename_patterns: &[&str]) -> Vec<Regex> {
+    filename_patterns
+        .iter()
+        .map(|pattern| Regex::new(pattern).unwrap())
+        .collect()
+}
+
+#[cfg(any(
+    feature = "cab",
+    feature = "cpio",
+    feature = "cramfs",
+    feature = "dmg",
+    feature = "iso",
+    feature = "lha",
+    feature = "rar",
+    feature = "romfs",
+    feature = "rpm",
+    feature = "wim",
+    feature = "xar"
+))]
+fn builtin(
+    ty: ArchiveType,
+    mimetypes: &'static [&'static str],
+    filename_patterns: &[&str],
+    open: OpenFn,
+) -> Box<dyn FormatHandler> {
+    Box::new(BuiltinFormat {
+        ty,
+        mimetypes,
+        filename_patterns: compile_patterns(filename_patterns),
+        open,
+        open_reader: None,
+        open_stream: None,
+    })
+}
+
+/// Like `builtin` for formats that can also be opened from a reader.
+#[cfg(any(feature = "cab", feature = "zip"))]
+fn seekable(
+    ty: ArchiveType,
+    mimetypes: &'static [&'static str],
+    filename_patterns: &[&str],
+    open: OpenFn,
+    open_reader: OpenReaderFn,
+) -> Box<dyn FormatHandler> {
+    Box::new(BuiltinFormat {
+        ty,
+        mimetypes,
+        filename_patterns: compile_patterns(filename_patterns),
+        open,
+        open_reader: Some(open_reader),
+        open_stream: None,
+    })
+}
+
+/// Like `builtin` for formats that can also be opened from a stream.
+#[cfg(feature = "ar")]
+fn streamable(
+    ty: ArchiveType,
+    mimetypes: &'static [&'static str],
+    filename_patterns: &[&str],
+    open: OpenFn,
+    open_stream: OpenStreamFn,
+) -> Box<dyn FormatHandler> {
+    Box::new(BuiltinFormat {
+        ty,
+        mimetypes,
+        filename_patterns: compile_patterns(filename_patterns),
+        open,
+        open_reader: None,
+        open_stream: Some(open_stream),
+    })
+}
+
+#[cfg(feature = "tar")]
+fn tarball(
+    ty: ArchiveType,
+    mimetypes: &'static [&'static str],
+    filename_patterns: &[&str],
+    compression: Compression,
+) -> Box<dyn FormatHandler> {
+    Box::new(CompressedFormat {
+        ty,
+        mimetypes,
+        filename_patterns: compile_patterns(filename_patterns),
+        compression,
+        inner: Some(ArchiveType::Tar),
+    })
+}
+
+fn single_file(
+    ty: ArchiveType,
+    filename_patterns: &[&str],
+    compression: Compression,
+) -> Box<dyn FormatHandler> {
+    Box::new(CompressedFormat {
+        ty,
+        mimetypes: &[],
+        filename_patterns: compile_patterns(filename_patterns),
+        compression,
+        inner: None,
+    })
+}
+
+/// Looks up the handler registered for an archive type.
+fn handler_for(ty: ArchiveType) -> Option<&'static dyn FormatHandler> {
+    FORMATS
+        .iter()
+        .find(|handler| handler.archive_type() == ty)
+        .map(|handler| &**handler)
+}
+
+/// Looks up the archive type of a compressed tarball or file in the
+/// registry, see `FormatHandler::compression`.
+fn type_for_compression(
+    compression: Compression,
+    inner: Option<ArchiveType>,
+) -> Option<ArchiveType> {
+    FORMATS
+        .iter()
+        .find(|handler| handler.compression() == Some((compression, inner)))
+        .map(|handler| handler.archive_type())
+}
+
+/// Looks up the archive type for a mimetype in the registry.
+fn type_for_mimetype(mimetype: &str) -> Option<ArchiveType> {
+    FORMATS
+        .iter()
+        .find(|handler| handler.mimetypes().contains(&mimetype))
+        .map(|handler| handler.archive_type())
+}
+
+/// Iterates over the filename patterns of all registered formats.
+fn filename_patterns() -> impl Iterator<Item = (&'static Regex, ArchiveType)> {
+    FORMATS.iter().flat_map(|handler| {
+        let ty = handler.archive_type();
+        handler
+            .filename_patterns()
+            .iter()
+            .map(move |regex| (regex, ty))
+    })
 }
 
 lazy_static! {
-    /// A mapping of mimetype to archive type.
+    /// The registry of all supported formats.
     ///
-    /// These do not contain mimetypes for compression algorithms as they are
-    /// specially handled.
-    static ref BY_MIMETYPE: std::collections::HashMap<&'static str, ArchiveType> = {
-        let mut rv = std::collections::HashMap::new();
-        rv.insert("application/x-archive", ArchiveType::Ar);
-        rv.insert("application/vnd.ms-cab-compressed", ArchiveType::Cab);
-        rv.insert("application/zip", ArchiveType::Zip);
-        rv.insert("application/x-tar", ArchiveType::Tar);
-        rv
-    };
+    /// Filename patterns are tried in registration order, so compressed
+    /// tarballs are registered before the single compressed files.
+    static ref FORMATS: Vec<Box<dyn FormatHandler>> = vec![
+        #[cfg(feature = "ar")]
+        streamable(
+            ArchiveType::Ar,
+            &["application/x-archive"],
+            &[r"(?i)\.ar?$"],
+            |path| Ok(Box::new(ArArchive::open(path)?)),
+            |rdr, name| Ok(Box::new(ArArchive::from_reader(rdr, name))),
+        ),
+        #[cfg(feature = "cab")]
+        seekable(
+            ArchiveType::Cab,
+            &["application/vnd.ms-cab-compressed"],
+            &[r"(?i)\.cab?$"],
+            |path| Ok(Box::new(CabArchive::open(path)?)),
+            |rdr, name| Ok(Box::new(CabArchive::from_reader(rdr, name)?)),
+        ),
+        #[cfg(feature = "cab")]
+        builtin(ArchiveType::PeCab, &[], &[], |path| {
+            Ok(Box::new(CabArchive::find_in_executable(path)?))
+        }),
+        #[cfg(feature = "zip")]
+        seekable(
+            ArchiveType::Zip,
+            &["application/zip"],
+            &[r"(?i)\.zip$"],
+            |path| Ok(Box::new(ZipArchive::open(path)?)),
+            |rdr, name| Ok(Box::new(ZipArchive::from_reader(rdr, name)?)),
+        ),
+        // the zip reader skips over the executable on its own
+        #[cfg(feature = "zip")]
+        seekable(
+            ArchiveType::PeZip,
+            &[],
+            &[],
+            |path| Ok(Box::new(ZipArchive::find_in_executable(path)?)),
+            |rdr, name| Ok(Box::new(ZipArchive::from_reader(rdr, name)?)),
+        ),
+        #[cfg(feature = "iso")]
+        builtin(ArchiveType::Iso, &[], &[r"(?i)\.iso$"], |path| {
+            Ok(Box::new(IsoArchive::open(path)?))
+        }),
+        #[cfg(feature = "cpio")]
+        builtin(ArchiveType::Cpio, &["application/x-cpio"], &[r"(?i)\.cpio$"], |path| {
+            Ok(Box::new(CpioArchive::open(path)?))
+        }),
+        #[cfg(feature = "cramfs")]
+        builtin(ArchiveType::Cramfs, &[], &[r"(?i)\.cramfs$"], |path| {
+            Ok(Box::new(CramfsArchive::open(path)?))
+        }),
+        #[cfg(feature = "dmg")]
+        builtin(
+            ArchiveType::Dmg,
+            &["application/x-apple-diskimage"],
+            &[r"(?i)\.dmg$"],
+            |path| Ok(Box::new(DmgArchive::open(path)?)),
+        ),
+        #[cfg(feature = "romfs")]
+        builtin(ArchiveType::Romfs, &[], &[r"(?i)\.romfs$"], |path| {
+            Ok(Box::new(RomfsArchive::open(path)?))
+        }),
+        #[cfg(feature = "lha")]
+        builtin(ArchiveType::Lha, &["application/x-lha"], &[r"(?i)\.(lzh|lha)$"], |path| {
+            Ok(Box::new(LhaArchive::open(path)?))
+        }),
+        #[cfg(feature = "rar")]
+        builtin(
+            ArchiveType::Rar,
+            &["application/vnd.rar", "application/x-rar-compressed", "application/x-rar"],
+            &[r"(?i)\.(rar|r\d\d)$"],
+            |path| Ok(Box::new(RarArchive::open(path)?)),
+        ),
+        #[cfg(feature = "rpm")]
+        builtin(ArchiveType::Rpm, &["application/x-rpm"], &[r"(?i)\.rpm$"], |path| {
+            Ok(Box::new(RpmArchive::open(path)?))
+        }),
+        #[cfg(feature = "wim")]
+        builtin(ArchiveType::Wim, &["application/x-ms-wim"], &[r"(?i)\.(wim|esd)$"], |path| {
+            Ok(Box::new(WimArchive::open(path)?))
+        }),
+        #[cfg(feature = "xar")]
+        builtin(ArchiveType::Xar, &["application/x-xar"], &[r"(?i)\.(xar|pkg)$"], |path| {
+            Ok(Box::new(XarArchive::open(path)?))
+        }),
+        #[cfg(feature = "tar")]
+        tarball(
+            ArchiveType::Tar,
+            &["application/x-tar"],
+            &[r"(?i)\.tar$", r"(?i)\.pax$"],
+            Compression::Uncompressed,
+        ),
+        #[cfg(feature = "tar")]
+        tarball(ArchiveType::TarGz, &[], &[r"(?i)\.t(ar\.gz|gz)$"], Compression::Gz),
+        #[cfg(feature = "tar")]
+        tarball(ArchiveType::TarXz, &[], &[r"(?i)\.t(ar\.xz|xz)$"], Compression::Xz),
+        #[cfg(feature = "tar")]
+        tarball(ArchiveType::TarBz2, &[], &[r"(?i)\.t(ar\.bz2|bz2?)$"], Compression::Bz2),
+        #[cfg(feature = "tar")]
+        tarball(ArchiveType::TarZst, &[], &[r"(?i)\.t(ar\.zst|zst)$"], Compression::Zstd),
+        #[cfg(feature = "tar")]
+        tarball(ArchiveType::TarLz4, &[], &[r"(?i)\.tar\.lz4$"], Compression::Lz4),
+        #[cfg(feature = "tar")]
+        tarball(ArchiveType::TarLzma, &[], &[r"(?i)\.t(ar\.lzma|lz)$"], Compression::Lzma),
+        // lowercase `.z` is the unrelated pack format
+        #[cfg(feature = "tar")]
+        tarball(ArchiveType::TarZ, &[], &[r"(\.tar\.Z|(?i:\.taz))$"], Compression::Compress),
+        single_file(ArchiveType::SingleFileGz, &[], Compression::Gz),
+        single_file(ArchiveType::SingleFileXz, &[], Compression::Xz),
+        single_file(ArchiveType::SingleFileBz2, &[], Compression::Bz2),
+        single_file(ArchiveType::SingleFileZst, &[r"(?i)\.zst$"], Compression::Zstd),
+        single_file(ArchiveType::SingleFileLz4, &[r"(?i)\.lz4$"], Compression::Lz4),
+        // brotli has no magic number so it can only be detected by name
+        single_file(ArchiveType::SingleFileBr, &[r"(?i)\.br$"], Compression::Brotli),
+        single_file(ArchiveType::SingleFileLzma, &[r"(?i)\.lzma$"], Compression::Lzma),
+        single_file(ArchiveType::SingleFileZ, &[r"\.Z$"], Compression::Compress),
+        single_file(ArchiveType::SingleFileSnappy, &[r"(?i)\.sz$"], Compression::Snappy),
+    ];
 
-    /// Mapping of regexes to filenames.
-    static ref BY_PATTERN: Vec<(Regex, ArchiveType)> = vec![
-        (Regex::new(r"(?i)\.ar?$").unwrap(), ArchiveType::Ar),
-        (Regex::new(r"(?i)\.cab?$").unwrap(), ArchiveType::Cab),
-        (Regex::new(r"(?i)\.zip$").unwrap(), ArchiveType::Zip),
-        (Regex::new(r"(?i)\.tar$").unwrap(), ArchiveType::Tar),
-        (Regex::new(r"(?i)\.t(ar\.gz|gz)$").unwrap(), ArchiveType::TarGz),
-        (Regex::new(r"(?i)\.t(ar\.xz|xz)$").unwrap(), ArchiveType::TarXz),
-        (Regex::new(r"(?i)\.t(ar\.bz2|bz2?)$").unwrap(), ArchiveType::TarBz2),
+    /// Signatures at fixed offsets for types tree_magic does not know about.
+    static ref BY_SIGNATURE: Vec<(usize, &'static [u8], ArchiveType)> = vec![
+        #[cfg(feature = "iso")]
+        (0x8001, &b"CD001"[..], ArchiveType::Iso),
+        #[cfg(feature = "cpio")]
+        (0, self::cpio::NEWC_MAGIC, ArchiveType::Cpio),
+        #[cfg(feature = "cpio")]
+        (0, self::cpio::NEWC_CRC_MAGIC, ArchiveType::Cpio),
+        #[cfg(feature = "cpio")]
+        (0, self::cpio::ODC_MAGIC, ArchiveType::Cpio),
+        #[cfg(feature = "cramfs")]
+        (0, self::cramfs::MAGIC, ArchiveType::Cramfs),
+        #[cfg(feature = "cramfs")]
+        (512, self::cramfs::MAGIC, ArchiveType::Cramfs),
+        #[cfg(feature = "romfs")]
+        (0, self::romfs::MAGIC, ArchiveType::Romfs),
+        // tree_magic only knows some of the lha methods
+        #[cfg(feature = "lha")]
+        (2, self::lha::METHOD_PREFIX, ArchiveType::Lha),
+        #[cfg(feature = "rar")]
+        (0, self::rar::RAR4_MAGIC, ArchiveType::Rar),
+        #[cfg(feature = "rar")]
+        (0, self::rar::RAR5_MAGIC, ArchiveType::Rar),
     ];
+
+}
+
+#[cfg(all(feature = "tar", feature = "zip"))]
+#[test]
+fn test_detect_mismatch() {
+    use std::io::Write;
+
+    use ::zip::write::{FileOptions, ZipWriter};
+
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("renamed.tar");
+    let mut w = ZipWriter::new(fs::File::create(&archive_path).unwrap());
+    w.start_file("hello.txt", FileOptions::default()).unwrap();
+    w.write_all(b"hello").unwrap();
+    w.finish().unwrap();
+
+    let detection = ArchiveType::detect(&archive_path);
+    assert_eq!(detection.by_magic, Some(ArchiveType::Zip));
+    assert_eq!(detection.by_filename, Some(ArchiveType::Tar));
+    assert_eq!(detection.archive_type(), Some(ArchiveType::Zip));
+    assert!(detection.is_mismatch());
+
+    fs::rename(&archive_path, dir.join("renamed.zip")).unwrap();
+    assert!(!ArchiveType::detect(&dir.join("renamed.zip")).is_mismatch());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_format_registry() {
+    for ty in ArchiveType::iter() {
+        let handlers = FORMATS.iter().filter(|x| x.archive_type() == ty).count();
+        assert_eq!(handlers, 1, "{} has {} handlers", ty, handlers);
+    }
+    #[cfg(feature = "zip")]
+    assert_eq!(type_for_mimetype("application/zip"), Some(ArchiveType::Zip));
+    assert!(type_for_mimetype("application/gzip").is_none());
+    #[cfg(feature = "tar")]
+    assert_eq!(
+        ArchiveType::determine_by_filename(&"foo.tar.lz4"),
+        Some(ArchiveType::TarLz4)
+    );
+    assert_eq!(
+        ArchiveType::determine_by_filename(&"foo.lz4"),
+        Some(ArchiveType::SingleFileLz4)
+    );
+    for handler in FORMATS.iter() {
+        if let Some((compression, inner)) = handler.compression() {
+            let ty = handler.archive_type();
+            assert_eq!(compression.as_archive_type(inner), Some(ty));
+            assert!(ty.can_stream(), "{} cannot be streamed", ty);
+        }
+    }
+    #[cfg(feature = "tar")]
+    assert_eq!(
+        Compression::for_tarball(ArchiveType::Tar),
+        Some(Compression::Uncompressed)
+    );
+    assert_eq!(
+        Compression::for_archive_type(ArchiveType::SingleFileBr),
+        Some(Compression::Brotli)
+    );
+    #[cfg(feature = "ar")]
+    assert!(ArchiveType::Ar.can_stream());
+    #[cfg(feature = "zip")]
+    assert!(!ArchiveType::Zip.can_stream());
+}
+
+#[test]
+fn test_from_name() {
+    for ty in ArchiveType::iter() {
+        assert_eq!(ArchiveType::from_name(&ty.to_string()), Some(ty));
+        assert_eq!(
+            ArchiveType::from_name(&ty.to_string().to_uppercase()),
+            Some(ty)
+        );
+        for alias in ty.aliases() {
+            assert_eq!(ArchiveType::from_name(alias), Some(ty));
+        }
+    }
+    assert_eq!(
+        ArchiveType::from_name("GZIP"),
+        Some(ArchiveType::SingleFileGz)
+    );
+    assert!(ArchiveType::from_name("7z").is_none());
+}
+
+#[cfg(feature = "tar")]
+#[test]
+fn test_detect_behind_compression() {
+    use std::io::Write;
+
+    use crate::utils::make_test_dir;
+
+    let mut tarball = ::tar::Builder::new(vec![]);
+    let mut header = ::tar::Header::new_gnu();
+    header.set_path("hello.txt").unwrap();
+    header.set_size(5);
+    header.set_cksum();
+    tarball.append(&header, &b"hello"[..]).unwrap();
+    let tarball = tarball.into_inner().unwrap();
+
+    let dir = make_test_dir();
+    for &(compression, ty) in &[
+        (Compression::Gz, ArchiveType::TarGz),
+        (Compression::Xz, ArchiveType::TarXz),
+        (Compression::Bz2, ArchiveType::TarBz2),
+        (Compression::Zstd, ArchiveType::TarZst),
+        (Compression::Lz4, ArchiveType::TarLz4),
+    ] {
+        let path = dir.join("archive.bin");
+        let mut w = compression
+            .compress(fs::File::create(&path).unwrap())
+            .unwrap();
+        w.write_all(&tarball).unwrap();
+        w.finish().unwrap();
+        assert_eq!(ArchiveType::for_path(&path), Some(ty));
+    }
+
+    // legacy lzma has no magic number and is only sniffed with its extension
+    let mut w = Compression::Lzma.compress(vec![]).unwrap();
+    w.write_all(&tarball).unwrap();
+    let compressed = w.finish().unwrap();
+    fs::write(dir.join("archive.bin"), &compressed).unwrap();
+    assert_eq!(ArchiveType::for_path(&dir.join("archive.bin")), None);
+    fs::write(dir.join("archive.lzma"), &compressed).unwrap();
+    assert_eq!(
+        ArchiveType::for_path(&dir.join("archive.lzma")),
+        Some(ArchiveType::TarLzma)
+    );
+    assert_eq!(
+        ArchiveType::for_bytes(&compressed, Some("archive.lzma")),
+        Some(ArchiveType::TarLzma)
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "tar")]
+#[test]
+fn test_detect_lz4_large_blocks() {
+    use crate::utils::make_test_dir;
+
+    // the first block of the frame is way larger than the sniffed bytes
+    let mut state = 0x2545_f491u32;
+    let mut data = vec![];
+    while data.len() < 2_000_000 {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        data.extend(format!("{} ", state % 10_000).as_bytes());
+    }
+    let mut tarball = ::tar::Builder::new(vec![]);
+    let mut header = ::tar::Header::new_gnu();
+    header.set_path("data.bin").unwrap();
+    header.set_size(data.len() as u64);
+    header.set_cksum();
+    tarball.append(&header, &data[..]).unwrap();
+    let tarball = tarball.into_inner().unwrap();
+    let mut w = lz4::EncoderBuilder::new()
+        .block_size(lz4::BlockSize::Max4MB)
+        .build(vec![])
+        .unwrap();
+    std::io::Write::write_all(&mut w, &tarball).unwrap();
+    let (compressed, rv) = w.finish();
+    rv.unwrap();
+    assert!(compressed.len() > MAGIC_SIZE);
+
+    let dir = make_test_dir();
+    let path = dir.join("archive.bin");
+    fs::write(&path, &compressed).unwrap();
+    assert_eq!(ArchiveType::for_path(&path), Some(ArchiveType::TarLz4));
+    assert_eq!(
+        ArchiveType::for_bytes(&compressed, None),
+        Some(ArchiveType::TarLz4)
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "tar")]
+#[test]
+fn test_inner_mimetype() {
+    use std::io::Write;
+
+    use crate::utils::make_test_dir;
+
+    let mut tarball = ::tar::Builder::new(vec![]);
+    let mut header = ::tar::Header::new_gnu();
+    header.set_path("hello.txt").unwrap();
+    header.set_size(5);
+    header.set_cksum();
+    tarball.append(&header, &b"hello"[..]).unwrap();
+    let tarball = tarball.into_inner().unwrap();
+
+    let dir = make_test_dir();
+    let blob: Vec<u8> = (0..4096u32).map(|x| (x * 7919 % 251) as u8).collect();
+    for &(name, contents, ty, mimetype) in &[
+        (
+            "hello.tar.gz",
+            &tarball,
+            ArchiveType::TarGz,
+            "application/x-tar",
+        ),
+        (
+            "hello.txt.gz",
+            &b"hello world\n".to_vec(),
+            ArchiveType::SingleFileGz,
+            "text/plain",
+        ),
+        (
+            "blob.gz",
+            &blob,
+            ArchiveType::SingleFileGz,
+            "application/octet-stream",
+        ),
+    ] {
+        let path = dir.join(name);
+        let mut w = Compression::Gz
+            .compress(fs::File::create(&path).unwrap())
+            .unwrap();
+        w.write_all(contents).unwrap();
+        w.finish().unwrap();
+        assert_eq!(ArchiveType::for_path(&path), Some(ty));
+        assert_eq!(inner_mimetype_for_path(&path, ty), Some(mimetype));
+    }
+    fs::write(dir.join("hello.txt"), b"hello").unwrap();
+    assert_eq!(
+        inner_mimetype_for_path(&dir.join("hello.txt"), ArchiveType::Tar),
+        None
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(all(feature = "tar", feature = "zip"))]
+#[test]
+fn test_for_bytes() {
+    use std::io::Write;
+
+    let zip = build_zip(&[("a.txt", b"a")]);
+    assert_eq!(ArchiveType::for_bytes(&zip, None), Some(ArchiveType::Zip));
+    assert_eq!(
+        ArchiveType::for_bytes(&zip, Some("data.tar")),
+        Some(ArchiveType::Zip)
+    );
+
+    let mut tarball = ::tar::Builder::new(vec![]);
+    let mut header = ::tar::Header::new_gnu();
+    header.set_path("hello.txt").unwrap();
+    header.set_size(5);
+    header.set_cksum();
+    tarball.append(&header, &b"hello"[..]).unwrap();
+    let mut w = Compression::Gz.compress(vec![]).unwrap();
+    w.write_all(&tarball.into_inner().unwrap()).unwrap();
+    let tgz = w.finish().unwrap();
+    assert_eq!(ArchiveType::for_bytes(&tgz, None), Some(ArchiveType::TarGz));
+
+    // unknown contents fall back to the hint name
+    let text = b"just some text\n";
+    assert!(ArchiveType::for_bytes(text, None).is_none());
+    assert_eq!(
+        ArchiveType::for_bytes(text, Some("data.tar.xz")),
+        Some(ArchiveType::TarXz)
+    );
+}
+
+#[cfg(all(feature = "tar", feature = "zip"))]
+#[test]
+fn test_open_reader() {
+    use std::io::Write;
+
+    use crate::archive::{UnpackHelper, UnpackOptions};
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let zip = build_zip(&[("a.txt", b"a"), ("b.txt", b"b")]);
+    let mut archive = ArchiveType::Zip
+        .open_reader(Cursor::new(zip), "memory.zip")
+        .unwrap();
+    assert_eq!(archive.entries().unwrap().len(), 2);
+    let mut helper = UnpackHelper::create(&*archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "memory");
+    assert_eq!(fs::read(rv.join("b.txt")).unwrap(), b"b");
+
+    let mut tarball = ::tar::Builder::new(vec![]);
+    let mut header = ::tar::Header::new_gnu();
+    header.set_path("hello.txt").unwrap();
+    header.set_size(5);
+    header.set_cksum();
+    tarball.append(&header, &b"hello"[..]).unwrap();
+    let mut w = Compression::Gz.compress(vec![]).unwrap();
+    w.write_all(&tarball.into_inner().unwrap()).unwrap();
+    let tgz = w.finish().unwrap();
+    let mut archive = ArchiveType::TarGz
+        .open_reader(Cursor::new(tgz), "stream.tar.gz")
+        .unwrap();
+    assert!(archive.total_size().is_none());
+    let mut helper = UnpackHelper::create(&*archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(fs::read(rv).unwrap(), b"hello");
+
+    // streams can only be read once
+    let err = archive.entries().unwrap_err();
+    assert!(err.to_string().contains("can only be read once"));
+
+    #[cfg(feature = "iso")]
+    assert!(ArchiveType::Iso
+        .open_reader(Cursor::new(vec![]), "image.iso")
+        .is_err());
+    fs::remove_dir_all(&dir).unwrap();
 }
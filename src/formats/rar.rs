@@ -0,0 +1,584 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::archive::{Archive, ArchiveEntry, EntryKind, UnpackHelper};
+use crate::error::{bail, UnboxError};
+use crate::utils::is_unsafe_path;
+
+pub const RAR4_MAGIC: &[u8] = b"Rar!\x1a\x07\x00";
+pub const RAR5_MAGIC: &[u8] = b"Rar!\x1a\x07\x01\x00";
+
+const RAR4_MAIN: u8 = 0x73;
+const RAR4_FILE: u8 = 0x74;
+const RAR4_END: u8 = 0x7b;
+const RAR4_METHOD_STORE: u8 = 0x30;
+
+const RAR5_MAIN: u64 = 1;
+const RAR5_FILE: u64 = 2;
+const RAR5_ENCRYPTION: u64 = 4;
+const RAR5_END: u64 = 5;
+
+lazy_static! {
+    static ref PART_VOLUME: Regex = Regex::new(r"(?i)^(.*\.part)(\d+)(\.rar)$").unwrap();
+    static ref OLD_VOLUME: Regex = Regex::new(r"(?i)^(.*\.)(rar|r\d\d)$").unwrap();
+}
+
+/// A file header as found in a single volume.
+#[derive(Debug)]
+struct FileHeader {
+    name: String,
+    is_dir: bool,
+    stored: bool,
+    size: u64,
+    data_offset: u64,
+    data_size: u64,
+    split_before: bool,
+    split_after: bool,
+}
+
+/// The relevant contents of a single volume.
+#[derive(Debug, Default)]
+struct Volume {
+    is_volume: bool,
+    headers: Vec<FileHeader>,
+}
+
+#[derive(Debug)]
+struct RarEntry {
+    path: PathBuf,
+    is_dir: bool,
+    size: u64,
+    /// The volume, offset and length of each part of the data.
+    parts: Vec<(PathBuf, u64, u64)>,
+}
+
+/// A rar archive, optionally split over multiple volumes.
+///
+/// Only stored rar archives are supported.  Decompression is not
+/// implemented, so archives with compressed entries are rejected when
+/// they are opened rather than half way through unpacking.
+#[derive(Debug)]
+pub struct RarArchive {
+    path: PathBuf,
+    total_size: u64,
+    entries: Vec<RarEntry>,
+    volumes: Vec<PathBuf>,
+}
+
+fn read_u16<R: Read>(rdr: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    rdr.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(rdr: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    rdr.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Reads a variable length integer with 7 bits per byte.
+///
+/// Returns the value and the number of bytes it took up.
+fn read_vint<R: Read>(rdr: &mut R) -> Result<(u64, u64), UnboxError> {
+    let mut rv = 0;
+    for idx in 0..10 {
+        let mut byte = [0u8; 1];
+        rdr.read_exact(&mut byte)?;
+        rv |= u64::from(byte[0] & 0x7f) << (idx * 7);
+        if byte[0] & 0x80 == 0 {
+            return Ok((rv, idx + 1));
+        }
+    }
+    bail!("invalid variable length integer in rar header");
+}
+
+/// Returns the name of the volume following the given one.
+///
+/// This understands both the `name.partN.rar` and the older `name.rNN`
+/// naming schemes.
+fn next_volume_path(path: &Path) -> Option<PathBuf> {
+    let filename = path.file_name()?.to_str()?;
+    let next = if let Some(caps) = PART_VOLUME.captures(filename) {
+        let number = &caps[2];
+        format!(
+            "{}{:0width$}{}",
+            &caps[1],
+            number.parse::<u64>().ok()? + 1,
+            &caps[3],
+            width = number.len()
+        )
+    } else if let Some(caps) = OLD_VOLUME.captures(filename) {
+        let ext = &caps[2];
+        let number = if ext.eq_ignore_ascii_case("rar") {
+            0
+        } else {
+            ext[1..].parse::<u64>().ok()? + 1
+        };
+        format!("{}r{:02}", &caps[1], number)
+    } else {
+        return None;
+    };
+    Some(path.with_file_name(next))
+}
+
+/// Reads the headers of a rar 1.5 to 4.x volume.
+fn read_rar4_volume<R: Read + Seek>(rdr: &mut R) -> Result<Volume, UnboxError> {
+    let mut volume = Volume::default();
+    let mut offset = RAR4_MAGIC.len() as u64;
+    loop {
+        rdr.seek(SeekFrom::Start(offset))?;
+        let crc = match read_u16(rdr) {
+            Ok(crc) => crc,
+            // older archives do not necessarily have an end block
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        };
+        let mut header = vec![0u8; 5];
+        rdr.read_exact(&mut header)?;
+        let kind = header[0];
+        let flags = u16::from_le_bytes([header[1], header[2]]);
+        let header_size = u64::from(u16::from_le_bytes([header[3], header[4]]));
+        if header_size < 7 {
+            bail!("invalid rar block header");
+        }
+        header.resize(header_size as usize - 2, 0);
+        rdr.read_exact(&mut header[5..])?;
+        if crc32fast::hash(&header) as u16 != crc {
+            bail!("corrupted rar block header");
+        }
+
+        let mut fields = &header[5..];
+        let mut add_size = if flags & 0x8000 != 0 || kind == RAR4_FILE {
+            u64::from(read_u32(&mut fields)?)
+        } else {
+            0
+        };
+        match kind {
+            RAR4_MAIN => {
+                if flags & 0x0080 != 0 {
+                    bail!("rar archives with encrypted headers are not supported");
+                }
+                volume.is_volume = flags & 0x0001 != 0;
+            }
+            RAR4_FILE => {
+                let mut size = u64::from(read_u32(&mut fields)?);
+                let mut info = [0u8; 11];
+                fields.read_exact(&mut info)?;
+                let method = info[10];
+                let name_len = read_u16(&mut fields)? as usize;
+                read_u32(&mut fields)?;
+                if flags & 0x0100 != 0 {
+                    add_size |= u64::from(read_u32(&mut fields)?) << 32;
+                    size |= u64::from(read_u32(&mut fields)?) << 32;
+                }
+                if flags & 0x0004 != 0 {
+                    bail!("encrypted rar archives are not supported");
+                }
+                let mut name = fields.get(..name_len).unwrap_or(fields);
+                // unicode names follow the ascii name after a nul byte
+                if let Some(idx) = name.iter().position(|&x| x == 0) {
+                    name = &name[..idx];
+                }
+                volume.headers.push(FileHeader {
+                    name: String::from_utf8_lossy(name).replace('\\', "/"),
+                    is_dir: flags & 0x00e0 == 0x00e0,
+                    stored: method == RAR4_METHOD_STORE,
+                    size,
+                    data_offset: offset + header_size,
+                    data_size: add_size,
+                    split_before: flags & 0x0001 != 0,
+                    split_after: flags & 0x0002 != 0,
+                });
+            }
+            RAR4_END => break,
+            _ => {}
+        }
+        offset += header_size + add_size;
+    }
+    Ok(volume)
+}
+
+/// Reads the headers of a rar 5 volume.
+fn read_rar5_volume<R: Read + Seek>(rdr: &mut R) -> Result<Volume, UnboxError> {
+    let mut volume = Volume::default();
+    let mut offset = RAR5_MAGIC.len() as u64;
+    loop {
+        rdr.seek(SeekFrom::Start(offset))?;
+        let crc = read_u32(rdr)?;
+
+        // the size is covered by the crc so we need its raw bytes.  It is
+        // limited to three bytes.
+        let mut size_buf = vec![];
+        loop {
+            let mut byte = [0u8; 1];
+            rdr.read_exact(&mut byte)?;
+            size_buf.push(byte[0]);
+            if byte[0] & 0x80 == 0 {
+                break;
+            } else if size_buf.len() == 3 {
+                bail!("invalid rar block header");
+            }
+        }
+        let (size, size_len) = read_vint(&mut &size_buf[..])?;
+        let mut header = vec![0u8; size as usize];
+        rdr.read_exact(&mut header)?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&size_buf);
+        hasher.update(&header);
+        if hasher.finalize() != crc {
+            bail!("corrupted rar block header");
+        }
+
+        let mut fields = &header[..];
+        let (kind, _) = read_vint(&mut fields)?;
+        let (flags, _) = read_vint(&mut fields)?;
+        if flags & 0x0001 != 0 {
+            read_vint(&mut fields)?;
+        }
+        let data_size = if flags & 0x0002 != 0 {
+            read_vint(&mut fields)?.0
+        } else {
+            0
+        };
+        let data_offset = offset + 4 + size_len + size;
+        match kind {
+            RAR5_MAIN => volume.is_volume = read_vint(&mut fields)?.0 & 0x0001 != 0,
+            RAR5_FILE => {
+                let (file_flags, _) = read_vint(&mut fields)?;
+                let (file_size, _) = read_vint(&mut fields)?;
+                read_vint(&mut fields)?;
+                if file_flags & 0x0002 != 0 {
+                    read_u32(&mut fields)?;
+                }
+                if file_flags & 0x0004 != 0 {
+                    read_u32(&mut fields)?;
+                }
+                let (compression, _) = read_vint(&mut fields)?;
+                read_vint(&mut fields)?;
+                let (name_len, _) = read_vint(&mut fields)?;
+                let name = match fields.get(..name_len as usize) {
+                    Some(name) => name,
+                    None => bail!("invalid rar file header"),
+                };
+                volume.headers.push(FileHeader {
+                    name: String::from_utf8_lossy(name).to_string(),
+                    is_dir: file_flags & 0x0001 != 0,
+                    stored: (compression >> 7) & 7 == 0,
+                    size: file_size,
+                    data_offset,
+                    data_size,
+                    split_before: flags & 0x0008 != 0,
+                    split_after: flags & 0x0010 != 0,
+                });
+            }
+            RAR5_ENCRYPTION => bail!("encrypted rar archives are not supported"),
+            RAR5_END => break,
+            _ => {}
+        }
+        offset = data_offset + data_size;
+    }
+    Ok(volume)
+}
+
+fn read_volume(path: &Path) -> Result<Volume, UnboxError> {
+    let mut rdr = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    rdr.read_exact(&mut magic)?;
+    if &magic[..] == RAR5_MAGIC {
+        read_rar5_volume(&mut rdr)
+    } else if &magic[..7] == RAR4_MAGIC {
+        read_rar4_volume(&mut rdr)
+    } else {
+        bail!("{} is not a rar archive", path.display());
+    }
+}
+
+impl RarArchive {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, UnboxError> {
+        let path = path.as_ref().canonicalize()?;
+        let mut entries: Vec<RarEntry> = vec![];
+        let mut volume_path = path.clone();
+        let mut volumes = vec![];
+
+        // files split over multiple volumes continue in the next volume so
+        // we follow them until one is not split any more.
+        loop {
+            let volume = read_volume(&volume_path)?;
+            volumes.push(volume_path.clone());
+            let mut continues = false;
+            for header in volume.headers {
+                if !header.stored && !header.is_dir {
+                    bail!(
+                        "{} is compressed, only stored rar archives are supported",
+                        header.name
+                    );
+                }
+                let part = (volume_path.clone(), header.data_offset, header.data_size);
+                match entries.last_mut() {
+                    Some(entry) if header.split_before => entry.parts.push(part),
+                    _ => entries.push(RarEntry {
+                        path: PathBuf::from(&header.name),
+                        is_dir: header.is_dir,
+                        size: header.size,
+                        parts: vec![part],
+                    }),
+                }
+                continues = header.split_after;
+            }
+            if !continues {
+                break;
+            }
+            volume_path = match next_volume_path(&volume_path) {
+                Some(next) if volume.is_volume && next.is_file() => next,
+                Some(next) if volume.is_volume => bail!("missing rar volume {}", next.display()),
+                _ => bail!(
+                    "cannot determine the next volume of {}",
+                    volume_path.display()
+                ),
+            };
+        }
+
+        entries.retain(|x| !is_unsafe_path(&x.path));
+        let total_size = entries.iter().map(|x| x.size).sum();
+        Ok(RarArchive {
+            path,
+            total_size,
+            entries,
+            volumes,
+        })
+    }
+}
+
+impl Archive for RarArchive {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn volumes(&self) -> Vec<PathBuf> {
+        self.volumes.clone()
+    }
+
+    fn total_size(&self) -> Option<u64> {
+        Some(self.total_size)
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        Some(self.entries.len() as u64)
+    }
+
+    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), UnboxError> {
+        for entry in &self.entries {
+            if helper.skip_entry() {
+                continue;
+            }
+            if entry.is_dir {
+                helper.create_dir(&entry.path)?;
+                continue;
+            }
+            let mut rdr: Box<dyn Read> = Box::new(io::empty());
+            for (volume, offset, len) in &entry.parts {
+                let mut f = File::open(volume)?;
+                f.seek(SeekFrom::Start(*offset))?;
+                rdr = Box::new(rdr.chain(f.take(*len)));
+            }
+            helper
+                .write_file_with_progress(&entry.path, rdr.take(entry.size))
+                .map_err(|err| helper.entry_error(&entry.path, err))?;
+        }
+        Ok(())
+    }
+
+    fn entries(&mut self) -> Result<Vec<ArchiveEntry>, UnboxError> {
+        Ok(self
+            .entries
+            .iter()
+            .map(|entry| {
+                let kind = if entry.is_dir {
+                    EntryKind::Directory
+                } else {
+                    EntryKind::File
+                };
+                ArchiveEntry::new(entry.path.to_string_lossy(), entry.size, kind)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+fn rar5_block(kind: u8, flags: u8, fields: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut header = vec![kind, flags | if data.is_empty() { 0 } else { 0x02 }];
+    if !data.is_empty() {
+        assert!(data.len() < 0x80);
+        header.push(data.len() as u8);
+    }
+    header.extend(fields);
+    assert!(header.len() < 0x80);
+    header.insert(0, header.len() as u8);
+    let mut rv = crc32fast::hash(&header).to_le_bytes().to_vec();
+    rv.extend(header);
+    rv.extend(data);
+    rv
+}
+
+#[cfg(test)]
+fn rar5_file(name: &str, flags: u8, data: &[u8], size: usize, is_dir: bool) -> Vec<u8> {
+    let mut fields = vec![if is_dir { 1 } else { 0 }, size as u8, 0, 0, 0];
+    fields.push(name.len() as u8);
+    fields.extend(name.as_bytes());
+    rar5_block(RAR5_FILE as u8, flags, &fields, data)
+}
+
+#[cfg(test)]
+fn rar5_volume(is_volume: bool, blocks: &[Vec<u8>]) -> Vec<u8> {
+    let mut rv = RAR5_MAGIC.to_vec();
+    rv.extend(rar5_block(RAR5_MAIN as u8, 0, &[is_volume as u8], b""));
+    for block in blocks {
+        rv.extend(block);
+    }
+    rv.extend(rar5_block(RAR5_END as u8, 0, &[0], b""));
+    rv
+}
+
+#[test]
+fn test_rar5() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("test.rar");
+    fs::write(
+        &archive_path,
+        rar5_volume(
+            false,
+            &[
+                rar5_file("sub", 0, b"", 0, true),
+                rar5_file("sub/hello.txt", 0, b"hello", 5, false),
+                rar5_file("../evil.txt", 0, b"evil", 4, false),
+            ],
+        ),
+    )
+    .unwrap();
+    assert_eq!(ArchiveType::for_path(&archive_path), Some(ArchiveType::Rar));
+
+    let mut archive = RarArchive::open(&archive_path).unwrap();
+    assert_eq!(archive.entry_count(), Some(2));
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "sub");
+    assert_eq!(fs::read(rv.join("hello.txt")).unwrap(), b"hello");
+    assert!(!dir.join("evil.txt").exists());
+
+    // compression method 3 in the compression info
+    let mut fields = vec![0, 5, 0, 0x80, 0x03, 0, 6];
+    fields.extend(b"hi.txt");
+    let compressed = rar5_block(RAR5_FILE as u8, 0, &fields, b"xxxxx");
+    fs::write(&archive_path, rar5_volume(false, &[compressed])).unwrap();
+    let err = RarArchive::open(&archive_path).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "hi.txt is compressed, only stored rar archives are supported"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_rar5_volumes() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let first = dir.join("multi.part1.rar");
+    fs::write(
+        &first,
+        rar5_volume(true, &[rar5_file("big.txt", 0x10, b"hello ", 11, false)]),
+    )
+    .unwrap();
+    let err = RarArchive::open(&first).unwrap_err();
+    assert!(err.to_string().starts_with("missing rar volume"));
+    assert!(err.to_string().ends_with("multi.part2.rar"));
+
+    fs::write(
+        dir.join("multi.part2.rar"),
+        rar5_volume(true, &[rar5_file("big.txt", 0x08, b"world", 11, false)]),
+    )
+    .unwrap();
+    let mut archive = RarArchive::open(&first).unwrap();
+    assert_eq!(archive.entry_count(), Some(1));
+    assert_eq!(
+        archive.volumes(),
+        vec![first.clone(), dir.join("multi.part2.rar")]
+    );
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(fs::read(rv).unwrap(), b"hello world");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_rar4() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    fn block(kind: u8, flags: u16, fields: &[u8]) -> Vec<u8> {
+        let mut header = vec![kind];
+        header.extend(&flags.to_le_bytes());
+        header.extend(&(fields.len() as u16 + 7).to_le_bytes());
+        header.extend(fields);
+        let mut rv = (crc32fast::hash(&header) as u16).to_le_bytes().to_vec();
+        rv.extend(header);
+        rv
+    }
+
+    fn file(name: &str, data: &[u8], method: u8) -> Vec<u8> {
+        let mut fields = vec![];
+        fields.extend(&(data.len() as u32).to_le_bytes());
+        fields.extend(&(data.len() as u32).to_le_bytes());
+        fields.extend(&[0u8; 9]);
+        fields.extend(&[20, method]);
+        fields.extend(&(name.len() as u16).to_le_bytes());
+        fields.extend(&[0u8; 4]);
+        fields.extend(name.as_bytes());
+        let mut rv = block(RAR4_FILE, 0x8000, &fields);
+        rv.extend(data);
+        rv
+    }
+
+    let build = |method| {
+        let mut rv = RAR4_MAGIC.to_vec();
+        rv.extend(block(RAR4_MAIN, 0, &[0u8; 6]));
+        rv.extend(file("docs\\readme.txt", b"read me", method));
+        rv.extend(block(RAR4_END, 0, b""));
+        rv
+    };
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("old.rar");
+    fs::write(&archive_path, build(0x33)).unwrap();
+    let err = RarArchive::open(&archive_path).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "docs/readme.txt is compressed, only stored rar archives are supported"
+    );
+
+    fs::write(&archive_path, build(RAR4_METHOD_STORE)).unwrap();
+    let mut archive = RarArchive::open(&archive_path).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(fs::read(rv.join("readme.txt")).unwrap(), b"read me");
+    fs::remove_dir_all(&dir).unwrap();
+}
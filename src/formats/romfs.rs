@@ -0,0 +1,266 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::archive::{Archive, ArchiveEntry, EntryKind, UnpackHelper};
+use crate::error::{bail, UnboxError};
+use crate::utils::is_unsafe_path;
+
+pub const MAGIC: &[u8] = b"-rom1fs-";
+
+const TYPE_HARDLINK: u32 = 0;
+const TYPE_DIRECTORY: u32 = 1;
+const TYPE_FILE: u32 = 2;
+
+/// A romfs file header.
+#[derive(Debug, Clone)]
+struct Header {
+    next: u64,
+    kind: u32,
+    spec_info: u64,
+    size: u64,
+    name: Vec<u8>,
+    data_offset: u64,
+}
+
+#[derive(Debug)]
+struct RomfsEntry {
+    path: PathBuf,
+    header: Header,
+}
+
+#[derive(Debug)]
+pub struct RomfsArchive {
+    path: PathBuf,
+    total_size: u64,
+    entries: Vec<RomfsEntry>,
+}
+
+fn align16(value: u64) -> u64 {
+    (value + 15) & !15
+}
+
+/// Reads a nul terminated name padded to 16 bytes.
+fn read_name<R: Read>(rdr: &mut R) -> Result<Vec<u8>, UnboxError> {
+    let mut name = vec![];
+    loop {
+        let mut chunk = [0u8; 16];
+        rdr.read_exact(&mut chunk)?;
+        match chunk.iter().position(|&x| x == 0) {
+            Some(idx) => {
+                name.extend(&chunk[..idx]);
+                return Ok(name);
+            }
+            None => name.extend(&chunk[..]),
+        }
+    }
+}
+
+fn read_header<R: Read + Seek>(rdr: &mut R, offset: u64) -> Result<Header, UnboxError> {
+    let mut buf = [0u8; 16];
+    rdr.seek(SeekFrom::Start(offset))?;
+    rdr.read_exact(&mut buf)?;
+    let word =
+        |idx: usize| u32::from_be_bytes([buf[idx], buf[idx + 1], buf[idx + 2], buf[idx + 3]]);
+    let name = read_name(rdr)?;
+    Ok(Header {
+        next: u64::from(word(0) & !15),
+        kind: word(0) & 7,
+        spec_info: u64::from(word(4)),
+        size: u64::from(word(8)),
+        data_offset: offset + 16 + align16(name.len() as u64 + 1),
+        name,
+    })
+}
+
+/// Recursively reads the directory starting at the given header.
+fn read_directory<R: Read + Seek>(
+    rdr: &mut R,
+    mut offset: u64,
+    prefix: &Path,
+    seen: &mut HashSet<u64>,
+    entries: &mut Vec<RomfsEntry>,
+) -> Result<(), UnboxError> {
+    while offset != 0 {
+        // guard against loops in the linked lists
+        if !seen.insert(offset) {
+            break;
+        }
+        let header = read_header(rdr, offset)?;
+        offset = header.next;
+        if header.name == b"." || header.name == b".." {
+            continue;
+        }
+        let path = prefix.join(String::from_utf8_lossy(&header.name).as_ref());
+        if is_unsafe_path(&path) {
+            continue;
+        }
+
+        // hard links refer to the header of the actual file
+        let header = if header.kind == TYPE_HARDLINK {
+            let target = read_header(rdr, header.spec_info)?;
+            if target.kind != TYPE_FILE {
+                debug!("skipping hard link {}", path.display());
+                continue;
+            }
+            target
+        } else {
+            header
+        };
+
+        match header.kind {
+            TYPE_DIRECTORY => {
+                let first = header.spec_info;
+                entries.push(RomfsEntry { path, header });
+                let prefix = entries.last().unwrap().path.clone();
+                read_directory(rdr, first, &prefix, seen, entries)?;
+            }
+            TYPE_FILE => entries.push(RomfsEntry { path, header }),
+            _ => debug!("skipping special file {}", path.display()),
+        }
+    }
+    Ok(())
+}
+
+impl RomfsArchive {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, UnboxError> {
+        let path = path.as_ref().canonicalize()?;
+        let mut rdr = BufReader::new(File::open(&path)?);
+
+        let mut buf = [0u8; 16];
+        rdr.read_exact(&mut buf)?;
+        if &buf[..8] != MAGIC {
+            bail!("not a romfs image");
+        }
+        let volume_name = read_name(&mut rdr)?;
+        let first = 16 + align16(volume_name.len() as u64 + 1);
+
+        let mut entries = vec![];
+        read_directory(
+            &mut rdr,
+            first,
+            Path::new(""),
+            &mut HashSet::new(),
+            &mut entries,
+        )?;
+
+        let total_size = entries
+            .iter()
+            .filter(|x| x.header.kind == TYPE_FILE)
+            .map(|x| x.header.size)
+            .sum();
+        Ok(RomfsArchive {
+            path,
+            total_size,
+            entries,
+        })
+    }
+}
+
+impl Archive for RomfsArchive {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn total_size(&self) -> Option<u64> {
+        Some(self.total_size)
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        Some(self.entries.len() as u64)
+    }
+
+    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), UnboxError> {
+        let mut f = File::open(&self.path)?;
+        for entry in &self.entries {
+            if helper.skip_entry() {
+                continue;
+            }
+            if entry.header.kind == TYPE_DIRECTORY {
+                helper.create_dir(&entry.path)?;
+            } else {
+                f.seek(SeekFrom::Start(entry.header.data_offset))?;
+                let rdr = (&mut f).take(entry.header.size);
+                helper
+                    .write_file_with_progress(&entry.path, rdr)
+                    .map_err(|err| helper.entry_error(&entry.path, err))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn entries(&mut self) -> Result<Vec<ArchiveEntry>, UnboxError> {
+        Ok(self
+            .entries
+            .iter()
+            .map(|entry| {
+                if entry.header.kind == TYPE_DIRECTORY {
+                    ArchiveEntry::new(entry.path.to_string_lossy(), 0, EntryKind::Directory)
+                } else {
+                    ArchiveEntry::new(
+                        entry.path.to_string_lossy(),
+                        entry.header.size,
+                        EntryKind::File,
+                    )
+                }
+            })
+            .collect())
+    }
+}
+
+#[test]
+fn test_romfs() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    fn header(next: u32, kind: u32, spec_info: u32, data: &[u8], name: &[u8]) -> Vec<u8> {
+        let mut rv = vec![];
+        rv.extend(&(next | kind).to_be_bytes());
+        rv.extend(&spec_info.to_be_bytes());
+        rv.extend(&(data.len() as u32).to_be_bytes());
+        rv.extend(&[0u8; 4]);
+        rv.extend(name);
+        rv.resize(align16(rv.len() as u64 + 1) as usize, 0);
+        rv.extend(data);
+        rv.resize(align16(rv.len() as u64) as usize, 0);
+        rv
+    }
+
+    // layout: superblock at 0, root entries "." at 32, "sub" at 64 and
+    // "a.txt" at 96.  The sub directory has "b.txt" at 144 and a hard link
+    // to "a.txt" at 192.
+    let mut image = vec![];
+    image.extend(MAGIC);
+    image.extend(&[0u8; 8]);
+    image.extend(b"volume\0\0\0\0\0\0\0\0\0\0");
+    image.extend(header(64, 0, 32, b"", b"."));
+    image.extend(header(96, TYPE_DIRECTORY, 144, b"", b"sub"));
+    image.extend(header(0, TYPE_FILE, 0, b"hello", b"a.txt"));
+    image.extend(header(192, TYPE_FILE, 0, b"world", b"b.txt"));
+    image.extend(header(0, TYPE_HARDLINK, 96, b"", b"link.txt"));
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("image.romfs");
+    fs::write(&archive_path, &image).unwrap();
+    assert_eq!(
+        ArchiveType::for_path(&archive_path),
+        Some(ArchiveType::Romfs)
+    );
+
+    let mut archive = RomfsArchive::open(&archive_path).unwrap();
+    assert_eq!(archive.total_size(), Some(15));
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "image");
+    assert_eq!(fs::read(rv.join("a.txt")).unwrap(), b"hello");
+    assert_eq!(fs::read(rv.join("sub/b.txt")).unwrap(), b"world");
+    assert_eq!(fs::read(rv.join("sub/link.txt")).unwrap(), b"hello");
+    fs::remove_dir_all(&dir).unwrap();
+}
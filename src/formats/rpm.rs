@@ -0,0 +1,400 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::archive::{Archive, ArchiveEntry, UnpackHelper};
+use crate::error::{bail, UnboxError};
+use crate::formats::cpio::{cpio_entries, unpack_cpio};
+use crate::formats::Compression;
+
+const LEAD_MAGIC: &[u8] = b"\xed\xab\xee\xdb";
+const HEADER_MAGIC: &[u8] = b"\x8e\xad\xe8";
+
+const LEAD_SIZE: u64 = 96;
+
+const TAG_NAME: u32 = 1000;
+const TAG_VERSION: u32 = 1001;
+const TAG_RELEASE: u32 = 1002;
+const TAG_ARCH: u32 = 1022;
+const TAG_REQUIREFLAGS: u32 = 1048;
+const TAG_REQUIRENAME: u32 = 1049;
+const TAG_REQUIREVERSION: u32 = 1050;
+const TAG_PAYLOADFORMAT: u32 = 1124;
+const TAG_PAYLOADCOMPRESSOR: u32 = 1125;
+
+const TYPE_INT32: u32 = 4;
+const TYPE_STRING: u32 = 6;
+const TYPE_STRING_ARRAY: u32 = 8;
+
+/// The comparison bits of the dependency flags.
+const SENSE_LESS: u32 = 0x02;
+const SENSE_GREATER: u32 = 0x04;
+const SENSE_EQUAL: u32 = 0x08;
+
+/// The tags of the header reported as metadata.
+const METADATA_TAGS: [(&str, u32); 4] = [
+    ("Name", TAG_NAME),
+    ("Version", TAG_VERSION),
+    ("Release", TAG_RELEASE),
+    ("Architecture", TAG_ARCH),
+];
+
+/// A header structure of an rpm with its index and data store.
+#[derive(Debug)]
+struct Header {
+    /// The tag, type, offset and count of each value.
+    index: Vec<(u32, u32, u32, u32)>,
+    store: Vec<u8>,
+}
+
+impl Header {
+    /// Reads a header and returns it with its size in bytes.
+    fn read<R: Read>(rdr: &mut R) -> Result<(Header, u64), UnboxError> {
+        let mut buf = [0u8; 16];
+        rdr.read_exact(&mut buf)?;
+        if &buf[..3] != HEADER_MAGIC {
+            bail!("invalid rpm header");
+        }
+        let word = |buf: &[u8], idx: usize| {
+            u32::from_be_bytes([buf[idx], buf[idx + 1], buf[idx + 2], buf[idx + 3]])
+        };
+        let count = word(&buf, 8);
+        let store_size = word(&buf, 12);
+        if count > 0x10000 || store_size > 0x1000_0000 {
+            bail!("rpm header is too large");
+        }
+
+        let mut entries = vec![0u8; count as usize * 16];
+        rdr.read_exact(&mut entries)?;
+        let index = entries
+            .chunks(16)
+            .map(|x| (word(x, 0), word(x, 4), word(x, 8), word(x, 12)))
+            .collect();
+        let mut store = vec![0u8; store_size as usize];
+        rdr.read_exact(&mut store)?;
+        let size = 16 + u64::from(count) * 16 + u64::from(store_size);
+        Ok((Header { index, store }, size))
+    }
+
+    /// Returns the data and count of a tag with the given type.
+    fn get(&self, tag: u32, ty: u32) -> Option<(&[u8], u32)> {
+        let &(_, _, offset, count) = self
+            .index
+            .iter()
+            .find(|&&(x, x_ty, _, _)| x == tag && x_ty == ty)?;
+        Some((self.store.get(offset as usize..)?, count))
+    }
+
+    /// Returns the value of a string tag.
+    fn get_string(&self, tag: u32) -> Option<String> {
+        let (value, _) = self.get(tag, TYPE_STRING)?;
+        let end = value.iter().position(|&x| x == 0)?;
+        Some(String::from_utf8_lossy(&value[..end]).to_string())
+    }
+
+    /// Returns the values of a string array tag.
+    fn get_string_array(&self, tag: u32) -> Option<Vec<String>> {
+        let (mut value, count) = self.get(tag, TYPE_STRING_ARRAY)?;
+        let mut rv = vec![];
+        for _ in 0..count {
+            let end = value.iter().position(|&x| x == 0)?;
+            rv.push(String::from_utf8_lossy(&value[..end]).to_string());
+            value = &value[end + 1..];
+        }
+        Some(rv)
+    }
+
+    /// Returns the values of an integer array tag.
+    fn get_u32_array(&self, tag: u32) -> Option<Vec<u32>> {
+        let (value, count) = self.get(tag, TYPE_INT32)?;
+        let value = value.get(..count as usize * 4)?;
+        Some(
+            value
+                .chunks(4)
+                .map(|x| u32::from_be_bytes([x[0], x[1], x[2], x[3]]))
+                .collect(),
+        )
+    }
+
+    /// Returns the requirements of the package such as `bash >= 4.0`.
+    fn get_requires(&self) -> Option<String> {
+        let names = self.get_string_array(TAG_REQUIRENAME)?;
+        let versions = self
+            .get_string_array(TAG_REQUIREVERSION)
+            .unwrap_or_default();
+        let flags = self.get_u32_array(TAG_REQUIREFLAGS).unwrap_or_default();
+        let requires: Vec<_> = names
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| {
+                let version = versions.get(idx).filter(|x| !x.is_empty());
+                let flags = flags.get(idx).copied().unwrap_or(0);
+                let operator = match flags & (SENSE_LESS | SENSE_GREATER | SENSE_EQUAL) {
+                    SENSE_LESS => "<",
+                    x if x == SENSE_LESS | SENSE_EQUAL => "<=",
+                    SENSE_GREATER => ">",
+                    x if x == SENSE_GREATER | SENSE_EQUAL => ">=",
+                    SENSE_EQUAL => "=",
+                    _ => return name.clone(),
+                };
+                match version {
+                    Some(version) => format!("{} {} {}", name, operator, version),
+                    None => name.clone(),
+                }
+            })
+            .collect();
+        Some(requires.join(", "))
+    }
+}
+
+#[derive(Debug)]
+pub struct RpmArchive {
+    path: PathBuf,
+    total_size: u64,
+    payload_offset: u64,
+    compression: Compression,
+    metadata: Vec<(String, String)>,
+}
+
+impl RpmArchive {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, UnboxError> {
+        let path = path.as_ref().canonicalize()?;
+        let total_size = path.metadata()?.len();
+        let mut rdr = BufReader::new(File::open(&path)?);
+
+        let mut lead = [0u8; LEAD_SIZE as usize];
+        rdr.read_exact(&mut lead)?;
+        if &lead[..4] != LEAD_MAGIC {
+            bail!("not an rpm package");
+        }
+
+        // the signature header is padded to eight bytes, the main header
+        // directly follows and is in turn followed by the payload.
+        let (_, signature_size) = Header::read(&mut rdr)?;
+        let padding = (8 - signature_size % 8) % 8;
+        rdr.seek(SeekFrom::Current(padding as i64))?;
+        let (header, header_size) = Header::read(&mut rdr)?;
+        let payload_offset = LEAD_SIZE + signature_size + padding + header_size;
+
+        match header.get_string(TAG_PAYLOADFORMAT).as_deref() {
+            None | Some("cpio") => {}
+            Some(format) => bail!("unsupported rpm payload format '{}'", format),
+        }
+        let compression = match header.get_string(TAG_PAYLOADCOMPRESSOR).as_deref() {
+            None | Some("gzip") => Compression::Gz,
+            Some("xz") => Compression::Xz,
+            Some("zstd") => Compression::Zstd,
+            Some("bzip2") => Compression::Bz2,
+            Some("lzma") => Compression::Lzma,
+            Some(compressor) => bail!("unsupported rpm payload compression '{}'", compressor),
+        };
+        let mut metadata: Vec<_> = METADATA_TAGS
+            .iter()
+            .filter_map(|&(key, tag)| Some((key.to_string(), header.get_string(tag)?)))
+            .collect();
+        if let Some(requires) = header.get_requires() {
+            metadata.push(("Requires".to_string(), requires));
+        }
+
+        Ok(RpmArchive {
+            path,
+            total_size,
+            payload_offset,
+            compression,
+            metadata,
+        })
+    }
+
+    /// Opens the file positioned at the start of the payload.
+    fn open_payload(&self) -> Result<File, UnboxError> {
+        let mut f = File::open(&self.path)?;
+        f.seek(SeekFrom::Start(self.payload_offset))?;
+        Ok(f)
+    }
+}
+
+impl Archive for RpmArchive {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn total_size(&self) -> Option<u64> {
+        Some(self.total_size - self.payload_offset)
+    }
+
+    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), UnboxError> {
+        let rdr = helper.wrap_read(self.open_payload()?);
+        unpack_cpio(self.compression.decompress(rdr)?, helper)
+    }
+
+    fn entries(&mut self) -> Result<Vec<ArchiveEntry>, UnboxError> {
+        cpio_entries(self.compression.decompress(self.open_payload()?)?)
+    }
+
+    fn metadata(&self) -> Result<Vec<(String, String)>, UnboxError> {
+        Ok(self.metadata.clone())
+    }
+}
+
+/// Builds an rpm package with the given header tags, requirements (name,
+/// flags and version) and payload.
+#[cfg(test)]
+fn build_rpm(tags: &[(u32, &str)], requires: &[(&str, u32, &str)], payload: &[u8]) -> Vec<u8> {
+    let header = |tags: &[(u32, &str)], requires: &[(&str, u32, &str)]| {
+        let mut values = vec![];
+        for &(tag, value) in tags {
+            values.push((tag, TYPE_STRING, 1, format!("{}\0", value).into_bytes()));
+        }
+        if !requires.is_empty() {
+            let count = requires.len() as u32;
+            let flags = requires.iter().flat_map(|x| x.1.to_be_bytes()).collect();
+            let names = requires
+                .iter()
+                .flat_map(|x| format!("{}\0", x.0).into_bytes());
+            let versions = requires
+                .iter()
+                .flat_map(|x| format!("{}\0", x.2).into_bytes());
+            values.push((TAG_REQUIREFLAGS, TYPE_INT32, count, flags));
+            values.push((TAG_REQUIRENAME, TYPE_STRING_ARRAY, count, names.collect()));
+            values.push((
+                TAG_REQUIREVERSION,
+                TYPE_STRING_ARRAY,
+                count,
+                versions.collect(),
+            ));
+        }
+        let mut index: Vec<u8> = vec![];
+        let mut store = vec![];
+        for (tag, ty, count, data) in &values {
+            // integers are aligned to their size
+            if *ty == TYPE_INT32 {
+                store.resize(store.len().next_multiple_of(4), 0);
+            }
+            for &word in &[*tag, *ty, store.len() as u32, *count] {
+                index.extend(&word.to_be_bytes());
+            }
+            store.extend(data);
+        }
+        let mut rv = HEADER_MAGIC.to_vec();
+        rv.extend(&[1, 0, 0, 0, 0]);
+        rv.extend(&(values.len() as u32).to_be_bytes());
+        rv.extend(&(store.len() as u32).to_be_bytes());
+        rv.extend(index);
+        rv.extend(store);
+        rv
+    };
+
+    let mut rv = LEAD_MAGIC.to_vec();
+    rv.resize(LEAD_SIZE as usize, 0);
+    let signature = header(&[(1000, "x")], &[]);
+    rv.extend(&signature);
+    rv.resize(rv.len() + (8 - signature.len() % 8) % 8, 0);
+    rv.extend(header(tags, requires));
+    rv.extend(payload);
+    rv
+}
+
+#[test]
+fn test_rpm() {
+    use std::fs;
+    use std::io::Write;
+
+    use crate::archive::UnpackOptions;
+    use crate::formats::cpio::build_newc_cpio;
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    let cpio = build_newc_cpio(&[
+        ("./usr", 0o040_755, b""),
+        ("./usr/bin", 0o040_755, b""),
+        ("./usr/bin/hello", 0o100_755, b"#!/bin/sh\necho hello\n"),
+        ("./usr/share/doc/hello/README", 0o100_644, b"hello\n"),
+    ]);
+    let mut w = Compression::Xz.compress(vec![]).unwrap();
+    w.write_all(&cpio).unwrap();
+    let payload = w.finish().unwrap();
+    let tags = [
+        (TAG_NAME, "hello"),
+        (TAG_VERSION, "1.0"),
+        (TAG_RELEASE, "1"),
+        (TAG_ARCH, "noarch"),
+        (TAG_PAYLOADFORMAT, "cpio"),
+        (TAG_PAYLOADCOMPRESSOR, "xz"),
+    ];
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("hello-1.0-1.noarch.rpm");
+    let requires = [
+        ("/bin/sh", 0, ""),
+        ("glibc", SENSE_GREATER | SENSE_EQUAL, "2.17"),
+        (
+            "rpmlib(CompressedFileNames)",
+            SENSE_LESS | SENSE_EQUAL,
+            "3.0.4-1",
+        ),
+    ];
+    fs::write(&archive_path, build_rpm(&tags, &requires, &payload)).unwrap();
+    fs::copy(&archive_path, dir.join("renamed.bin")).unwrap();
+    assert_eq!(ArchiveType::for_path(&archive_path), Some(ArchiveType::Rpm));
+    assert_eq!(
+        ArchiveType::for_path(&dir.join("renamed.bin")),
+        Some(ArchiveType::Rpm)
+    );
+
+    let mut archive = RpmArchive::open(&archive_path).unwrap();
+    assert_eq!(
+        archive.metadata().unwrap(),
+        vec![
+            ("Name".to_string(), "hello".to_string()),
+            ("Version".to_string(), "1.0".to_string()),
+            ("Release".to_string(), "1".to_string()),
+            ("Architecture".to_string(), "noarch".to_string()),
+            (
+                "Requires".to_string(),
+                "/bin/sh, glibc >= 2.17, rpmlib(CompressedFileNames) <= 3.0.4-1".to_string()
+            ),
+        ]
+    );
+    assert_eq!(archive.entries().unwrap().len(), 4);
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "usr");
+    assert_eq!(
+        fs::read(rv.join("bin/hello")).unwrap(),
+        b"#!/bin/sh\necho hello\n"
+    );
+    assert_eq!(
+        fs::read(rv.join("share/doc/hello/README")).unwrap(),
+        b"hello\n"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+
+    // older packages use lzma compressed payloads
+    let dir = make_test_dir();
+    let archive_path = dir.join("lzma.rpm");
+    let mut w = Compression::Lzma.compress(vec![]).unwrap();
+    w.write_all(&cpio).unwrap();
+    let tags = [(TAG_PAYLOADCOMPRESSOR, "lzma")];
+    fs::write(&archive_path, build_rpm(&tags, &[], &w.finish().unwrap())).unwrap();
+    let mut archive = RpmArchive::open(&archive_path).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(
+        fs::read(rv.join("share/doc/hello/README")).unwrap(),
+        b"hello\n"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+
+    // unsupported payloads are rejected upfront
+    let dir = make_test_dir();
+    let archive_path = dir.join("lz4.rpm");
+    let tags = [(TAG_PAYLOADCOMPRESSOR, "lz4")];
+    fs::write(&archive_path, build_rpm(&tags, &[], &payload)).unwrap();
+    assert_eq!(
+        RpmArchive::open(&archive_path).unwrap_err().to_string(),
+        "unsupported rpm payload compression 'lz4'"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,332 @@
+use std::convert::TryFrom;
+use std::io::{Read, Seek};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use cms::cert::x509::der::asn1::OctetStringRef;
+use cms::cert::x509::der::oid::ObjectIdentifier;
+use cms::cert::x509::der::{Decode, Encode};
+use cms::cert::x509::Certificate;
+use cms::cert::CertificateChoices;
+use cms::content_info::ContentInfo;
+use cms::signed_data::{SignedData, SignerIdentifier};
+use rsa::pkcs1v15::{Signature, VerifyingKey};
+use rsa::pkcs8::DecodePublicKey;
+use rsa::signature::Verifier;
+use rsa::RsaPublicKey;
+use sha1::Sha1;
+use sha2::{Digest as _, Sha256};
+use zip::read::ZipArchive as ZipArchiveReader;
+
+use crate::error::{bail, UnboxError};
+
+const OID_SHA1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.14.3.2.26");
+const OID_SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.1");
+const OID_MESSAGE_DIGEST: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.4");
+
+/// The digest algorithms used by jar signatures.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum DigestAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+impl DigestAlgorithm {
+    /// The prefix of the manifest attributes holding these digests.
+    fn attribute_prefix(self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha1 => "SHA1",
+            DigestAlgorithm::Sha256 => "SHA-256",
+        }
+    }
+
+    fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            DigestAlgorithm::Sha1 => Sha1::digest(data).to_vec(),
+            DigestAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+        }
+    }
+
+    /// Verifies an RSA PKCS#1 v1.5 signature made with this digest.
+    fn verify_rsa(self, key: RsaPublicKey, msg: &[u8], signature: &[u8]) -> Result<(), UnboxError> {
+        let signature = Signature::try_from(signature)?;
+        match self {
+            DigestAlgorithm::Sha1 => VerifyingKey::<Sha1>::new(key).verify(msg, &signature)?,
+            DigestAlgorithm::Sha256 => VerifyingKey::<Sha256>::new(key).verify(msg, &signature)?,
+        }
+        Ok(())
+    }
+}
+
+/// A manifest section as list of attributes.
+type Section = Vec<(String, String)>;
+
+/// Parses a manifest or signature file into its sections.
+///
+/// The first section holds the main attributes.  Long values are continued
+/// on the next line starting with a single space.
+fn parse_manifest(data: &[u8]) -> Result<Vec<Section>, UnboxError> {
+    let text = String::from_utf8_lossy(data);
+    let mut sections = vec![];
+    let mut section: Section = vec![];
+    for line in text.split('\n').map(|x| x.trim_end_matches('\r')) {
+        if let Some(continuation) = line.strip_prefix(' ') {
+            match section.last_mut() {
+                Some(attr) => attr.1.push_str(continuation),
+                None => bail!("invalid manifest continuation line"),
+            }
+        } else if line.is_empty() {
+            if !section.is_empty() {
+                sections.push(section);
+                section = vec![];
+            }
+        } else if let Some(idx) = line.find(": ") {
+            section.push((line[..idx].to_string(), line[idx + 2..].to_string()));
+        } else {
+            bail!("invalid manifest line '{}'", line);
+        }
+    }
+    if !section.is_empty() {
+        sections.push(section);
+    }
+    Ok(sections)
+}
+
+fn get_attr<'a>(section: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    section
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, v)| v.as_str())
+}
+
+/// Looks up a base64 encoded digest attribute of a section.
+fn get_digest(
+    section: &[(String, String)],
+    suffix: &str,
+) -> Result<Option<(DigestAlgorithm, Vec<u8>)>, UnboxError> {
+    for &algorithm in &[DigestAlgorithm::Sha256, DigestAlgorithm::Sha1] {
+        let key = format!("{}-{}", algorithm.attribute_prefix(), suffix);
+        if let Some(value) = get_attr(section, &key) {
+            return Ok(Some((algorithm, BASE64.decode(value)?)));
+        }
+    }
+    Ok(None)
+}
+
+fn read_entry<R: Read + Seek>(
+    rdr: &mut ZipArchiveReader<R>,
+    name: &str,
+) -> Result<Vec<u8>, UnboxError> {
+    let mut rv = vec![];
+    rdr.by_name(name)?.read_to_end(&mut rv)?;
+    Ok(rv)
+}
+
+/// Verifies the PKCS#7 signature block over the signature file.
+///
+/// Returns the subject of the signing certificate.
+fn verify_signature_block(block: &[u8], signature_file: &[u8]) -> Result<String, UnboxError> {
+    let invalid = |err| UnboxError::Message(format!("invalid signature block: {}", err));
+    let signed_data: SignedData = ContentInfo::from_der(block)
+        .map_err(invalid)?
+        .content
+        .decode_as()
+        .map_err(invalid)?;
+    let signer = match signed_data.signer_infos.0.iter().next() {
+        Some(signer) => signer,
+        None => bail!("signature block has no signer"),
+    };
+    let digest_algorithm = match signer.digest_alg.oid {
+        OID_SHA1 => DigestAlgorithm::Sha1,
+        OID_SHA256 => DigestAlgorithm::Sha256,
+        oid => bail!("unsupported digest algorithm {}", oid),
+    };
+
+    // find the certificate of the signer
+    let certificate: &Certificate = signed_data
+        .certificates
+        .iter()
+        .flat_map(|x| x.0.iter())
+        .filter_map(|x| match x {
+            CertificateChoices::Certificate(cert) => Some(cert),
+            _ => None,
+        })
+        .find(|cert| match signer.sid {
+            SignerIdentifier::IssuerAndSerialNumber(ref id) => {
+                cert.tbs_certificate.issuer == id.issuer
+                    && cert.tbs_certificate.serial_number == id.serial_number
+            }
+            SignerIdentifier::SubjectKeyIdentifier(..) => false,
+        })
+        .ok_or_else(|| UnboxError::Message("certificate of signer not found".into()))?;
+    let spki = certificate
+        .tbs_certificate
+        .subject_public_key_info
+        .to_der()
+        .map_err(invalid)?;
+    let key = RsaPublicKey::from_public_key_der(&spki)
+        .map_err(|_| UnboxError::Message("only RSA signatures are supported".into()))?;
+
+    // with signed attributes the signature covers those and they in turn
+    // carry the digest of the signature file.
+    let signed_attrs;
+    let msg = match signer.signed_attrs {
+        Some(ref attrs) => {
+            let digest = attrs
+                .iter()
+                .find(|x| x.oid == OID_MESSAGE_DIGEST)
+                .and_then(|x| x.values.iter().next())
+                .ok_or_else(|| UnboxError::Message("signature block has no message digest".into()))?
+                .decode_as::<OctetStringRef>()
+                .map_err(invalid)?;
+            if digest.as_bytes() != &digest_algorithm.digest(signature_file)[..] {
+                bail!("signature file does not match the signature block");
+            }
+            signed_attrs = attrs.to_der().map_err(invalid)?;
+            &signed_attrs[..]
+        }
+        None => signature_file,
+    };
+    digest_algorithm
+        .verify_rsa(key, msg, signer.signature.as_bytes())
+        .map_err(|_| UnboxError::Message("signature does not verify".into()))?;
+
+    Ok(certificate.tbs_certificate.subject.to_string())
+}
+
+/// Checks if an entry is the manifest or part of a signature.
+///
+/// These are the only entries that are not covered by the manifest.
+fn is_signature_entry(name: &str) -> bool {
+    let name = match name.strip_prefix("META-INF/") {
+        Some(name) if !name.contains('/') => name.to_uppercase(),
+        _ => return false,
+    };
+    name == "MANIFEST.MF"
+        || [".SF", ".RSA", ".DSA", ".EC"]
+            .iter()
+            .any(|ext| name.ends_with(ext))
+}
+
+/// Verifies the v1 signature of a signed jar (or v1 signed apk).
+///
+/// This checks the signature block over the signature file, the digest of
+/// the manifest in the signature file and the digests of all entries in
+/// the manifest.  Every file except for the manifest and the signature
+/// files must be signed.  Returns the identity of the signer which is not
+/// checked against any trusted certificates.  APK v2 signing blocks are
+/// not supported.
+pub fn verify_jar<R: Read + Seek>(rdr: &mut ZipArchiveReader<R>) -> Result<String, UnboxError> {
+    let names: Vec<String> = (0..rdr.len())
+        .map(|idx| Ok(rdr.by_index(idx)?.name().to_string()))
+        .collect::<Result<_, UnboxError>>()?;
+
+    // entries are looked up by name, so a second entry with the name of a
+    // signed one would go unchecked
+    let mut seen = std::collections::HashSet::new();
+    if let Some(name) = names.iter().find(|x| !seen.insert(x.as_str())) {
+        bail!("duplicate entry {}", name);
+    }
+    let signature_file_name = match names
+        .iter()
+        .find(|x| x.starts_with("META-INF/") && x.to_uppercase().ends_with(".SF"))
+    {
+        Some(name) => name.clone(),
+        None => bail!("archive is not signed"),
+    };
+    let stem = &signature_file_name[..signature_file_name.len() - 3];
+    let block_name = match names
+        .iter()
+        .find(|x| x.eq_ignore_ascii_case(&format!("{}.RSA", stem)))
+    {
+        Some(name) => name.clone(),
+        None => bail!("only RSA signatures are supported"),
+    };
+
+    let manifest = read_entry(rdr, "META-INF/MANIFEST.MF")?;
+    let signature_file = read_entry(rdr, &signature_file_name)?;
+    let signer = verify_signature_block(&read_entry(rdr, &block_name)?, &signature_file)?;
+
+    // the signature file signs the manifest
+    let signature_sections = parse_manifest(&signature_file)?;
+    match get_digest(
+        signature_sections.first().map_or(&[][..], |x| &x[..]),
+        "Digest-Manifest",
+    )? {
+        Some((algorithm, digest)) => {
+            if algorithm.digest(&manifest) != digest {
+                bail!("manifest does not match the signature file");
+            }
+        }
+        None => bail!("signature file has no manifest digest"),
+    }
+
+    // and the manifest signs the entries
+    let mut signed = vec![];
+    for section in parse_manifest(&manifest)?.iter().skip(1) {
+        let name = match get_attr(section, "Name") {
+            Some(name) => name,
+            None => continue,
+        };
+        if let Some((algorithm, digest)) = get_digest(section, "Digest")? {
+            if algorithm.digest(&read_entry(rdr, name)?) != digest {
+                bail!("digest of {} does not match", name);
+            }
+            signed.push(name.to_string());
+        }
+    }
+    for name in &names {
+        if !name.ends_with('/') && !is_signature_entry(name) && !signed.contains(name) {
+            bail!("{} is not signed", name);
+        }
+    }
+
+    Ok(signer)
+}
+
+#[test]
+fn test_verify_jar() {
+    use std::io::Cursor;
+
+    use crate::formats::build_zip;
+
+    let jar = &include_bytes!("../../tests/fixtures/signed.jar")[..];
+    let mut rdr = ZipArchiveReader::new(Cursor::new(jar)).unwrap();
+    let signer = verify_jar(&mut rdr).unwrap();
+    assert_eq!(signer, "CN=Unbox Test Signer,O=Unbox");
+
+    // tampering with an entry or adding one breaks the signature
+    let mut entries = vec![];
+    for idx in 0..rdr.len() {
+        let mut file = rdr.by_index(idx).unwrap();
+        let mut contents = vec![];
+        file.read_to_end(&mut contents).unwrap();
+        entries.push((file.name().to_string(), contents));
+    }
+    let rebuild = |entries: &[(String, Vec<u8>)]| {
+        let entries: Vec<(&str, &[u8])> = entries.iter().map(|(a, b)| (&a[..], &b[..])).collect();
+        ZipArchiveReader::new(Cursor::new(build_zip(&entries))).unwrap()
+    };
+    assert!(verify_jar(&mut rebuild(&entries)).is_ok());
+
+    let mut tampered = entries.clone();
+    tampered.last_mut().unwrap().1 = b"evil\n".to_vec();
+    assert!(verify_jar(&mut rebuild(&tampered)).is_err());
+
+    let mut added = entries.clone();
+    added.push(("added.txt".to_string(), b"evil\n".to_vec()));
+    assert!(verify_jar(&mut rebuild(&added)).is_err());
+
+    // only the signature files themselves are exempt within META-INF
+    let mut hidden = entries.clone();
+    hidden.push(("META-INF/evil.class".to_string(), b"evil\n".to_vec()));
+    let err = verify_jar(&mut rebuild(&hidden)).unwrap_err();
+    assert_eq!(err.to_string(), "META-INF/evil.class is not signed");
+
+    // a second entry with the name of a signed one
+    let mut duplicate = entries.clone();
+    let signed_entry = duplicate.last().unwrap().0.clone();
+    duplicate.push((signed_entry.clone(), b"evil\n".to_vec()));
+    let err = verify_jar(&mut rebuild(&duplicate)).unwrap_err();
+    assert_eq!(err.to_string(), format!("duplicate entry {}", signed_entry));
+}
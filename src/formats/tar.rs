@@ -1,30 +1,229 @@
-use std::fs::File;
-use std::io::BufReader;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 
-use failure::Error;
+use filetime::FileTime;
+use log::debug;
 use tar::Archive as TarArchiveReader;
+use tar::Builder as TarBuilder;
+use tar::{Entry, EntryType, Header};
 
-use crate::archive::{Archive, UnpackHelper};
+use crate::archive::{
+    open_source, Archive, ArchiveEntry, EntryKind, EntryMetadata, StreamSource, UnpackHelper,
+};
+use crate::error::UnboxError;
 use crate::formats::Compression;
+use crate::utils::safe_join;
 
 #[derive(Debug)]
 pub struct TarArchive {
     path: PathBuf,
-    total_size: u64,
+    total_size: Option<u64>,
     compression: Compression,
+    source: Option<StreamSource>,
 }
 
 impl TarArchive {
-    pub fn open<P: AsRef<Path>>(path: P, compression: Compression) -> Result<Self, Error> {
+    pub fn open<P: AsRef<Path>>(path: P, compression: Compression) -> Result<Self, UnboxError> {
         let path = path.as_ref().canonicalize()?;
         let total_size = path.metadata()?.len();
         Ok(TarArchive {
             path,
-            total_size,
+            total_size: Some(total_size),
             compression,
+            source: None,
         })
     }
+
+    /// Opens a tarball from a stream instead of a file.
+    ///
+    /// The name stands in for the path of the archive.  The stream can
+    /// only be read once, so the tarball can either be listed or unpacked.
+    pub fn from_reader<R: Read + 'static>(rdr: R, name: &str, compression: Compression) -> Self {
+        TarArchive {
+            path: PathBuf::from(name),
+            total_size: None,
+            compression,
+            source: Some(StreamSource::new(rdr)),
+        }
+    }
+
+    fn open_source(&self) -> Result<Box<dyn Read>, UnboxError> {
+        open_source(&self.path, self.source.as_ref())
+    }
+}
+
+/// Applies the unpack options to an entry unpacked into the scratchpad.
+///
+/// Tar restores permissions itself, so if they should be ignored files and
+/// directories are reset to the default permissions instead.  The
+/// modification times of directories are restored last by the helper.
+/// Files are counted with the given size.
+fn finish_entry(
+    helper: &mut UnpackHelper,
+    header: &Header,
+    size: u64,
+    path: &Path,
+) -> Result<(), UnboxError> {
+    let entry_type = header.entry_type();
+    if let (true, Ok(mtime)) = (entry_type.is_dir(), header.mtime()) {
+        helper.restore_dir_mtime(path, FileTime::from_unix_time(mtime as i64, 0));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if !helper.preserve_permissions() && (is_regular_file(entry_type) || entry_type.is_dir()) {
+            if let Some(dst) = safe_join(helper.path(), path) {
+                let mode = if entry_type.is_dir() { 0o755 } else { 0o644 };
+                fs::set_permissions(dst, fs::Permissions::from_mode(mode))?;
+            }
+        }
+    }
+    if is_regular_file(entry_type) {
+        helper.sync_file(path)?;
+        helper.count_file(size);
+    }
+    Ok(())
+}
+
+fn entry_metadata(header: &Header) -> EntryMetadata {
+    EntryMetadata {
+        mode: header.mode().ok(),
+        owner: match (header.uid(), header.gid()) {
+            (Ok(uid), Ok(gid)) => u32::try_from(uid).ok().zip(u32::try_from(gid).ok()),
+            _ => None,
+        },
+        mtime: header
+            .mtime()
+            .ok()
+            .map(|x| FileTime::from_unix_time(x as i64, 0)),
+    }
+}
+
+/// Returns `true` for entries holding the contents of a regular file.
+///
+/// Unlike `EntryType::is_file` this includes sparse and contiguous files.
+fn is_regular_file(entry_type: EntryType) -> bool {
+    entry_type.is_file() || entry_type.is_gnu_sparse() || entry_type.is_contiguous()
+}
+
+/// Describes entry types that are not unpacked.
+///
+/// Returns `None` for files, directories, symlinks and hardlinks.
+fn unsupported_entry_type(entry_type: EntryType) -> Option<&'static str> {
+    match entry_type {
+        EntryType::Regular
+        | EntryType::Continuous
+        | EntryType::GNUSparse
+        | EntryType::Directory
+        | EntryType::Symlink
+        | EntryType::Link => None,
+        EntryType::Char => Some("character device"),
+        EntryType::Block => Some("block device"),
+        EntryType::Fifo => Some("fifo"),
+        _ => Some("special file"),
+    }
+}
+
+/// Unpacks a single entry of a tarball.
+fn unpack_entry<R: Read>(
+    helper: &mut UnpackHelper,
+    entry: &mut Entry<R>,
+) -> Result<(), UnboxError> {
+    if helper.dirs_only() {
+        let path = entry.path()?.into_owned();
+        if entry.header().entry_type().is_dir() {
+            helper.create_dir(&path)?;
+        } else if let Some(parent) = path.parent() {
+            helper.create_dir(parent)?;
+        }
+        return Ok(());
+    }
+    // tar unpacks into the scratchpad on its own which has to be
+    // avoided in dry runs.
+    if helper.dry_run() {
+        let path = entry.path()?.into_owned();
+        if entry.header().entry_type().is_dir() {
+            helper.create_dir(&path)?;
+        } else {
+            helper.write_file_from(&path, entry)?;
+        }
+        return Ok(());
+    }
+    let entry_type = entry.header().entry_type();
+    if let Some(kind) = unsupported_entry_type(entry_type) {
+        helper.warn(&format!("skipping {} {}", kind, entry.path()?.display()));
+        return Ok(());
+    }
+    // the helper has to see the contents to deduplicate them or to pass
+    // them on to a custom sink.  Sparse files are not deduplicated as
+    // the helper would write out their holes as zeros.
+    let dedup = helper.dedup() && !entry_type.is_gnu_sparse();
+    if (dedup || helper.has_custom_sink()) && is_regular_file(entry_type) {
+        let path = entry.path()?.into_owned();
+        let metadata = entry_metadata(entry.header());
+        return helper.write_file_with_metadata(&path, entry, metadata);
+    }
+    if entry_type.is_hard_link() {
+        if let Some(target) = entry.link_name()? {
+            helper.copy_hard_link(entry.path()?, target)?;
+        }
+        return Ok(());
+    }
+    if helper.has_custom_sink() {
+        let path = entry.path()?.into_owned();
+        if entry_type.is_dir() {
+            helper.create_dir_with_metadata(&path, entry_metadata(entry.header()))?;
+        } else if let Some(target) = entry.link_name()? {
+            helper.create_symlink(&path, &target.to_string_lossy())?;
+        }
+        return Ok(());
+    }
+    // unpacking in place needs the files written under a temporary
+    // name which tar only supports when unpacking single entries.  Tar
+    // seeks over the holes of sparse files so they stay sparse on
+    // filesystems supporting it and are filled with zeros otherwise.
+    if helper.strip_components() > 0 || helper.flatten() || helper.atomic_per_file() {
+        let path = match helper.strip_path(&entry.path()?) {
+            Some(_) if helper.flatten() && entry_type.is_dir() => return Ok(()),
+            Some(path) if helper.flatten() => match helper.flatten_path(&path) {
+                Some(path) => path,
+                None => return Ok(()),
+            },
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let dst = match safe_join(helper.path(), &path) {
+            Some(dst) => dst,
+            None => {
+                debug!("skipping unsafe path {}", path.display());
+                return Ok(());
+            }
+        };
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        helper.report_file(&path);
+        if is_regular_file(entry_type) {
+            helper.write_file_atomically(&dst, |dst| {
+                entry.unpack(dst)?;
+                Ok(())
+            })?;
+        } else {
+            entry.unpack(&dst)?;
+        }
+        finish_entry(helper, entry.header(), entry.size(), &path)?;
+        return Ok(());
+    }
+    let path = entry.path()?.into_owned();
+    if safe_join(helper.path(), &path).is_none() {
+        debug!("skipping unsafe path {}", path.display());
+        return Ok(());
+    }
+    helper.report_file(&path);
+    entry.unpack_in(helper.path())?;
+    finish_entry(helper, entry.header(), entry.size(), &path)
 }
 
 impl Archive for TarArchive {
@@ -33,20 +232,923 @@ impl Archive for TarArchive {
     }
 
     fn total_size(&self) -> Option<u64> {
-        Some(self.total_size)
+        self.total_size
     }
 
-    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), Error> {
-        let f = BufReader::new(helper.wrap_read(File::open(&self.path)?));
-        let rdr = self.compression.decompress(f)?;
+    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), UnboxError> {
+        let f = BufReader::new(helper.wrap_read(self.open_source()?));
+        let rdr = helper.limit_size(self.compression.decompress_nested(f)?);
         let mut archive = TarArchiveReader::new(rdr);
+        archive.set_preserve_mtime(helper.preserve_mtime());
+        archive.set_preserve_ownerships(helper.preserve_owner());
+        let mut offset = 0;
         for entry in archive.entries()? {
-            let mut entry = entry?;
+            let mut entry = entry.map_err(|err| helper.offset_error(offset, err.into()))?;
+            // the next header follows the data padded to full blocks.  The
+            // size of sparse files includes their holes which are not stored.
+            let stored_size = entry.header().entry_size().unwrap_or_else(|_| entry.size());
+            offset = entry.raw_file_position() + stored_size.div_ceil(512) * 512;
+            if helper.skip_entry() {
+                continue;
+            }
             if let Ok(path) = entry.path() {
-                helper.report_file(&path);
+                if !helper.is_selected(&path) {
+                    continue;
+                }
             }
-            entry.unpack_in(helper.path())?;
+            let name = PathBuf::from(String::from_utf8_lossy(&entry.path_bytes()).into_owned());
+            unpack_entry(helper, &mut entry).map_err(|err| helper.entry_error(&name, err))?;
         }
         Ok(())
     }
+
+    fn entries(&mut self) -> Result<Vec<ArchiveEntry>, UnboxError> {
+        let rdr = self
+            .compression
+            .decompress_nested(BufReader::new(self.open_source()?))?;
+        let mut archive = TarArchiveReader::new(rdr);
+        let mut rv = vec![];
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let entry_type = entry.header().entry_type();
+            let kind = if entry_type.is_dir() {
+                EntryKind::Directory
+            } else if is_regular_file(entry_type) {
+                EntryKind::File
+            } else {
+                EntryKind::Other
+            };
+            rv.push(ArchiveEntry::new(
+                entry.path()?.to_string_lossy(),
+                entry.size(),
+                kind,
+            ));
+        }
+        Ok(rv)
+    }
+}
+
+/// Packs a directory into a tarball written to the given writer.
+///
+/// The contents are placed in a folder with the given name.  Modification
+/// times and permissions are retained.
+pub fn pack_directory<W: Write>(src: &Path, name: &str, w: W) -> Result<W, UnboxError> {
+    let mut builder = TarBuilder::new(w);
+    builder.follow_symlinks(false);
+    builder.append_dir_all(name, src)?;
+    Ok(builder.into_inner()?)
+}
+
+#[test]
+fn test_pax_archive() {
+    use std::fs;
+    use std::fs::File;
+
+    use tar::{Builder, EntryType, Header};
+
+    use crate::archive::UnpackOptions;
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    fn pax_record(key: &str, value: &str) -> Vec<u8> {
+        let rest = format!(" {}={}\n", key, value);
+        let mut len = rest.len();
+        while format!("{}{}", len, rest).len() != len {
+            len += 1;
+        }
+        format!("{}{}", len, rest).into_bytes()
+    }
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("extended.pax");
+    let long_path = format!("{}/file.txt", "nested-directory".repeat(10));
+
+    let mut builder = Builder::new(File::create(&archive_path).unwrap());
+    let mut pax = pax_record("path", &long_path);
+    pax.extend(pax_record("SCHILY.xattr.user.origin", "unbox"));
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::XHeader);
+    header.set_path("PaxHeaders/file.txt").unwrap();
+    header.set_size(pax.len() as u64);
+    header.set_cksum();
+    builder.append(&header, &pax[..]).unwrap();
+    let mut header = Header::new_ustar();
+    header.set_path("file.txt").unwrap();
+    header.set_mode(0o644);
+    header.set_size(5);
+    header.set_cksum();
+    builder.append(&header, &b"hello"[..]).unwrap();
+    builder.finish().unwrap();
+    drop(builder);
+
+    assert_eq!(
+        ArchiveType::determine_by_filename(&archive_path),
+        Some(ArchiveType::Tar)
+    );
+    assert_eq!(ArchiveType::for_path(&archive_path), Some(ArchiveType::Tar));
+
+    let mut archive = TarArchive::open(&archive_path, Compression::Uncompressed).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+
+    assert_eq!(
+        rv,
+        dir.canonicalize()
+            .unwrap()
+            .join("nested-directory".repeat(10))
+    );
+    assert_eq!(fs::read(rv.join("file.txt")).unwrap(), b"hello");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_entry_index() {
+    use std::fs;
+    use std::fs::File;
+
+    use tar::{Builder, Header};
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("letters.tar");
+    let mut builder = Builder::new(File::create(&archive_path).unwrap());
+    for name in &["a.txt", "b.txt", "c.txt"] {
+        let mut header = Header::new_ustar();
+        header.set_path(name).unwrap();
+        header.set_mode(0o644);
+        header.set_size(5);
+        header.set_cksum();
+        builder.append(&header, name.as_bytes()).unwrap();
+    }
+    builder.finish().unwrap();
+    drop(builder);
+
+    let unpack = |entry_index| {
+        let mut archive = TarArchive::open(&archive_path, Compression::Uncompressed).unwrap();
+        let options = UnpackOptions {
+            entry_index: Some(entry_index),
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        helper.commit()
+    };
+
+    let rv = unpack(0).unwrap();
+    assert_eq!(rv.file_name().unwrap(), "a.txt");
+    assert_eq!(fs::read(&rv).unwrap(), b"a.txt");
+
+    let rv = unpack(1).unwrap();
+    assert_eq!(rv.file_name().unwrap(), "b.txt");
+    assert_eq!(fs::read(&rv).unwrap(), b"b.txt");
+
+    let err = unpack(3).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "entry index 3 is out of range, the archive has 3 entries"
+    );
+    assert!(!dir.join("c.txt").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_compressed_tarballs() {
+    use std::fs;
+    use std::fs::File;
+
+    use crate::archive::UnpackOptions;
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    let src = make_test_dir();
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::write(src.join("a.txt"), b"hello").unwrap();
+    fs::write(src.join("sub/b.txt"), b"world".repeat(1000)).unwrap();
+
+    for &(ext, compression) in &[("tar.xz", Compression::Xz), ("tar.bz2", Compression::Bz2)] {
+        let dir = make_test_dir();
+        let archive_path = dir.join(format!("archive.{}", ext));
+        let w = compression
+            .compress(File::create(&archive_path).unwrap())
+            .unwrap();
+        pack_directory(&src, "archive", w)
+            .unwrap()
+            .finish()
+            .unwrap();
+
+        let ty = ArchiveType::for_path(&archive_path).unwrap();
+        assert_eq!(Compression::for_tarball(ty), Some(compression));
+        let mut archive = ty.open(&archive_path).unwrap();
+        let mut helper = UnpackHelper::create(&*archive, &dir, UnpackOptions::default()).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        let stats = helper.stats();
+        assert_eq!((stats.files, stats.bytes), (2, 5005));
+        let rv = helper.commit().unwrap();
+        assert_eq!(fs::read(rv.join("a.txt")).unwrap(), b"hello");
+        assert_eq!(
+            fs::read(rv.join("sub/b.txt")).unwrap(),
+            b"world".repeat(1000)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    fs::remove_dir_all(&src).unwrap();
+}
+
+#[test]
+fn test_double_compressed_tarball() {
+    use std::fs;
+    use std::fs::File;
+
+    use crate::archive::UnpackOptions;
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    let src = make_test_dir();
+    fs::write(src.join("a.txt"), b"hello").unwrap();
+    let dir = make_test_dir();
+    let archive_path = dir.join("archive.tar.gz.gz");
+    let outer = Compression::Gz
+        .compress(File::create(&archive_path).unwrap())
+        .unwrap();
+    let inner = Compression::Gz.compress(outer).unwrap();
+    pack_directory(&src, "archive", inner)
+        .unwrap()
+        .finish()
+        .unwrap()
+        .finish()
+        .unwrap();
+
+    let ty = ArchiveType::for_path(&archive_path).unwrap();
+    assert_eq!(ty, ArchiveType::TarGz);
+    let mut archive = ty.open(&archive_path).unwrap();
+    assert_eq!(archive.entries().unwrap()[0].name, "archive");
+    let mut helper = UnpackHelper::create(&*archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(fs::read(rv.join("a.txt")).unwrap(), b"hello");
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&src).unwrap();
+}
+
+#[test]
+fn test_entries() {
+    use std::fs;
+    use std::fs::File;
+
+    use tar::{Builder, EntryType, Header};
+
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("listing.tar");
+    let mut builder = Builder::new(File::create(&archive_path).unwrap());
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::Directory);
+    header.set_path("project/").unwrap();
+    header.set_size(0);
+    header.set_cksum();
+    builder.append(&header, &b""[..]).unwrap();
+    let mut header = Header::new_ustar();
+    header.set_path("project/a.txt").unwrap();
+    header.set_size(5);
+    header.set_cksum();
+    builder.append(&header, &b"hello"[..]).unwrap();
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::Symlink);
+    header.set_path("project/link").unwrap();
+    header.set_link_name("a.txt").unwrap();
+    header.set_size(0);
+    header.set_cksum();
+    builder.append(&header, &b""[..]).unwrap();
+    builder.finish().unwrap();
+    drop(builder);
+
+    let mut archive = TarArchive::open(&archive_path, Compression::Uncompressed).unwrap();
+    assert_eq!(
+        archive.entries().unwrap(),
+        vec![
+            ArchiveEntry::new("project", 0, EntryKind::Directory),
+            ArchiveEntry::new("project/a.txt", 5, EntryKind::File),
+            ArchiveEntry::new("project/link", 0, EntryKind::Other),
+        ]
+    );
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_strip_components() {
+    use std::fs::File;
+
+    use tar::{Builder, Header};
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("release.tar");
+    let mut builder = Builder::new(File::create(&archive_path).unwrap());
+    for name in &["top.txt", "release-1.0/b.txt", "release-1.0/sub/a.txt"] {
+        let mut header = Header::new_ustar();
+        header.set_path(name).unwrap();
+        header.set_mode(0o644);
+        header.set_size(name.len() as u64);
+        header.set_cksum();
+        builder.append(&header, name.as_bytes()).unwrap();
+    }
+    builder.finish().unwrap();
+    drop(builder);
+
+    let unpack = |strip_components| {
+        let mut archive = TarArchive::open(&archive_path, Compression::Uncompressed).unwrap();
+        let options = UnpackOptions {
+            strip_components,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        helper.commit().unwrap()
+    };
+
+    // the top level file is skipped, the rest lands in a folder named
+    // after the archive
+    let rv = unpack(1);
+    assert_eq!(rv.file_name().unwrap(), "release");
+    assert!(!rv.join("top.txt").exists());
+    assert_eq!(fs::read(rv.join("b.txt")).unwrap(), b"release-1.0/b.txt");
+    assert_eq!(
+        fs::read(rv.join("sub/a.txt")).unwrap(),
+        b"release-1.0/sub/a.txt"
+    );
+
+    // only a single file is left
+    let rv = unpack(2);
+    assert_eq!(rv.file_name().unwrap(), "a.txt");
+    assert_eq!(fs::read(&rv).unwrap(), b"release-1.0/sub/a.txt");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_flatten() {
+    use std::fs::File;
+
+    use tar::{Builder, EntryType, Header};
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("flat.tar");
+    let mut builder = Builder::new(File::create(&archive_path).unwrap());
+    for name in &["a/x.txt", "b/x.txt", "b/sub/y.txt"] {
+        let mut header = Header::new_ustar();
+        header.set_path(name).unwrap();
+        header.set_mode(0o644);
+        header.set_size(name.len() as u64);
+        header.set_cksum();
+        builder.append(&header, name.as_bytes()).unwrap();
+    }
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::Link);
+    header.set_size(0);
+    builder
+        .append_link(&mut header, "c/link.txt", "b/sub/y.txt")
+        .unwrap();
+    builder.finish().unwrap();
+    drop(builder);
+
+    let mut archive = TarArchive::open(&archive_path, Compression::Uncompressed).unwrap();
+    let options = UnpackOptions {
+        flatten: true,
+        ..UnpackOptions::default()
+    };
+    let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "flat");
+    assert_eq!(fs::read_dir(&rv).unwrap().count(), 4);
+    assert_eq!(fs::read(rv.join("x.txt")).unwrap(), b"a/x.txt");
+    assert_eq!(fs::read(rv.join("x-2.txt")).unwrap(), b"b/x.txt");
+    assert_eq!(fs::read(rv.join("y.txt")).unwrap(), b"b/sub/y.txt");
+    assert_eq!(fs::read(rv.join("link.txt")).unwrap(), b"b/sub/y.txt");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_path_traversal() {
+    use std::fs::File;
+
+    use tar::{Builder, Header};
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("evil.tar");
+    let mut builder = Builder::new(File::create(&archive_path).unwrap());
+    for name in &[
+        "pkg/../../etc/passwd",
+        "/abs/path",
+        "./pkg/ok.txt",
+        "pkg/b.txt",
+    ] {
+        // the builder refuses unsafe paths so the name is set directly
+        let mut header = Header::new_old();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_mode(0o644);
+        header.set_size(name.len() as u64);
+        header.set_cksum();
+        builder.append(&header, name.as_bytes()).unwrap();
+    }
+    builder.finish().unwrap();
+    drop(builder);
+
+    for &strip_components in &[0, 1] {
+        let mut archive = TarArchive::open(&archive_path, Compression::Uncompressed).unwrap();
+        let options = UnpackOptions {
+            strip_components,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        let rv = helper.commit().unwrap();
+        assert_eq!(fs::read(rv.join("ok.txt")).unwrap(), b"./pkg/ok.txt");
+        assert_eq!(fs::read(rv.join("b.txt")).unwrap(), b"pkg/b.txt");
+        assert_eq!(fs::read_dir(&rv).unwrap().count(), 2);
+        assert!(!dir.join("etc").exists());
+        fs::remove_dir_all(&rv).unwrap();
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlink_escape() {
+    use std::fs::File;
+
+    use tar::Builder;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let victim = dir.join("victim");
+    fs::create_dir_all(&victim).unwrap();
+    let archive_path = dir.join("escape.tar");
+    let mut builder = Builder::new(File::create(&archive_path).unwrap());
+    let mut append = |name: &str, entry_type, link_name: Option<&Path>, data: &[u8]| {
+        let mut header = Header::new_gnu();
+        header.set_path(name).unwrap();
+        header.set_entry_type(entry_type);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        if let Some(link_name) = link_name {
+            header.set_link_name(link_name).unwrap();
+        }
+        header.set_cksum();
+        builder.append(&header, data).unwrap();
+    };
+    // the second entry goes through the symlink of the first one
+    append("top/link", EntryType::Symlink, Some(&victim), b"");
+    append("top/link/owned.txt", EntryType::Regular, None, b"owned");
+    append("top/ok.txt", EntryType::Regular, None, b"ok");
+    builder.finish().unwrap();
+    drop(builder);
+
+    let check = |options| {
+        let mut archive = TarArchive::open(&archive_path, Compression::Uncompressed).unwrap();
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        let rv = helper.commit().unwrap();
+        assert_eq!(fs::read(rv.join("ok.txt")).unwrap(), b"ok");
+        assert!(!victim.join("owned.txt").exists());
+        fs::remove_dir_all(&rv).unwrap();
+    };
+    check(UnpackOptions {
+        strip_components: 1,
+        ..UnpackOptions::default()
+    });
+    check(UnpackOptions {
+        atomic_per_file: true,
+        ..UnpackOptions::default()
+    });
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_hard_link_over_symlink() {
+    use std::fs::File;
+
+    use tar::Builder;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let victim = dir.join("victim.txt");
+    fs::write(&victim, b"victim").unwrap();
+    let archive_path = dir.join("hardlink.tar");
+    let mut builder = Builder::new(File::create(&archive_path).unwrap());
+    let mut append = |name: &str, entry_type, link_name: Option<&Path>, data: &[u8]| {
+        let mut header = Header::new_gnu();
+        header.set_path(name).unwrap();
+        header.set_entry_type(entry_type);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        if let Some(link_name) = link_name {
+            header.set_link_name(link_name).unwrap();
+        }
+        header.set_cksum();
+        builder.append(&header, data).unwrap();
+    };
+    append("pkg/a.txt", EntryType::Regular, None, b"hello");
+    append("pkg/evil", EntryType::Symlink, Some(&victim), b"");
+    append(
+        "pkg/evil",
+        EntryType::Link,
+        Some(Path::new("pkg/a.txt")),
+        b"",
+    );
+    builder.finish().unwrap();
+    drop(builder);
+
+    for &(strip_components, atomic_per_file) in &[(0, false), (1, false), (0, true)] {
+        let mut archive = TarArchive::open(&archive_path, Compression::Uncompressed).unwrap();
+        let options = UnpackOptions {
+            strip_components,
+            atomic_per_file,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        let rv = helper.commit().unwrap();
+        assert_eq!(fs::read(&victim).unwrap(), b"victim");
+        let evil = rv.join("evil");
+        assert!(fs::symlink_metadata(&evil).unwrap().is_file());
+        assert_eq!(fs::read(&evil).unwrap(), b"hello");
+        fs::remove_dir_all(&rv).unwrap();
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_ignore_permissions() {
+    use std::fs::File;
+    use std::os::unix::fs::PermissionsExt;
+
+    use tar::{Builder, Header};
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("tools.tar");
+    let mut builder = Builder::new(File::create(&archive_path).unwrap());
+    for &(name, mode) in &[("tools/run.sh", 0o755), ("tools/frozen.txt", 0o444)] {
+        let mut header = Header::new_ustar();
+        header.set_path(name).unwrap();
+        header.set_mode(mode);
+        header.set_size(4);
+        header.set_cksum();
+        builder.append(&header, &b"data"[..]).unwrap();
+    }
+    builder.finish().unwrap();
+    drop(builder);
+
+    let unpack = |ignore_permissions| {
+        let mut archive = TarArchive::open(&archive_path, Compression::Uncompressed).unwrap();
+        let options = UnpackOptions {
+            ignore_permissions,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        helper.commit().unwrap()
+    };
+    let mode = |path: PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+    let rv = unpack(false);
+    assert_eq!(mode(rv.join("run.sh")), 0o755);
+    assert_eq!(mode(rv.join("frozen.txt")), 0o444);
+
+    let rv = unpack(true);
+    assert_eq!(mode(rv.join("run.sh")), 0o644);
+    assert_eq!(mode(rv.join("frozen.txt")), 0o644);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_owner() {
+    use std::fs::File;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    use tar::{Builder, EntryType, Header};
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::{is_root, make_test_dir};
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("owned.tar");
+    let mut builder = Builder::new(File::create(&archive_path).unwrap());
+    let mut header = Header::new_ustar();
+    header.set_path("owned/").unwrap();
+    header.set_entry_type(EntryType::Directory);
+    header.set_mode(0o755);
+    header.set_uid(1234);
+    header.set_gid(5678);
+    header.set_size(0);
+    header.set_cksum();
+    builder.append(&header, &[][..]).unwrap();
+    let mut header = Header::new_ustar();
+    header.set_path("owned/run.sh").unwrap();
+    header.set_mode(0o750);
+    header.set_uid(1234);
+    header.set_gid(5678);
+    header.set_size(4);
+    header.set_cksum();
+    builder.append(&header, &b"data"[..]).unwrap();
+    builder.finish().unwrap();
+    drop(builder);
+
+    let unpack = |owner, dedup| {
+        let mut archive = TarArchive::open(&archive_path, Compression::Uncompressed).unwrap();
+        let options = UnpackOptions {
+            owner,
+            dedup,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        helper.commit().unwrap()
+    };
+    let current_uid = fs::metadata(&dir).unwrap().uid();
+
+    for &dedup in &[false, true] {
+        let rv = unpack(false, dedup);
+        assert_eq!(fs::metadata(rv.join("run.sh")).unwrap().uid(), current_uid);
+        fs::remove_dir_all(&rv).unwrap();
+
+        // only root can change the owner, everybody else keeps the files
+        let rv = unpack(true, dedup);
+        let metadata = fs::metadata(rv.join("run.sh")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o750);
+        if is_root() {
+            assert_eq!((metadata.uid(), metadata.gid()), (1234, 5678));
+            let metadata = fs::metadata(&rv).unwrap();
+            assert_eq!((metadata.uid(), metadata.gid()), (1234, 5678));
+        } else {
+            assert_eq!(metadata.uid(), current_uid);
+        }
+        fs::remove_dir_all(&rv).unwrap();
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_dir_mtime() {
+    use std::fs::File;
+
+    use tar::{Builder, EntryType, Header};
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("dated.tar");
+    let mut builder = Builder::new(File::create(&archive_path).unwrap());
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::Directory);
+    header.set_path("dated/").unwrap();
+    header.set_mode(0o755);
+    header.set_mtime(981_173_106);
+    header.set_size(0);
+    header.set_cksum();
+    builder.append(&header, &b""[..]).unwrap();
+    let mut header = Header::new_ustar();
+    header.set_path("dated/a.txt").unwrap();
+    header.set_mode(0o644);
+    header.set_mtime(981_173_106);
+    header.set_size(5);
+    header.set_cksum();
+    builder.append(&header, &b"hello"[..]).unwrap();
+    builder.finish().unwrap();
+    drop(builder);
+
+    let mut archive = TarArchive::open(&archive_path, Compression::Uncompressed).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+
+    // writing the file must not clobber the time of the directory
+    let mtime = FileTime::from_last_modification_time(&fs::metadata(&rv).unwrap());
+    assert_eq!(mtime.unix_seconds(), 981_173_106);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_unsupported_entry_types() {
+    use std::fs::File;
+
+    use tar::Builder;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("special.tar");
+    let mut builder = Builder::new(File::create(&archive_path).unwrap());
+    let mut append = |name: &str, entry_type, link_name: Option<&str>, data: &[u8]| {
+        let mut header = Header::new_gnu();
+        header.set_path(name).unwrap();
+        header.set_entry_type(entry_type);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        if let Some(link_name) = link_name {
+            header.set_link_name(link_name).unwrap();
+        }
+        header.set_cksum();
+        builder.append(&header, data).unwrap();
+    };
+    append("pkg/a.txt", EntryType::Regular, None, b"hello");
+    append("pkg/fifo", EntryType::Fifo, None, b"");
+    append("pkg/null", EntryType::Char, None, b"");
+    append("pkg/link.txt", EntryType::Link, Some("pkg/a.txt"), b"");
+    append(
+        "pkg/dangling.txt",
+        EntryType::Link,
+        Some("pkg/missing.txt"),
+        b"",
+    );
+    append("pkg/b.txt", EntryType::Regular, None, b"world");
+    builder.finish().unwrap();
+    drop(builder);
+
+    for &strip_components in &[0, 1] {
+        let mut archive = TarArchive::open(&archive_path, Compression::Uncompressed).unwrap();
+        let options = UnpackOptions {
+            strip_components,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        let rv = helper.commit().unwrap();
+        assert_eq!(fs::read(rv.join("a.txt")).unwrap(), b"hello");
+        assert_eq!(fs::read(rv.join("link.txt")).unwrap(), b"hello");
+        assert_eq!(fs::read(rv.join("b.txt")).unwrap(), b"world");
+        assert!(!rv.join("fifo").exists());
+        assert!(!rv.join("null").exists());
+        assert!(!rv.join("dangling.txt").exists());
+        fs::remove_dir_all(&rv).unwrap();
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_dry_run() {
+    use std::fs::File;
+
+    use tar::Builder;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir().canonicalize().unwrap();
+    let archive_path = dir.join("release.tar");
+    let mut builder = Builder::new(File::create(&archive_path).unwrap());
+    for name in &["release-1.0/a.txt", "release-1.0/sub/b.txt"] {
+        let mut header = Header::new_gnu();
+        header.set_path(name).unwrap();
+        header.set_size(name.len() as u64);
+        header.set_cksum();
+        builder.append(&header, name.as_bytes()).unwrap();
+    }
+    builder.finish().unwrap();
+    drop(builder);
+
+    let mut archive = TarArchive::open(&archive_path, Compression::Uncompressed).unwrap();
+    let options = UnpackOptions {
+        dry_run: true,
+        ..UnpackOptions::default()
+    };
+    let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    assert_eq!(helper.commit().unwrap(), dir.join("release-1.0"));
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_entry_errors() {
+    use std::error::Error;
+
+    use tar::Builder;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let mut builder = Builder::new(vec![]);
+    for name in &["a.txt", "b.txt", "c.txt"] {
+        let mut header = Header::new_ustar();
+        header.set_path(name).unwrap();
+        header.set_mode(0o644);
+        header.set_size(1000);
+        header.set_cksum();
+        builder.append(&header, &[b'x'; 1000][..]).unwrap();
+    }
+    let tarball = builder.into_inner().unwrap();
+    let unpack = |name: &str, data: &[u8]| {
+        let archive_path = dir.join(name);
+        fs::write(&archive_path, data).unwrap();
+        let mut archive = TarArchive::open(&archive_path, Compression::Uncompressed).unwrap();
+        let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+        archive.unpack(&mut helper).unwrap_err()
+    };
+
+    // the header of the third entry follows two entries of 1024 bytes
+    // each plus their headers.
+    let mut corrupt = tarball.clone();
+    corrupt[3072 + 148] ^= 1;
+    let err = unpack("corrupt.tar", &corrupt);
+    assert_eq!(
+        err.to_string(),
+        "failed to read the entry at offset 3072 of corrupt.tar"
+    );
+    assert!(err.source().is_some());
+
+    let err = unpack("truncated.tar", &tarball[..2048]);
+    assert_eq!(
+        err.to_string(),
+        "failed to extract entry 'b.txt' from truncated.tar"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_sparse_file() {
+    use std::fs::File;
+
+    use tar::Builder;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir().canonicalize().unwrap();
+    let archive_path = dir.join("sparse.tar");
+    let real_size = 8 * 1024 * 1024;
+    let mut header = Header::new_gnu();
+    header.set_path("disk.img").unwrap();
+    header.set_mode(0o644);
+    header.set_entry_type(EntryType::GNUSparse);
+    header.set_size(1024);
+    {
+        let gnu = header.as_gnu_mut().unwrap();
+        gnu.sparse[0].set_offset(0);
+        gnu.sparse[0].set_length(512);
+        gnu.sparse[1].set_offset(real_size / 2);
+        gnu.sparse[1].set_length(512);
+        // the map ends with an empty chunk at the end of the file
+        gnu.sparse[2].set_offset(real_size);
+        gnu.sparse[2].set_length(0);
+        gnu.set_real_size(real_size);
+    }
+    header.set_cksum();
+    let mut data = vec![0; 1024];
+    data[..5].copy_from_slice(b"hello");
+    data[512..517].copy_from_slice(b"world");
+    let mut builder = Builder::new(File::create(&archive_path).unwrap());
+    builder.append(&header, &data[..]).unwrap();
+    builder.finish().unwrap();
+    drop(builder);
+
+    for dedup in [false, true] {
+        let mut archive = TarArchive::open(&archive_path, Compression::Uncompressed).unwrap();
+        let options = UnpackOptions {
+            dedup,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        let rv = helper.commit().unwrap();
+        assert_eq!(rv, dir.join("disk.img"));
+
+        let contents = fs::read(&rv).unwrap();
+        assert_eq!(contents.len() as u64, real_size);
+        assert_eq!(&contents[..5], b"hello");
+        assert_eq!(&contents[real_size as usize / 2..][..5], b"world");
+        assert_eq!(contents.iter().filter(|&&x| x != 0).count(), 10);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert!(rv.metadata().unwrap().blocks() * 512 < real_size / 2);
+        }
+        fs::remove_file(&rv).unwrap();
+    }
+    fs::remove_dir_all(&dir).unwrap();
 }
@@ -0,0 +1,983 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use filetime::FileTime;
+use log::debug;
+use roxmltree::Document;
+
+use crate::archive::{Archive, ArchiveEntry, EntryKind, EntryMetadata, UnpackHelper};
+use crate::error::{bail, UnboxError};
+use crate::utils::is_unsafe_path;
+
+pub const MAGIC: &[u8] = b"MSWIM\0\0\0";
+
+const HEADER_SIZE: usize = 208;
+const DEFAULT_CHUNK_SIZE: u64 = 32768;
+
+const HDR_FLAG_COMPRESSION: u32 = 0x0000_0002;
+const HDR_FLAG_XPRESS: u32 = 0x0002_0000;
+const HDR_FLAG_LZX: u32 = 0x0004_0000;
+const HDR_FLAG_LZMS: u32 = 0x0008_0000;
+
+const RESHDR_FLAG_METADATA: u8 = 0x02;
+const RESHDR_FLAG_COMPRESSED: u8 = 0x04;
+const RESHDR_FLAG_SOLID: u8 = 0x10;
+
+const RESHDR_SIZE: usize = 24;
+const BLOB_ENTRY_SIZE: usize = 50;
+const DENTRY_MIN_SIZE: u64 = 102;
+const STREAM_ENTRY_MIN_SIZE: u64 = 38;
+
+const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
+const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x400;
+
+/// Seconds between the windows and the unix epoch.
+const EPOCH_DIFFERENCE: i64 = 11_644_473_600;
+
+const MAX_TABLE_SIZE: u64 = 0x1000_0000;
+
+fn le_u16(buf: &[u8], idx: usize) -> u16 {
+    u16::from_le_bytes([buf[idx], buf[idx + 1]])
+}
+
+fn le_u32(buf: &[u8], idx: usize) -> u32 {
+    u32::from_le_bytes([buf[idx], buf[idx + 1], buf[idx + 2], buf[idx + 3]])
+}
+
+fn le_u64(buf: &[u8], idx: usize) -> u64 {
+    let mut rv = [0u8; 8];
+    rv.copy_from_slice(&buf[idx..idx + 8]);
+    u64::from_le_bytes(rv)
+}
+
+fn align8(value: u64) -> u64 {
+    (value + 7) & !7
+}
+
+/// The compression used for the resources of an image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ResourceCompression {
+    None,
+    Xpress,
+}
+
+/// Locates a resource in the file.
+#[derive(Debug, Copy, Clone)]
+struct ResourceHeader {
+    size: u64,
+    flags: u8,
+    offset: u64,
+    uncompressed_size: u64,
+}
+
+impl ResourceHeader {
+    fn parse(buf: &[u8]) -> ResourceHeader {
+        ResourceHeader {
+            size: le_u64(buf, 0) & 0x00ff_ffff_ffff_ffff,
+            flags: buf[7],
+            offset: le_u64(buf, 8),
+            uncompressed_size: le_u64(buf, 16),
+        }
+    }
+}
+
+/// Reads the bits of an XPRESS stream.
+///
+/// Bits are read from 16 bit little endian words starting at the most
+/// significant bit while extra length bytes are interleaved with them.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits: u32,
+    extra: i32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        let mut rv = BitReader {
+            data,
+            pos: 0,
+            bits: 0,
+            extra: 16,
+        };
+        rv.bits = (rv.next_word() << 16) | rv.next_word();
+        rv
+    }
+
+    /// Reads the next word, past the end of the data zeroes are read.
+    fn next_word(&mut self) -> u32 {
+        let rv = match self.data.get(self.pos..self.pos + 2) {
+            Some(word) => u32::from(le_u16(word, 0)),
+            None => 0,
+        };
+        self.pos += 2;
+        rv
+    }
+
+    fn peek(&self, count: u32) -> u32 {
+        if count == 0 {
+            0
+        } else {
+            self.bits >> (32 - count)
+        }
+    }
+
+    fn consume(&mut self, count: u32) {
+        self.bits <<= count;
+        self.extra -= count as i32;
+        if self.extra < 0 {
+            let word = self.next_word();
+            self.bits |= word << -self.extra;
+            self.extra += 16;
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let rv = self
+            .data
+            .get(self.pos)
+            .copied()
+            .ok_or_else(invalid_xpress)?;
+        self.pos += 1;
+        Ok(rv)
+    }
+}
+
+fn invalid_xpress() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid xpress data")
+}
+
+/// Builds a lookup table for canonical huffman codes of up to 15 bits.
+///
+/// Entries contain the symbol and the length of its code, unused entries
+/// are zero.  Returns `None` if the code lengths are oversubscribed.
+fn build_decode_table(lengths: &[u8]) -> Option<Vec<u16>> {
+    let mut table = vec![0u16; 1 << 15];
+    let mut code = 0;
+    for len in 1..=15 {
+        for (sym, _) in lengths.iter().enumerate().filter(|x| *x.1 == len) {
+            let end = code + (1 << (15 - len));
+            for entry in table.get_mut(code..end)? {
+                *entry = ((sym as u16) << 4) | u16::from(len);
+            }
+            code = end;
+        }
+    }
+    Some(table)
+}
+
+/// Decompresses a chunk compressed with XPRESS huffman.
+fn xpress_decompress(data: &[u8], size: usize) -> io::Result<Vec<u8>> {
+    if data.len() < 256 {
+        return Err(invalid_xpress());
+    }
+    let lengths: Vec<u8> = data[..256]
+        .iter()
+        .flat_map(|&x| [x & 0xf, x >> 4])
+        .collect();
+    let table = build_decode_table(&lengths).ok_or_else(invalid_xpress)?;
+    let mut rdr = BitReader::new(&data[256..]);
+
+    let mut rv = Vec::with_capacity(size);
+    while rv.len() < size {
+        let entry = table[rdr.peek(15) as usize];
+        if entry == 0 {
+            return Err(invalid_xpress());
+        }
+        rdr.consume(u32::from(entry & 0xf));
+        let sym = entry >> 4;
+        if sym < 256 {
+            rv.push(sym as u8);
+            continue;
+        }
+
+        let log2_offset = u32::from((sym >> 4) & 0xf);
+        let mut length = usize::from(sym & 0xf);
+        if length == 0xf {
+            length += usize::from(rdr.read_byte()?);
+            if length == 0xf + 0xff {
+                length = usize::from(u16::from_le_bytes([rdr.read_byte()?, rdr.read_byte()?]));
+            }
+        }
+        length += 3;
+        let offset = ((1 << log2_offset) | rdr.peek(log2_offset)) as usize;
+        rdr.consume(log2_offset);
+        if offset > rv.len() {
+            return Err(invalid_xpress());
+        }
+        // matches may overlap with the bytes they produce
+        let start = rv.len() - offset;
+        for idx in start..start + cmp::min(length, size - rv.len()) {
+            let byte = rv[idx];
+            rv.push(byte);
+        }
+    }
+    Ok(rv)
+}
+
+/// Reads a compressed resource chunk by chunk.
+struct ChunkReader {
+    file: File,
+    header: ResourceHeader,
+    chunk_size: u64,
+    /// The offsets of the chunks within the resource and its end.
+    chunks: Vec<u64>,
+    next_chunk: usize,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ChunkReader {
+    fn new(mut file: File, header: ResourceHeader, chunk_size: u64) -> Result<Self, UnboxError> {
+        let count = header.uncompressed_size.div_ceil(chunk_size);
+        let entry_size = if header.uncompressed_size > 0xffff_ffff {
+            8
+        } else {
+            4
+        };
+        let table_size = count.saturating_sub(1) * entry_size;
+        if table_size > header.size {
+            bail!("invalid wim resource");
+        }
+        let mut table = vec![0u8; table_size as usize];
+        file.seek(SeekFrom::Start(header.offset))?;
+        file.read_exact(&mut table)?;
+
+        // the table stores the offsets of all but the first chunk relative
+        // to the end of the table.
+        let mut chunks = vec![table_size];
+        for entry in table.chunks(entry_size as usize) {
+            let offset = match entry_size {
+                8 => le_u64(entry, 0),
+                _ => u64::from(le_u32(entry, 0)),
+            };
+            chunks.push(table_size.saturating_add(offset));
+        }
+        chunks.push(header.size);
+        if chunks
+            .windows(2)
+            .any(|x| x[0] > x[1] || x[1] - x[0] > chunk_size)
+        {
+            bail!("invalid wim chunk table");
+        }
+
+        Ok(ChunkReader {
+            file,
+            header,
+            chunk_size,
+            chunks,
+            next_chunk: 0,
+            buf: vec![],
+            pos: 0,
+        })
+    }
+
+    /// Decompresses the next chunk and returns `false` at the end.
+    fn read_chunk(&mut self) -> io::Result<bool> {
+        let idx = self.next_chunk;
+        if idx + 1 >= self.chunks.len() {
+            return Ok(false);
+        }
+        let (start, end) = (self.chunks[idx], self.chunks[idx + 1]);
+        let size = cmp::min(
+            self.chunk_size,
+            self.header.uncompressed_size - idx as u64 * self.chunk_size,
+        );
+        let mut data = vec![0u8; (end - start) as usize];
+        self.file
+            .seek(SeekFrom::Start(self.header.offset + start))?;
+        self.file.read_exact(&mut data)?;
+
+        // chunks that did not get smaller are stored uncompressed
+        self.buf = if data.len() as u64 == size {
+            data
+        } else {
+            xpress_decompress(&data, size as usize)?
+        };
+        self.pos = 0;
+        self.next_chunk += 1;
+        Ok(true)
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.buf.len() {
+            if !self.read_chunk()? {
+                return Ok(0);
+            }
+        }
+        let count = cmp::min(buf.len(), self.buf.len() - self.pos);
+        buf[..count].copy_from_slice(&self.buf[self.pos..self.pos + count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+/// A directory entry in the metadata resource of an image.
+#[derive(Debug)]
+struct Dentry {
+    attributes: u32,
+    subdir_offset: u64,
+    last_write_time: u64,
+    hash: Option<[u8; 20]>,
+    name: String,
+}
+
+/// Reads the directory entry at the given offset.
+///
+/// Returns the entry and the offset of its next sibling or `None` at the
+/// end of a directory.
+fn read_dentry(meta: &[u8], offset: u64) -> Result<Option<(Dentry, u64)>, UnboxError> {
+    let buf = match meta.get(offset as usize..) {
+        Some(buf) if buf.len() >= 8 => buf,
+        _ => bail!("invalid wim directory entry"),
+    };
+    let length = le_u64(buf, 0);
+    if length <= 8 {
+        return Ok(None);
+    }
+    let name_size = u64::from(le_u16(buf, 100));
+    if length < DENTRY_MIN_SIZE + name_size || length > buf.len() as u64 {
+        bail!("invalid wim directory entry");
+    }
+    let name: Vec<u16> = buf[102..102 + name_size as usize]
+        .chunks(2)
+        .map(|x| le_u16(x, 0))
+        .collect();
+    let mut hash = Some(buf[64..84].try_into().unwrap()).filter(|x| x != &[0u8; 20]);
+
+    // newer images store the unnamed data stream as an extra stream
+    let mut next = offset + align8(length);
+    for _ in 0..le_u16(buf, 96) {
+        let stream = match meta.get(next as usize..) {
+            Some(stream) if stream.len() as u64 >= STREAM_ENTRY_MIN_SIZE => stream,
+            _ => bail!("invalid wim stream entry"),
+        };
+        let stream_length = le_u64(stream, 0);
+        if stream_length < STREAM_ENTRY_MIN_SIZE || stream_length > stream.len() as u64 {
+            bail!("invalid wim stream entry");
+        }
+        let stream_hash: [u8; 20] = stream[16..36].try_into().unwrap();
+        if le_u16(stream, 36) == 0 && hash.is_none() && stream_hash != [0u8; 20] {
+            hash = Some(stream_hash);
+        }
+        next += align8(stream_length);
+    }
+
+    Ok(Some((
+        Dentry {
+            attributes: le_u32(buf, 8),
+            subdir_offset: le_u64(buf, 16),
+            last_write_time: le_u64(buf, 56),
+            hash,
+            name: String::from_utf16_lossy(&name),
+        },
+        next,
+    )))
+}
+
+#[derive(Debug)]
+enum Kind {
+    Directory,
+    /// A file and its data, empty files have none.
+    File(Option<ResourceHeader>),
+}
+
+#[derive(Debug)]
+struct WimEntry {
+    path: PathBuf,
+    kind: Kind,
+    mtime: Option<FileTime>,
+}
+
+/// Converts a windows file time to a file time.
+fn convert_file_time(value: u64) -> Option<FileTime> {
+    if value == 0 {
+        return None;
+    }
+    Some(FileTime::from_unix_time(
+        (value / 10_000_000) as i64 - EPOCH_DIFFERENCE,
+        (value % 10_000_000) as u32 * 100,
+    ))
+}
+
+/// Recursively reads the directory starting at the given entry.
+fn read_directory(
+    meta: &[u8],
+    mut offset: u64,
+    prefix: &Path,
+    blobs: &HashMap<[u8; 20], ResourceHeader>,
+    seen: &mut HashSet<u64>,
+    entries: &mut Vec<WimEntry>,
+) -> Result<(), UnboxError> {
+    // guard against loops in the directory tree
+    while seen.insert(offset) {
+        let (dentry, next) = match read_dentry(meta, offset)? {
+            Some(rv) => rv,
+            None => break,
+        };
+        offset = next;
+        let path = prefix.join(&dentry.name);
+        if dentry.name.is_empty() || is_unsafe_path(&path) {
+            debug!("skipping unsafe path {}", path.display());
+            continue;
+        }
+        if dentry.attributes & FILE_ATTRIBUTE_REPARSE_POINT != 0 {
+            debug!("skipping reparse point {}", path.display());
+            continue;
+        }
+        let mtime = convert_file_time(dentry.last_write_time);
+        if dentry.attributes & FILE_ATTRIBUTE_DIRECTORY != 0 {
+            entries.push(WimEntry {
+                path: path.clone(),
+                kind: Kind::Directory,
+                mtime,
+            });
+            if dentry.subdir_offset != 0 {
+                read_directory(meta, dentry.subdir_offset, &path, blobs, seen, entries)?;
+            }
+            continue;
+        }
+        let resource = match dentry.hash {
+            Some(hash) => match blobs.get(&hash) {
+                Some(resource) => Some(*resource),
+                None => bail!("data of {} is missing in the wim image", path.display()),
+            },
+            None => None,
+        };
+        entries.push(WimEntry {
+            path,
+            kind: Kind::File(resource),
+            mtime,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct WimArchive {
+    path: PathBuf,
+    compression: ResourceCompression,
+    chunk_size: u64,
+    blobs: HashMap<[u8; 20], ResourceHeader>,
+    /// The metadata resources of the images.
+    images: Vec<ResourceHeader>,
+    image_names: Vec<(String, String)>,
+    /// The number of the image the entries belong to.
+    image: u32,
+    entries: Vec<WimEntry>,
+}
+
+impl WimArchive {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, UnboxError> {
+        let path = path.as_ref().canonicalize()?;
+        let mut header = [0u8; HEADER_SIZE];
+        File::open(&path)?.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            bail!("not a wim image");
+        }
+
+        let flags = le_u32(&header, 16);
+        let compression = if flags & HDR_FLAG_COMPRESSION == 0 {
+            ResourceCompression::None
+        } else if flags & HDR_FLAG_XPRESS != 0 {
+            ResourceCompression::Xpress
+        } else if flags & HDR_FLAG_LZX != 0 {
+            bail!("unsupported wim compression 'lzx'");
+        } else if flags & HDR_FLAG_LZMS != 0 {
+            bail!("unsupported wim compression 'lzms'");
+        } else {
+            bail!("unsupported wim compression");
+        };
+        let chunk_size = match u64::from(le_u32(&header, 20)) {
+            0 => DEFAULT_CHUNK_SIZE,
+            chunk_size => chunk_size,
+        };
+        if le_u16(&header, 42) > 1 {
+            bail!("split wim images are not supported");
+        }
+
+        let mut rv = WimArchive {
+            path,
+            compression,
+            chunk_size,
+            blobs: HashMap::new(),
+            images: vec![],
+            image_names: vec![],
+            image: 1,
+            entries: vec![],
+        };
+
+        // metadata resources are listed in the order of the images
+        let table = rv.read_resource(&ResourceHeader::parse(&header[48..]))?;
+        for entry in table.chunks_exact(BLOB_ENTRY_SIZE) {
+            let resource = ResourceHeader::parse(&entry[..RESHDR_SIZE]);
+            if resource.flags & RESHDR_FLAG_METADATA != 0 {
+                rv.images.push(resource);
+            } else {
+                rv.blobs.insert(entry[30..50].try_into().unwrap(), resource);
+            }
+        }
+
+        let xml = ResourceHeader::parse(&header[72..]);
+        if xml.size > 0 {
+            rv.image_names = read_image_names(&rv.read_resource(&xml)?)?;
+        }
+        if !rv.images.is_empty() {
+            rv.entries = rv.read_image(1)?;
+        }
+        Ok(rv)
+    }
+
+    /// Opens a reader for the uncompressed contents of a resource.
+    fn open_resource(&self, resource: &ResourceHeader) -> Result<Box<dyn Read>, UnboxError> {
+        if resource.flags & RESHDR_FLAG_SOLID != 0 {
+            bail!("solid wim resources are not supported");
+        }
+        let mut file = File::open(&self.path)?;
+        if resource.flags & RESHDR_FLAG_COMPRESSED == 0 {
+            file.seek(SeekFrom::Start(resource.offset))?;
+            return Ok(Box::new(file.take(resource.uncompressed_size)));
+        }
+        if self.compression == ResourceCompression::None {
+            bail!("invalid wim resource");
+        }
+        Ok(Box::new(ChunkReader::new(
+            file,
+            *resource,
+            self.chunk_size,
+        )?))
+    }
+
+    /// Reads a table or metadata resource into memory.
+    fn read_resource(&self, resource: &ResourceHeader) -> Result<Vec<u8>, UnboxError> {
+        if resource.uncompressed_size > MAX_TABLE_SIZE {
+            bail!("wim metadata is too large");
+        }
+        let mut rv = Vec::with_capacity(resource.uncompressed_size as usize);
+        self.open_resource(resource)?.read_to_end(&mut rv)?;
+        Ok(rv)
+    }
+
+    /// Reads the directory tree of the image with the given number.
+    fn read_image(&self, image: u32) -> Result<Vec<WimEntry>, UnboxError> {
+        let resource = match (image as usize).checked_sub(1) {
+            Some(idx) if idx < self.images.len() => self.images[idx],
+            _ => bail!(
+                "image {} does not exist, the wim file has {} images",
+                image,
+                self.images.len()
+            ),
+        };
+        let meta = self.read_resource(&resource)?;
+
+        // the directory tree follows the security data
+        if meta.len() < 8 {
+            bail!("invalid wim metadata");
+        }
+        let root_offset = cmp::max(align8(u64::from(le_u32(&meta, 0))), 8);
+        let mut entries = vec![];
+        if let Some((root, _)) = read_dentry(&meta, root_offset)? {
+            if root.subdir_offset != 0 {
+                read_directory(
+                    &meta,
+                    root.subdir_offset,
+                    Path::new(""),
+                    &self.blobs,
+                    &mut HashSet::new(),
+                    &mut entries,
+                )?;
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// Reads the names of the images from the xml data.
+fn read_image_names(data: &[u8]) -> Result<Vec<(String, String)>, UnboxError> {
+    let text: Vec<u16> = data.chunks_exact(2).map(|x| le_u16(x, 0)).collect();
+    let text = String::from_utf16_lossy(&text);
+    let doc = match Document::parse(text.trim_start_matches('\u{feff}')) {
+        Ok(doc) => doc,
+        Err(err) => bail!("invalid wim xml data: {}", err),
+    };
+    Ok(doc
+        .root_element()
+        .children()
+        .filter(|x| x.has_tag_name("IMAGE"))
+        .filter_map(|image| {
+            let index = image.attribute("INDEX")?;
+            let name = image.children().find(|x| x.has_tag_name("NAME"))?.text()?;
+            Some((format!("Image {}", index), name.to_string()))
+        })
+        .collect())
+}
+
+impl Archive for WimArchive {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn total_size(&self) -> Option<u64> {
+        Some(
+            self.entries
+                .iter()
+                .map(|entry| match entry.kind {
+                    Kind::File(Some(ref resource)) => resource.uncompressed_size,
+                    _ => 0,
+                })
+                .sum(),
+        )
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        Some(self.entries.len() as u64)
+    }
+
+    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), UnboxError> {
+        if let Some(image) = helper.image() {
+            if image != self.image {
+                self.entries = self.read_image(image)?;
+                self.image = image;
+            }
+        }
+        for entry in &self.entries {
+            if helper.skip_entry() {
+                continue;
+            }
+            let metadata = EntryMetadata {
+                mode: None,
+                owner: None,
+                mtime: entry.mtime,
+            };
+            match entry.kind {
+                Kind::Directory => helper.create_dir_with_metadata(&entry.path, metadata)?,
+                Kind::File(None) => {
+                    helper.write_file_with_metadata(&entry.path, io::empty(), metadata)?
+                }
+                Kind::File(Some(ref resource)) => self
+                    .open_resource(resource)
+                    .and_then(|rdr| helper.write_file_with_metadata(&entry.path, rdr, metadata))
+                    .map_err(|err| helper.entry_error(&entry.path, err))?,
+            }
+        }
+        Ok(())
+    }
+
+    fn entries(&mut self) -> Result<Vec<ArchiveEntry>, UnboxError> {
+        Ok(self
+            .entries
+            .iter()
+            .map(|entry| {
+                let name = entry.path.to_string_lossy();
+                match entry.kind {
+                    Kind::Directory => ArchiveEntry::new(name, 0, EntryKind::Directory),
+                    Kind::File(resource) => ArchiveEntry::new(
+                        name,
+                        resource.map_or(0, |x| x.uncompressed_size),
+                        EntryKind::File,
+                    ),
+                }
+            })
+            .collect())
+    }
+
+    fn metadata(&self) -> Result<Vec<(String, String)>, UnboxError> {
+        let mut rv = vec![("Images".to_string(), self.images.len().to_string())];
+        rv.extend(self.image_names.iter().cloned());
+        Ok(rv)
+    }
+}
+
+/// Compresses a chunk with XPRESS huffman.
+///
+/// All symbols use nine bit codes which keeps this simple, matches are
+/// found through the last position of their first three bytes.
+#[cfg(test)]
+fn xpress_compress(data: &[u8]) -> Vec<u8> {
+    /// Writes the bits of the stream, extra bytes go after the words
+    /// reserved for the bits.
+    struct BitWriter {
+        out: Vec<u8>,
+        bits: u32,
+        count: u32,
+        next_word: usize,
+        next_word2: usize,
+    }
+
+    impl BitWriter {
+        fn write_bits(&mut self, value: u32, count: u32) {
+            self.bits = (self.bits << count) | value;
+            self.count += count;
+            if self.count > 16 {
+                self.count -= 16;
+                let word = (self.bits >> self.count) as u16;
+                self.out[self.next_word..self.next_word + 2].copy_from_slice(&word.to_le_bytes());
+                self.next_word = self.next_word2;
+                self.next_word2 = self.out.len();
+                self.out.extend(&[0, 0]);
+            }
+        }
+    }
+
+    let mut w = BitWriter {
+        out: vec![0x99; 256],
+        bits: 0,
+        count: 0,
+        next_word: 256,
+        next_word2: 258,
+    };
+    w.out.extend(&[0; 4]);
+    let mut last = HashMap::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let prev = data
+            .get(pos..pos + 3)
+            .and_then(|key| last.insert(key, pos))
+            .filter(|&prev| pos - prev < 0x10000);
+        let length = match prev {
+            Some(prev) => (0..cmp::min(data.len() - pos, 0x10000 + 2))
+                .take_while(|&idx| data[prev + idx] == data[pos + idx])
+                .count(),
+            None => 0,
+        };
+        if length < 3 {
+            w.write_bits(u32::from(data[pos]), 9);
+            pos += 1;
+            continue;
+        }
+        let offset = (pos - prev.unwrap()) as u32;
+        let log2_offset = 31 - offset.leading_zeros();
+        let adjusted = length as u32 - 3;
+        w.write_bits(256 + (log2_offset << 4) + cmp::min(adjusted, 0xf), 9);
+        if adjusted >= 0xf {
+            w.out.push(cmp::min(adjusted - 0xf, 0xff) as u8);
+            if adjusted - 0xf >= 0xff {
+                w.out.extend(&(adjusted as u16).to_le_bytes());
+            }
+        }
+        w.write_bits(offset - (1 << log2_offset), log2_offset);
+        pos += length;
+    }
+    let word = (w.bits << (16 - w.count)) as u16;
+    w.out[w.next_word..w.next_word + 2].copy_from_slice(&word.to_le_bytes());
+    w.out
+}
+
+#[test]
+fn test_xpress() {
+    for data in &[
+        b"".to_vec(),
+        b"a".to_vec(),
+        b"hello hello hello world".to_vec(),
+        b"abc".repeat(10000),
+        (0..20000u32).map(|x| (x * x % 251) as u8).collect(),
+    ] {
+        let compressed = xpress_compress(data);
+        assert_eq!(&xpress_decompress(&compressed, data.len()).unwrap(), data);
+    }
+}
+
+#[test]
+fn test_wim() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    fn reshdr(size: usize, flags: u8, offset: usize, uncompressed_size: usize) -> Vec<u8> {
+        let mut rv = (size as u64).to_le_bytes().to_vec();
+        rv[7] = flags;
+        rv.extend(&(offset as u64).to_le_bytes());
+        rv.extend(&(uncompressed_size as u64).to_le_bytes());
+        rv
+    }
+
+    fn dentry(attributes: u32, hash: [u8; 20], name: &str, stream: Option<[u8; 20]>) -> Vec<u8> {
+        let name: Vec<u8> = name.encode_utf16().flat_map(|x| x.to_le_bytes()).collect();
+        let mut rv = vec![0u8; 102];
+        rv[0..8].copy_from_slice(&(102 + name.len() as u64 + 2).to_le_bytes());
+        rv[8..12].copy_from_slice(&attributes.to_le_bytes());
+        rv[56..64].copy_from_slice(&132_000_000_000_000_000u64.to_le_bytes());
+        rv[64..84].copy_from_slice(&hash);
+        rv[96..98].copy_from_slice(&(stream.is_some() as u16).to_le_bytes());
+        rv[100..102].copy_from_slice(&(name.len() as u16).to_le_bytes());
+        rv.extend(name);
+        rv.resize(align8(rv.len() as u64 + 2) as usize, 0);
+        if let Some(hash) = stream {
+            rv.extend(&40u64.to_le_bytes());
+            rv.extend(&[0; 8]);
+            rv.extend(&hash);
+            rv.extend(&[0; 4]);
+        }
+        rv
+    }
+
+    /// Entries of a listing: the listing of a subdirectory, the default
+    /// hash, the name and the hash of an extra unnamed stream.
+    type Listing<'a> = &'a [(Option<usize>, [u8; 20], &'a str, Option<[u8; 20]>)];
+
+    /// Builds a metadata resource from directory listings.
+    ///
+    /// The first listing is the root, subdirectories are given as the index
+    /// of their listing.
+    fn metadata(listings: &[Listing]) -> Vec<u8> {
+        let mut rv = 8u32.to_le_bytes().to_vec();
+        rv.extend(&[0; 4]);
+        let mut offsets = vec![];
+        let mut patches = vec![];
+        rv.extend(dentry(FILE_ATTRIBUTE_DIRECTORY, [0; 20], "", None));
+        patches.push((8 + 16, 0));
+        rv.extend(&[0; 8]);
+        for listing in listings {
+            offsets.push(rv.len());
+            for &(subdir, hash, name, stream) in listing.iter() {
+                let attributes = match (subdir, name) {
+                    (Some(_), _) => FILE_ATTRIBUTE_DIRECTORY,
+                    (None, "link") => FILE_ATTRIBUTE_REPARSE_POINT,
+                    (None, _) => 0,
+                };
+                if let Some(subdir) = subdir {
+                    patches.push((rv.len() + 16, subdir));
+                }
+                rv.extend(dentry(attributes, hash, name, stream));
+            }
+            rv.extend(&[0; 8]);
+        }
+        for (pos, listing) in patches {
+            rv[pos..pos + 8].copy_from_slice(&(offsets[listing] as u64).to_le_bytes());
+        }
+        rv
+    }
+
+    let readme = b"hello wim\n".to_vec();
+    let big: Vec<u8> = (0..4000)
+        .flat_map(|x| format!("line {} of the big file\n", x % 700).into_bytes())
+        .collect();
+    let (readme_hash, big_hash) = ([1u8; 20], [2u8; 20]);
+
+    // the big file is compressed into chunks of 32k
+    let mut chunks: Vec<Vec<u8>> = big
+        .chunks(DEFAULT_CHUNK_SIZE as usize)
+        .map(xpress_compress)
+        .collect();
+    let mut big_resource = vec![];
+    let mut offset = 0;
+    for chunk in &chunks[..chunks.len() - 1] {
+        offset += chunk.len() as u32;
+        big_resource.extend(&offset.to_le_bytes());
+    }
+    big_resource.extend(chunks.drain(..).flatten());
+
+    let meta1 = metadata(&[
+        &[
+            (Some(1), [0; 20], "Windows", None),
+            (None, [0; 20], "readme.txt", Some(readme_hash)),
+            (None, [0; 20], "empty.txt", None),
+        ],
+        &[
+            (None, big_hash, "big.txt", None),
+            (None, readme_hash, "..", None),
+            (None, readme_hash, "link", None),
+        ],
+    ]);
+    let meta2 = metadata(&[&[(None, readme_hash, "only.txt", None)]]);
+    let xml: Vec<u8> = "\u{feff}<WIM><IMAGE INDEX=\"1\"><NAME>Full</NAME></IMAGE>\
+                        <IMAGE INDEX=\"2\"><NAME>Minimal</NAME></IMAGE></WIM>"
+        .encode_utf16()
+        .flat_map(|x| x.to_le_bytes())
+        .collect();
+
+    let mut wim = vec![0u8; HEADER_SIZE];
+    let mut table = vec![];
+    for &(data, flags, hash, size) in &[
+        (&readme, 0, readme_hash, readme.len()),
+        (&big_resource, RESHDR_FLAG_COMPRESSED, big_hash, big.len()),
+        (&meta1, RESHDR_FLAG_METADATA, [3; 20], meta1.len()),
+        (&meta2, RESHDR_FLAG_METADATA, [4; 20], meta2.len()),
+    ] {
+        table.extend(reshdr(data.len(), flags, wim.len(), size));
+        table.extend(&1u16.to_le_bytes());
+        table.extend(&1u32.to_le_bytes());
+        table.extend(&hash);
+        wim.extend(data.iter());
+    }
+    let mut header = MAGIC.to_vec();
+    header.extend(&(HEADER_SIZE as u32).to_le_bytes());
+    header.extend(&0x10d00u32.to_le_bytes());
+    header.extend(&(HDR_FLAG_COMPRESSION | HDR_FLAG_XPRESS).to_le_bytes());
+    header.extend(&(DEFAULT_CHUNK_SIZE as u32).to_le_bytes());
+    header.extend(&[0; 16]);
+    header.extend(&1u16.to_le_bytes());
+    header.extend(&1u16.to_le_bytes());
+    header.extend(&2u32.to_le_bytes());
+    header.extend(reshdr(table.len(), 0, wim.len(), table.len()));
+    header.extend(reshdr(xml.len(), 0, wim.len() + table.len(), xml.len()));
+    wim[..header.len()].copy_from_slice(&header);
+    wim.extend(table);
+    wim.extend(xml);
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("install.wim");
+    fs::write(&archive_path, &wim).unwrap();
+    fs::copy(&archive_path, dir.join("renamed.bin")).unwrap();
+    assert_eq!(ArchiveType::for_path(&archive_path), Some(ArchiveType::Wim));
+    assert_eq!(
+        ArchiveType::for_path(&dir.join("renamed.bin")),
+        Some(ArchiveType::Wim)
+    );
+
+    let mut archive = WimArchive::open(&archive_path).unwrap();
+    assert_eq!(
+        archive.metadata().unwrap(),
+        vec![
+            ("Images".to_string(), "2".to_string()),
+            ("Image 1".to_string(), "Full".to_string()),
+            ("Image 2".to_string(), "Minimal".to_string()),
+        ]
+    );
+    assert_eq!(archive.entries().unwrap().len(), 4);
+    assert_eq!(
+        archive.total_size(),
+        Some((readme.len() + big.len()) as u64)
+    );
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "install");
+    assert_eq!(fs::read(rv.join("readme.txt")).unwrap(), readme);
+    assert_eq!(fs::read(rv.join("empty.txt")).unwrap(), b"");
+    assert_eq!(fs::read(rv.join("Windows/big.txt")).unwrap(), big);
+    assert!(!rv.join("Windows/link").exists());
+    assert_eq!(
+        FileTime::from_last_modification_time(&rv.join("readme.txt").metadata().unwrap()),
+        FileTime::from_unix_time(1_555_526_400, 0)
+    );
+    fs::remove_dir_all(&rv).unwrap();
+
+    // other images are selected through the options
+    let unpack_image = |image| {
+        let mut archive = WimArchive::open(&archive_path).unwrap();
+        let options = UnpackOptions {
+            image: Some(image),
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper)?;
+        helper.commit()
+    };
+    let rv = unpack_image(2).unwrap();
+    assert_eq!(rv.file_name().unwrap(), "only.txt");
+    assert_eq!(fs::read(&rv).unwrap(), readme);
+    assert_eq!(
+        unpack_image(3).unwrap_err().to_string(),
+        "image 3 does not exist, the wim file has 2 images"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use libflate::zlib;
+use log::debug;
+use roxmltree::{Document, Node};
+
+use crate::archive::{Archive, ArchiveEntry, EntryKind, EntryMetadata, UnpackHelper};
+use crate::error::{bail, UnboxError};
+use crate::formats::Compression;
+use crate::utils::is_unsafe_path;
+
+pub const MAGIC: &[u8] = b"xar!";
+
+const HEADER_SIZE: u64 = 28;
+const MAX_TOC_SIZE: u64 = 0x1000_0000;
+
+/// How the data of a file is stored in the heap.
+#[derive(Debug, Copy, Clone)]
+enum Encoding {
+    Raw,
+    /// Called gzip in the toc but actually a zlib stream.
+    Zlib,
+    Compressed(Compression),
+}
+
+/// The location of a file's data in the heap.
+#[derive(Debug, Clone)]
+struct Data {
+    offset: u64,
+    length: u64,
+    size: u64,
+    encoding: Encoding,
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+    Directory,
+    File(Data),
+    Symlink(String),
+    /// A hard link to the file with the given id.
+    HardLink(String),
+}
+
+#[derive(Debug)]
+struct XarEntry {
+    path: PathBuf,
+    kind: Kind,
+    mode: Option<u32>,
+}
+
+#[derive(Debug)]
+pub struct XarArchive {
+    path: PathBuf,
+    heap_offset: u64,
+    entries: Vec<XarEntry>,
+}
+
+/// Returns the text of the named child element.
+fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.children()
+        .find(|x| x.has_tag_name(name))
+        .and_then(|x| x.text())
+        .map(|x| x.trim())
+}
+
+fn parse_data(node: Node) -> Result<Data, UnboxError> {
+    let number = |name: &str| -> Result<u64, UnboxError> {
+        match child_text(node, name).map(|x| x.parse()) {
+            Some(Ok(value)) => Ok(value),
+            _ => bail!("invalid xar toc: bad {} of file data", name),
+        }
+    };
+    let style = node
+        .children()
+        .find(|x| x.has_tag_name("encoding"))
+        .and_then(|x| x.attribute("style"));
+    let encoding = match style {
+        None | Some("application/octet-stream") => Encoding::Raw,
+        Some("application/x-gzip") => Encoding::Zlib,
+        Some("application/x-bzip2") => Encoding::Compressed(Compression::Bz2),
+        Some("application/x-xz") => Encoding::Compressed(Compression::Xz),
+        Some("application/x-lzma") => Encoding::Compressed(Compression::Lzma),
+        Some(style) => bail!("unsupported xar encoding '{}'", style),
+    };
+    Ok(Data {
+        offset: number("offset")?,
+        length: number("length")?,
+        size: number("size")?,
+        encoding,
+    })
+}
+
+/// Recursively collects the entries of the `<file>` elements below a node.
+fn read_files(
+    node: Node,
+    prefix: &Path,
+    ids: &mut HashMap<String, Data>,
+    entries: &mut Vec<XarEntry>,
+) -> Result<(), UnboxError> {
+    for file in node.children().filter(|x| x.has_tag_name("file")) {
+        let name = child_text(file, "name").unwrap_or("");
+        let path = prefix.join(name);
+        if name.is_empty() || is_unsafe_path(&path) {
+            debug!("skipping unsafe path {}", path.display());
+            continue;
+        }
+        let data = match file.children().find(|x| x.has_tag_name("data")) {
+            Some(data) => Some(parse_data(data)?),
+            None => None,
+        };
+        if let (Some(id), Some(data)) = (file.attribute("id"), &data) {
+            ids.insert(id.to_string(), data.clone());
+        }
+
+        let ty = file.children().find(|x| x.has_tag_name("type"));
+        let kind = match (ty.and_then(|x| x.text()), data) {
+            (Some("directory"), _) => Kind::Directory,
+            (Some("file"), Some(data)) => Kind::File(data),
+            (Some("file"), None) => Kind::File(Data {
+                offset: 0,
+                length: 0,
+                size: 0,
+                encoding: Encoding::Raw,
+            }),
+            (Some("symlink"), _) => match child_text(file, "link") {
+                Some(target) => Kind::Symlink(target.to_string()),
+                None => bail!("invalid xar toc: symlink without target"),
+            },
+            // the original of a set of hard links carries the data
+            (Some("hardlink"), Some(data)) => Kind::File(data),
+            (Some("hardlink"), None) => match ty.and_then(|x| x.attribute("link")) {
+                Some(id) => Kind::HardLink(id.to_string()),
+                None => bail!("invalid xar toc: hardlink without target"),
+            },
+            _ => {
+                debug!("skipping special file {}", path.display());
+                continue;
+            }
+        };
+        let mode = child_text(file, "mode").and_then(|x| u32::from_str_radix(x, 8).ok());
+        let is_dir = matches!(kind, Kind::Directory);
+        entries.push(XarEntry {
+            path: path.clone(),
+            kind,
+            mode,
+        });
+        if is_dir {
+            read_files(file, &path, ids, entries)?;
+        }
+    }
+    Ok(())
+}
+
+impl XarArchive {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, UnboxError> {
+        let path = path.as_ref().canonicalize()?;
+        let mut rdr = BufReader::new(File::open(&path)?);
+
+        let mut header = [0u8; HEADER_SIZE as usize];
+        rdr.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            bail!("not a xar archive");
+        }
+        let quad = |idx: usize| {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&header[idx..idx + 8]);
+            u64::from_be_bytes(buf)
+        };
+        let header_size = u64::from(u16::from_be_bytes([header[4], header[5]]));
+        let toc_compressed = quad(8);
+        let toc_size = quad(16);
+        if header_size < HEADER_SIZE || toc_size > MAX_TOC_SIZE {
+            bail!("invalid xar header");
+        }
+
+        // the header may be followed by the name of a custom checksum
+        // algorithm, the compressed toc starts after the declared size.
+        rdr.seek(SeekFrom::Start(header_size))?;
+        let mut toc = String::new();
+        zlib::Decoder::new(rdr.take(toc_compressed))?
+            .take(toc_size)
+            .read_to_string(&mut toc)?;
+        let doc = match Document::parse(&toc) {
+            Ok(doc) => doc,
+            Err(err) => bail!("invalid xar toc: {}", err),
+        };
+        let root = match doc
+            .root_element()
+            .children()
+            .find(|x| x.has_tag_name("toc"))
+        {
+            Some(root) => root,
+            None => bail!("invalid xar toc: missing toc element"),
+        };
+
+        let mut ids = HashMap::new();
+        let mut entries = vec![];
+        read_files(root, Path::new(""), &mut ids, &mut entries)?;
+
+        // hard links are resolved once all files are known
+        for entry in &mut entries {
+            if let Kind::HardLink(ref id) = entry.kind {
+                entry.kind = match ids.get(id) {
+                    Some(data) => Kind::File(data.clone()),
+                    None => bail!("invalid xar toc: unknown hardlink target {}", id),
+                };
+            }
+        }
+
+        Ok(XarArchive {
+            path,
+            heap_offset: header_size + toc_compressed,
+            entries,
+        })
+    }
+
+    /// Opens a reader for the decoded data of a file.
+    fn open_data(&self, data: &Data) -> Result<Box<dyn Read>, UnboxError> {
+        let mut f = File::open(&self.path)?;
+        f.seek(SeekFrom::Start(self.heap_offset + data.offset))?;
+        let rdr = f.take(data.length);
+        Ok(match data.encoding {
+            Encoding::Raw => Box::new(rdr),
+            Encoding::Zlib => Box::new(zlib::Decoder::new(rdr)?),
+            Encoding::Compressed(compression) => compression.decompress(rdr)?,
+        })
+    }
+}
+
+impl Archive for XarArchive {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn total_size(&self) -> Option<u64> {
+        Some(
+            self.entries
+                .iter()
+                .map(|entry| match entry.kind {
+                    Kind::File(ref data) => data.size,
+                    _ => 0,
+                })
+                .sum(),
+        )
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        Some(self.entries.len() as u64)
+    }
+
+    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), UnboxError> {
+        for entry in &self.entries {
+            if helper.skip_entry() {
+                continue;
+            }
+            match entry.kind {
+                Kind::Directory => helper.create_dir(&entry.path)?,
+                Kind::File(ref data) => {
+                    let metadata = EntryMetadata {
+                        mode: entry.mode,
+                        owner: None,
+                        mtime: None,
+                    };
+                    self.open_data(data)
+                        .and_then(|rdr| {
+                            helper.write_file_with_metadata(
+                                &entry.path,
+                                rdr.take(data.size),
+                                metadata,
+                            )
+                        })
+                        .map_err(|err| helper.entry_error(&entry.path, err))?;
+                }
+                Kind::Symlink(ref target) => helper.create_symlink(&entry.path, target)?,
+                Kind::HardLink(..) => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    fn entries(&mut self) -> Result<Vec<ArchiveEntry>, UnboxError> {
+        Ok(self
+            .entries
+            .iter()
+            .map(|entry| {
+                let name = entry.path.to_string_lossy();
+                match entry.kind {
+                    Kind::Directory => ArchiveEntry::new(name, 0, EntryKind::Directory),
+                    Kind::File(ref data) => ArchiveEntry::new(name, data.size, EntryKind::File),
+                    _ => ArchiveEntry::new(name, 0, EntryKind::Other),
+                }
+            })
+            .collect())
+    }
+}
+
+/// Builds a xar archive from a toc with `{heap}` placeholders.
+///
+/// Each file is given as its encoding and contents, the placeholders are
+/// replaced with the `<data>` element of the corresponding file.
+#[cfg(test)]
+fn build_xar(toc: &str, files: &[(&str, &[u8])]) -> Vec<u8> {
+    use std::io::Write;
+
+    let mut heap = vec![];
+    let mut toc = toc.to_string();
+    for &(style, contents) in files {
+        let encoded = if style == "application/x-gzip" {
+            let mut encoder = zlib::Encoder::new(vec![]).unwrap();
+            encoder.write_all(contents).unwrap();
+            encoder.finish().into_result().unwrap()
+        } else {
+            contents.to_vec()
+        };
+        let data = format!(
+            "<data><length>{}</length><offset>{}</offset><size>{}</size>\
+             <encoding style=\"{}\"/></data>",
+            encoded.len(),
+            heap.len(),
+            contents.len(),
+            style
+        );
+        toc = toc.replacen("{heap}", &data, 1);
+        heap.extend(encoded);
+    }
+
+    let mut encoder = zlib::Encoder::new(vec![]).unwrap();
+    encoder.write_all(toc.as_bytes()).unwrap();
+    let toc_compressed = encoder.finish().into_result().unwrap();
+
+    let mut rv = MAGIC.to_vec();
+    rv.extend(&(HEADER_SIZE as u16).to_be_bytes());
+    rv.extend(&1u16.to_be_bytes());
+    rv.extend(&(toc_compressed.len() as u64).to_be_bytes());
+    rv.extend(&(toc.len() as u64).to_be_bytes());
+    rv.extend(&0u32.to_be_bytes());
+    rv.extend(toc_compressed);
+    rv.extend(heap);
+    rv
+}
+
+#[test]
+fn test_xar() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    let toc = r#"<?xml version="1.0" encoding="UTF-8"?>
+<xar><toc>
+  <file id="1"><name>payload</name><type>directory</type>
+    <file id="2"><name>a.txt</name><type>file</type><mode>0644</mode>{heap}</file>
+    <file id="3"><name>b.txt</name><type link="original">hardlink</type>{heap}</file>
+    <file id="4"><name>c.txt</name><type link="3">hardlink</type></file>
+    <file id="5"><name>sub</name><type>directory</type>
+      <file id="6"><name>link.txt</name><type>symlink</type><link type="file">../a.txt</link></file>
+    </file>
+  </file>
+  <file id="7"><name>../evil.txt</name><type>file</type>{heap}</file>
+</toc></xar>"#;
+    let contents = b"hello xar ".repeat(100);
+    let files: [(&str, &[u8]); 3] = [
+        ("application/octet-stream", b"hello"),
+        ("application/x-gzip", &contents),
+        ("application/octet-stream", b"evil"),
+    ];
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("payload.pkg");
+    fs::write(&archive_path, build_xar(toc, &files)).unwrap();
+    fs::copy(&archive_path, dir.join("renamed.bin")).unwrap();
+    assert_eq!(ArchiveType::for_path(&archive_path), Some(ArchiveType::Xar));
+    assert_eq!(
+        ArchiveType::for_path(&dir.join("renamed.bin")),
+        Some(ArchiveType::Xar)
+    );
+
+    let mut archive = XarArchive::open(&archive_path).unwrap();
+    assert_eq!(archive.entries().unwrap().len(), 6);
+    assert_eq!(archive.total_size(), Some(5 + 2 * contents.len() as u64));
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "payload");
+    assert_eq!(fs::read(rv.join("a.txt")).unwrap(), b"hello");
+    assert_eq!(fs::read(rv.join("b.txt")).unwrap(), contents);
+    assert_eq!(fs::read(rv.join("c.txt")).unwrap(), contents);
+    #[cfg(unix)]
+    assert_eq!(fs::read(rv.join("sub/link.txt")).unwrap(), b"hello");
+    assert!(!dir.join("evil.txt").exists());
+    fs::remove_dir_all(&dir).unwrap();
+
+    // unknown encodings are rejected upfront
+    let dir = make_test_dir();
+    let archive_path = dir.join("broken.xar");
+    let toc = "<xar><toc><file id=\"1\"><name>a</name><type>file</type>{heap}</file></toc></xar>";
+    let files: [(&str, &[u8]); 1] = [("application/x-foo", b"a")];
+    fs::write(&archive_path, build_xar(toc, &files)).unwrap();
+    assert_eq!(
+        XarArchive::open(&archive_path).unwrap_err().to_string(),
+        "unsupported xar encoding 'application/x-foo'"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,32 +1,938 @@
-use std::fs::{create_dir_all, File};
-use std::io::BufReader;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::str;
 
-use failure::Error;
-use zip::read::ZipArchive as ZipArchiveReader;
+use aes::cipher::{KeyIvInit, StreamCipher};
+use chardetng::EncodingDetector;
+use encoding_rs::Encoding;
+use filetime::FileTime;
+use hmac::{Hmac, Mac};
+use libflate::deflate;
+use log::debug;
+use sha1::Sha1;
+use zip::read::{ZipArchive as ZipArchiveReader, ZipFile};
+use zip::result::ZipError;
+use zip::CompressionMethod;
 
-use crate::archive::{Archive, UnpackHelper};
+use crate::archive::{Archive, ArchiveEntry, EntryKind, EntryMetadata, ReadSeek, UnpackHelper};
+use crate::error::{bail, UnboxError};
+use crate::formats::describe_mimetype;
+use crate::utils::days_from_civil;
+
+/// The compression method marking AES encrypted entries.
+const AES_METHOD: u16 = 99;
+
+/// The id of the extra field describing an AES encrypted entry.
+const AES_EXTRA_FIELD: u16 = 0x9901;
+
+/// The length of the authentication code after AES encrypted data.
+const AES_AUTH_CODE_LEN: u64 = 10;
+
+/// The compression method of deflate64 which the zip crate cannot decode.
+const DEFLATE64_METHOD: u16 = 9;
+
+const S_IFMT: u32 = 0o170_000;
+const S_IFLNK: u32 = 0o120_000;
+
+/// The id of the extra field holding unix timestamps.
+const EXTENDED_TIMESTAMP: u16 = 0x5455;
+
+/// The id of the extra field holding the name as UTF-8.
+const UNICODE_PATH: u16 = 0x7075;
+
+const EOCD_MAGIC: &[u8] = b"PK\x05\x06";
+const CENTRAL_HEADER_MAGIC: &[u8] = b"PK\x01\x02";
+const EOCD_SIZE: usize = 22;
+const ZIP64_EOCD_MAGIC: &[u8] = b"PK\x06\x06";
+const ZIP64_LOCATOR_MAGIC: &[u8] = b"PK\x06\x07";
+const ZIP64_LOCATOR_SIZE: usize = 20;
+const ZIP64_EOCD_SIZE: usize = 56;
+const CENTRAL_HEADER_SIZE: usize = 46;
 
 #[derive(Debug)]
 pub struct ZipArchive {
     path: PathBuf,
-    rdr: ZipArchiveReader<BufReader<File>>,
+    rdr: ZipArchiveReader<BufReader<VolumeReader>>,
     total_size: u64,
+    from_reader: bool,
+    volumes: Vec<PathBuf>,
 }
 
 impl ZipArchive {
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, UnboxError> {
         let path = path.as_ref().canonicalize()?;
-        let mut rdr = ZipArchiveReader::new(BufReader::new(File::open(&path)?))?;
+        let volumes = VolumeReader::open(&path)?;
+        ZipArchive::from_volumes(volumes, path, false)
+    }
+
+    /// Opens a zip archive from a reader instead of a file.
+    ///
+    /// The name stands in for the path of the archive.  As the reader
+    /// cannot be opened again, entries are always unpacked serially and
+    /// split archives are not supported.
+    pub fn from_reader<R: Read + Seek + 'static>(rdr: R, name: &str) -> Result<Self, UnboxError> {
+        let volumes = VolumeReader::from_reader(rdr)?;
+        ZipArchive::from_volumes(volumes, PathBuf::from(name), true)
+    }
+
+    fn from_volumes(
+        volumes: VolumeReader,
+        path: PathBuf,
+        from_reader: bool,
+    ) -> Result<Self, UnboxError> {
+        let volume_paths = volumes.paths.clone();
+        let mut rdr = ZipArchiveReader::new(BufReader::new(volumes))?;
         let total_size = (0..rdr.len())
-            .map(|x| rdr.by_index(x).ok().map_or(0, |x| x.size()))
+            .map(|x| rdr.by_index_raw(x).ok().map_or(0, |x| x.size()))
             .sum();
         Ok(ZipArchive {
             path,
             rdr,
             total_size,
+            from_reader,
+            volumes: volume_paths,
         })
     }
+
+    /// Opens a zip archive appended to an executable such as the ones of
+    /// self-extracting installers.
+    ///
+    /// The zip reader locates the archive from its end and accounts for
+    /// the executable in front of it on its own.
+    pub fn find_in_executable<P: AsRef<Path>>(path: P) -> Result<Self, UnboxError> {
+        ZipArchive::open(path)
+    }
+}
+
+/// Reads the size and offset of the central directory from the zip64 end of
+/// central directory record.
+///
+/// The record is located through the locator directly in front of the
+/// regular end of central directory record at `pos`.  Returns the position
+/// of the record with the size and offset.
+fn zip64_directory(bytes: &[u8], pos: usize) -> Option<(usize, usize, usize)> {
+    let locator = pos.checked_sub(ZIP64_LOCATOR_SIZE)?;
+    if bytes.get(locator..locator + 4)? != ZIP64_LOCATOR_MAGIC {
+        return None;
+    }
+    // the record usually directly precedes the locator
+    let record = locator.checked_sub(ZIP64_EOCD_SIZE)?;
+    if bytes.get(record..record + 4)? != ZIP64_EOCD_MAGIC {
+        return None;
+    }
+    let dword = |idx: usize| {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&bytes[record + idx..record + idx + 8]);
+        usize::try_from(u64::from_le_bytes(buf)).ok()
+    };
+    Some((record, dword(40)?, dword(48)?))
+}
+
+/// Finds the offset of a zip archive appended to an executable.
+///
+/// This scans backwards for the end of central directory record which
+/// is only followed by the archive comment of up to 64KB.  The central
+/// directory has to directly precede it, or the zip64 records if the
+/// archive is too large for the regular one.
+fn zip_offset(bytes: &[u8]) -> Result<usize, UnboxError> {
+    let end = bytes.len().saturating_sub(EOCD_SIZE - 1);
+    let start = bytes.len().saturating_sub(EOCD_SIZE + 65535);
+    for pos in (start..end).rev() {
+        if &bytes[pos..pos + 4] != EOCD_MAGIC {
+            continue;
+        }
+        let word = |idx: usize| {
+            u32::from_le_bytes([
+                bytes[pos + idx],
+                bytes[pos + idx + 1],
+                bytes[pos + idx + 2],
+                bytes[pos + idx + 3],
+            ]) as usize
+        };
+        let (end, directory_size, directory_offset) =
+            if word(12) == 0xffff_ffff || word(16) == 0xffff_ffff {
+                match zip64_directory(bytes, pos) {
+                    Some(rv) => rv,
+                    None => continue,
+                }
+            } else {
+                (pos, word(12), word(16))
+            };
+        let directory_start = match end.checked_sub(directory_size) {
+            Some(directory_start) if directory_size > 0 => directory_start,
+            _ => continue,
+        };
+        if bytes.get(directory_start..directory_start + 4) != Some(CENTRAL_HEADER_MAGIC) {
+            continue;
+        }
+        // some tools store offsets relative to the executable instead
+        return Ok(directory_start.saturating_sub(directory_offset));
+    }
+    bail!("no zip in executable");
+}
+
+/// Checks if the executable in the given bytes has a zip archive appended.
+pub fn contains_zip(bytes: &[u8]) -> bool {
+    zip_offset(bytes).is_ok()
+}
+
+/// A part of the data presented by a [`VolumeReader`].
+enum Part {
+    /// The first bytes of a file or reader and the position of its handle.
+    File(Box<dyn ReadSeek>, u64, u64),
+    Memory(Vec<u8>),
+}
+
+impl fmt::Debug for Part {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Part::File(_, len, pos) => f.debug_tuple("File").field(&len).field(&pos).finish(),
+            Part::Memory(ref buf) => f.debug_tuple("Memory").field(&buf.len()).finish(),
+        }
+    }
+}
+
+impl Part {
+    fn len(&self) -> u64 {
+        match *self {
+            Part::File(_, len, _) => len,
+            Part::Memory(ref buf) => buf.len() as u64,
+        }
+    }
+}
+
+/// Presents the volumes of a split zip archive as a single archive.
+///
+/// Split archives as created by WinZip and Info-ZIP store the archive in
+/// `.z01` to `.zNN` volumes followed by the `.zip` holding the central
+/// directory.  The offsets in there are relative to the volume which the
+/// zip reader does not support, so the volumes are concatenated and the
+/// central directory is rewritten to use offsets into the concatenation.
+/// Regular archives are passed through unchanged.
+#[derive(Debug)]
+struct VolumeReader {
+    parts: Vec<Part>,
+    pos: u64,
+    /// The files holding the volumes, empty for a reader.
+    paths: Vec<PathBuf>,
+}
+
+impl VolumeReader {
+    fn new(parts: Vec<Part>) -> VolumeReader {
+        VolumeReader {
+            parts,
+            pos: 0,
+            paths: vec![],
+        }
+    }
+
+    fn open(path: &Path) -> Result<VolumeReader, UnboxError> {
+        let mut rv = VolumeReader::open_volumes(path)?;
+        rv.paths.push(path.to_path_buf());
+        Ok(rv)
+    }
+
+    fn open_volumes(path: &Path) -> Result<VolumeReader, UnboxError> {
+        let mut f = File::open(path)?;
+        let len = f.metadata()?.len();
+        let eocd = read_eocd(&mut f, len)?;
+        let last_disk = match eocd {
+            Some(ref eocd) => u16::from_le_bytes([eocd[4], eocd[5]]),
+            None => 0,
+        };
+        // the disk number is only spanning if it is not a zip64 marker
+        let eocd = match eocd {
+            Some(eocd) if last_disk != 0 && last_disk != 0xffff => eocd,
+            _ => return Ok(VolumeReader::new(vec![Part::File(Box::new(f), len, 0)])),
+        };
+        debug!(
+            "{} is the last of {} volumes",
+            path.display(),
+            last_disk + 1
+        );
+
+        let volume_paths: Vec<_> = (1..=last_disk).map(|x| volume_path(path, x)).collect();
+        let missing: Vec<_> = volume_paths
+            .iter()
+            .filter(|x| !x.is_file())
+            .map(|x| x.file_name().unwrap_or_default().to_string_lossy())
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "split zip archive is missing volumes: {}",
+                missing.join(", ")
+            );
+        }
+        let mut volumes = vec![];
+        for volume_path in &volume_paths {
+            let f = File::open(volume_path)?;
+            let len = f.metadata()?.len();
+            volumes.push(Part::File(Box::new(f), len, 0));
+        }
+        volumes.push(Part::File(Box::new(f), len - eocd.len() as u64, 0));
+        let starts: Vec<u64> = volumes
+            .iter()
+            .scan(0, |pos, part| {
+                let start = *pos;
+                *pos += part.len();
+                Some(start)
+            })
+            .collect();
+
+        let word = |idx: usize| u16::from_le_bytes([eocd[idx], eocd[idx + 1]]);
+        let dword = |idx: usize| {
+            u32::from_le_bytes([eocd[idx], eocd[idx + 1], eocd[idx + 2], eocd[idx + 3]])
+        };
+        let directory_start = match starts.get(usize::from(word(6))) {
+            Some(start) => start + u64::from(dword(16)),
+            None => bail!("invalid central directory volume in split zip archive"),
+        };
+        let directory_size = u64::from(dword(12));
+        if directory_start + directory_size > volumes.iter().map(|x| x.len()).sum() {
+            bail!("invalid central directory size in split zip archive");
+        }
+        let mut rdr = VolumeReader::new(volumes);
+        rdr.seek(SeekFrom::Start(directory_start))?;
+        let mut directory = vec![0; directory_size as usize];
+        rdr.read_exact(&mut directory)?;
+        rebase_directory(&mut directory, &starts)?;
+
+        let directory_start = match u32::try_from(directory_start) {
+            Ok(directory_start) => directory_start,
+            Err(_) => bail!("split zip archive is too large"),
+        };
+        let mut tail = directory;
+        tail.extend(EOCD_MAGIC);
+        tail.extend(&[0, 0, 0, 0]);
+        tail.extend(&eocd[10..12]);
+        tail.extend(&eocd[10..16]);
+        tail.extend(&directory_start.to_le_bytes());
+        tail.extend(&eocd[20..]);
+
+        // everything up to the central directory is passed through
+        let mut parts = vec![];
+        for (part, start) in rdr.parts.into_iter().zip(starts) {
+            if let Part::File(f, len, pos) = part {
+                if start < u64::from(directory_start) {
+                    let len = len.min(u64::from(directory_start) - start);
+                    parts.push(Part::File(f, len, pos));
+                }
+            }
+        }
+        parts.push(Part::Memory(tail));
+        let mut rv = VolumeReader::new(parts);
+        rv.paths = volume_paths;
+        Ok(rv)
+    }
+
+    /// Presents a single reader holding the entire archive.
+    fn from_reader<R: Read + Seek + 'static>(mut rdr: R) -> Result<VolumeReader, UnboxError> {
+        let len = rdr.seek(SeekFrom::End(0))?;
+        Ok(VolumeReader::new(vec![Part::File(Box::new(rdr), len, len)]))
+    }
+
+    fn len(&self) -> u64 {
+        self.parts.iter().map(|x| x.len()).sum()
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut start = 0;
+        for part in &mut self.parts {
+            let len = part.len();
+            if self.pos >= start + len {
+                start += len;
+                continue;
+            }
+            let offset = self.pos - start;
+            let size = buf.len().min((len - offset) as usize);
+            let read = match *part {
+                Part::File(ref mut f, _, ref mut pos) => {
+                    if *pos != offset {
+                        f.seek(SeekFrom::Start(offset))?;
+                    }
+                    let read = f.read(&mut buf[..size])?;
+                    *pos = offset + read as u64;
+                    read
+                }
+                Part::Memory(ref data) => {
+                    let offset = offset as usize;
+                    buf[..size].copy_from_slice(&data[offset..offset + size]);
+                    size
+                }
+            };
+            self.pos += read as u64;
+            return Ok(read);
+        }
+        Ok(0)
+    }
+}
+
+impl Seek for VolumeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => checked_offset(self.len(), offset),
+            SeekFrom::Current(offset) => checked_offset(self.pos, offset),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+fn checked_offset(base: u64, offset: i64) -> Option<u64> {
+    if offset < 0 {
+        base.checked_sub(offset.unsigned_abs())
+    } else {
+        base.checked_add(offset as u64)
+    }
+}
+
+/// Reads the end of central directory record including the comment.
+///
+/// The file is rewound afterwards.
+fn read_eocd(f: &mut File, len: u64) -> Result<Option<Vec<u8>>, UnboxError> {
+    let tail_len = len.min((EOCD_SIZE + 65535) as u64);
+    let mut tail = vec![0; tail_len as usize];
+    f.seek(SeekFrom::Start(len - tail_len))?;
+    f.read_exact(&mut tail)?;
+    f.seek(SeekFrom::Start(0))?;
+    let end = tail.len().saturating_sub(EOCD_SIZE - 1);
+    Ok((0..end)
+        .rev()
+        .find(|&pos| &tail[pos..pos + 4] == EOCD_MAGIC)
+        .map(|pos| tail.split_off(pos)))
+}
+
+/// Returns the path of the volume with the given number of a split zip
+/// archive.
+///
+/// The first volume of `foo.zip` is `foo.z01`.
+fn volume_path(path: &Path, number: u16) -> PathBuf {
+    let extension = format!("z{:02}", number);
+    match path.extension().and_then(|x| x.to_str()) {
+        Some(ext) if ext.chars().all(|x| x.is_ascii_uppercase()) => {
+            path.with_extension(extension.to_ascii_uppercase())
+        }
+        _ => path.with_extension(extension),
+    }
+}
+
+/// Rewrites the offsets in the central directory of a split archive to
+/// point into the concatenated volumes.
+fn rebase_directory(directory: &mut [u8], starts: &[u64]) -> Result<(), UnboxError> {
+    let mut pos = 0;
+    while directory.len() >= pos + CENTRAL_HEADER_SIZE {
+        let header = &mut directory[pos..];
+        if &header[..4] != CENTRAL_HEADER_MAGIC {
+            bail!("invalid central directory in split zip archive");
+        }
+        let word = |idx: usize| usize::from(u16::from_le_bytes([header[idx], header[idx + 1]]));
+        let disk = word(34);
+        let size = CENTRAL_HEADER_SIZE + word(28) + word(30) + word(32);
+        let offset = u32::from_le_bytes([header[42], header[43], header[44], header[45]]);
+        if offset == 0xffff_ffff {
+            bail!("zip64 split archives are not supported");
+        }
+        let offset = match starts.get(disk) {
+            Some(start) => u32::try_from(start + u64::from(offset)),
+            None => bail!("invalid volume number in split zip archive"),
+        };
+        let offset = match offset {
+            Ok(offset) => offset,
+            Err(_) => bail!("split zip archive is too large"),
+        };
+        header[34..36].copy_from_slice(&[0, 0]);
+        header[42..46].copy_from_slice(&offset.to_le_bytes());
+        pos += size;
+    }
+    Ok(())
+}
+
+/// Refines the label of zip based formats by peeking at the first entry.
+///
+/// Many formats built on top of zip store a marker as first entry such as
+/// the `mimetype` of EPUB and OpenDocument files or the content types of
+/// office documents.  Only the first entry is read.
+pub fn refined_label<P: AsRef<Path>>(path: P) -> Option<&'static str> {
+    let mut rdr = ZipArchiveReader::new(BufReader::new(File::open(path).ok()?)).ok()?;
+    let mut file = rdr.by_index(0).ok()?;
+    let name = file.name().to_string();
+    let mut contents = String::new();
+    match name.as_str() {
+        "mimetype" | "[Content_Types].xml" => {
+            file.by_ref()
+                .take(65536)
+                .read_to_string(&mut contents)
+                .ok()?;
+        }
+        "AndroidManifest.xml" => return Some("android package"),
+        "META-INF/" | "META-INF/MANIFEST.MF" => return Some("java archive"),
+        _ => return None,
+    }
+    describe_mimetype(contents.trim())
+}
+
+/// Converts an entry name into a path.
+///
+/// Entries that would escape the extraction folder are rejected by the
+/// unpack helper.
+fn entry_path(name: &str) -> PathBuf {
+    let name = name.split('\0').next().unwrap_or("").replace('\\', "/");
+    PathBuf::from(name)
+}
+
+/// How the names of entries without the UTF-8 flag are decoded.
+#[derive(Debug, Copy, Clone)]
+enum NameEncoding {
+    /// Names that are valid UTF-8 are taken as is as many tools do not set
+    /// the flag.  Others are decoded with the encoding guessed from all
+    /// names or code page 437 if there is no confident guess.
+    Auto(Option<&'static Encoding>),
+    /// Code page 437, the historical default of zip.  It is decoded by the
+    /// zip crate as `encoding_rs` does not support it.
+    Cp437,
+    /// The encoding picked by the user.
+    Fixed(&'static Encoding),
+}
+
+/// Returns `true` if the name of an entry is flagged as UTF-8.
+///
+/// The zip crate does not expose the flag but decodes names without it as
+/// code page 437 which differs from the raw bytes for all non ASCII names.
+fn has_utf8_name(file: &ZipFile) -> bool {
+    str::from_utf8(file.name_raw()) == Ok(file.name())
+}
+
+/// Picks the encoding for entry names without the UTF-8 flag.
+///
+/// Unless an encoding label is given, the encoding is guessed from all names
+/// that are not valid UTF-8 together as single names are often too short for
+/// a good guess.
+fn name_encoding(
+    rdr: &mut ZipArchiveReader<BufReader<VolumeReader>>,
+    label: Option<&str>,
+) -> Result<NameEncoding, UnboxError> {
+    if let Some(label) = label {
+        if ["cp437", "ibm437", "437"].contains(&label.to_ascii_lowercase().as_str()) {
+            return Ok(NameEncoding::Cp437);
+        }
+        match Encoding::for_label(label.as_bytes()) {
+            Some(encoding) => return Ok(NameEncoding::Fixed(encoding)),
+            None => bail!("unknown encoding '{}'", label),
+        }
+    }
+
+    let mut detector = EncodingDetector::new();
+    let mut found = false;
+    for idx in 0..rdr.len() {
+        let file = rdr.by_index_raw(idx)?;
+        if !has_utf8_name(&file) && str::from_utf8(file.name_raw()).is_err() {
+            detector.feed(file.name_raw(), false);
+            found = true;
+        }
+    }
+    if !found {
+        return Ok(NameEncoding::Auto(None));
+    }
+    detector.feed(b"", true);
+    // zip tools on windows use the DOS code pages for names.  The detector
+    // does not know code page 437 and takes such names for one of the
+    // windows code pages, so only multi-byte encodings and the cyrillic
+    // DOS code page are trusted.
+    match detector.guess_assess(None, false) {
+        (encoding, true) if !encoding.is_single_byte() || encoding == encoding_rs::IBM866 => {
+            Ok(NameEncoding::Auto(Some(encoding)))
+        }
+        _ => Ok(NameEncoding::Auto(None)),
+    }
+}
+
+/// Decodes the name of an entry with the encoding for names without the
+/// UTF-8 flag.
+fn decode_name(file: &ZipFile, encoding: NameEncoding) -> String {
+    let raw = file.name_raw();
+    if has_utf8_name(file) {
+        return file.name().to_string();
+    }
+    match encoding {
+        NameEncoding::Auto(guessed) => match (str::from_utf8(raw), guessed) {
+            (Ok(name), _) => name.to_string(),
+            (Err(_), Some(encoding)) => encoding.decode_without_bom_handling(raw).0.into_owned(),
+            (Err(_), None) => file.name().to_string(),
+        },
+        NameEncoding::Cp437 => file.name().to_string(),
+        NameEncoding::Fixed(encoding) => encoding.decode_without_bom_handling(raw).0.into_owned(),
+    }
+}
+
+/// Returns `true` if the entry with the given name is a directory.
+fn is_dir(file: &ZipFile, name: &str) -> bool {
+    file.unix_mode().unwrap_or(0) & 16384 != 0 || name.ends_with('/')
+}
+
+/// Returns `true` if the entry is a symlink with the target as contents.
+fn is_symlink(file: &ZipFile) -> bool {
+    file.unix_mode().unwrap_or(0) & S_IFMT == S_IFLNK
+}
+
+/// Iterates over the ids and contents of the extra fields of an entry.
+///
+/// Iteration stops at a truncated field.
+fn extra_fields<'a>(file: &'a ZipFile) -> impl Iterator<Item = (u16, &'a [u8])> {
+    let mut extra = file.extra_data();
+    std::iter::from_fn(move || {
+        if extra.len() < 4 {
+            return None;
+        }
+        let id = u16::from_le_bytes([extra[0], extra[1]]);
+        let len = usize::from(u16::from_le_bytes([extra[2], extra[3]]));
+        let data = extra.get(4..4 + len)?;
+        extra = &extra[4 + len..];
+        Some((id, data))
+    })
+}
+
+/// Returns a readable name of the compression method of an entry.
+fn compression_name(file: &ZipFile) -> String {
+    // the zip crate has no constants for the less common methods
+    #[allow(deprecated)]
+    match file.compression().to_u16() {
+        0 => "stored".into(),
+        8 => "deflate".into(),
+        DEFLATE64_METHOD => "deflate64".into(),
+        12 => "bzip2".into(),
+        14 => "lzma".into(),
+        93 => "zstd".into(),
+        AES_METHOD => "aes".into(),
+        method => format!("method {}", method),
+    }
+}
+
+/// Returns the modification time of an entry.
+///
+/// The extended timestamp field is used if present as it is in UTC.  The
+/// regular timestamp has no time zone and is interpreted as UTC.
+fn entry_mtime(file: &ZipFile) -> Option<FileTime> {
+    for (id, data) in extra_fields(file) {
+        if id == EXTENDED_TIMESTAMP && data.len() >= 5 && data[0] & 1 != 0 {
+            let mtime = i32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+            return Some(FileTime::from_unix_time(i64::from(mtime), 0));
+        }
+    }
+
+    let dt = file.last_modified();
+    if dt.month() == 0 || dt.day() == 0 {
+        return None;
+    }
+    let days = days_from_civil(
+        i64::from(dt.year()),
+        i64::from(dt.month()),
+        i64::from(dt.day()),
+    );
+    let seconds =
+        i64::from(dt.hour()) * 3600 + i64::from(dt.minute()) * 60 + i64::from(dt.second());
+    Some(FileTime::from_unix_time(days * 86400 + seconds, 0))
+}
+
+/// Checks if an entry is compressed with deflate64.
+fn is_deflate64(file: &ZipFile) -> bool {
+    // the zip crate has no constant for deflate64
+    #[allow(deprecated)]
+    let deflate64 = CompressionMethod::from_u16(DEFLATE64_METHOD);
+    file.compression() == deflate64
+}
+
+/// The WinZip AES parameters of an entry.
+#[derive(Debug, Clone, Copy)]
+struct AesInfo {
+    /// 1 for AE-1 which keeps the crc32, 2 for AE-2 which does not.
+    version: u16,
+    /// 1, 2 or 3 for 128, 192 or 256 bit keys.
+    strength: u8,
+    /// The compression method of the data once it is decrypted.
+    method: u16,
+}
+
+/// Returns the AES parameters of an entry if it is AES encrypted.
+fn aes_info(file: &ZipFile) -> Option<AesInfo> {
+    // the zip crate has no constant for the AES marker
+    #[allow(deprecated)]
+    let aes = CompressionMethod::from_u16(AES_METHOD);
+    if file.compression() != aes {
+        return None;
+    }
+    extra_fields(file)
+        .find(|&(id, data)| id == AES_EXTRA_FIELD && data.len() >= 7)
+        .map(|(_, data)| AesInfo {
+            version: u16::from_le_bytes([data[0], data[1]]),
+            strength: data[4],
+            method: u16::from_le_bytes([data[5], data[6]]),
+        })
+}
+
+/// Decrypts the data of a WinZip AES encrypted entry.
+///
+/// The authentication code that follows the data is checked once all of
+/// the data was read.
+struct AesReader<R> {
+    rdr: R,
+    cipher: Box<dyn StreamCipher>,
+    mac: Hmac<Sha1>,
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: Read> AesReader<R> {
+    /// Reads the salt and checks the password against the verifier.
+    ///
+    /// The size is the size of the raw entry including salt and
+    /// authentication code.
+    fn new(mut rdr: R, password: &str, strength: u8, size: u64) -> Result<Self, UnboxError> {
+        let key_len = match strength {
+            1 => 16,
+            2 => 24,
+            3 => 32,
+            _ => bail!("unsupported AES key strength {}", strength),
+        };
+        let overhead = key_len as u64 / 2 + 2 + AES_AUTH_CODE_LEN;
+        if size < overhead {
+            bail!("truncated AES encrypted entry");
+        }
+        let mut salt = vec![0; key_len / 2];
+        rdr.read_exact(&mut salt)?;
+        let mut verifier = [0; 2];
+        rdr.read_exact(&mut verifier)?;
+
+        let mut keys = vec![0; key_len * 2 + 2];
+        pbkdf2::pbkdf2_hmac::<Sha1>(password.as_bytes(), &salt, 1000, &mut keys);
+        if keys[key_len * 2..] != verifier {
+            bail!("invalid password");
+        }
+
+        // the counter is little endian and starts at one
+        let mut iv = [0; 16];
+        iv[0] = 1;
+        let key = &keys[..key_len];
+        let cipher: Box<dyn StreamCipher> = match key_len {
+            16 => Box::new(ctr::Ctr128LE::<aes::Aes128>::new(key.into(), &iv.into())),
+            24 => Box::new(ctr::Ctr128LE::<aes::Aes192>::new(key.into(), &iv.into())),
+            _ => Box::new(ctr::Ctr128LE::<aes::Aes256>::new(key.into(), &iv.into())),
+        };
+        let mac = Hmac::<Sha1>::new_from_slice(&keys[key_len..key_len * 2])
+            .expect("hmac accepts keys of any size");
+        Ok(AesReader {
+            rdr,
+            cipher,
+            mac,
+            remaining: size - overhead,
+            done: false,
+        })
+    }
+}
+
+impl<R: Read> Read for AesReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            if !self.done {
+                self.done = true;
+                let mut code = [0; AES_AUTH_CODE_LEN as usize];
+                self.rdr.read_exact(&mut code)?;
+                let expected = self.mac.clone().finalize().into_bytes();
+                if expected[..code.len()] != code {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "AES authentication code mismatch",
+                    ));
+                }
+            }
+            return Ok(0);
+        }
+        let len = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = self.rdr.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.mac.update(&buf[..n]);
+        self.cipher.apply_keystream(&mut buf[..n]);
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Opens an entry for reading, decrypting it with the password if needed.
+///
+/// ZipCrypto entries are decrypted by the zip crate.  AES encrypted and
+/// deflate64 entries are opened raw and have to be read through
+/// `entry_reader`.
+fn open_entry<'a>(
+    rdr: &'a mut ZipArchiveReader<BufReader<VolumeReader>>,
+    idx: usize,
+    password: Option<&str>,
+) -> Result<ZipFile<'a>, UnboxError> {
+    let raw = {
+        let file = rdr.by_index_raw(idx)?;
+        let method = match aes_info(&file) {
+            Some(_) if password.is_none() => bail!("archive is encrypted, pass --password"),
+            Some(aes) => aes.method,
+            #[allow(deprecated)]
+            None => file.compression().to_u16(),
+        };
+        if method == DEFLATE64_METHOD && !cfg!(feature = "deflate64") {
+            bail!(
+                "unsupported compression method {} in entry {}",
+                DEFLATE64_METHOD,
+                file.name()
+            );
+        }
+        aes_info(&file).is_some() || is_deflate64(&file)
+    };
+    if raw {
+        return Ok(rdr.by_index_raw(idx)?);
+    }
+    match password {
+        Some(password) => match rdr.by_index_decrypt(idx, password.as_bytes())? {
+            Ok(file) => Ok(file),
+            Err(_) => bail!("invalid password"),
+        },
+        None => match rdr.by_index(idx) {
+            Err(ZipError::UnsupportedArchive(msg)) if msg == ZipError::PASSWORD_REQUIRED => {
+                bail!("archive is encrypted, pass --password")
+            }
+            other => Ok(other?),
+        },
+    }
+}
+
+/// Returns a reader for the contents of an entry opened by `open_entry`.
+fn entry_reader<'a>(
+    file: ZipFile<'a>,
+    password: Option<&str>,
+) -> Result<Box<dyn Read + 'a>, UnboxError> {
+    let aes = match aes_info(&file) {
+        Some(aes) => aes,
+        None if is_deflate64(&file) => return Ok(decompress_raw(Box::new(file), DEFLATE64_METHOD)),
+        None => return Ok(Box::new(file)),
+    };
+    let size = file.compressed_size();
+    let password = password.unwrap_or_default();
+    let rdr = AesReader::new(file, password, aes.strength, size)?;
+    match aes.method {
+        0 | 8 | DEFLATE64_METHOD => Ok(decompress_raw(Box::new(rdr), aes.method)),
+        method => bail!("unsupported compression method {} in AES entry", method),
+    }
+}
+
+/// Decompresses raw stored, deflate or deflate64 data.
+fn decompress_raw<'a>(rdr: Box<dyn Read + 'a>, method: u16) -> Box<dyn Read + 'a> {
+    match method {
+        8 => Box::new(deflate::Decoder::new(rdr)),
+        #[cfg(feature = "deflate64")]
+        DEFLATE64_METHOD => Box::new(deflate64::Deflate64Decoder::new(rdr)),
+        _ => rdr,
+    }
+}
+
+/// Unpacks the entry at the given index.
+/// Computes the crc32 of the data read from an entry.
+struct Crc32Read<R> {
+    rdr: R,
+    hasher: crc32fast::Hasher,
+    len: u64,
+}
+
+impl<R: Read> Crc32Read<R> {
+    fn new(rdr: R) -> Crc32Read<R> {
+        Crc32Read {
+            rdr,
+            hasher: crc32fast::Hasher::new(),
+            len: 0,
+        }
+    }
+
+    /// Compares the data read against the size and checksum stored in the
+    /// archive.  Entries which were not read to the end are not checked.
+    fn verify(self, size: u64, crc: u32) -> Result<(), UnboxError> {
+        if self.len != size {
+            return Ok(());
+        }
+        let found = self.hasher.finalize();
+        if found != crc {
+            bail!("crc32 mismatch (expected {:08x}, found {:08x})", crc, found);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Crc32Read<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.rdr.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+fn unpack_entry(
+    rdr: &mut ZipArchiveReader<BufReader<VolumeReader>>,
+    idx: usize,
+    encoding: NameEncoding,
+    helper: &UnpackHelper,
+) -> Result<(), UnboxError> {
+    let file = open_entry(rdr, idx, helper.password())?;
+    let name = decode_name(&file, encoding);
+    let path = entry_path(&name);
+    let rv = if is_dir(&file, &name) {
+        let metadata = EntryMetadata {
+            mode: None,
+            owner: None,
+            mtime: entry_mtime(&file),
+        };
+        helper.create_dir_with_metadata(&path, metadata)
+    } else if is_symlink(&file) {
+        let mut target = String::new();
+        entry_reader(file, helper.password())?
+            .take(4096)
+            .read_to_string(&mut target)?;
+        helper.create_symlink(&path, &target)
+    } else {
+        let metadata = EntryMetadata {
+            mode: file.unix_mode(),
+            owner: None,
+            mtime: entry_mtime(&file),
+        };
+        // the zip crate fails on a bad checksum too but without saying
+        // which checksum it expected.  It does not check deflate64 and AES
+        // entries as it only reads them raw.  AE-2 entries have no checksum,
+        // the authentication code covers them instead.
+        let aes = aes_info(&file);
+        if helper.is_testing() || is_deflate64(&file) || aes.is_some() {
+            let (size, crc) = (file.size(), file.crc32());
+            let mut rdr = Crc32Read::new(entry_reader(file, helper.password())?);
+            let rv = helper.write_file_with_metadata(&path, &mut rdr, metadata);
+            match aes {
+                Some(aes) if aes.version == 2 => rv,
+                _ => rdr.verify(size, crc).and(rv),
+            }
+        } else {
+            helper.write_file_with_metadata(&path, file, metadata)
+        }
+    };
+    rv.map_err(|err| helper.entry_error(&path, err))
 }
 
 impl Archive for ZipArchive {
@@ -34,23 +940,1221 @@ impl Archive for ZipArchive {
         &self.path
     }
 
+    fn volumes(&self) -> Vec<PathBuf> {
+        self.volumes.clone()
+    }
+
     fn total_size(&self) -> Option<u64> {
         Some(self.total_size)
     }
 
-    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), Error> {
+    #[cfg(feature = "signatures")]
+    fn verify_signature(&mut self) -> Result<String, UnboxError> {
+        super::signature::verify_jar(&mut self.rdr)
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        Some(self.rdr.len() as u64)
+    }
+
+    fn unpack(&mut self, helper: &mut UnpackHelper) -> Result<(), UnboxError> {
+        let encoding = name_encoding(&mut self.rdr, helper.filename_encoding())?;
+        let mut files = vec![];
         for idx in 0..self.rdr.len() {
-            let file = self.rdr.by_index(idx)?;
-            let name = file.sanitized_name();
-            if file.unix_mode().unwrap_or(0) & 16384 == 0 && !file.name().ends_with("/") {
-                helper.write_file_with_progress(name, file)?;
-            } else {
-                let path = helper.path().join(name);
-                if !path.exists() {
-                    create_dir_all(&path)?;
+            if helper.skip_entry() {
+                continue;
+            }
+            // directories and symlinks are created upfront, the files
+            // are then unpacked on the thread pool.
+            if helper.is_parallel() && !self.from_reader {
+                let file = self.rdr.by_index_raw(idx)?;
+                let name = decode_name(&file, encoding);
+                if !is_dir(&file, &name) && !is_symlink(&file) {
+                    files.push(idx);
+                    continue;
                 }
             }
+            unpack_entry(&mut self.rdr, idx, encoding, helper)?;
         }
-        Ok(())
+
+        let path = &self.path;
+        let helper = &*helper;
+        helper.for_each_parallel(
+            files,
+            || {
+                Ok(ZipArchiveReader::new(BufReader::new(VolumeReader::open(
+                    path,
+                )?))?)
+            },
+            |rdr, idx| unpack_entry(rdr, idx, encoding, helper),
+        )
+    }
+
+    fn entries(&mut self) -> Result<Vec<ArchiveEntry>, UnboxError> {
+        let encoding = name_encoding(&mut self.rdr, None)?;
+        let mut rv = vec![];
+        for idx in 0..self.rdr.len() {
+            let file = self.rdr.by_index_raw(idx)?;
+            let name = decode_name(&file, encoding);
+            let kind = if is_dir(&file, &name) {
+                EntryKind::Directory
+            } else if is_symlink(&file) {
+                EntryKind::Other
+            } else {
+                EntryKind::File
+            };
+            let mut entry = ArchiveEntry::new(name, file.size(), kind);
+            entry.compression = Some(compression_name(&file));
+            entry.compressed_size = Some(file.compressed_size());
+            entry.crc32 = Some(file.crc32());
+            entry.mtime = entry_mtime(&file);
+            entry.comment = Some(file.comment().to_string()).filter(|x| !x.is_empty());
+            entry.unicode_path = extra_fields(&file).any(|(id, _)| id == UNICODE_PATH);
+            rv.push(entry);
+        }
+        Ok(rv)
+    }
+}
+
+#[test]
+fn test_dirs_only() {
+    use std::fs;
+    use std::io::Write;
+
+    use zip::write::{FileOptions, ZipWriter};
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("scaffold.zip");
+    let mut w = ZipWriter::new(File::create(&archive_path).unwrap());
+    w.add_directory("empty/", FileOptions::default()).unwrap();
+    w.start_file("a/b/file.txt", FileOptions::default())
+        .unwrap();
+    w.write_all(b"hello").unwrap();
+    w.start_file("top.txt", FileOptions::default()).unwrap();
+    w.write_all(b"world").unwrap();
+    w.finish().unwrap();
+
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    let options = UnpackOptions {
+        dirs_only: true,
+        ..UnpackOptions::default()
+    };
+    let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+
+    assert!(rv.join("empty").is_dir());
+    assert!(rv.join("a/b").is_dir());
+    assert!(!rv.join("a/b/file.txt").exists());
+    assert!(!rv.join("top.txt").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_empty_dirs() {
+    use std::fs;
+    use std::io::Write;
+
+    use zip::write::{FileOptions, ZipWriter};
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("layout.zip");
+    let mut w = ZipWriter::new(File::create(&archive_path).unwrap());
+    w.add_directory("empty/", FileOptions::default()).unwrap();
+    w.add_directory("nested/deeper/", FileOptions::default())
+        .unwrap();
+    w.start_file("readme.txt", FileOptions::default()).unwrap();
+    w.write_all(b"hello").unwrap();
+    w.finish().unwrap();
+
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+
+    assert!(rv.join("empty").is_dir());
+    assert!(rv.join("nested/deeper").is_dir());
+    assert_eq!(fs::read(rv.join("readme.txt")).unwrap(), b"hello");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Builds a zip file with stored entries.
+///
+/// Names ending in a slash are added as directories.
+#[cfg(test)]
+pub fn build_zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+    use std::io::{Cursor, Write};
+
+    use zip::write::{FileOptions, ZipWriter};
+    use zip::CompressionMethod;
+
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let mut w = ZipWriter::new(Cursor::new(vec![]));
+    for &(name, data) in entries {
+        if name.ends_with('/') {
+            w.add_directory(name, options).unwrap();
+        } else {
+            w.start_file(name, options).unwrap();
+            w.write_all(data).unwrap();
+        }
+    }
+    w.finish().unwrap().into_inner()
+}
+
+/// Builds a zip file with stored entries and raw (non UTF-8 flagged) names.
+///
+/// This is for the tests that need names in other encodings or patch the
+/// headers by hand.
+#[cfg(test)]
+fn build_raw_zip(entries: &[(&[u8], &[u8])]) -> Vec<u8> {
+    let mut rv = vec![];
+    let mut central = vec![];
+    for &(name, data) in entries {
+        let offset = rv.len() as u32;
+        let mut fixed = vec![];
+        fixed.extend(&[20, 0, 0, 0, 0, 0, 0, 0, 0x21, 0]);
+        fixed.extend(&crc32fast::hash(data).to_le_bytes());
+        fixed.extend(&(data.len() as u32).to_le_bytes());
+        fixed.extend(&(data.len() as u32).to_le_bytes());
+        fixed.extend(&(name.len() as u16).to_le_bytes());
+        fixed.extend(&[0, 0]);
+
+        rv.extend(b"PK\x03\x04");
+        rv.extend(&fixed);
+        rv.extend(name);
+        rv.extend(data);
+
+        central.extend(b"PK\x01\x02");
+        central.extend(&[20, 0]);
+        central.extend(&fixed);
+        central.extend(&[0; 10]);
+        central.extend(&offset.to_le_bytes());
+        central.extend(name);
+    }
+
+    let central_offset = rv.len() as u32;
+    rv.extend(&central);
+    rv.extend(EOCD_MAGIC);
+    rv.extend(&[0, 0, 0, 0]);
+    rv.extend(&(entries.len() as u16).to_le_bytes());
+    rv.extend(&(entries.len() as u16).to_le_bytes());
+    rv.extend(&(central.len() as u32).to_le_bytes());
+    rv.extend(&central_offset.to_le_bytes());
+    rv.extend(&[0, 0]);
+    rv
+}
+
+/// Splits a zip file into volumes of the given size like `zip -s` does.
+#[cfg(test)]
+fn split_zip(zip: &[u8], volume_size: usize) -> Vec<Vec<u8>> {
+    let mut data = b"PK\x07\x08".to_vec();
+    data.extend(zip);
+    let eocd = data.len() - EOCD_SIZE;
+    let last_disk = eocd / volume_size;
+    let locate = |offset: usize| {
+        let disk = (offset / volume_size).min(last_disk);
+        (disk as u16, (offset - disk * volume_size) as u32)
+    };
+    let dword = |data: &[u8], idx: usize| {
+        u32::from_le_bytes([data[idx], data[idx + 1], data[idx + 2], data[idx + 3]]) as usize
+    };
+    let directory_start = dword(&data, eocd + 16) + 4;
+    let mut pos = directory_start;
+    while &data[pos..pos + 4] == CENTRAL_HEADER_MAGIC {
+        let (disk, offset) = locate(dword(&data, pos + 42) + 4);
+        data[pos + 34..pos + 36].copy_from_slice(&disk.to_le_bytes());
+        data[pos + 42..pos + 46].copy_from_slice(&offset.to_le_bytes());
+        let word = |idx: usize| usize::from(u16::from_le_bytes([data[idx], data[idx + 1]]));
+        pos += CENTRAL_HEADER_SIZE + word(pos + 28) + word(pos + 30) + word(pos + 32);
+    }
+    let (disk, offset) = locate(directory_start);
+    data[eocd + 4..eocd + 6].copy_from_slice(&(last_disk as u16).to_le_bytes());
+    data[eocd + 6..eocd + 8].copy_from_slice(&disk.to_le_bytes());
+    data[eocd + 16..eocd + 20].copy_from_slice(&offset.to_le_bytes());
+    let mut volumes: Vec<_> = data[..last_disk * volume_size]
+        .chunks(volume_size)
+        .map(|x| x.to_vec())
+        .collect();
+    volumes.push(data[last_disk * volume_size..].to_vec());
+    volumes
+}
+
+#[test]
+fn test_detect_name_encoding() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let names = ["日本語のファイル名.txt", "テキスト文書のサンプル.txt"];
+    let encoded: Vec<_> = names
+        .iter()
+        .map(|name| encoding_rs::SHIFT_JIS.encode(name).0.into_owned())
+        .collect();
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("regional.zip");
+    fs::write(
+        &archive_path,
+        build_raw_zip(&[(&encoded[0], b"hello"), (&encoded[1], b"world")]),
+    )
+    .unwrap();
+
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+
+    assert_eq!(fs::read(rv.join(names[0])).unwrap(), b"hello");
+    assert_eq!(fs::read(rv.join(names[1])).unwrap(), b"world");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cp437_names() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir().canonicalize().unwrap();
+    let archive_path = dir.join("windows.zip");
+    // names without the UTF-8 flag, the last one is UTF-8 nonetheless
+    fs::write(
+        &archive_path,
+        build_raw_zip(&[
+            (b"Bilder/caf\x82.txt", b"a"),
+            (b"Bilder/\x9abersicht.txt", b"b"),
+            ("Bilder/größe.txt".as_bytes(), b"c"),
+        ]),
+    )
+    .unwrap();
+
+    let unpack = |encoding: Option<&str>| {
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        let options = UnpackOptions {
+            filename_encoding: encoding.map(|x| x.to_string()),
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        helper.commit().unwrap()
+    };
+    let names = |path: &Path| {
+        let mut rv: Vec<_> = fs::read_dir(path)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect();
+        rv.sort();
+        rv
+    };
+
+    let rv = unpack(None);
+    assert_eq!(names(&rv), vec!["café.txt", "größe.txt", "Übersicht.txt"]);
+    fs::remove_dir_all(&rv).unwrap();
+
+    // with an explicit encoding all unflagged names are decoded with it
+    let rv = unpack(Some("CP437"));
+    assert_eq!(names(&rv), vec!["café.txt", "gr├╢├ƒe.txt", "Übersicht.txt"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_into_archive_name() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let single = dir.join("single.zip");
+    fs::write(&single, build_zip(&[("inner/a.txt", b"a")])).unwrap();
+    let multi = dir.join("multi.zip");
+    fs::write(&multi, build_zip(&[("a.txt", b"a"), ("b.txt", b"b")])).unwrap();
+
+    let unpack = |path: &Path| {
+        let mut archive = ZipArchive::open(path).unwrap();
+        let options = UnpackOptions {
+            into_archive_name: true,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        helper.commit().unwrap()
+    };
+
+    let rv = unpack(&single);
+    assert_eq!(rv.file_name().unwrap(), "single");
+    assert_eq!(fs::read(rv.join("inner/a.txt")).unwrap(), b"a");
+
+    let rv = unpack(&multi);
+    assert_eq!(rv.file_name().unwrap(), "multi");
+    assert_eq!(fs::read(rv.join("a.txt")).unwrap(), b"a");
+    assert_eq!(fs::read(rv.join("b.txt")).unwrap(), b"b");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_unwrap_contents() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let multi = dir.join("multi.zip");
+    fs::write(&multi, build_zip(&[("a.txt", b"a"), ("b/c.txt", b"c")])).unwrap();
+
+    let unpack = |dry_run: bool| {
+        let mut archive = ZipArchive::open(&multi).unwrap();
+        let options = UnpackOptions {
+            unwrap_contents: true,
+            dry_run,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        helper.commit().unwrap()
+    };
+
+    // the items are moved straight into the destination
+    assert_eq!(unpack(true), dir.canonicalize().unwrap());
+    assert_eq!(unpack(false), dir.canonicalize().unwrap());
+    assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"a");
+    assert_eq!(fs::read(dir.join("b/c.txt")).unwrap(), b"c");
+
+    // but not if any of them would be in the way
+    let rv = unpack(false);
+    assert_eq!(rv.file_name().unwrap(), "multi");
+    assert_eq!(fs::read(rv.join("a.txt")).unwrap(), b"a");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_refined_label() {
+    use std::fs;
+
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let epub = dir.join("book.epub");
+    fs::write(
+        &epub,
+        build_zip(&[
+            ("mimetype", b"application/epub+zip"),
+            ("META-INF/container.xml", b"<container/>"),
+        ]),
+    )
+    .unwrap();
+    let docx = dir.join("letter.docx");
+    fs::write(
+        &docx,
+        build_zip(&[
+            (
+                "[Content_Types].xml",
+                b"<Types><Override ContentType=\"application/vnd.openxmlformats-\
+                  officedocument.wordprocessingml.document.main+xml\"/></Types>",
+            ),
+            ("word/document.xml", b"<document/>"),
+        ]),
+    )
+    .unwrap();
+    let plain = dir.join("plain.zip");
+    fs::write(&plain, build_zip(&[("a.txt", b"a")])).unwrap();
+
+    assert_eq!(ArchiveType::Zip.label(&epub), "epub ebook");
+    assert_eq!(ArchiveType::Zip.label(&docx), "word document");
+    assert_eq!(ArchiveType::Zip.label(&plain), "zip archive");
+
+    // opendocument files are recognized by their mimetype and refined by
+    // the marker entry like the other zip based formats
+    let odt = dir.join("document.odt");
+    fs::write(
+        &odt,
+        build_zip(&[
+            ("mimetype", b"application/vnd.oasis.opendocument.text"),
+            ("content.xml", b"<document/>"),
+        ]),
+    )
+    .unwrap();
+    let bytes = fs::read(&odt).unwrap();
+    assert_eq!(
+        crate::formats::detailed_mimetype(&bytes),
+        "application/vnd.oasis.opendocument.text"
+    );
+    assert_eq!(ArchiveType::for_path(&odt), Some(ArchiveType::Zip));
+    assert_eq!(ArchiveType::Zip.label(&odt), "opendocument text");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_encrypted() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    // created with `zip -P hunter2` which uses ZipCrypto
+    let dir = make_test_dir();
+    let archive_path = dir.join("secret.zip");
+    fs::write(
+        &archive_path,
+        &include_bytes!("../../tests/fixtures/encrypted.zip")[..],
+    )
+    .unwrap();
+
+    let unpack = |password: Option<&str>| {
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        let options = UnpackOptions {
+            password: password.map(|x| x.to_string()),
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper)?;
+        helper.commit()
+    };
+
+    let err = unpack(None).unwrap_err();
+    assert_eq!(err.to_string(), "archive is encrypted, pass --password");
+    let err = unpack(Some("wrong")).unwrap_err();
+    assert_eq!(err.to_string(), "invalid password");
+
+    let rv = unpack(Some("hunter2")).unwrap();
+    assert_eq!(rv, dir.join("docs"));
+    assert_eq!(fs::read(rv.join("readme.txt")).unwrap(), b"hello world");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_aes_encrypted() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    // readme.txt is stored with AES-256 as AE-2, lorem.txt is deflated
+    // with AES-128 as AE-1
+    let dir = make_test_dir();
+    let archive_path = dir.join("aes.zip");
+    let data = &include_bytes!("../../tests/fixtures/aes.zip")[..];
+    fs::write(&archive_path, data).unwrap();
+
+    let unpack = |password: Option<&str>| {
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        let options = UnpackOptions {
+            password: password.map(|x| x.to_string()),
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper)?;
+        helper.commit()
+    };
+
+    let err = unpack(None).unwrap_err();
+    assert_eq!(err.to_string(), "archive is encrypted, pass --password");
+    let err = unpack(Some("wrong")).unwrap_err();
+    assert_eq!(err.to_string(), "invalid password");
+
+    let rv = unpack(Some("hunter2")).unwrap();
+    assert_eq!(rv, dir.join("docs"));
+    assert_eq!(fs::read(rv.join("readme.txt")).unwrap(), b"hello world");
+    assert_eq!(
+        fs::read_to_string(rv.join("lorem.txt")).unwrap(),
+        "lorem ipsum dolor sit amet ".repeat(20)
+    );
+    fs::remove_dir_all(&rv).unwrap();
+
+    // tampering with the encrypted data fails the authentication code
+    let mut tampered = data.to_vec();
+    let name = b"docs/readme.txt";
+    let pos = tampered
+        .windows(name.len())
+        .position(|x| x == name)
+        .unwrap();
+    // skip the name, the extra fields, the salt and the password verifier
+    let extra_len = usize::from(u16::from_le_bytes([tampered[pos - 2], tampered[pos - 1]]));
+    tampered[pos + name.len() + extra_len + 16 + 2] ^= 1;
+    fs::write(&archive_path, tampered).unwrap();
+    let err = unpack(Some("hunter2")).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "failed to extract entry 'docs/readme.txt' from aes.zip"
+    );
+    assert_eq!(
+        std::error::Error::source(&err).unwrap().to_string(),
+        "AES authentication code mismatch"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_entries() {
+    use std::fs;
+
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("listing.zip");
+    fs::write(
+        &archive_path,
+        &include_bytes!("../../tests/fixtures/encrypted.zip")[..],
+    )
+    .unwrap();
+
+    // listing does not need the password.  Encrypted entries are preceded
+    // by a 12 byte header.
+    let entry = |name, size, kind, compressed_size, crc32| ArchiveEntry {
+        compression: Some("stored".into()),
+        compressed_size: Some(compressed_size),
+        crc32: Some(crc32),
+        mtime: Some(FileTime::from_unix_time(1_577_934_246, 0)),
+        ..ArchiveEntry::new(name, size, kind)
+    };
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    assert_eq!(
+        archive.entries().unwrap(),
+        vec![
+            entry("docs", 0, EntryKind::Directory, 0, 0),
+            entry(
+                "docs/readme.txt",
+                11,
+                EntryKind::File,
+                23,
+                crc32fast::hash(b"hello world")
+            ),
+        ]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_entry_comment_and_unicode_path() {
+    use std::fs;
+
+    use crate::utils::make_test_dir;
+
+    // build_raw_zip entries have neither extra fields nor comments, so
+    // patch them into the central directory entry by hand
+    let mut extra = vec![];
+    extra.extend(&UNICODE_PATH.to_le_bytes());
+    extra.extend(&(5 + "café.txt".len() as u16).to_le_bytes());
+    extra.push(1);
+    extra.extend(&crc32fast::hash(b"caf\x82.txt").to_le_bytes());
+    extra.extend("café.txt".as_bytes());
+    let comment = b"saved by an old dos tool";
+
+    let mut data = build_raw_zip(&[(b"caf\x82.txt", b"a")]);
+    let central = data.windows(4).position(|x| x == b"PK\x01\x02").unwrap();
+    data[central + 30..central + 32].copy_from_slice(&(extra.len() as u16).to_le_bytes());
+    data[central + 32..central + 34].copy_from_slice(&(comment.len() as u16).to_le_bytes());
+    let name_end = central + 46 + 8;
+    let patch: Vec<u8> = extra.iter().chain(comment.iter()).cloned().collect();
+    data.splice(name_end..name_end, patch.iter().cloned());
+    let eocd = data.len() - 22;
+    let central_size = u32::from_le_bytes([
+        data[eocd + 12],
+        data[eocd + 13],
+        data[eocd + 14],
+        data[eocd + 15],
+    ]) + patch.len() as u32;
+    data[eocd + 12..eocd + 16].copy_from_slice(&central_size.to_le_bytes());
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("annotated.zip");
+    fs::write(&archive_path, &data).unwrap();
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    let entries = archive.entries().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0].unicode_path);
+    assert_eq!(
+        entries[0].comment.as_deref(),
+        Some("saved by an old dos tool")
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_filter() {
+    use std::fs;
+
+    use crate::archive::{EntryFilter, UnpackOptions};
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("mixed.zip");
+    fs::write(
+        &archive_path,
+        build_zip(&[
+            ("docs/", b""),
+            ("docs/guide.txt", b"guide"),
+            ("notes.txt", b"notes"),
+            ("image.png", b"png"),
+        ]),
+    )
+    .unwrap();
+
+    let unpack = |include: &[&str], exclude: &[&str]| {
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        let options = UnpackOptions {
+            filter: EntryFilter::new(include, exclude).unwrap(),
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        helper.commit().unwrap()
+    };
+
+    // patterns match across folders
+    let rv = unpack(&["*.txt"], &[]);
+    assert_eq!(rv.file_name().unwrap(), "mixed");
+    assert_eq!(fs::read(rv.join("docs/guide.txt")).unwrap(), b"guide");
+    assert_eq!(fs::read(rv.join("notes.txt")).unwrap(), b"notes");
+    assert!(!rv.join("image.png").exists());
+    fs::remove_dir_all(&rv).unwrap();
+
+    // exclude wins over include
+    let rv = unpack(&["*.txt"], &["docs/*"]);
+    assert_eq!(rv.file_name().unwrap(), "notes.txt");
+    fs::remove_file(&rv).unwrap();
+
+    // nothing matching is not an error but leaves an empty folder
+    let rv = unpack(&["*.rs"], &[]);
+    assert_eq!(rv.file_name().unwrap(), "mixed");
+    assert_eq!(fs::read_dir(&rv).unwrap().count(), 0);
+
+    assert!(EntryFilter::new(&["[unclosed"], &[]).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_path_traversal() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("evil.zip");
+    fs::write(
+        &archive_path,
+        build_zip(&[
+            ("../../etc/passwd", b"root"),
+            ("/abs/path", b"abs"),
+            ("..\\windows.txt", b"windows"),
+            ("docs/../readme.txt", b"readme"),
+            ("ok.txt", b"ok"),
+        ]),
+    )
+    .unwrap();
+
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "evil");
+    assert_eq!(fs::read(rv.join("readme.txt")).unwrap(), b"readme");
+    assert_eq!(fs::read(rv.join("ok.txt")).unwrap(), b"ok");
+    assert_eq!(fs::read_dir(&rv).unwrap().count(), 2);
+    assert!(!dir.join("etc").exists());
+    assert!(!dir.join("windows.txt").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_permissions() {
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+
+    use zip::write::{FileOptions, ZipWriter};
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("tools.zip");
+    let mut w = ZipWriter::new(File::create(&archive_path).unwrap());
+    w.start_file("run.sh", FileOptions::default().unix_permissions(0o755))
+        .unwrap();
+    w.write_all(b"#!/bin/sh\n").unwrap();
+    w.start_file("frozen.txt", FileOptions::default().unix_permissions(0o444))
+        .unwrap();
+    w.write_all(b"frozen").unwrap();
+    w.finish().unwrap();
+
+    let unpack = |ignore_permissions| {
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        let options = UnpackOptions {
+            ignore_permissions,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        helper.commit().unwrap()
+    };
+    let mode = |path: PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+    let rv = unpack(false);
+    assert_eq!(mode(rv.join("run.sh")), 0o755);
+    assert_eq!(mode(rv.join("frozen.txt")), 0o444);
+    assert_eq!(fs::read(rv.join("frozen.txt")).unwrap(), b"frozen");
+
+    let rv = unpack(true);
+    assert_eq!(mode(rv.join("run.sh")) & 0o111, 0);
+    assert_ne!(mode(rv.join("frozen.txt")) & 0o200, 0);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_mtime() {
+    use std::fs;
+    use std::io::Write;
+
+    use zip::write::{FileOptions, ZipWriter};
+    use zip::DateTime;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("dated.zip");
+    let options = FileOptions::default()
+        .last_modified_time(DateTime::from_date_and_time(2001, 2, 3, 4, 5, 6).unwrap());
+    let mut w = ZipWriter::new(File::create(&archive_path).unwrap());
+    w.add_directory("docs/", options).unwrap();
+    w.start_file("docs/a.txt", options).unwrap();
+    w.write_all(b"hello").unwrap();
+    w.start_file("b.txt", options).unwrap();
+    w.write_all(b"world").unwrap();
+    w.finish().unwrap();
+
+    let unpack = |ignore_mtime| {
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        let options = UnpackOptions {
+            ignore_mtime,
+            ..UnpackOptions::default()
+        };
+        let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+        archive.unpack(&mut helper).unwrap();
+        helper.commit().unwrap()
+    };
+    let mtime = |path: PathBuf| {
+        FileTime::from_last_modification_time(&fs::metadata(path).unwrap()).unix_seconds()
+    };
+
+    let rv = unpack(false);
+    assert_eq!(mtime(rv.join("docs")), 981_173_106);
+    assert_eq!(mtime(rv.join("docs/a.txt")), 981_173_106);
+    assert_eq!(mtime(rv.join("b.txt")), 981_173_106);
+
+    let rv = unpack(true);
+    assert!(mtime(rv.join("b.txt")) > 981_173_106);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_extended_timestamp() {
+    use std::fs;
+
+    use crate::utils::make_test_dir;
+
+    // build_raw_zip entries have no extra field, so patch in a central
+    // directory entry by hand
+    let mut extra = vec![];
+    extra.extend(&EXTENDED_TIMESTAMP.to_le_bytes());
+    extra.extend(&5u16.to_le_bytes());
+    extra.push(1);
+    extra.extend(&1_275_898_150i32.to_le_bytes());
+
+    let mut data = build_raw_zip(&[(b"a.txt", b"a")]);
+    let central = data.windows(4).position(|x| x == b"PK\x01\x02").unwrap();
+    data[central + 30..central + 32].copy_from_slice(&(extra.len() as u16).to_le_bytes());
+    let name_end = central + 46 + 5;
+    data.splice(name_end..name_end, extra.iter().cloned());
+    let eocd = data.len() - 22;
+    let central_size = u32::from_le_bytes([
+        data[eocd + 12],
+        data[eocd + 13],
+        data[eocd + 14],
+        data[eocd + 15],
+    ]) + extra.len() as u32;
+    data[eocd + 12..eocd + 16].copy_from_slice(&central_size.to_le_bytes());
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("stamped.zip");
+    fs::write(&archive_path, &data).unwrap();
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    let file = archive.rdr.by_index_raw(0).unwrap();
+    assert_eq!(
+        entry_mtime(&file),
+        Some(FileTime::from_unix_time(1_275_898_150, 0))
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlinks() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    // created with `zip -y` which stores symlinks as links
+    let dir = make_test_dir();
+    let archive_path = dir.join("links.zip");
+    fs::write(
+        &archive_path,
+        &include_bytes!("../../tests/fixtures/links.zip")[..],
+    )
+    .unwrap();
+
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+
+    let link = rv.join("lib/libfoo.so");
+    assert!(fs::symlink_metadata(&link)
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert_eq!(fs::read_link(&link).unwrap(), Path::new("libfoo.so.1"));
+    assert_eq!(fs::read(&link).unwrap(), b"elf");
+    assert_eq!(fs::read(rv.join("current/libfoo.so.1")).unwrap(), b"elf");
+    assert!(fs::symlink_metadata(rv.join("escape")).is_err());
+    assert!(fs::symlink_metadata(rv.join("absolute")).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_chained_symlinks() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    // a/b points to .. so a/b/c is next to a and must not point up again
+    let dir = make_test_dir();
+    let archive_path = dir.join("chained.zip");
+    fs::write(
+        &archive_path,
+        &include_bytes!("../../tests/fixtures/chained_links.zip")[..],
+    )
+    .unwrap();
+
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert!(!dir.join("evil.txt").exists());
+    assert!(fs::symlink_metadata(rv.join("c")).unwrap().is_dir());
+    assert_eq!(fs::read(rv.join("c/evil.txt")).unwrap(), b"owned\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_parallel() {
+    use std::fs;
+    use std::io::Write;
+
+    use zip::write::{FileOptions, ZipWriter};
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("many.zip");
+    let mut w = ZipWriter::new(File::create(&archive_path).unwrap());
+    w.add_directory("empty/", FileOptions::default()).unwrap();
+    for idx in 0..64 {
+        w.start_file(
+            format!("dir{}/sub/file{}.txt", idx % 4, idx),
+            FileOptions::default(),
+        )
+        .unwrap();
+        w.write_all(format!("contents {}", idx).as_bytes()).unwrap();
+    }
+    w.finish().unwrap();
+
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    let options = UnpackOptions {
+        jobs: Some(4),
+        ..UnpackOptions::default()
+    };
+    let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+    assert!(helper.is_parallel());
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+
+    assert!(rv.join("empty").is_dir());
+    for idx in 0..64 {
+        let path = rv.join(format!("dir{}/sub/file{}.txt", idx % 4, idx));
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            format!("contents {}", idx)
+        );
     }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_find_in_executable() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    let mut exe = b"MZ".to_vec();
+    exe.resize(4096, 0);
+    assert!(!contains_zip(&exe));
+    exe.extend(build_zip(&[
+        ("hello.txt", b"hello"),
+        ("world.txt", b"world"),
+    ]));
+    exe.extend(b"\x00\x00");
+    assert_eq!(ArchiveType::for_bytes(&exe, None), Some(ArchiveType::PeZip));
+
+    let dir = make_test_dir();
+    let archive_path = dir.join("setup.exe");
+    fs::write(&archive_path, &exe).unwrap();
+    assert_eq!(
+        ArchiveType::for_path(&archive_path),
+        Some(ArchiveType::PeZip)
+    );
+
+    let mut archive = ZipArchive::find_in_executable(&archive_path).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(rv.file_name().unwrap(), "setup");
+    assert_eq!(fs::read(rv.join("hello.txt")).unwrap(), b"hello");
+    assert_eq!(fs::read(rv.join("world.txt")).unwrap(), b"world");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_zip64() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+
+    // sizes beyond 4GB are read from the zip64 extra fields.  The fixture
+    // holds 5GB of bzip2 compressed zeroes.
+    let archive_path = dir.join("large.zip");
+    fs::write(
+        &archive_path,
+        &include_bytes!("../../tests/fixtures/large.zip")[..],
+    )
+    .unwrap();
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    assert_eq!(archive.total_size(), Some(5 + (5 << 30)));
+    let sizes: Vec<_> = archive.entries().unwrap().iter().map(|x| x.size).collect();
+    assert_eq!(sizes, vec![5, 5 << 30]);
+
+    // including when appended to an executable.  This one was created with
+    // `zip -fz` which forces the zip64 format.
+    let mut exe = b"MZ".to_vec();
+    exe.resize(4096, 0);
+    exe.extend(&include_bytes!("../../tests/fixtures/zip64.zip")[..]);
+    assert!(contains_zip(&exe));
+    let archive_path = dir.join("setup.exe");
+    fs::write(&archive_path, &exe).unwrap();
+    assert_eq!(
+        ArchiveType::for_path(&archive_path),
+        Some(ArchiveType::PeZip)
+    );
+    let mut archive = ZipArchive::find_in_executable(&archive_path).unwrap();
+    let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(fs::read(rv.join("hello.txt")).unwrap(), b"hello");
+    assert_eq!(fs::read(rv.join("world.txt")).unwrap(), b"world");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_split_archive() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::formats::ArchiveType;
+    use crate::utils::make_test_dir;
+
+    let dir = make_test_dir();
+    let large: Vec<u8> = (0..1000u32).map(|x| (x % 251) as u8).collect();
+    let volumes = split_zip(
+        &build_zip(&[
+            ("a.txt", b"hello"),
+            ("dir/large.bin", &large),
+            ("dir/b.txt", b"world"),
+        ]),
+        300,
+    );
+    assert_eq!(volumes.len(), 5);
+    let archive_path = dir.join("split.zip");
+    for (idx, volume) in volumes.iter().enumerate() {
+        let path = if idx + 1 == volumes.len() {
+            archive_path.clone()
+        } else {
+            volume_path(&archive_path, idx as u16 + 1)
+        };
+        fs::write(path, volume).unwrap();
+    }
+    assert_eq!(ArchiveType::for_path(&archive_path), Some(ArchiveType::Zip));
+
+    let mut archive = ZipArchive::open(&archive_path).unwrap();
+    let mut expected_volumes: Vec<_> = (1..5).map(|x| volume_path(&archive_path, x)).collect();
+    expected_volumes.push(archive_path.clone());
+    assert_eq!(archive.volumes(), expected_volumes);
+    let options = UnpackOptions {
+        jobs: Some(2),
+        ..UnpackOptions::default()
+    };
+    let mut helper = UnpackHelper::create(&archive, &dir, options).unwrap();
+    archive.unpack(&mut helper).unwrap();
+    let rv = helper.commit().unwrap();
+    assert_eq!(fs::read(rv.join("a.txt")).unwrap(), b"hello");
+    assert_eq!(fs::read(rv.join("dir/large.bin")).unwrap(), large);
+    assert_eq!(fs::read(rv.join("dir/b.txt")).unwrap(), b"world");
+
+    // the size of the central directory is checked against the volumes
+    let mut last = volumes.last().unwrap().clone();
+    let eocd = last.len() - EOCD_SIZE;
+    last[eocd + 12..eocd + 16].copy_from_slice(&u32::MAX.to_le_bytes());
+    fs::write(&archive_path, last).unwrap();
+    let err = ZipArchive::open(&archive_path).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "invalid central directory size in split zip archive"
+    );
+
+    // missing volumes are listed
+    fs::remove_file(dir.join("split.z01")).unwrap();
+    fs::remove_file(dir.join("split.z03")).unwrap();
+    let err = ZipArchive::open(&archive_path).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "split zip archive is missing volumes: split.z01, split.z03"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_deflate64() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    // the fixture uses the deflate64 length code for a 40000 byte match
+    // and a distance beyond the 32k window of deflate.
+    let fixture = &include_bytes!("../../tests/fixtures/deflate64.zip")[..];
+    let line = b"hello from deflate64\n";
+    let mut expected: Vec<u8> = line.iter().cycle().take(40021).copied().collect();
+    expected.extend_from_within(21..121);
+
+    let dir = make_test_dir().canonicalize().unwrap();
+    let unpack = |data: &[u8]| {
+        let archive_path = dir.join("deflate64.zip");
+        fs::write(&archive_path, data).unwrap();
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+        archive.unpack(&mut helper)?;
+        helper.commit()
+    };
+
+    if cfg!(feature = "deflate64") {
+        let rv = unpack(fixture).unwrap();
+        assert_eq!(rv, dir.join("hello.txt"));
+        assert_eq!(fs::read(&rv).unwrap(), expected);
+        fs::remove_file(&rv).unwrap();
+
+        // the zip crate does not check the checksum of deflate64 entries
+        let mut corrupt = fixture.to_vec();
+        let central = fixture
+            .windows(4)
+            .position(|x| x == CENTRAL_HEADER_MAGIC)
+            .unwrap();
+        corrupt[central + 16] ^= 1;
+        let err = unpack(&corrupt).unwrap_err();
+        assert_eq!(
+            std::error::Error::source(&err).unwrap().to_string(),
+            "crc32 mismatch (expected 670fc1aa, found 670fc1ab)"
+        );
+    } else {
+        let err = unpack(fixture).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unsupported compression method 9 in entry hello.txt"
+        );
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_data_descriptor() {
+    use std::fs;
+
+    use crate::archive::UnpackOptions;
+    use crate::utils::make_test_dir;
+
+    // written by a streaming writer, so the local headers have the data
+    // descriptor flag set and neither sizes nor checksums.
+    let fixture = &include_bytes!("../../tests/fixtures/streamed.zip")[..];
+    assert_eq!(fixture[6] & 0x8, 0x8);
+    assert_eq!(&fixture[14..26], &[0; 12]);
+    let deflated = b"streamed by a writer that did not know the size\n".repeat(20);
+
+    let dir = make_test_dir().canonicalize().unwrap();
+    let unpack = |data: &[u8]| {
+        let archive_path = dir.join("streamed.zip");
+        fs::write(&archive_path, data).unwrap();
+        let mut archive = ZipArchive::open(&archive_path).unwrap();
+        assert_eq!(archive.total_size(), Some(deflated.len() as u64 + 21));
+        let mut helper = UnpackHelper::create(&archive, &dir, UnpackOptions::default()).unwrap();
+        archive.unpack(&mut helper)?;
+        helper.commit()
+    };
+
+    let rv = unpack(fixture).unwrap();
+    assert_eq!(rv, dir.join("streamed"));
+    assert_eq!(fs::read(rv.join("deflated.txt")).unwrap(), deflated);
+    assert_eq!(
+        fs::read(rv.join("stored.txt")).unwrap(),
+        b"stored without sizes\n"
+    );
+    fs::remove_dir_all(&rv).unwrap();
+
+    // the checksums from the central directory are verified
+    let mut corrupt = fixture.to_vec();
+    let central = fixture
+        .windows(4)
+        .position(|x| x == CENTRAL_HEADER_MAGIC)
+        .unwrap();
+    corrupt[central + 16] ^= 1;
+    let err = unpack(&corrupt).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "failed to extract entry 'streamed/deflated.txt' from streamed.zip"
+    );
+    assert_eq!(
+        std::error::Error::source(&err).unwrap().to_string(),
+        "Invalid checksum"
+    );
+    fs::remove_dir_all(&dir).unwrap();
 }
@@ -0,0 +1,34 @@
+//! Unpacks various types of archives.
+//!
+//! This is the library behind the `unbox` command line utility.  The type
+//! of an archive is detected with `ArchiveType` and the archive is then
+//! unpacked through an `UnpackHelper` which takes care of writing into a
+//! temporary location first:
+//!
+//! ```no_run
+//! use unbox::{ArchiveType, UnpackHelper, UnpackOptions};
+//!
+//! # fn main() -> Result<(), unbox::UnboxError> {
+//! let path = "archive.zip";
+//! let mut archive = ArchiveType::for_path(&path).unwrap().open(&path)?;
+//! let mut helper = UnpackHelper::create(&*archive, &".", UnpackOptions::default())?;
+//! archive.unpack(&mut helper)?;
+//! println!("unpacked to {}", helper.commit()?.display());
+//! # Ok(())
+//! # }
+//! ```
+
+mod archive;
+#[doc(hidden)]
+pub mod cli;
+mod error;
+mod formats;
+mod utils;
+
+pub use crate::archive::{
+    Archive, ArchiveEntry, DiskSink, EntryFilter, EntryKind, EntryMetadata, FileSink,
+    HashAlgorithm, IndicatifProgress, NoProgress, OnConflict, ProgressMode, ProgressRead,
+    ProgressReporter, UnpackHelper, UnpackOptions, UnpackStats,
+};
+pub use crate::error::UnboxError;
+pub use crate::formats::{ArchiveType, Compression, Detection};
@@ -1,16 +1,16 @@
-mod archive;
-mod cli;
-mod formats;
-mod utils;
-
 fn main() {
+    use std::error::Error;
     use std::io::Write;
 
-    if let Err(err) = crate::cli::main() {
+    env_logger::init();
+
+    if let Err(err) = unbox::cli::main() {
         let mut stderr = std::io::stderr();
         writeln!(&mut stderr, "error: {}", err).ok();
-        for cause in err.iter_causes() {
+        let mut source = err.source();
+        while let Some(cause) = source {
             writeln!(&mut stderr, "  caused by: {}", cause).ok();
+            source = cause.source();
         }
 
         if std::env::var("RUST_BACKTRACE").is_ok() {
@@ -1,9 +1,12 @@
+use std::collections::hash_map::DefaultHasher;
 use std::env;
 use std::fs;
-use std::io;
-use std::path::{Path, PathBuf};
+use std::hash::Hasher;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
 
 use lazy_static::lazy_static;
+use log::debug;
 use regex::Regex;
 use uuid::Uuid;
 
@@ -26,33 +29,418 @@ pub fn increment_string(s: &str) -> String {
     }
 }
 
-pub fn rename_resolving_conflict(src: &Path, dst: &Path) -> io::Result<PathBuf> {
+/// Inserts a conflict suffix before the extension of a file name.
+///
+/// The `{n}` placeholder in the pattern is replaced with the counter.
+/// Compound extensions such as `.tar.gz` are kept together and directory
+/// names get the suffix appended as dots in them rarely start extensions.
+pub fn apply_conflict_suffix(name: &str, pattern: &str, n: u64, is_dir: bool) -> String {
+    let suffix = pattern.replace("{n}", &n.to_string());
+    let idx = match name.rfind('.') {
+        Some(idx) if idx > 0 && !is_dir => idx,
+        _ => return format!("{}{}", name, suffix),
+    };
+    let idx = match name[..idx].len().checked_sub(4) {
+        Some(tar_idx) if tar_idx > 0 && name[tar_idx..idx].eq_ignore_ascii_case(".tar") => tar_idx,
+        _ => idx,
+    };
+    format!("{}{}{}", &name[..idx], suffix, &name[idx..])
+}
+
+/// Parses a byte size with an optional binary unit suffix like `1G`.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let s = s
+        .strip_suffix("iB")
+        .or_else(|| s.strip_suffix('B'))
+        .unwrap_or(s);
+    let (num, shift) = match s.chars().last()?.to_ascii_uppercase() {
+        'K' => (&s[..s.len() - 1], 10),
+        'M' => (&s[..s.len() - 1], 20),
+        'G' => (&s[..s.len() - 1], 30),
+        'T' => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    num.trim().parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Returns the number of days since the unix epoch for a date.
+#[cfg(any(test, feature = "lha", feature = "zip"))]
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Returns the year, month and day of a number of days since the unix
+/// epoch.  This is the inverse of `days_from_civil`.
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Checks if a relative entry path could escape the folder it's joined to.
+#[cfg(any(
+    feature = "cramfs",
+    feature = "dmg",
+    feature = "iso",
+    feature = "lha",
+    feature = "rar",
+    feature = "romfs",
+    feature = "wim",
+    feature = "xar"
+))]
+pub fn is_unsafe_path(path: &Path) -> bool {
+    path.components().any(|component| match component {
+        Component::ParentDir | Component::RootDir | Component::Prefix(..) => true,
+        Component::Normal(..) | Component::CurDir => false,
+    })
+}
+
+/// Joins a relative entry path onto a root folder.
+///
+/// The path is normalized lexically and `None` is returned if it is
+/// absolute or would escape the root folder through `..` components.
+/// Symlinks that already exist below the root are followed and `None` is
+/// also returned if one of them leads the parent of the path out of the
+/// root.
+pub fn safe_join(root: &Path, entry: &Path) -> Option<PathBuf> {
+    let mut rv = PathBuf::new();
+    for component in entry.components() {
+        match component {
+            Component::Normal(part) => rv.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !rv.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(..) => return None,
+        }
+    }
+    let rv = root.join(rv);
+    resolve_below(root, &rv).map(|_| rv)
+}
+
+/// Resolves where a path below a root folder ends up on disk.
+///
+/// The parent folders are resolved through the symlinks that exist on
+/// disk, the file name itself is kept.  Returns the resolved path relative
+/// to the root or `None` if a symlink leads out of the root or dangles.  If
+/// the root does not exist the path is taken as it is.
+fn resolve_below(root: &Path, path: &Path) -> Option<PathBuf> {
+    let rel = path.strip_prefix(root).ok()?;
+    let canonical_root = match root.canonicalize() {
+        Ok(canonical_root) => canonical_root,
+        Err(_) => return Some(rel.to_path_buf()),
+    };
+    let (parent, name) = match (rel.parent(), rel.file_name()) {
+        (Some(parent), Some(name)) => (parent, name),
+        _ => return Some(rel.to_path_buf()),
+    };
+
+    // folders that do not exist yet are created below the deepest one
+    // that exists.
+    let mut existing = parent;
+    let mut missing = vec![name];
+    loop {
+        let full = root.join(existing);
+        match full.canonicalize() {
+            Ok(resolved) => {
+                let mut rv = resolved.strip_prefix(&canonical_root).ok()?.to_path_buf();
+                rv.extend(missing.iter().rev());
+                return Some(rv);
+            }
+            // the folder might have been created by another thread since,
+            // otherwise it is a dangling symlink.
+            Err(_) if fs::symlink_metadata(&full).is_ok() => {
+                if full.canonicalize().is_err() {
+                    return None;
+                }
+            }
+            Err(_) => {
+                missing.push(existing.file_name()?);
+                existing = existing.parent()?;
+            }
+        }
+    }
+}
+
+/// Checks if a symlink at the given path below a root folder would point
+/// outside of the root.
+///
+/// The location of the link and its target are resolved through the
+/// symlinks that already exist on disk.  Absolute targets are never
+/// allowed.
+pub fn is_escaping_symlink(root: &Path, link: &Path, target: &Path) -> bool {
+    let mut resolved = match resolve_below(root, link) {
+        Some(link) => link.parent().map(Path::to_path_buf).unwrap_or_default(),
+        None => return true,
+    };
+    let canonical_root = root.canonicalize().ok();
+    for component in target.components() {
+        match component {
+            Component::Normal(part) => {
+                resolved.push(part);
+                let full = root.join(&resolved);
+                let is_symlink =
+                    fs::symlink_metadata(&full).is_ok_and(|x| x.file_type().is_symlink());
+                if let (true, Some(canonical_root)) = (is_symlink, &canonical_root) {
+                    resolved = match full.canonicalize() {
+                        Ok(x) => match x.strip_prefix(canonical_root) {
+                            Ok(x) => x.to_path_buf(),
+                            Err(_) => return true,
+                        },
+                        Err(_) => return true,
+                    };
+                }
+            }
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !resolved.pop() {
+                    return true;
+                }
+            }
+            Component::RootDir | Component::Prefix(..) => return true,
+        }
+    }
+    false
+}
+
+/// Expands arguments that look like glob patterns.
+///
+/// Shells on Windows do not expand wildcards so we do it ourselves.  An
+/// argument is only expanded if no file exists under the literal name, so
+/// that already expanded arguments on unix are left alone.  Patterns that
+/// do not match anything are passed through as-is.
+pub fn expand_globs(args: &[&str]) -> Vec<String> {
+    let mut rv = vec![];
+    for &arg in args {
+        if !Path::new(arg).exists() && arg.contains(&['*', '?', '['][..]) {
+            if let Ok(paths) = glob::glob(arg) {
+                let matches: Vec<_> = paths
+                    .filter_map(Result::ok)
+                    .map(|path| path.to_string_lossy().to_string())
+                    .collect();
+                if !matches.is_empty() {
+                    rv.extend(matches);
+                    continue;
+                }
+            }
+        }
+        rv.push(arg.to_string());
+    }
+    rv
+}
+
+/// Renames src to dst picking a new name if dst already exists.
+///
+/// New names are generated with `increment_string` unless a conflict
+/// suffix pattern is given.
+pub fn rename_resolving_conflict(
+    src: &Path,
+    dst: &Path,
+    suffix: Option<&str>,
+) -> io::Result<PathBuf> {
+    let is_dir = fs::symlink_metadata(src).is_ok_and(|x| x.is_dir());
+    let new_dst = resolve_conflict(dst, suffix, is_dir)?;
+    fs::rename(src, &new_dst)?;
+    Ok(new_dst)
+}
+
+/// Picks the name `rename_resolving_conflict` would rename to.
+pub fn resolve_conflict(dst: &Path, suffix: Option<&str>, is_dir: bool) -> io::Result<PathBuf> {
     // simple case: dst does not exist yet
     if !dst.exists() {
-        fs::rename(src, dst)?;
         return Ok(dst.to_path_buf());
     }
 
     let dst = env::current_dir()?.join(dst);
-    let parent = dst.parent().ok_or_else(|| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            "Could not determine parent for rename",
-        )
-    })?;
-    let mut basename = dst
+    let parent = dst
+        .parent()
+        .ok_or_else(|| io::Error::other("Could not determine parent for rename"))?;
+    let original_basename = dst
         .file_name()
         .map(|x| x.to_string_lossy().to_string())
         .unwrap();
-    loop {
-        let new_basename = increment_string(&basename);
+    let mut basename = original_basename.clone();
+    for counter in 2.. {
+        let new_basename = match suffix {
+            Some(pattern) => apply_conflict_suffix(&original_basename, pattern, counter, is_dir),
+            None => increment_string(&basename),
+        };
         let new_dst = parent.join(&new_basename);
         if !new_dst.exists() {
-            fs::rename(src, &new_dst)?;
+            debug!(
+                "{} already exists, using {} instead",
+                dst.display(),
+                new_dst.display()
+            );
             return Ok(new_dst);
         }
         basename = new_basename;
     }
+    unreachable!()
+}
+
+/// Flushes a directory to disk so that renames within it are durable.
+///
+/// This is a noop on platforms that cannot open directories.
+pub fn sync_dir(path: &Path) -> io::Result<()> {
+    if cfg!(unix) {
+        fs::File::open(path)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Returns `true` if the process runs as root.
+///
+/// This is always `false` on platforms without users ids.
+pub fn is_root() -> bool {
+    #[cfg(unix)]
+    {
+        unsafe { libc::geteuid() == 0 }
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+/// Recursively finds all files below a path.
+///
+/// If the path is a file itself only that file is returned.
+pub fn find_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut rv = vec![];
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            rv.extend(find_files(&entry?.path())?);
+        }
+    } else {
+        rv.push(path.to_path_buf());
+    }
+    Ok(rv)
+}
+
+/// Compares the contents of two files by streaming through both.
+pub fn files_equal(a: &Path, b: &Path) -> io::Result<bool> {
+    let (mut a, mut b) = (fs::File::open(a)?, fs::File::open(b)?);
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    let mut buf_a = [0u8; 65536];
+    let mut buf_b = [0u8; 65536];
+    loop {
+        let len = a.read(&mut buf_a)?;
+        if len == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..len])?;
+        if buf_a[..len] != buf_b[..len] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Computes a fingerprint of the contents of a file.
+///
+/// This is not a cryptographic hash but good enough to recognize a file
+/// that was seen before.
+pub fn file_fingerprint(path: &Path) -> io::Result<u64> {
+    let mut f = fs::File::open(path)?;
+    let mut hasher = DefaultHasher::new();
+    let mut buf = [0u8; 65536];
+    loop {
+        let len = f.read(&mut buf)?;
+        if len == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&buf[..len]);
+    }
+}
+
+/// Merges src into dst only replacing files whose contents differ.
+///
+/// Files that are identical are left alone so that their modification
+/// times are retained.  With `keep_newer` files modified after the ones
+/// that would replace them are left alone as well.  Whatever is not moved
+/// over stays in src.  Returns how many files were kept as they were newer
+/// and the size of the files they would have been replaced with.
+pub fn merge_into(src: &Path, dst: &Path, keep_newer: bool) -> io::Result<(u64, u64)> {
+    let src_meta = fs::symlink_metadata(src)?;
+    let dst_meta = match fs::symlink_metadata(dst) {
+        Ok(meta) => meta,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            fs::rename(src, dst)?;
+            return Ok((0, 0));
+        }
+        Err(err) => return Err(err),
+    };
+
+    let mut kept = (0, 0);
+    if src_meta.is_dir() && dst_meta.is_dir() {
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            let (files, bytes) =
+                merge_into(&entry.path(), &dst.join(entry.file_name()), keep_newer)?;
+            kept.0 += files;
+            kept.1 += bytes;
+        }
+    } else if keep_newer
+        && src_meta.is_file()
+        && dst_meta.is_file()
+        && dst_meta.modified()? > src_meta.modified()?
+    {
+        debug!("{} is newer, keeping it", dst.display());
+        kept = (1, src_meta.len());
+    } else if src_meta.is_file() && dst_meta.is_file() && files_equal(src, dst)? {
+        debug!("{} is unchanged", dst.display());
+    } else {
+        debug!("replacing {}", dst.display());
+        if dst_meta.is_dir() {
+            fs::remove_dir_all(dst)?;
+        } else if src_meta.is_dir() {
+            fs::remove_file(dst)?;
+        }
+        fs::rename(src, dst)?;
+    }
+    Ok(kept)
+}
+
+/// Moves the contents of the src folder into the dst folder.
+///
+/// Folders existing on both sides are merged, other conflicting items are
+/// renamed like `rename_resolving_conflict` does.  Returns the number of
+/// items that had to be renamed.
+pub fn merge_resolving_conflicts(src: &Path, dst: &Path, suffix: Option<&str>) -> io::Result<u64> {
+    let mut collisions = 0;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() && fs::symlink_metadata(&target).is_ok_and(|x| x.is_dir()) {
+            collisions += merge_resolving_conflicts(&entry.path(), &target, suffix)?;
+        } else if rename_resolving_conflict(&entry.path(), &target, suffix)? != target {
+            collisions += 1;
+        }
+    }
+    fs::remove_dir_all(src)?;
+    Ok(collisions)
 }
 
 /// When constructed with a path creates a temporary directory that can be
@@ -60,7 +448,6 @@ pub fn rename_resolving_conflict(src: &Path, dst: &Path) -> io::Result<PathBuf>
 #[derive(Debug)]
 pub struct TempDirectory {
     tmp: PathBuf,
-    dst: PathBuf,
 }
 
 impl TempDirectory {
@@ -75,8 +462,7 @@ impl TempDirectory {
 
         let parent = match dst.parent() {
             None => {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
+                return Err(io::Error::other(
                     "destination folder cannot be toplevel directory",
                 ));
             }
@@ -104,17 +490,25 @@ impl TempDirectory {
             && fs::remove_dir(&dummy_path).is_ok()
         {
             fs::create_dir(&tmp)?;
-            Ok(TempDirectory { tmp, dst })
+            Ok(TempDirectory { tmp })
 
         // otherwise we use a temporary folder within the destination path.
         } else {
             Ok(TempDirectory {
                 tmp: parent.join(&basename),
-                dst,
             })
         }
     }
 
+    /// Creates a temp directory that is never created on disk.
+    ///
+    /// This is used for dry runs which must not write anything.
+    pub fn unused<P: AsRef<Path>>(path: &P) -> TempDirectory {
+        TempDirectory {
+            tmp: path.as_ref().to_path_buf(),
+        }
+    }
+
     /// Returns the path to the temporary directory
     pub fn path(&self) -> &Path {
         &self.tmp
@@ -129,6 +523,141 @@ impl TempDirectory {
     }
 }
 
+impl Drop for TempDirectory {
+    fn drop(&mut self) {
+        // makes sure nothing is left behind if unpacking fails halfway.
+        // After a successful cleanup or move this does nothing.
+        fs::remove_dir_all(&self.tmp).ok();
+    }
+}
+
+/// Creates an empty scratch directory for a test.
+#[cfg(test)]
+pub fn make_test_dir() -> PathBuf {
+    let path = env::temp_dir().join(format!("unbox-test-{}", Uuid::new_v4()));
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("1024"), Some(1024));
+    assert_eq!(parse_size("1K"), Some(1024));
+    assert_eq!(parse_size("512m"), Some(512 * 1024 * 1024));
+    assert_eq!(parse_size("1G"), Some(1024 * 1024 * 1024));
+    assert_eq!(parse_size("2GiB"), Some(2 * 1024 * 1024 * 1024));
+    assert_eq!(parse_size("10B"), Some(10));
+    assert_eq!(parse_size(""), None);
+    assert_eq!(parse_size("G"), None);
+    assert_eq!(parse_size("1X"), None);
+    assert_eq!(parse_size("99999999999T"), None);
+}
+
+#[test]
+fn test_civil_from_days() {
+    assert_eq!(civil_from_days(0), (1970, 1, 1));
+    assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    for &(year, month, day) in &[(1980, 1, 1), (2024, 2, 29), (2107, 12, 31)] {
+        let days = days_from_civil(year, month, day);
+        assert_eq!(civil_from_days(days), (year, month, day));
+    }
+}
+
+#[test]
+fn test_safe_join() {
+    let root = Path::new("/tmp/unbox");
+    assert_eq!(
+        safe_join(root, Path::new("a/./b.txt")),
+        Some(root.join("a/b.txt"))
+    );
+    assert_eq!(
+        safe_join(root, Path::new("a/../b.txt")),
+        Some(root.join("b.txt"))
+    );
+    assert_eq!(safe_join(root, Path::new(".")), Some(root.to_path_buf()));
+    assert_eq!(safe_join(root, Path::new("../../etc/passwd")), None);
+    assert_eq!(safe_join(root, Path::new("a/../../b.txt")), None);
+    assert_eq!(safe_join(root, Path::new("/abs/path")), None);
+}
+
+#[test]
+fn test_is_escaping_symlink() {
+    let root = Path::new("/tmp/unbox");
+    let link = root.join("a/b/link");
+    assert!(!is_escaping_symlink(root, &link, Path::new("target")));
+    assert!(!is_escaping_symlink(root, &link, Path::new("../../x")));
+    assert!(!is_escaping_symlink(root, &link, Path::new("../c/../../x")));
+    assert!(is_escaping_symlink(root, &link, Path::new("../../../x")));
+    assert!(is_escaping_symlink(root, &link, Path::new("/etc/passwd")));
+    assert!(is_escaping_symlink(
+        root,
+        &root.join("link"),
+        Path::new("..")
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlinks_on_disk() {
+    use std::os::unix::fs::symlink;
+
+    let dir = make_test_dir();
+    let root = dir.join("root");
+    fs::create_dir_all(root.join("top/sub")).unwrap();
+    fs::create_dir_all(dir.join("outside")).unwrap();
+    symlink(dir.join("outside"), root.join("top/link")).unwrap();
+    symlink("sub", root.join("top/inside")).unwrap();
+    symlink("missing", root.join("top/dangling")).unwrap();
+    symlink("..", root.join("top/up")).unwrap();
+
+    // symlinks created by earlier entries must not lead out of the root
+    assert_eq!(safe_join(&root, Path::new("top/link/owned.txt")), None);
+    assert_eq!(safe_join(&root, Path::new("top/link/a/b.txt")), None);
+    assert_eq!(safe_join(&root, Path::new("top/dangling/x.txt")), None);
+    assert_eq!(
+        safe_join(&root, Path::new("top/inside/x.txt")),
+        Some(root.join("top/inside/x.txt"))
+    );
+    assert_eq!(
+        safe_join(&root, Path::new("top/up/new/x.txt")),
+        Some(root.join("top/up/new/x.txt"))
+    );
+    assert_eq!(
+        safe_join(&root, Path::new("top/link")),
+        Some(root.join("top/link"))
+    );
+
+    // top/up is the root, so a link next to it must not go up again
+    assert!(is_escaping_symlink(
+        &root,
+        &root.join("top/up/c"),
+        Path::new("..")
+    ));
+    assert!(!is_escaping_symlink(
+        &root,
+        &root.join("top/up/c"),
+        Path::new("top")
+    ));
+    assert!(is_escaping_symlink(
+        &root,
+        &root.join("top/link/c"),
+        Path::new("x")
+    ));
+    // targets are resolved through existing symlinks too
+    assert!(is_escaping_symlink(
+        &root,
+        &root.join("c"),
+        Path::new("top/up/..")
+    ));
+    assert!(!is_escaping_symlink(
+        &root,
+        &root.join("c"),
+        Path::new("top/inside/..")
+    ));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
 #[test]
 fn test_increment_string() {
     assert_eq!(increment_string("foo"), "foo-2");
@@ -137,3 +666,78 @@ fn test_increment_string() {
     assert_eq!(increment_string("foo-2.txt"), "foo-3.txt");
     assert_eq!(increment_string("Something (2)"), "Something (3)");
 }
+
+#[test]
+fn test_conflict_suffix() {
+    assert_eq!(apply_conflict_suffix("foo", " ({n})", 2, false), "foo (2)");
+    assert_eq!(
+        apply_conflict_suffix("foo.txt", " ({n})", 3, false),
+        "foo (3).txt"
+    );
+    assert_eq!(
+        apply_conflict_suffix("foo.txt", "_copy{n}", 2, false),
+        "foo_copy2.txt"
+    );
+    assert_eq!(
+        apply_conflict_suffix(".hidden", "-{n}", 2, false),
+        ".hidden-2"
+    );
+    assert_eq!(
+        apply_conflict_suffix("backup.tar.gz", " ({n})", 2, false),
+        "backup (2).tar.gz"
+    );
+    assert_eq!(
+        apply_conflict_suffix("backup.TAR.XZ", "-{n}", 2, false),
+        "backup-2.TAR.XZ"
+    );
+    assert_eq!(
+        apply_conflict_suffix("project-1.2", " ({n})", 2, true),
+        "project-1.2 (2)"
+    );
+    assert_eq!(
+        apply_conflict_suffix("project-1.2", " ({n})", 2, false),
+        "project-1 (2).2"
+    );
+
+    let dir = make_test_dir();
+    for _ in 0..3 {
+        fs::write(dir.join("src.txt"), b"").unwrap();
+        rename_resolving_conflict(&dir.join("src.txt"), &dir.join("foo.txt"), Some(" ({n})"))
+            .unwrap();
+    }
+    assert!(dir.join("foo.txt").exists());
+    assert!(dir.join("foo (2).txt").exists());
+    assert!(dir.join("foo (3).txt").exists());
+
+    // directories are not split at dots
+    for _ in 0..2 {
+        fs::create_dir(dir.join("src")).unwrap();
+        rename_resolving_conflict(&dir.join("src"), &dir.join("project-1.2"), Some(" ({n})"))
+            .unwrap();
+    }
+    assert!(dir.join("project-1.2 (2)").is_dir());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_expand_globs() {
+    let dir = make_test_dir();
+    for name in &["a.zip", "b.zip", "c.tar"] {
+        fs::write(dir.join(name), b"").unwrap();
+    }
+    let pattern = dir.join("*.zip").to_string_lossy().to_string();
+    let literal = dir.join("c.tar").to_string_lossy().to_string();
+    let missing = dir.join("*.rar").to_string_lossy().to_string();
+
+    let rv = expand_globs(&[&pattern, &literal, &missing]);
+    assert_eq!(
+        rv,
+        vec![
+            dir.join("a.zip").to_string_lossy().to_string(),
+            dir.join("b.zip").to_string_lossy().to_string(),
+            literal,
+            missing,
+        ]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
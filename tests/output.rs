@@ -0,0 +1,168 @@
+#![cfg(all(unix, feature = "tar", feature = "zip"))]
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[test]
+fn test_output_to_stdout() {
+    let unbox = Command::new(env!("CARGO_BIN_EXE_unbox"))
+        .arg("tests/fixtures/signed.jar")
+        .arg("-o")
+        .arg("-")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(unbox.status.success());
+
+    let mut tar = Command::new("tar")
+        .arg("-tf")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    tar.stdin.take().unwrap().write_all(&unbox.stdout).unwrap();
+    let listing = tar.wait_with_output().unwrap();
+    assert!(listing.status.success());
+
+    let mut names: Vec<_> = String::from_utf8(listing.stdout)
+        .unwrap()
+        .lines()
+        .map(|x| x.to_string())
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            "signed/",
+            "signed/META-INF",
+            "signed/META-INF/MANIFEST.MF",
+            "signed/META-INF/TEST.RSA",
+            "signed/META-INF/TEST.SF",
+            "signed/dir",
+            "signed/dir/inner.txt",
+            "signed/hello.txt",
+        ]
+    );
+}
+
+#[test]
+fn test_stdout_only_carries_data() {
+    let unbox = Command::new(env!("CARGO_BIN_EXE_unbox"))
+        .arg("tests/fixtures/signed.jar")
+        .arg("-o")
+        .arg("-")
+        .output()
+        .unwrap();
+    assert!(unbox.status.success());
+
+    // stdout is nothing but the tarball, status lines go to stderr
+    assert_eq!(unbox.stdout.len() % 512, 0);
+    assert!(unbox.stdout.ends_with(&[0; 1024]));
+    assert!(!String::from_utf8_lossy(&unbox.stdout).contains("written to stdout"));
+    let stderr = String::from_utf8(unbox.stderr).unwrap();
+    assert!(stderr.ends_with("tests/fixtures/signed.jar: written to stdout\n"));
+}
+
+#[test]
+fn test_remove() {
+    use std::fs;
+
+    let dir = std::env::temp_dir().join(format!("unbox-remove-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let archive_path = dir.join("signed.jar");
+    let unpack = |flag: &str| {
+        fs::copy("tests/fixtures/signed.jar", &archive_path).unwrap();
+        Command::new(env!("CARGO_BIN_EXE_unbox"))
+            .arg(&archive_path)
+            .arg(flag)
+            .arg("-q")
+            .arg("-C")
+            .arg(&dir)
+            .arg("--on-conflict")
+            .arg("skip")
+            .stderr(Stdio::null())
+            .status()
+            .unwrap()
+    };
+
+    assert!(unpack("--keep").success());
+    assert!(archive_path.is_file());
+    fs::remove_dir_all(dir.join("signed")).unwrap();
+
+    assert!(unpack("-r").success());
+    assert!(dir.join("signed/hello.txt").is_file());
+    assert!(!archive_path.exists());
+
+    // skipped archives are kept
+    assert!(unpack("--remove").success());
+    assert!(archive_path.is_file());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_very_verbose_detection_timing() {
+    let unbox = Command::new(env!("CARGO_BIN_EXE_unbox"))
+        .arg("tests/fixtures/signed.jar")
+        .arg("-vv")
+        .arg("--dry-run")
+        .stdout(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(unbox.status.success());
+
+    let stderr = String::from_utf8(unbox.stderr).unwrap();
+    assert!(stderr.contains("tests/fixtures/signed.jar: detected zip archive in "));
+
+    // a single -v does not print the timing
+    let unbox = Command::new(env!("CARGO_BIN_EXE_unbox"))
+        .arg("tests/fixtures/signed.jar")
+        .arg("-v")
+        .arg("--dry-run")
+        .stdout(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(unbox.status.success());
+    assert!(!String::from_utf8(unbox.stderr)
+        .unwrap()
+        .contains("detected"));
+}
+
+#[test]
+fn test_stream_tarball_from_stdin() {
+    use std::fs;
+
+    let dir = std::env::temp_dir().join(format!("unbox-stdin-{}", std::process::id()));
+    fs::create_dir_all(dir.join("src/pkg")).unwrap();
+    fs::write(dir.join("src/pkg/hello.txt"), b"hello").unwrap();
+    let tar = Command::new("tar")
+        .arg("-cf")
+        .arg("-")
+        .arg("-C")
+        .arg(dir.join("src"))
+        .arg("pkg")
+        .output()
+        .unwrap();
+    assert!(tar.status.success());
+
+    let out = dir.join("out");
+    fs::create_dir_all(&out).unwrap();
+    let mut unbox = Command::new(env!("CARGO_BIN_EXE_unbox"))
+        .arg("-")
+        .arg("--name")
+        .arg("pkg.tar")
+        .arg("-q")
+        .current_dir(&out)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    unbox.stdin.take().unwrap().write_all(&tar.stdout).unwrap();
+    assert!(unbox.wait().unwrap().success());
+
+    // the tarball is read from the stream without buffering it first
+    assert_eq!(fs::read(out.join("pkg/hello.txt")).unwrap(), b"hello");
+    assert_eq!(fs::read_dir(&out).unwrap().count(), 1);
+    fs::remove_dir_all(&dir).unwrap();
+}